@@ -0,0 +1,277 @@
+/// Self-contained pile archives.
+///
+/// `ARCHIVE <pile> <file>` packs one pile into a tarball (see tar.rs) in the
+/// directory `DUST_EXPORT_PATH` (default `exports`) on the server, and
+/// `UNARCHIVE <file> [AS <pile>]` loads one from there into a new pile, on
+/// this server or another:
+///
+/// manifest.json             {"version":1,"pile":..,"created_at":..,
+///                           "data_fmt":..,"documents":..,"tombstones":..,
+///                           "settings":{..}}
+/// <pile>/<uuid>.<data_fmt>  a document
+/// <pile>/<uuid>.tombstone   a soft deleted document
+/// <pile>/.ordered.*.jsonl   an ordered index's key file (see ordered.rs)
+///
+/// The settings are the pile's metadata (schema, indexes, triggers, ...)
+/// without its statistics, which the loaded pile counts afresh. The pile is
+/// archived under its lock, so the archive is a consistent snapshot; corrupt
+/// documents (see quarantine.rs), the recycle bin and document sidecars are
+/// left out. Loading goes through the WAL like any write, and the key files
+/// land last so they match the loaded documents.
+use crate::pile::{self, document_paths, pile_path, tombstone_paths, PileMeta};
+use crate::wal::{self, WalEntry, WalOp};
+use crate::{apply_wal_entries, env_or, is_valid_document_id, quarantine, tar, timestamp_now};
+use chrono::Utc;
+use dustcfg::get_env_var;
+use serde_json::{from_slice, json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+const ARCHIVE_VERSION: u64 = 1;
+const LOAD_BATCH_SIZE: usize = 1000;
+
+fn archive_file_path(file_name: &str) -> Result<PathBuf, io::Error> {
+    if !is_valid_document_id(file_name) {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid archive file name: \"{}\"", file_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    Ok(PathBuf::from(env_or("DUST_EXPORT_PATH", "exports".to_owned())).join(file_name))
+}
+
+/// Example:
+/// in: ARCHIVE users users.tar
+/// out: 1204
+///
+/// Returns how many documents (tombstones included) were archived
+pub fn archive(pile_name: &str, file_name: &str) -> Result<usize, io::Error> {
+    let file_path = archive_file_path(file_name)?;
+    if file_path.exists() {
+        let e_kind = io::ErrorKind::AlreadyExists;
+        let e = format!("Archive file already exists: \"{}\"", file_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_path = pile_path(pile_name)?;
+    if !Path::new(&pile_path).is_dir() {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Could not find pile: \"{}\"", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_lock = pile::lock(pile_name);
+    let guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut entries = Vec::new();
+    let mut document_count = 0;
+    for file_path in document_paths(pile_name)? {
+        let data = fs::read(&file_path)?;
+        if let Err(e) = from_slice::<Value>(&data) {
+            quarantine::report(&file_path, &e.into());
+            continue;
+        }
+        entries.push(pile_entry(pile_name, &file_path, data));
+        document_count += 1;
+    }
+
+    let mut tombstone_count = 0;
+    for file_path in tombstone_paths(pile_name)? {
+        let data = fs::read(&file_path)?;
+        entries.push(pile_entry(pile_name, &file_path, data));
+        tombstone_count += 1;
+    }
+
+    for dir_entry in fs::read_dir(&pile_path)? {
+        let file_name = dir_entry?.file_name().to_string_lossy().into_owned();
+        if is_key_file(&file_name) {
+            let file_path = Path::new(&pile_path).join(&file_name);
+            let data = fs::read(&file_path)?;
+            entries.push(pile_entry(pile_name, &file_path, data));
+        }
+    }
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    drop(guard);
+
+    let mut settings = serde_json::Map::new();
+    for key in pile_meta.keys() {
+        if key != "stats" {
+            settings.insert(
+                key.clone(),
+                pile_meta.get(&key).cloned().unwrap_or_default(),
+            );
+        }
+    }
+    let manifest = json!({
+        "version": ARCHIVE_VERSION,
+        "pile": pile_name,
+        "created_at": timestamp_now(),
+        "data_fmt": get_env_var("DUST_DATA_FMT"),
+        "documents": document_count,
+        "tombstones": tombstone_count,
+        "settings": settings,
+    });
+    entries.insert(
+        0,
+        tar::Entry {
+            path: MANIFEST_FILE_NAME.to_owned(),
+            data: manifest.to_string().into_bytes(),
+        },
+    );
+
+    // A key file is named after its field, and one whose file name is over
+    // the 100 bytes a tar header holds (past the pile's directory) is left
+    // out; the loaded pile builds that index on first use
+    entries.retain(|entry| tar::fits(&entry.path) || !is_key_file(entry_file_name(entry)));
+
+    // Written aside and renamed into place, so a half-written archive is
+    // never mistaken for one
+    let export_dir = file_path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(export_dir)?;
+    let tmp_path = export_dir.join(format!(".{}.tmp", file_name));
+    tar::write(&tmp_path, &entries, Utc::now().timestamp().max(0) as u64)?;
+    fs::rename(tmp_path, file_path)?;
+
+    Ok(document_count + tombstone_count)
+}
+
+/// Example:
+/// in: UNARCHIVE users.tar AS users_restored
+/// out: 1204
+///
+/// Loads the archive into the pile it was made from (or the one given),
+/// which must not exist yet, and returns how many documents were loaded
+pub fn unarchive(file_name: &str, as_pile_name: Option<&str>) -> Result<usize, io::Error> {
+    let file_path = archive_file_path(file_name)?;
+    let entries = match tar::read(&file_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = format!("Could not find archive file: \"{}\"", file_name);
+            return Err(io::Error::new(e_kind, e));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let manifest: Value = match entries
+        .iter()
+        .find(|entry| entry.path == MANIFEST_FILE_NAME)
+    {
+        Some(entry) => from_slice(&entry.data)?,
+        None => {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = format!("Archive has no {}: \"{}\"", MANIFEST_FILE_NAME, file_name);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+    if manifest["version"].as_u64() != Some(ARCHIVE_VERSION) {
+        let e_kind = io::ErrorKind::InvalidData;
+        let e = format!("Unsupported archive version: {}", manifest["version"]);
+        return Err(io::Error::new(e_kind, e));
+    }
+    let (Some(source_pile_name), Some(data_fmt)) =
+        (manifest["pile"].as_str(), manifest["data_fmt"].as_str())
+    else {
+        let e_kind = io::ErrorKind::InvalidData;
+        let e = format!(
+            "Archive manifest is missing its pile or data_fmt: {}",
+            manifest
+        );
+        return Err(io::Error::new(e_kind, e));
+    };
+    let pile_name = as_pile_name.unwrap_or(source_pile_name);
+
+    let target_path = pile_path(pile_name)?;
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+    if Path::new(&target_path).exists() {
+        let e_kind = io::ErrorKind::AlreadyExists;
+        let e = format!("Pile \"{}\" already exists", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    if let Some(settings) = manifest["settings"].as_object() {
+        for (key, value) in settings {
+            pile_meta.set(key, value.clone());
+        }
+    }
+    pile_meta.save()?;
+
+    let mut wal_entries = Vec::new();
+    let mut key_files = Vec::new();
+    let mut loaded_count = 0;
+    for entry in &entries {
+        let file_name = match entry.path.strip_prefix(&format!("{}/", source_pile_name)) {
+            Some(file_name) if !file_name.contains('/') => file_name,
+            _ => continue,
+        };
+        if is_key_file(file_name) {
+            key_files.push((file_name, &entry.data));
+            continue;
+        }
+
+        let (uuid, extension) = match file_name.rsplit_once('.') {
+            Some((uuid, extension)) if is_valid_document_id(uuid) => (uuid, extension),
+            _ => continue,
+        };
+        let is_tombstone = extension == pile::TOMBSTONE_EXTENSION;
+        if extension != data_fmt && !is_tombstone {
+            continue;
+        }
+
+        let data = match String::from_utf8(entry.data.clone()) {
+            Ok(data) => data,
+            Err(_) => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Archived document is not valid UTF-8: \"{}\"", entry.path);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+        wal_entries.push(WalEntry::new(WalOp::Create {
+            pile: pile_name.to_owned(),
+            uuid: uuid.to_owned(),
+            data,
+        }));
+        if is_tombstone {
+            wal_entries.push(WalEntry::new(WalOp::Tombstone {
+                pile: pile_name.to_owned(),
+                uuid: uuid.to_owned(),
+            }));
+        }
+        loaded_count += 1;
+
+        if wal_entries.len() >= LOAD_BATCH_SIZE {
+            let _applying = wal::append_all(&wal_entries)?;
+            apply_wal_entries(&wal_entries)?;
+            wal_entries.clear();
+        }
+    }
+    let _applying = wal::append_all(&wal_entries)?;
+    apply_wal_entries(&wal_entries)?;
+
+    for (file_name, data) in key_files {
+        fs::write(Path::new(&target_path).join(file_name), data)?;
+    }
+
+    Ok(loaded_count)
+}
+
+fn pile_entry(pile_name: &str, file_path: &Path, data: Vec<u8>) -> tar::Entry {
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    tar::Entry {
+        path: format!("{}/{}", pile_name, file_name),
+        data,
+    }
+}
+
+fn entry_file_name(entry: &tar::Entry) -> &str {
+    entry.path.rsplit('/').next().unwrap_or_default()
+}
+
+fn is_key_file(file_name: &str) -> bool {
+    file_name.starts_with(".ordered.") && file_name.ends_with(".jsonl")
+}
@@ -0,0 +1,254 @@
+/// Full and incremental backups of the storage root.
+///
+/// A full backup copies every pile into `<DUST_BACKUP_PATH>/<name>/data` and
+/// remembers how far into the WAL it reaches. An incremental backup archives
+/// only the WAL bytes appended since the previous backup (full or
+/// incremental), so restoring means copying the full backup back and then
+/// replaying each incremental of its chain in order.
+///
+/// A full backup runs hot, next to CREATE/UPDATE traffic. Every file is first
+/// hard linked into the backup, which takes the storage root's file set at
+/// one instant however long the copy takes (a document deleted meanwhile
+/// stays in it), and the links are then broken one file at a time by copying
+/// each over its link. Writes never change a file in place but rename a new
+/// one over it (documents, sidecars, key files and manifests alike), so they
+/// don't reach a linked file. Where the backup path is on another
+/// filesystem, files are copied straight away. The full backup also keeps
+/// the WAL bytes appended while it ran, which a restore replays on top of
+/// its data.
+///
+/// Every backup directory holds a `backup.json` manifest:
+///
+/// {"kind":"full","created_at":"...","parent":null,"wal_offset":1234}
+use crate::wal;
+use chrono::Utc;
+use dustcfg::get_env_var;
+use serde_json::{from_str, json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE_NAME: &str = "backup.json";
+const WAL_SEGMENT_FILE_NAME: &str = "wal.log";
+
+struct Manifest {
+    name: String,
+    kind: String,
+    parent: Option<String>,
+    wal_offset: u64,
+}
+
+/// Everything needed to restore a backup: the full backup's data directory
+/// followed by the WAL segments of the incrementals layered on top of it
+pub struct RestoreChain {
+    pub data_dir: PathBuf,
+    pub wal_segments: Vec<PathBuf>,
+}
+
+/// Example:
+/// in: BACKUP FULL
+/// out: 20230401T120000123Z-full
+pub fn create_full() -> Result<String, io::Error> {
+    let name = format!("{}-full", Utc::now().format("%Y%m%dT%H%M%S%3fZ"));
+    let backup_dir = backup_root().join(&name);
+
+    // Take the WAL offset *before* copying: anything written while the copy
+    // runs is kept with the backup, and replaying it is idempotent. Writes
+    // already in the WAL but not yet applied may miss the copy, so the
+    // segment starts at the oldest of them.
+    let started_wal_offset = wal::replay_offset()?;
+
+    let mut linked_paths = Vec::new();
+    link_dir_all(
+        Path::new(&get_env_var("DUST_DATA_STORAGE_PATH")),
+        &backup_dir.join("data"),
+        &mut linked_paths,
+    )?;
+    for linked_path in linked_paths {
+        unlink_file(&linked_path)?;
+    }
+
+    let (wal_segment, wal_offset) = wal::read_since(started_wal_offset)?;
+    fs::write(backup_dir.join(WAL_SEGMENT_FILE_NAME), wal_segment)?;
+
+    write_manifest(&backup_dir, "full", None, wal_offset)?;
+
+    Ok(name)
+}
+
+/// Example:
+/// in: BACKUP INCREMENTAL
+/// out: 20230401T130000123Z-incremental
+pub fn create_incremental() -> Result<String, io::Error> {
+    let parent = match latest_manifest()? {
+        Some(parent) => parent,
+        None => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = "No full backup to base an incremental backup on".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let name = format!("{}-incremental", Utc::now().format("%Y%m%dT%H%M%S%3fZ"));
+    let backup_dir = backup_root().join(&name);
+
+    let (wal_segment, wal_offset) = wal::read_since(parent.wal_offset)?;
+    fs::create_dir_all(&backup_dir)?;
+    fs::write(backup_dir.join(WAL_SEGMENT_FILE_NAME), wal_segment)?;
+
+    write_manifest(&backup_dir, "incremental", Some(&parent.name), wal_offset)?;
+
+    Ok(name)
+}
+
+/// Walks from the named backup back to the full backup it is based on
+pub fn resolve_chain(name: &str) -> Result<RestoreChain, io::Error> {
+    let mut wal_segments = Vec::new();
+    let mut manifest = read_manifest(name)?;
+
+    while manifest.kind == "incremental" {
+        wal_segments.push(
+            backup_root()
+                .join(&manifest.name)
+                .join(WAL_SEGMENT_FILE_NAME),
+        );
+
+        manifest = match manifest.parent {
+            Some(ref parent) => read_manifest(parent)?,
+            None => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Incremental backup \"{}\" has no parent", manifest.name);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+    }
+
+    // Full backups made before they ran hot have no WAL segment
+    let full_wal_segment = backup_root()
+        .join(&manifest.name)
+        .join(WAL_SEGMENT_FILE_NAME);
+    if full_wal_segment.is_file() {
+        wal_segments.push(full_wal_segment);
+    }
+
+    wal_segments.reverse();
+
+    Ok(RestoreChain {
+        data_dir: backup_root().join(&manifest.name).join("data"),
+        wal_segments,
+    })
+}
+
+pub fn copy_dir_all(from: &Path, to: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Mirrors the directory tree with hard links, collecting the linked files,
+/// and copies the files that can't be linked (e.g. across filesystems)
+fn link_dir_all(from: &Path, to: &Path, linked_paths: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_dir_all(&entry.path(), &target, linked_paths)?;
+        } else if fs::hard_link(entry.path(), &target).is_ok() {
+            linked_paths.push(target);
+        } else {
+            match fs::copy(entry.path(), &target) {
+                Ok(_) => (),
+                // Removed (e.g. renamed into a tombstone) since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces a linked file, which shares its data with the storage root, by a
+/// copy of its own
+fn unlink_file(file_path: &Path) -> Result<(), io::Error> {
+    let mut tmp_path = file_path.as_os_str().to_owned();
+    tmp_path.push(".backup.tmp");
+    fs::copy(file_path, &tmp_path)?;
+    fs::rename(tmp_path, file_path)
+}
+
+fn backup_root() -> PathBuf {
+    PathBuf::from(get_env_var("DUST_BACKUP_PATH"))
+}
+
+fn write_manifest(
+    backup_dir: &Path,
+    kind: &str,
+    parent: Option<&str>,
+    wal_offset: u64,
+) -> Result<(), io::Error> {
+    let manifest = json!({
+        "kind": kind,
+        "created_at": Utc::now().to_rfc3339(),
+        "parent": parent,
+        "wal_offset": wal_offset,
+    });
+
+    fs::write(backup_dir.join(MANIFEST_FILE_NAME), manifest.to_string())
+}
+
+fn read_manifest(name: &str) -> Result<Manifest, io::Error> {
+    let manifest_path = backup_root().join(name).join(MANIFEST_FILE_NAME);
+    let json_content: Value = from_str(&fs::read_to_string(manifest_path)?)?;
+
+    let kind = json_content.get("kind").and_then(Value::as_str);
+    let wal_offset = json_content.get("wal_offset").and_then(Value::as_u64);
+
+    match (kind, wal_offset) {
+        (Some(kind), Some(wal_offset)) => Ok(Manifest {
+            name: name.to_owned(),
+            kind: kind.to_owned(),
+            parent: json_content
+                .get("parent")
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+            wal_offset,
+        }),
+        _ => {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = format!("Backup \"{}\" has an invalid manifest", name);
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+/// Backup names start with their creation time, so the latest one sorts last
+fn latest_manifest() -> Result<Option<Manifest>, io::Error> {
+    let mut names = match fs::read_dir(backup_root()) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(MANIFEST_FILE_NAME).is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<String>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    names.sort();
+
+    match names.last() {
+        Some(name) => Ok(Some(read_manifest(name)?)),
+        None => Ok(None),
+    }
+}
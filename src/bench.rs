@@ -0,0 +1,237 @@
+/// Built-in benchmark client.
+///
+/// dustdb bench <addr> [--requests N] [--concurrency N] [--mix create=50,find=40,ping=10]
+///                     [--pile <name>]
+///
+/// Sends a mix of synthetic requests to a running server (one connection per
+/// request, as the protocol requires) and reports throughput plus latency
+/// percentiles per command, so storage changes can be compared run to run.
+use dustcfg::encode_utf8_to_hex;
+use futures::SinkExt;
+use rand::Rng;
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum BenchOp {
+    Create,
+    Find,
+    Ping,
+}
+
+impl BenchOp {
+    fn parse(input: &str) -> Result<BenchOp, String> {
+        match input.to_lowercase().as_str() {
+            "create" => Ok(BenchOp::Create),
+            "find" => Ok(BenchOp::Find),
+            "ping" => Ok(BenchOp::Ping),
+            other => Err(format!("Unknown bench operation: {}", other)),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            BenchOp::Create => "CREATE",
+            BenchOp::Find => "FIND",
+            BenchOp::Ping => "PING",
+        }
+    }
+}
+
+struct BenchConfig {
+    addr: String,
+    requests: usize,
+    concurrency: usize,
+    mix: Vec<(BenchOp, u32)>,
+    pile: String,
+}
+
+impl BenchConfig {
+    fn parse(args: &[String]) -> Result<BenchConfig, String> {
+        let mut config = BenchConfig {
+            addr: match args.first() {
+                Some(addr) => addr.clone(),
+                None => return Err("bench must have a server address specified".to_owned()),
+            },
+            requests: 10_000,
+            concurrency: 32,
+            mix: vec![(BenchOp::Create, 50), (BenchOp::Find, 50)],
+            pile: "bench".to_owned(),
+        };
+
+        let mut flags = args[1..].iter();
+        while let Some(flag) = flags.next() {
+            let value = match flags.next() {
+                Some(value) => value,
+                None => return Err(format!("{} must have a value", flag)),
+            };
+
+            match flag.as_str() {
+                "--requests" => config.requests = value.parse().map_err(|e| format!("{}", e))?,
+                "--concurrency" => {
+                    config.concurrency = value.parse().map_err(|e| format!("{}", e))?
+                }
+                "--mix" => {
+                    config.mix = Vec::new();
+                    for weighted_op in value.split(',') {
+                        let (op, weight) = match weighted_op.split_once('=') {
+                            Some(weighted_op) => weighted_op,
+                            None => return Err("--mix entries must look like <op>=<weight>".into()),
+                        };
+                        let weight = weight.parse().map_err(|e| format!("{}", e))?;
+                        config.mix.push((BenchOp::parse(op)?, weight));
+                    }
+                }
+                "--pile" => config.pile = value.to_lowercase(),
+                other => return Err(format!("Unknown bench flag: {}", other)),
+            }
+        }
+
+        if config.mix.iter().map(|(_, weight)| weight).sum::<u32>() == 0 {
+            return Err("--mix must have at least one operation with a weight".to_owned());
+        }
+
+        Ok(config)
+    }
+
+    fn pick_op(&self) -> BenchOp {
+        let total: u32 = self.mix.iter().map(|(_, weight)| weight).sum();
+        let mut roll = rand::thread_rng().gen_range(0..total);
+        for (op, weight) in &self.mix {
+            if roll < *weight {
+                return *op;
+            }
+            roll -= weight;
+        }
+        self.mix[0].0
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let config = Arc::new(BenchConfig::parse(args)?);
+
+    // Emails of created documents, so FINDs can look up values that exist
+    let created_emails: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let latencies: Arc<Mutex<HashMap<BenchOp, Vec<Duration>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let errors = Arc::new(Mutex::new(0usize));
+
+    let started_at = Instant::now();
+    let mut workers = Vec::new();
+    for worker in 0..config.concurrency.max(1) {
+        let config = config.clone();
+        let created_emails = created_emails.clone();
+        let latencies = latencies.clone();
+        let errors = errors.clone();
+
+        workers.push(tokio::spawn(async move {
+            let mut i = worker;
+            while i < config.requests {
+                let op = config.pick_op();
+                let command = build_command(op, &config.pile, i, &created_emails);
+
+                let request_started_at = Instant::now();
+                match send(&config.addr, &command).await {
+                    Ok(response) if response.starts_with('0') => {
+                        let latency = request_started_at.elapsed();
+                        let mut latencies = latencies.lock().unwrap_or_else(|e| e.into_inner());
+                        latencies.entry(op).or_default().push(latency);
+                    }
+                    _ => *errors.lock().unwrap_or_else(|e| e.into_inner()) += 1,
+                }
+
+                i += config.concurrency.max(1);
+            }
+        }));
+    }
+
+    for worker in workers {
+        worker.await?;
+    }
+    let elapsed = started_at.elapsed();
+
+    let latencies = latencies.lock().unwrap_or_else(|e| e.into_inner());
+    let completed: usize = latencies.values().map(Vec::len).sum();
+    println!(
+        "{} request(s) in {:.2?} ({:.0} req/s), {} error(s)",
+        completed,
+        elapsed,
+        completed as f64 / elapsed.as_secs_f64(),
+        errors.lock().unwrap_or_else(|e| e.into_inner())
+    );
+
+    for (op, _) in &config.mix {
+        if let Some(op_latencies) = latencies.get(op) {
+            let mut op_latencies = op_latencies.clone();
+            op_latencies.sort();
+            println!(
+                "{:<7} n={:<8} p50={:<10.2?} p90={:<10.2?} p99={:<10.2?} max={:.2?}",
+                op.as_str(),
+                op_latencies.len(),
+                percentile(&op_latencies, 50.0),
+                percentile(&op_latencies, 90.0),
+                percentile(&op_latencies, 99.0),
+                op_latencies.last().copied().unwrap_or_default()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn build_command(op: BenchOp, pile: &str, i: usize, created_emails: &Mutex<Vec<String>>) -> String {
+    match op {
+        BenchOp::Create => {
+            let email = format!("bench-{}-{}@example.com", i, rand::random::<u32>());
+            let document = json!({
+                "name": format!("Bench User {}", i),
+                "email": email,
+                "score": rand::thread_rng().gen_range(0..1000),
+            });
+
+            created_emails
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(email);
+            // Pinned to hex, whatever the server's default encoding
+            format!(
+                "ENCODING HEX CREATE {} {}",
+                pile,
+                encode_utf8_to_hex(&document.to_string())
+            )
+        }
+        BenchOp::Find => {
+            let created_emails = created_emails.lock().unwrap_or_else(|e| e.into_inner());
+            let email = match created_emails.len() {
+                0 => "missing@example.com".to_owned(),
+                n => created_emails[rand::thread_rng().gen_range(0..n)].clone(),
+            };
+            format!("FIND {} email {}", pile, email)
+        }
+        BenchOp::Ping => "PING".to_owned(),
+    }
+}
+
+async fn send(addr: &str, command: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut lines = Framed::new(TcpStream::connect(addr).await?, LinesCodec::new());
+    lines.send(command).await?;
+
+    match lines.next().await {
+        Some(response) => Ok(response?),
+        None => Err("Connection closed without a response".into()),
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
@@ -0,0 +1,182 @@
+/// Per-pile bloom filters over declared fields.
+///
+/// A bloom filter answers "is this value definitely absent from the field?"
+/// without touching the pile, which turns FINDs for values that don't exist
+/// (e.g. dedup checks) from a full scan into a memory lookup. Filters live in
+/// memory only: each one is built by a single scan the first time it's
+/// needed and kept up to date by every write afterwards. Deleted values are
+/// never removed, which can only cause false positives (a scan that finds
+/// nothing), never false negatives.
+///
+/// An array field's elements are recorded each on their own as well as the
+/// array as a whole, so a filter can also rule out a CONTAINS.
+use crate::cache::read_document;
+use crate::pile::{self, document_paths};
+use crate::{memory, quarantine, query};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+/// Target false positive rate of ~1%
+const BITS_PER_VALUE: usize = 10;
+const NUM_HASHES: u64 = 7;
+const MIN_CAPACITY: usize = 1024;
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn with_capacity(capacity: usize) -> BloomFilter {
+        let capacity = capacity.max(MIN_CAPACITY);
+        BloomFilter {
+            bits: vec![0; (capacity * BITS_PER_VALUE).div_ceil(64)],
+            capacity,
+            len: 0,
+        }
+    }
+
+    /// Double hashing: bit i is h1 + i * h2, for each of the filter's hashes
+    fn bit_indexes(&self, value: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let h1 = hasher.finish();
+        0x9E37_79B9_7F4A_7C15_u64.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+
+        let num_bits = (self.bits.len() * 64) as u64;
+        (0..NUM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&mut self, value: &str) {
+        for bit in self.bit_indexes(value).collect::<Vec<usize>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn might_contain(&self, value: &str) -> bool {
+        self.bit_indexes(value)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Past its capacity a filter's false positive rate climbs quickly
+    fn is_saturated(&self) -> bool {
+        self.len > self.capacity
+    }
+}
+
+fn filters() -> &'static Mutex<HashMap<(String, String), BloomFilter>> {
+    static FILTERS: OnceLock<Mutex<HashMap<(String, String), BloomFilter>>> = OnceLock::new();
+    FILTERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Values are filtered by their text, which is what an equality in a query
+/// compares against (see query.rs)
+fn filter_keys(value: &Value) -> Vec<String> {
+    let mut keys = vec![query::text_of(value)];
+    if let Value::Array(elements) = value {
+        keys.extend(elements.iter().map(query::text_of));
+    }
+
+    keys
+}
+
+/// Returns false only if no document in the pile can have `value` in
+/// `field_name`. The filter is (re)built by scanning the pile when missing.
+pub fn might_contain(pile_name: &str, field_name: &str, value: &str) -> Result<bool, io::Error> {
+    let key = (pile_name.to_owned(), field_name.to_owned());
+    if let Some(filter) = filters()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&key)
+    {
+        return Ok(filter.might_contain(value));
+    }
+
+    // Hold the pile's write lock while scanning so no write can slip in
+    // between the scan and the filter going live
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let document_paths = document_paths(pile_name)?;
+    let mut filter = BloomFilter::with_capacity(document_paths.len() * 2);
+    for file_path in document_paths {
+        let document = match read_document(&file_path) {
+            Ok(document) => document,
+            Err(e) if quarantine::is_corrupt(&e) => {
+                quarantine::report(&file_path, &e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(field_value) = document.json.get(field_name) {
+            filter_keys(field_value)
+                .iter()
+                .for_each(|key| filter.insert(key));
+        }
+    }
+
+    let might_contain = filter.might_contain(value);
+    filters()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, filter);
+    memory::enforce();
+
+    Ok(might_contain)
+}
+
+/// Adds a newly written document's values to the pile's live filters. Must be
+/// called while holding the pile's write lock.
+pub fn record(pile_name: &str, field_names: &[String], json_content: &Value) {
+    let mut filters = filters().lock().unwrap_or_else(|e| e.into_inner());
+
+    for field_name in field_names {
+        let key = (pile_name.to_owned(), field_name.clone());
+        if let Some(filter) = filters.get_mut(&key) {
+            if let Some(field_value) = json_content.get(field_name) {
+                filter_keys(field_value)
+                    .iter()
+                    .for_each(|key| filter.insert(key));
+            }
+
+            // Dropping it makes the next lookup rebuild it at a larger size
+            if filter.is_saturated() {
+                filters.remove(&key);
+            }
+        }
+    }
+}
+
+pub fn bytes() -> usize {
+    filters()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .map(BloomFilter::size)
+        .sum()
+}
+
+/// Drops every filter; each is rebuilt by a scan the next time it's needed
+pub fn clear() {
+    filters().lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// Drops the filters of piles not in `pile_names` (e.g. piles removed since),
+/// returning how many were dropped
+pub fn retain_piles(pile_names: &[String]) -> usize {
+    let mut filters = filters().lock().unwrap_or_else(|e| e.into_inner());
+    let before = filters.len();
+    filters.retain(|(pile_name, _), _| pile_names.contains(pile_name));
+    before - filters.len()
+}
@@ -0,0 +1,183 @@
+/// In-process LRU cache of parsed documents.
+///
+/// Scans would otherwise re-read and re-parse every document file on every
+/// query. The cache is shared by all connections, keyed by document file
+/// path, bounded by `DUST_CACHE_MAX_BYTES` (default 64 MiB, 0 disables it)
+/// and invalidated by every write the server makes to a document file.
+use crate::extract::extract_fields;
+use crate::{env_or, memory};
+use serde_json::{from_str, json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub struct CachedDocument {
+    /// The document exactly as stored on disk
+    pub raw: String,
+    pub json: Value,
+}
+
+impl CachedDocument {
+    /// Rough in-memory footprint: the raw text plus its parsed form
+    fn size(&self) -> usize {
+        self.raw.len() * 2
+    }
+}
+
+struct Lru {
+    entries: HashMap<String, (Arc<CachedDocument>, u64)>,
+    /// Last use tick -> key, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<Arc<CachedDocument>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (document, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, key.to_owned());
+        *last_used = tick;
+
+        Some(document.clone())
+    }
+
+    fn insert(&mut self, key: String, document: Arc<CachedDocument>) {
+        if document.size() > self.max_bytes {
+            return;
+        }
+
+        self.remove(&key);
+        self.tick += 1;
+        self.bytes += document.size();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (document, self.tick));
+
+        let max_bytes = self.max_bytes;
+        self.evict_to(max_bytes);
+    }
+
+    fn evict_to(&mut self, max_bytes: usize) {
+        while self.bytes > max_bytes {
+            match self.order.pop_first() {
+                Some((_, oldest_key)) => {
+                    if let Some((evicted, _)) = self.entries.remove(&oldest_key) {
+                        self.bytes -= evicted.size();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((document, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.bytes -= document.size();
+        }
+    }
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+fn lru() -> &'static Mutex<Lru> {
+    static LRU: OnceLock<Mutex<Lru>> = OnceLock::new();
+    LRU.get_or_init(|| {
+        Mutex::new(Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            max_bytes: env_or("DUST_CACHE_MAX_BYTES", 64 * 1024 * 1024),
+        })
+    })
+}
+
+/// Reads and parses a document, going to disk only on a cache miss
+pub fn read_document(file_path: &Path) -> Result<Arc<CachedDocument>, io::Error> {
+    let key = file_path.to_string_lossy().into_owned();
+
+    if let Some(document) = lru().lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(document);
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+
+    let raw = fs::read_to_string(file_path)?;
+    let json = from_str(&raw)?;
+    let document = Arc::new(CachedDocument { raw, json });
+
+    lru()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, document.clone());
+    memory::enforce();
+
+    Ok(document)
+}
+
+/// Fast path for scans that only look at a few fields: a cached document is
+/// used as is, otherwise only those fields are extracted from the file.
+/// Probing never fills the cache, so a scan doesn't pay for parsing documents
+/// it discards.
+pub fn probe_fields(
+    file_path: &Path,
+    field_names: &[&str],
+) -> Result<Map<String, Value>, io::Error> {
+    let key = file_path.to_string_lossy().into_owned();
+
+    if let Some(document) = lru().lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(field_names
+            .iter()
+            .filter_map(|field_name| {
+                let field_value = document.json.get(*field_name)?.clone();
+                Some((field_name.to_string(), field_value))
+            })
+            .collect());
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+
+    let raw = fs::read_to_string(file_path)?;
+    Ok(extract_fields(&raw, field_names)?)
+}
+
+/// Drops a document file from the cache; called for every write to it
+pub fn invalidate(file_path: &str) {
+    lru()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(file_path);
+}
+
+pub fn bytes() -> usize {
+    lru().lock().unwrap_or_else(|e| e.into_inner()).bytes
+}
+
+/// Evicts least recently used documents until the cache fits in `max_bytes`
+pub fn shrink_to(max_bytes: usize) {
+    lru()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .evict_to(max_bytes);
+}
+
+pub fn stats() -> Value {
+    let lru = lru().lock().unwrap_or_else(|e| e.into_inner());
+
+    json!({
+        "hits": HITS.load(Ordering::Relaxed),
+        "misses": MISSES.load(Ordering::Relaxed),
+        "entries": lru.entries.len(),
+        "bytes": lru.bytes,
+        "max_bytes": lru.max_bytes,
+    })
+}
@@ -0,0 +1,140 @@
+/// Capped piles.
+///
+/// A capped pile holds at most a number of documents and/or bytes (as
+/// stored), set with `CAPPED <pile> [DOCUMENTS <n>] [BYTES <n>]` and lifted
+/// with `CAPPED <pile> OFF`. Once a write (a new document, or an update that
+/// grows one) takes the pile over its cap, the oldest documents are deleted
+/// until it fits again, so the pile works like a ring buffer:
+///
+/// max_documents  how many documents the pile keeps
+/// max_bytes      how many bytes of documents it keeps
+///
+/// Age goes by when a document was first written, as its metadata sidecar
+/// records it (see docmeta.rs). An update writes a new file over the
+/// document, so the file's own times don't tell; they only stand in for
+/// documents without a sidecar. The documents just written are never
+/// evicted, even if they alone exceed the cap. An append-only pile can't be
+/// capped.
+use crate::docmeta;
+use crate::logging::{self, Level};
+use crate::pile::{document_paths, PileMeta};
+use crate::{delete_document, stats};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The pile's cap, if it has one
+pub struct Cap {
+    pub max_documents: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Cap {
+    pub fn of(pile_meta: &PileMeta) -> Option<Cap> {
+        let capped = pile_meta.get("capped").and_then(Value::as_object)?;
+        Some(Cap {
+            max_documents: capped.get("max_documents").and_then(Value::as_u64),
+            max_bytes: capped.get("max_bytes").and_then(Value::as_u64),
+        })
+    }
+
+    fn is_exceeded(&self, documents: u64, bytes: u64) -> bool {
+        self.max_documents.is_some_and(|max| documents > max)
+            || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// Evicts the pile's oldest documents while it's over its cap (if any),
+/// sparing `written_uuids`. Must be called with the pile's lock held, after
+/// the write landed. The write itself stands either way, so a failure here is
+/// only reported.
+pub fn enforce(pile_meta: &PileMeta, pile_name: &str, written_uuids: &[&str]) {
+    let cap = match Cap::of(pile_meta) {
+        Some(cap) => cap,
+        None => return,
+    };
+
+    match evict(&cap, pile_name, written_uuids) {
+        Ok(0) => (),
+        Ok(evicted) => logging::debug(|| {
+            format!(
+                "Evicted {} document(s) from capped pile \"{}\"",
+                evicted, pile_name
+            )
+        }),
+        Err(e) => logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Error evicting documents from capped pile \"{}\": {:?}",
+                pile_name, e
+            ),
+        ),
+    }
+}
+
+fn evict(cap: &Cap, pile_name: &str, written_uuids: &[&str]) -> Result<usize, io::Error> {
+    // The running totals tell whether the pile is over its cap without
+    // listing it, which is the common case
+    let totals = stats::get(pile_name)?;
+    let mut documents = totals["documents"].as_u64().unwrap_or(0);
+    let mut bytes = totals["bytes"].as_u64().unwrap_or(0);
+    if !cap.is_exceeded(documents, bytes) {
+        return Ok(0);
+    }
+
+    let mut candidates = Vec::new();
+    for file_path in document_paths(pile_name)? {
+        let uuid = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(uuid) if !written_uuids.contains(&uuid) => uuid.to_owned(),
+            _ => continue,
+        };
+
+        match first_written(&file_path) {
+            Ok((written_at, size)) => candidates.push((written_at, uuid, size)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    candidates.sort();
+
+    let mut evicted = 0;
+    for (_, uuid, size) in candidates {
+        if !cap.is_exceeded(documents, bytes) {
+            break;
+        }
+
+        delete_document(pile_name, &uuid)?;
+        documents = documents.saturating_sub(1);
+        bytes = bytes.saturating_sub(size);
+        evicted += 1;
+    }
+
+    Ok(evicted)
+}
+
+fn first_written(file_path: &Path) -> Result<(SystemTime, u64), io::Error> {
+    let metadata = fs::metadata(file_path)?;
+    let recorded_at = match (
+        file_path.parent().and_then(|parent| parent.to_str()),
+        file_path.file_stem().and_then(|stem| stem.to_str()),
+    ) {
+        (Some(pile_path), Some(uuid)) => docmeta::read_sidecar(pile_path, uuid)
+            .ok()
+            .flatten()
+            .and_then(|sidecar| {
+                let created_at = sidecar.get("created_at")?.as_str()?;
+                DateTime::parse_from_rfc3339(created_at).ok()
+            }),
+        _ => None,
+    };
+
+    let written_at = match (recorded_at, metadata.created()) {
+        (Some(recorded_at), _) => SystemTime::from(recorded_at.with_timezone(&Utc)),
+        (None, Ok(created)) => created,
+        (None, Err(_)) => metadata.modified()?,
+    };
+    Ok((written_at, metadata.len()))
+}
@@ -0,0 +1,85 @@
+/// Message channels, for PUBLISH and SUBSCRIBE.
+///
+/// Channels pass short messages between clients without storing anything:
+/// `PUBLISH <channel> <payload>` hands the payload to every connection
+/// currently subscribed to the channel with `SUBSCRIBE <channel>`, and
+/// answers with how many there were. A message nobody is subscribed to is
+/// dropped, and a channel exists only while someone is subscribed to it.
+///
+/// Channel names are letters, digits, `-`, `_`, `.` and `:`. Rights on a
+/// channel are granted under its name prefixed with `channel:` (e.g.
+/// `GRANT app channel:deploys WRITE`), so they never overlap a pile's; a
+/// grant on `*` covers channels too. PUBLISH needs WRITE and SUBSCRIBE needs
+/// READ.
+/// Subscribers that fall more than `DUST_CHANNEL_BUFFER` (default 1024)
+/// messages behind miss messages, and are told so.
+use crate::env_or;
+use crate::payload::Encoding;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::broadcast;
+
+const MAX_CHANNEL_NAME_LENGTH: usize = 128;
+
+fn channels() -> &'static Mutex<HashMap<String, broadcast::Sender<Arc<String>>>> {
+    static CHANNELS: OnceLock<Mutex<HashMap<String, broadcast::Sender<Arc<String>>>>> =
+        OnceLock::new();
+    CHANNELS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The name rights on `channel` are granted under.
+pub fn grant_name(channel: &str) -> String {
+    format!("channel:{}", channel)
+}
+
+pub fn check_channel_name(channel: &str) -> Result<(), io::Error> {
+    let is_valid = !channel.is_empty()
+        && channel.len() <= MAX_CHANNEL_NAME_LENGTH
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    if !is_valid {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid channel name: \"{}\"", channel);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    Ok(())
+}
+
+/// Example:
+/// in: PUBLISH deploys 7B2276657273696F6E223A22312E342E30227D
+/// out: 2
+///
+/// The payload is any text, encoded like the PUBLISH, and reaches each
+/// subscriber encoded like its SUBSCRIBE. The reply is the number of
+/// subscribers it reached.
+pub fn publish(channel: &str, payload: &str, encoding: Encoding) -> Result<usize, io::Error> {
+    check_channel_name(channel)?;
+    // Every message is pushed as one line
+    let payload = encoding.decode(payload)?;
+    if payload.contains(['\n', '\r']) {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = "Message payloads can't contain line breaks".to_owned();
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let mut channels = channels().lock().unwrap_or_else(|e| e.into_inner());
+    // Channels everyone unsubscribed from are forgotten here
+    channels.retain(|_, sender| sender.receiver_count() > 0);
+
+    match channels.get(channel) {
+        // Failing only means the last subscriber just went away
+        Some(sender) => Ok(sender.send(Arc::new(payload)).unwrap_or(0)),
+        None => Ok(0),
+    }
+}
+
+pub fn subscribe(channel: &str) -> broadcast::Receiver<Arc<String>> {
+    let mut channels = channels().lock().unwrap_or_else(|e| e.into_inner());
+    channels
+        .entry(channel.to_owned())
+        .or_insert_with(|| broadcast::channel(env_or("DUST_CHANNEL_BUFFER", 1024_usize).max(1)).0)
+        .subscribe()
+}
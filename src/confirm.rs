@@ -0,0 +1,79 @@
+/// Confirmation of mass deletes.
+///
+/// A DELETEWHERE matching more than `DUST_CONFIRM_THRESHOLD` documents
+/// (default 1000, 0 turns confirmation off) deletes nothing the first time.
+/// It fails with CONFIRMATION_REQUIRED and a token instead, and only goes
+/// ahead when repeated with the token before the predicate:
+///
+/// in: DELETEWHERE sessions expires_at < 2024-01-01
+/// out: 8 Error deleting database entries: 1204 documents would be deleted, repeat the command with TOKEN 9b1d... within 60 seconds to go ahead
+/// in: DELETEWHERE sessions TOKEN 9b1d... expires_at < 2024-01-01
+/// out: 1204
+///
+/// A token is good for one use of the same command (pile and predicate as
+/// given) within `DUST_CONFIRM_TOKEN_TTL_SECS` (default 60), so a script
+/// can't delete a pile by accident without reading the response first.
+use crate::env_or;
+use dustcfg::generate_v4_uuid;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The error an operation fails with until it's confirmed, which clients
+/// tell by its error code (see errors.rs)
+#[derive(Debug)]
+pub struct ConfirmationRequired(String);
+
+impl fmt::Display for ConfirmationRequired {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfirmationRequired {}
+
+/// Outstanding tokens, with the operation each confirms and until when
+fn tokens() -> &'static Mutex<HashMap<String, (String, Instant)>> {
+    static TOKENS: OnceLock<Mutex<HashMap<String, (String, Instant)>>> = OnceLock::new();
+    TOKENS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Lets the operation (as described by `operation`, which tells it apart
+/// from any other) go ahead if it affects few enough documents or `token`
+/// confirms it, and otherwise fails with a fresh token
+pub fn check(operation: &str, affected: usize, token: Option<&str>) -> Result<(), io::Error> {
+    let threshold: usize = env_or("DUST_CONFIRM_THRESHOLD", 1000);
+    if threshold == 0 || affected <= threshold {
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    let mut tokens = tokens().lock().unwrap_or_else(|e| e.into_inner());
+    tokens.retain(|_, (_, valid_until)| *valid_until > now);
+
+    if let Some(token) = token {
+        match tokens.remove(token) {
+            Some((confirmed, _)) if confirmed == operation => return Ok(()),
+            _ => {
+                let e_kind = io::ErrorKind::InvalidInput;
+                let e = format!("Invalid or expired confirmation token: \"{}\"", token);
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+    }
+
+    let ttl_secs: u64 = env_or("DUST_CONFIRM_TOKEN_TTL_SECS", 60);
+    let token = generate_v4_uuid();
+    tokens.insert(
+        token.clone(),
+        (operation.to_owned(), now + Duration::from_secs(ttl_secs)),
+    );
+
+    let e = ConfirmationRequired(format!(
+        "{} documents would be deleted, repeat the command with TOKEN {} within {} seconds to go ahead",
+        affected, token, ttl_secs
+    ));
+    Err(io::Error::other(e))
+}
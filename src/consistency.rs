@@ -0,0 +1,85 @@
+/// Read-your-writes across connections.
+///
+/// Every write that lands bumps the server's commit sequence number. JSON
+/// responses (see `CommandOptions`) carry the number as of the response as
+/// `commit_seq`, which for a write covers the write itself. A command
+/// prefixed with `AFTER <seq>` waits until the server has committed that far
+/// before it runs, so a client that hands the number of its write on to
+/// another connection (or service) is guaranteed to read it there, whatever
+/// caches or indexes sit in between.
+///
+/// The wait is at most `DUST_AFTER_TIMEOUT_MS` (default 5000) long, after
+/// which the command fails instead of reading stale data. Numbers start from
+/// the server's start time in microseconds, so they keep growing across
+/// restarts as long as the server commits fewer than a million writes a
+/// second on average.
+use crate::env_or;
+use chrono::Utc;
+use std::io;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+struct CommitSeq {
+    committed: Mutex<u64>,
+    advanced: Condvar,
+}
+
+fn commit_seq() -> &'static CommitSeq {
+    static COMMIT_SEQ: OnceLock<CommitSeq> = OnceLock::new();
+    COMMIT_SEQ.get_or_init(|| CommitSeq {
+        committed: Mutex::new(Utc::now().timestamp_micros().max(0) as u64),
+        advanced: Condvar::new(),
+    })
+}
+
+/// Records that a write has landed, waking up anyone waiting for it
+pub fn commit() {
+    let commit_seq = commit_seq();
+    let mut committed = commit_seq
+        .committed
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    *committed += 1;
+    commit_seq.advanced.notify_all();
+}
+
+/// The sequence number of the latest write to land
+pub fn committed() -> u64 {
+    *commit_seq()
+        .committed
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Blocks until the write numbered `seq` has landed
+pub fn wait_for(seq: u64) -> Result<(), io::Error> {
+    let timeout = Duration::from_millis(env_or("DUST_AFTER_TIMEOUT_MS", 5000));
+    let deadline = Instant::now() + timeout;
+
+    let commit_seq = commit_seq();
+    let mut committed = commit_seq
+        .committed
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    while *committed < seq {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            let e_kind = io::ErrorKind::TimedOut;
+            let e = format!(
+                "Write {} not committed within {} ms (committed up to {})",
+                seq,
+                timeout.as_millis(),
+                *committed
+            );
+            return Err(io::Error::new(e_kind, e));
+        }
+
+        committed = commit_seq
+            .advanced
+            .wait_timeout(committed, remaining)
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+/// Minimal CSV support for bulk loading documents.
+///
+/// Records follow RFC 4180: fields are comma separated, may be wrapped in
+/// double quotes, and a doubled quote inside a quoted field is a literal
+/// quote. Quoted fields may span lines.
+use chrono::{DateTime, NaiveDate};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::io;
+
+/// Type hints that can be attached to a CSV column
+#[derive(Clone, Copy)]
+pub enum FieldType {
+    Int,
+    Float,
+    Bool,
+    Date,
+    String,
+}
+
+impl FieldType {
+    fn parse(input: &str) -> Result<FieldType, io::Error> {
+        match input.to_lowercase().as_str() {
+            "int" => Ok(FieldType::Int),
+            "float" => Ok(FieldType::Float),
+            "bool" => Ok(FieldType::Bool),
+            "date" => Ok(FieldType::Date),
+            "string" => Ok(FieldType::String),
+            other => Err(invalid_data(format!("Unknown CSV type hint: {}", other))),
+        }
+    }
+}
+
+/// Example:
+/// in: age:int,score:float,active:bool,born:date
+/// out: {"age": Int, "score": Float, "active": Bool, "born": Date}
+pub fn parse_type_hints(input: &str) -> Result<HashMap<String, FieldType>, io::Error> {
+    let mut type_hints = HashMap::new();
+
+    for hint in input.split(',').filter(|hint| !hint.is_empty()) {
+        match hint.split_once(':') {
+            Some((field, field_type)) => {
+                type_hints.insert(field.to_owned(), FieldType::parse(field_type)?);
+            }
+            None => {
+                return Err(invalid_data(format!(
+                    "CSV type hint must look like <field>:<type>, got: {}",
+                    hint
+                )))
+            }
+        }
+    }
+
+    Ok(type_hints)
+}
+
+/// Maps every CSV row onto a JSON object keyed by the header row. Columns
+/// without a type hint have their type inferred from the cell itself.
+pub fn parse_documents(
+    input: &str,
+    type_hints: &HashMap<String, FieldType>,
+) -> Result<Vec<Value>, io::Error> {
+    let mut records = parse_records(input)?.into_iter();
+
+    let headers = match records.next() {
+        Some(headers) => headers,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut documents = Vec::new();
+    for (row_number, record) in records.enumerate() {
+        if record.len() != headers.len() {
+            return Err(invalid_data(format!(
+                "CSV row {} has {} field(s), expected {}",
+                row_number + 1,
+                record.len(),
+                headers.len()
+            )));
+        }
+
+        let mut document = Map::new();
+        for (header, cell) in headers.iter().zip(record) {
+            let value = match type_hints.get(header) {
+                Some(field_type) => convert(&cell, *field_type)?,
+                None => infer(cell),
+            };
+            document.insert(header.to_owned(), value);
+        }
+
+        documents.push(Value::Object(document));
+    }
+
+    Ok(documents)
+}
+
+fn parse_records(input: &str) -> Result<Vec<Vec<String>>, io::Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (in_quotes, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => in_quotes = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => in_quotes = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') => (),
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err(invalid_data("CSV ends inside a quoted field".to_owned()));
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Skip blank lines, commonly found at the end of exported spreadsheets
+    records.retain(|record| !(record.len() == 1 && record[0].is_empty()));
+
+    Ok(records)
+}
+
+fn convert(cell: &str, field_type: FieldType) -> Result<Value, io::Error> {
+    if cell.is_empty() {
+        return Ok(Value::Null);
+    }
+
+    let invalid = || invalid_data(format!("Could not convert CSV value: {}", cell));
+
+    match field_type {
+        FieldType::Int => cell.parse::<i64>().map(Value::from).map_err(|_| invalid()),
+        FieldType::Float => cell
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(invalid),
+        FieldType::Bool => match cell.to_lowercase().as_str() {
+            "true" | "yes" | "1" => Ok(Value::Bool(true)),
+            "false" | "no" | "0" => Ok(Value::Bool(false)),
+            _ => Err(invalid()),
+        },
+        // JSON has no date type, so dates are normalized to ISO 8601 strings
+        FieldType::Date => match NaiveDate::parse_from_str(cell, "%Y-%m-%d") {
+            Ok(date) => Ok(Value::String(date.to_string())),
+            Err(_) => DateTime::parse_from_rfc3339(cell)
+                .map(|date_time| Value::String(date_time.to_rfc3339()))
+                .map_err(|_| invalid()),
+        },
+        FieldType::String => Ok(Value::String(cell.to_owned())),
+    }
+}
+
+fn infer(cell: String) -> Value {
+    if cell.is_empty() {
+        Value::Null
+    } else if let Ok(int) = cell.parse::<i64>() {
+        Value::from(int)
+    } else if let Some(float) = cell.parse::<f64>().ok().and_then(Number::from_f64) {
+        Value::Number(float)
+    } else if cell.eq_ignore_ascii_case("true") || cell.eq_ignore_ascii_case("false") {
+        Value::Bool(cell.eq_ignore_ascii_case("true"))
+    } else {
+        Value::String(cell)
+    }
+}
+
+fn invalid_data(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
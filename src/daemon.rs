@@ -0,0 +1,132 @@
+/// Process supervision.
+///
+/// Every dustdb process that writes to the storage root (the server and the
+/// restore modes) first takes an exclusive lock on `.dustdb.lock` in it, and
+/// writes its PID there. A second instance on the same storage refuses to
+/// start instead of racing the first one over the same files. The OS drops
+/// the lock when the process exits, even if it crashes, so a stale lock file
+/// never blocks a restart.
+///
+/// The lock file also tells whether the previous process shut down cleanly:
+/// a running process keeps its PID there, together with the WAL offset from
+/// which its writes are logged, and empties it on a clean exit. A lock file
+/// found non-empty means the previous process died, and the server recovers
+/// (see recovery.rs) before it accepts connections.
+///
+/// `dustdb --daemon` starts the server for a process supervisor: it stays in
+/// the foreground (the supervisor tracks it), writes its PID to
+/// `DUST_PID_FILE` (if set), and sends its diagnostics to dustlog instead of
+/// stdout.
+use crate::wal;
+use dustcfg::get_env_var;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+const LOCK_FILE_NAME: &str = ".dustdb.lock";
+
+/// Held for as long as the process runs
+pub struct StorageLock {
+    file: File,
+    last_shutdown: LastShutdown,
+}
+
+/// How the previous process on the storage ended
+pub enum LastShutdown {
+    Clean,
+    /// With the WAL offset from which its writes were logged, unless it was
+    /// too old a version to record one
+    Unclean {
+        wal_offset: Option<u64>,
+    },
+}
+
+impl StorageLock {
+    pub fn last_shutdown(&self) -> &LastShutdown {
+        &self.last_shutdown
+    }
+
+    /// Records that everything before `wal_offset` is applied to the piles,
+    /// so a recovery only has to replay what comes after it
+    pub fn checkpoint(&mut self, wal_offset: u64) -> Result<(), io::Error> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        write!(self.file, "{}\n{}", std::process::id(), wal_offset)?;
+        self.file.sync_data()
+    }
+
+    /// Marks the shutdown as clean, once nothing is written anymore
+    pub fn release(self) -> Result<(), io::Error> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+pub fn lock_storage() -> Result<StorageLock, io::Error> {
+    let storage_path = get_env_var("DUST_DATA_STORAGE_PATH");
+    fs::create_dir_all(&storage_path)?;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(Path::new(&storage_path).join(LOCK_FILE_NAME))?;
+
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => {
+            let mut holder_pid = String::new();
+            file.read_to_string(&mut holder_pid)?;
+
+            let e_kind = io::ErrorKind::WouldBlock;
+            let e = format!(
+                "Storage at {} is in use by another dustdb (PID {})",
+                storage_path,
+                holder_pid.lines().next().unwrap_or_default().trim()
+            );
+            return Err(io::Error::new(e_kind, e));
+        }
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+
+    let mut previous = String::new();
+    file.read_to_string(&mut previous)?;
+    let mut previous_lines = previous.lines();
+    let last_shutdown = match previous_lines.next() {
+        Some(pid) if !pid.trim().is_empty() => LastShutdown::Unclean {
+            wal_offset: previous_lines
+                .next()
+                .and_then(|line| line.trim().parse().ok()),
+        },
+        _ => LastShutdown::Clean,
+    };
+
+    // Until recovered, the writes of the previous process still need
+    // replaying from where it started
+    let wal_offset = match last_shutdown {
+        LastShutdown::Unclean { wal_offset } => wal_offset,
+        LastShutdown::Clean => Some(wal::end_offset()?),
+    };
+
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    if let Some(wal_offset) = wal_offset {
+        write!(file, "\n{}", wal_offset)?;
+    }
+    file.sync_data()?;
+
+    Ok(StorageLock {
+        file,
+        last_shutdown,
+    })
+}
+
+/// Writes the PID file for a supervisor, if one is configured
+pub fn write_pid_file() -> Result<(), io::Error> {
+    match std::env::var("DUST_PID_FILE") {
+        Ok(pid_path) => fs::write(pid_path, format!("{}\n", std::process::id())),
+        Err(_) => Ok(()),
+    }
+}
@@ -0,0 +1,169 @@
+/// Per-document metadata.
+///
+/// Every document has a hidden sidecar next to it, `.<uuid>.meta.json`, that
+/// is updated whenever the document is written:
+///
+/// size         the document's size in bytes, as stored
+/// checksum     CRC-32 of the stored bytes, as 8 hex digits
+/// created_at   when the document was first written
+/// modified_at  when the document was last written
+/// revision     how many times it was written, starting at 1
+///
+/// Writing the bytes a document already has (e.g. when the WAL is replayed)
+/// leaves its sidecar alone, so the revision only counts actual changes. The
+/// sidecar survives a soft delete and goes with the document once it's
+/// removed for good.
+///
+/// `FIND META ...` adds the sidecar to the found document as `_meta`. A
+/// document from before sidecars existed gets one the next time it's written,
+/// until then its metadata is derived from the file and has no revision.
+use crate::logging::{self, Level};
+use crate::timestamp_now;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{from_str, json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// CRC-32 (IEEE) lookup table, as used by zip and PNG
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xEDB8_8320 ^ (crc >> 1),
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The checksum the sidecar records for a document's stored bytes
+pub fn checksum(data: &[u8]) -> String {
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    format!("{:08x}", !crc)
+}
+
+fn sidecar_path(pile_path: &str, uuid: &str) -> String {
+    format!("{}/.{}.meta.json", pile_path, uuid)
+}
+
+/// Updates the sidecar after the document was written. The document itself
+/// has landed by then, so a failure here is only reported.
+pub fn record(pile_path: &str, uuid: &str, data: &[u8]) {
+    if let Err(e) = try_record(pile_path, uuid, data) {
+        logging::diagnostic(
+            Level::Error,
+            &format!("Error updating metadata of document \"{}\": {:?}", uuid, e),
+        );
+    }
+}
+
+fn try_record(pile_path: &str, uuid: &str, data: &[u8]) -> Result<(), io::Error> {
+    let checksum = checksum(data);
+    let modified_at = timestamp_now();
+    let (created_at, revision) = match read_sidecar(pile_path, uuid)? {
+        Some(sidecar) if sidecar["checksum"] == checksum.as_str() => return Ok(()),
+        Some(sidecar) => (
+            match sidecar.get("created_at") {
+                Some(Value::String(created_at)) => created_at.clone(),
+                _ => modified_at.clone(),
+            },
+            sidecar["revision"].as_u64().unwrap_or(0) + 1,
+        ),
+        None => (modified_at.clone(), 1),
+    };
+
+    let sidecar = json!({
+        "size": data.len(),
+        "checksum": checksum,
+        "created_at": created_at,
+        "modified_at": modified_at,
+        "revision": revision,
+    });
+
+    // Written aside and renamed into place, so a reader never sees half of it
+    let sidecar_path = sidecar_path(pile_path, uuid);
+    let tmp_path = format!("{}.tmp", sidecar_path);
+    fs::write(&tmp_path, sidecar.to_string())?;
+    fs::rename(tmp_path, sidecar_path)
+}
+
+/// Removes the sidecar along with its document. A missing sidecar is not an
+/// error, which keeps WAL replay idempotent.
+pub fn remove(pile_path: &str, uuid: &str) -> Result<(), io::Error> {
+    match fs::remove_file(sidecar_path(pile_path, uuid)) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The sidecar of the document at `file_path`, or its metadata as derived
+/// from the file if it has none yet
+pub fn load(file_path: &Path) -> Result<Value, io::Error> {
+    let (pile_path, uuid) = match (
+        file_path.parent().and_then(|parent| parent.to_str()),
+        file_path.file_stem().and_then(|stem| stem.to_str()),
+    ) {
+        (Some(pile_path), Some(uuid)) => (pile_path, uuid),
+        _ => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Not a document path: \"{}\"", file_path.display());
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    if let Some(sidecar) = read_sidecar(pile_path, uuid)? {
+        return Ok(sidecar);
+    }
+
+    let data = fs::read(file_path)?;
+    let metadata = fs::metadata(file_path)?;
+    let modified_at: DateTime<Utc> = metadata.modified()?.into();
+    let created_at: DateTime<Utc> = metadata.created().unwrap_or(modified_at.into()).into();
+    Ok(json!({
+        "size": data.len(),
+        "checksum": checksum(&data),
+        "created_at": created_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "modified_at": modified_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "revision": null,
+    }))
+}
+
+/// The IDs of every document in the pile with a sidecar, whether or not the
+/// document is still there
+pub fn sidecar_uuids(pile_path: &str) -> Result<Vec<String>, io::Error> {
+    let mut uuids = Vec::new();
+    for entry in fs::read_dir(pile_path)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(uuid) = file_name
+            .strip_prefix('.')
+            .and_then(|name| name.strip_suffix(".meta.json"))
+        {
+            uuids.push(uuid.to_owned());
+        }
+    }
+    uuids.sort();
+
+    Ok(uuids)
+}
+
+pub fn read_sidecar(pile_path: &str, uuid: &str) -> Result<Option<Value>, io::Error> {
+    match fs::read_to_string(sidecar_path(pile_path, uuid)) {
+        Ok(file_content) => Ok(Some(from_str(&file_content)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,50 @@
+/// Error codes.
+///
+/// Every error response carries one of these stable codes in place of the
+/// exit code, so clients can branch on the kind of failure without reading
+/// the message:
+///
+/// 1  INTERNAL               anything unexpected (e.g. a filesystem error)
+/// 2  PARSE_ERROR            the command itself could not be parsed
+/// 3  NOT_FOUND              a document, job, user or backup doesn't exist
+/// 4  CONFLICT               an ID or unique value is already taken
+///                          or an IF condition on a document doesn't hold
+/// 5  UNAUTHORIZED           missing credentials, or not enough rights
+/// 6  PAYLOAD_TOO_LARGE      a document is over the pile's size limit
+/// 7  INVALID_INPUT          well formed but unacceptable arguments or data
+/// 8  CONFIRMATION_REQUIRED  a mass delete waits for its token (see confirm.rs)
+///
+/// Codes are never renumbered; new kinds of failure get new numbers.
+use crate::confirm::ConfirmationRequired;
+use std::io;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    Internal = 1,
+    ParseError = 2,
+    NotFound = 3,
+    Conflict = 4,
+    Unauthorized = 5,
+    PayloadTooLarge = 6,
+    InvalidInput = 7,
+    ConfirmationRequired = 8,
+}
+
+impl ErrorCode {
+    pub fn of(e: &io::Error) -> ErrorCode {
+        if e.get_ref()
+            .is_some_and(|inner| inner.is::<ConfirmationRequired>())
+        {
+            return ErrorCode::ConfirmationRequired;
+        }
+
+        match e.kind() {
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::AlreadyExists => ErrorCode::Conflict,
+            io::ErrorKind::PermissionDenied => ErrorCode::Unauthorized,
+            io::ErrorKind::FileTooLarge => ErrorCode::PayloadTooLarge,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => ErrorCode::InvalidInput,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+/// Notifications of newly created documents, for WATCH, and of every
+/// committed write, for the HTTP change feed (see http.rs).
+///
+/// Every committed CREATE (including imports and trigger writes) is broadcast
+/// to the connections watching its pile. Watchers that fall more than
+/// `DUST_WATCH_BUFFER` (default 1024) documents behind miss documents, and
+/// are told so. Changes are broadcast the same way, with the same buffer.
+use crate::webhooks::WebhookEvent;
+use crate::{env_or, timestamp_now};
+use serde_json::{json, Value};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::broadcast;
+
+pub struct CreatedDocument {
+    pub pile: String,
+    /// The document as stored, with its UUID added as `_id`
+    pub document: Value,
+}
+
+fn sender() -> &'static broadcast::Sender<Arc<CreatedDocument>> {
+    static SENDER: OnceLock<broadcast::Sender<Arc<CreatedDocument>>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(env_or("DUST_WATCH_BUFFER", 1024_usize).max(1)).0)
+}
+
+/// Announces a new document. Must be called while holding the pile's write
+/// lock, so watchers see a pile's documents in the order they were written.
+pub fn publish(pile_name: &str, uuid: &str, json_content: &Value) {
+    let sender = sender();
+    if sender.receiver_count() == 0 {
+        return;
+    }
+
+    let mut document = json_content.clone();
+    if let Some(json_object) = document.as_object_mut() {
+        json_object.insert("_id".to_owned(), Value::from(uuid));
+    }
+
+    // Failing only means the last watcher just went away
+    let _ = sender.send(Arc::new(CreatedDocument {
+        pile: pile_name.to_owned(),
+        document,
+    }));
+}
+
+pub fn subscribe() -> broadcast::Receiver<Arc<CreatedDocument>> {
+    sender().subscribe()
+}
+
+pub struct Change {
+    pub pile: String,
+    pub event: WebhookEvent,
+    /// The change as JSON, as webhooks and sinks deliver it
+    pub payload: String,
+}
+
+fn change_sender() -> &'static broadcast::Sender<Arc<Change>> {
+    static SENDER: OnceLock<broadcast::Sender<Arc<Change>>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(env_or("DUST_WATCH_BUFFER", 1024_usize).max(1)).0)
+}
+
+/// Announces a committed write. `document` is the document as stored, or as
+/// it was before a delete.
+pub fn publish_change(event: WebhookEvent, pile_name: &str, uuid: &str, document: Option<&Value>) {
+    let sender = change_sender();
+    if sender.receiver_count() == 0 {
+        return;
+    }
+
+    let payload = json!({
+        "pile": pile_name,
+        "op": event.as_str(),
+        "id": uuid,
+        "document": document,
+        "timestamp": timestamp_now(),
+    })
+    .to_string();
+
+    // Failing only means the last subscriber just went away
+    let _ = sender.send(Arc::new(Change {
+        pile: pile_name.to_owned(),
+        event,
+        payload,
+    }));
+}
+
+pub fn subscribe_changes() -> broadcast::Receiver<Arc<Change>> {
+    change_sender().subscribe()
+}
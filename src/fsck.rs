@@ -0,0 +1,201 @@
+/// Storage integrity checks.
+///
+/// `FSCK [pile] [REPAIR]` goes over every document of the pile (or of every
+/// pile) and reports what doesn't add up:
+///
+/// corrupt            the document doesn't parse as JSON
+/// checksum_mismatch  its bytes don't match the checksum in its sidecar (see
+///                    docmeta.rs), i.e. it changed behind dustdb's back
+/// corrupt_meta       its sidecar doesn't parse
+/// orphaned_meta      a sidecar whose document is gone
+/// ordered_index      an ordered index key file (see ordered.rs) with entries
+///                    pointing at missing documents or holding stale values,
+///                    or missing documents that should be in it
+///
+/// With REPAIR, sidecars are rewritten from the documents as they are now,
+/// orphaned ones removed and broken key files discarded, to be rebuilt on
+/// first use. Corrupt documents are only reported; REPAIR <pile> quarantines
+/// them (see quarantine.rs).
+///
+/// The check runs online: each document is read under its pile's lock, one
+/// at a time, and at most `DUST_FSCK_DOCUMENTS_PER_SEC` (default 1000, 0 for
+/// no limit) are checked a second so it doesn't starve the server. A key file
+/// saved while the check was running isn't compared, as the documents already
+/// checked may have changed since.
+use crate::pile::{self, document_paths, pile_names, pile_path, PileMeta};
+use crate::{docmeta, env_or, ordered};
+use dustcfg::get_env_var;
+use serde_json::{from_slice, json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Paces the check to the configured rate
+struct Throttle {
+    documents_per_sec: u64,
+    started: Instant,
+    checked: u64,
+}
+
+impl Throttle {
+    fn new() -> Throttle {
+        Throttle {
+            documents_per_sec: env_or("DUST_FSCK_DOCUMENTS_PER_SEC", 1000),
+            started: Instant::now(),
+            checked: 0,
+        }
+    }
+
+    fn checked_one(&mut self) {
+        self.checked += 1;
+        if self.documents_per_sec == 0 {
+            return;
+        }
+
+        let due = Duration::from_secs_f64(self.checked as f64 / self.documents_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// Example:
+/// in: FSCK users REPAIR
+/// out: {"piles":1,"documents":1204,"issues":[{"pile":"users","kind":"checksum_mismatch","document":"cd8abd45-...","repaired":true}]}
+pub fn check(pile_name: Option<&str>, repair: bool) -> Result<Value, io::Error> {
+    let pile_names = match pile_name {
+        Some(pile_name) => vec![pile_name.to_owned()],
+        None => pile_names()?,
+    };
+
+    let mut throttle = Throttle::new();
+    let mut issues = Vec::new();
+    for pile_name in &pile_names {
+        check_pile(pile_name, repair, &mut throttle, &mut issues)?;
+    }
+
+    Ok(json!({
+        "piles": pile_names.len(),
+        "documents": throttle.checked,
+        "issues": issues,
+    }))
+}
+
+fn check_pile(
+    pile_name: &str,
+    repair: bool,
+    throttle: &mut Throttle,
+    issues: &mut Vec<Value>,
+) -> Result<(), io::Error> {
+    let started_at = SystemTime::now();
+    let pile_path = pile_path(pile_name)?;
+    if !Path::new(&pile_path).is_dir() {
+        return Ok(());
+    }
+    let ordered_fields = PileMeta::load(pile_name)?.ordered_fields();
+    let pile_lock = pile::lock(pile_name);
+
+    // The ordered fields of every document, `None` for corrupt ones
+    let mut documents: HashMap<String, Option<Map<String, Value>>> = HashMap::new();
+    for file_path in document_paths(pile_name)? {
+        let uuid = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(uuid) => uuid.to_owned(),
+            None => continue,
+        };
+
+        let guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let data = match fs::read(&file_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let fields = match from_slice::<Value>(&data) {
+            Ok(document) => Some(
+                ordered_fields
+                    .iter()
+                    .filter_map(|field| Some((field.clone(), document.get(field)?.clone())))
+                    .collect(),
+            ),
+            Err(_) => {
+                issues.push(issue(pile_name, "corrupt", &uuid, false));
+                None
+            }
+        };
+
+        match docmeta::read_sidecar(&pile_path, &uuid) {
+            Ok(Some(sidecar)) if sidecar["checksum"] != docmeta::checksum(&data).as_str() => {
+                // A corrupt document keeps its checksum, which tells what it
+                // held before
+                let repaired = repair && fields.is_some();
+                if repaired {
+                    docmeta::record(&pile_path, &uuid, &data);
+                }
+                issues.push(issue(pile_name, "checksum_mismatch", &uuid, repaired));
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                if repair {
+                    docmeta::remove(&pile_path, &uuid)?;
+                    docmeta::record(&pile_path, &uuid, &data);
+                }
+                issues.push(issue(pile_name, "corrupt_meta", &uuid, repair));
+            }
+            Err(e) => return Err(e),
+        }
+        drop(guard);
+
+        documents.insert(uuid, fields);
+        throttle.checked_one();
+    }
+
+    for uuid in docmeta::sidecar_uuids(&pile_path)? {
+        let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let document_exists = Path::new(&pile_path)
+            .join(format!("{}.{}", uuid, get_env_var("DUST_DATA_FMT")))
+            .exists();
+        let tombstone_exists = Path::new(&pile_path)
+            .join(format!("{}.{}", uuid, pile::TOMBSTONE_EXTENSION))
+            .exists();
+        if !document_exists && !tombstone_exists {
+            if repair {
+                docmeta::remove(&pile_path, &uuid)?;
+            }
+            issues.push(issue(pile_name, "orphaned_meta", &uuid, repair));
+        }
+    }
+
+    for field_name in &ordered_fields {
+        let wrong_entries =
+            match ordered::check_key_file(pile_name, field_name, &documents, started_at)? {
+                Some(wrong_entries) if wrong_entries > 0 => wrong_entries,
+                _ => continue,
+            };
+
+        if repair {
+            let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+            ordered::discard(pile_name)?;
+        }
+        issues.push(json!({
+            "pile": pile_name,
+            "kind": "ordered_index",
+            "field": field_name,
+            "entries": wrong_entries,
+            "repaired": repair,
+        }));
+    }
+
+    Ok(())
+}
+
+fn issue(pile_name: &str, kind: &str, uuid: &str, repaired: bool) -> Value {
+    json!({
+        "pile": pile_name,
+        "kind": kind,
+        "document": uuid,
+        "repaired": repaired,
+    })
+}
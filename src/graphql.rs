@@ -0,0 +1,1760 @@
+/// GraphQL over the piles' JSON Schemas.
+///
+/// `POST /graphql` on the HTTP listener (see http.rs) runs a GraphQL request,
+/// {"query":"...","variables":{..},"operationName":"..."}, against a schema
+/// generated from every pile that has a JSON Schema with `properties` (see
+/// schema.rs). The pile `order_items` becomes the object type `OrderItems`,
+/// with the document's UUID as `_id: ID!` and a field per property, and the
+/// root fields:
+///
+/// orderItems(id: ID!): OrderItems            the document with that ID, or null
+/// findOrderItems(<property>: <value>, ...,   the documents whose properties equal
+///   first: Int): [OrderItems!]               all the values given (null finds
+///                                            missing and null ones), like FIND;
+///                                            `first` (default 100) at most
+/// createOrderItems(input: OrderItemsInput!): OrderItems
+///                                            stores a new document, like CREATE
+/// updateOrderItems(id: ID!,                  applies the input as a merge patch,
+///   input: OrderItemsPatch!): OrderItems     like PATCH, so a property set to
+///                                            null is removed
+///
+/// The last two are mutations, and return the document as stored. Properties
+/// become String, Int, Float, Boolean or a list of those following their
+/// `type`, and the `JSON` scalar otherwise (objects, mixed types); only the
+/// scalar ones can be searched by. Properties the schema requires are
+/// required in the `...Input` type. Properties whose names aren't GraphQL
+/// names are left out, but kept by writes.
+///
+/// Reading a pile needs READ on it and writing WRITE, as with FIND and PATCH.
+/// Introspection (`__schema`, `__type`) describes every pile in the schema,
+/// so like SCHEMA GET it needs READ on each of them. Fragments, variables and
+/// `@skip`/`@include` are supported; subscriptions aren't (the HTTP listener
+/// streams changes instead).
+use crate::listeners::ListenerFlags;
+use crate::payload::Encoding;
+use crate::pile::{document_paths, pile_names, pile_path, PileMeta};
+use crate::query::Predicate;
+use crate::users::Right;
+use crate::{
+    authorize_rights, document_file_path, document_with_id, is_valid_document_id, matching_ids,
+};
+use serde_json::{json, Map, Number, Value};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// How many documents a find returns without `first`
+const DEFAULT_FIRST: usize = 100;
+
+/// Fragments can spread other fragments; deeper nesting is refused
+const MAX_FRAGMENT_DEPTH: usize = 32;
+
+/// Selection sets, lists, input objects and list types can nest this deep
+/// in a document; deeper nesting is refused
+const MAX_NESTING_DEPTH: usize = 64;
+
+/// Runs a GraphQL request, answering with its GraphQL response. Fails only
+/// when the credentials don't allow the operation, or storage does.
+pub fn execute(
+    request: &Value,
+    credentials: Option<(&str, &str)>,
+    flags: ListenerFlags,
+) -> Result<Value, io::Error> {
+    let query = match request.get("query").and_then(Value::as_str) {
+        Some(query) => query,
+        None => return Ok(error_response("Request must have a \"query\" string")),
+    };
+    let document = match Parser::parse(query) {
+        Ok(document) => document,
+        Err(e) => return Ok(error_response(&format!("Syntax error: {}", e))),
+    };
+
+    let operation_name = request.get("operationName").and_then(Value::as_str);
+    let operation = match (operation_name, document.operations.as_slice()) {
+        (None, [operation]) => operation,
+        (None, _) => {
+            return Ok(error_response(
+                "Request must have an \"operationName\" to pick one of its operations",
+            ))
+        }
+        (Some(name), operations) => {
+            match operations
+                .iter()
+                .find(|operation| operation.name.as_deref() == Some(name))
+            {
+                Some(operation) => operation,
+                None => return Ok(error_response(&format!("Unknown operation \"{}\"", name))),
+            }
+        }
+    };
+
+    let root_type = match operation.kind {
+        OperationKind::Query => "Query",
+        OperationKind::Mutation => "Mutation",
+        OperationKind::Subscription => {
+            return Ok(error_response("Subscriptions aren't supported"));
+        }
+    };
+
+    let empty_variables = Map::new();
+    let given_variables = match request.get("variables") {
+        Some(Value::Object(variables)) => variables,
+        None | Some(Value::Null) => &empty_variables,
+        Some(_) => return Ok(error_response("\"variables\" must be an object")),
+    };
+    let mut variables = Map::new();
+    for definition in &operation.variables {
+        match (given_variables.get(&definition.name), &definition.default) {
+            (Some(value), _) => {
+                variables.insert(definition.name.clone(), value.clone());
+            }
+            (None, Some(default)) => {
+                variables.insert(definition.name.clone(), default.resolve(&empty_variables));
+            }
+            (None, None) if definition.is_required => {
+                return Ok(error_response(&format!(
+                    "Variable \"${}\" is required",
+                    definition.name
+                )));
+            }
+            (None, None) => (),
+        }
+    }
+
+    let schema = Schema::load()?;
+    let context = Context {
+        schema: &schema,
+        fragments: &document.fragments,
+        variables: &variables,
+    };
+    if schema.type_def(root_type).is_none() {
+        return Ok(error_response(&format!(
+            "The schema has no {} type",
+            root_type
+        )));
+    }
+
+    let fields = match context.collect(operation.selections.iter(), root_type, 0) {
+        Ok(fields) => fields,
+        Err(e) => return Ok(error_response(&e)),
+    };
+    let mut errors = Vec::new();
+    context.validate(root_type, &fields, &mut errors);
+    if !errors.is_empty() {
+        let errors: Vec<Value> = errors.iter().map(|e| json!({ "message": e })).collect();
+        return Ok(json!({ "errors": errors }));
+    }
+
+    // One check for the whole operation, so the user is authenticated once
+    let mut required_rights = Vec::new();
+    for (_, group) in &fields {
+        match group[0].name.as_str() {
+            "__schema" | "__type" => required_rights.extend(
+                schema
+                    .piles
+                    .iter()
+                    .map(|pile_name| (pile_name.as_str(), Right::Read)),
+            ),
+            name => match schema.roots.get(name) {
+                Some(RootField::Read(pile_name)) | Some(RootField::Find(pile_name)) => {
+                    required_rights.push((pile_name.as_str(), Right::Read))
+                }
+                Some(RootField::Create(pile_name)) | Some(RootField::Update(pile_name)) => {
+                    required_rights.push((pile_name.as_str(), Right::Write))
+                }
+                None => (),
+            },
+        }
+    }
+    authorize_rights(required_rights, credentials, flags)?;
+
+    // Root fields run in order, which the spec requires of mutations
+    let mut data = Map::new();
+    let mut errors = Vec::new();
+    for (key, group) in &fields {
+        match context.resolve_root(root_type, group) {
+            Ok(value) => {
+                data.insert(key.clone(), value);
+            }
+            Err(e) => {
+                data.insert(key.clone(), Value::Null);
+                errors.push(json!({ "message": e, "path": [key] }));
+            }
+        }
+    }
+
+    Ok(match errors.is_empty() {
+        true => json!({ "data": data }),
+        false => json!({ "data": data, "errors": errors }),
+    })
+}
+
+fn error_response(message: &str) -> Value {
+    json!({ "errors": [{ "message": message }] })
+}
+
+/// Lexing and parsing of GraphQL documents
+/// (https://spec.graphql.org/October2021/#sec-Language)
+#[derive(Clone, PartialEq)]
+enum Token {
+    Punctuator(char),
+    Spread,
+    Name(String),
+    Int(String),
+    Float(String),
+    Str(String),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Punctuator(c) => format!("\"{}\"", c),
+            Token::Spread => "\"...\"".to_owned(),
+            Token::Name(name) => format!("\"{}\"", name),
+            Token::Int(number) | Token::Float(number) => number.clone(),
+            Token::Str(_) => "a string".to_owned(),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while let Some(&c) = chars.get(position) {
+        match c {
+            // Commas are insignificant, like whitespace
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => position += 1,
+            '#' => {
+                while chars.get(position).is_some_and(|&c| c != '\n' && c != '\r') {
+                    position += 1;
+                }
+            }
+            '!' | '$' | '&' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '|' | '}' => {
+                tokens.push(Token::Punctuator(c));
+                position += 1;
+            }
+            '.' if chars[position..].starts_with(&['.', '.', '.']) => {
+                tokens.push(Token::Spread);
+                position += 3;
+            }
+            '"' => {
+                let (text, next_position) = read_string(&chars, position)?;
+                tokens.push(Token::Str(text));
+                position = next_position;
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = position;
+                while chars
+                    .get(position)
+                    .is_some_and(|&c| c == '_' || c.is_ascii_alphanumeric())
+                {
+                    position += 1;
+                }
+                tokens.push(Token::Name(chars[start..position].iter().collect()));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = position;
+                let mut is_float = false;
+                position += 1;
+                while let Some(&c) = chars.get(position) {
+                    match c {
+                        '0'..='9' => (),
+                        '.' | 'e' | 'E' => is_float = true,
+                        '+' | '-' if matches!(chars[position - 1], 'e' | 'E') => (),
+                        _ => break,
+                    }
+                    position += 1;
+                }
+                let number: String = chars[start..position].iter().collect();
+                let is_valid = match is_float {
+                    true => number.parse::<f64>().is_ok() && !number.ends_with('.'),
+                    false => number.parse::<i64>().is_ok(),
+                };
+                if !is_valid {
+                    return Err(format!("Invalid number {}", number));
+                }
+                tokens.push(match is_float {
+                    true => Token::Float(number),
+                    false => Token::Int(number),
+                });
+            }
+            _ => return Err(format!("Unexpected character \"{}\"", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads the string starting at `start`, returning it along with the position
+/// after it
+fn read_string(chars: &[char], start: usize) -> Result<(String, usize), String> {
+    if chars[start..].starts_with(&['"', '"', '"']) {
+        return read_block_string(chars, start + 3);
+    }
+
+    let mut text = String::new();
+    let mut position = start + 1;
+    loop {
+        match chars.get(position) {
+            None | Some('\n') | Some('\r') => return Err("Unterminated string".to_owned()),
+            Some('"') => return Ok((text, position + 1)),
+            Some('\\') => {
+                let escaped = match chars.get(position + 1) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let (c, next_position) = read_unicode_escape(chars, position)?;
+                        text.push(c);
+                        position = next_position;
+                        continue;
+                    }
+                    _ => return Err("Invalid escape sequence in string".to_owned()),
+                };
+                text.push(escaped);
+                position += 2;
+            }
+            Some(&c) => {
+                text.push(c);
+                position += 1;
+            }
+        }
+    }
+}
+
+/// Reads a `\uXXXX` escape (or a surrogate pair of them) at `position`
+fn read_unicode_escape(chars: &[char], position: usize) -> Result<(char, usize), String> {
+    let code_unit = |position: usize| -> Option<u32> {
+        match chars.get(position..position + 6)? {
+            ['\\', 'u', digits @ ..] => {
+                u32::from_str_radix(&digits.iter().collect::<String>(), 16).ok()
+            }
+            _ => None,
+        }
+    };
+
+    let invalid = || "Invalid unicode escape in string".to_owned();
+    let high = code_unit(position).ok_or_else(invalid)?;
+    if (0xd800..0xdc00).contains(&high) {
+        let low = code_unit(position + 6)
+            .filter(|low| (0xdc00..0xe000).contains(low))
+            .ok_or_else(invalid)?;
+        let c = char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00));
+        return Ok((c.ok_or_else(invalid)?, position + 12));
+    }
+
+    Ok((char::from_u32(high).ok_or_else(invalid)?, position + 6))
+}
+
+/// Reads a `"""` block string from its content at `start`, removing the
+/// common indentation and blank first and last lines
+fn read_block_string(chars: &[char], start: usize) -> Result<(String, usize), String> {
+    let mut raw = String::new();
+    let mut position = start;
+    loop {
+        if chars[position..].starts_with(&['"', '"', '"']) {
+            break;
+        }
+        if chars[position..].starts_with(&['\\', '"', '"', '"']) {
+            raw.push_str("\"\"\"");
+            position += 4;
+            continue;
+        }
+        match chars.get(position) {
+            Some(&c) => raw.push(c),
+            None => return Err("Unterminated block string".to_owned()),
+        }
+        position += 1;
+    }
+
+    let lines: Vec<&str> = raw.lines().collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    let mut lines: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| match index {
+            0 => line,
+            _ => line.get(indent..).unwrap_or_default(),
+        })
+        .collect();
+    while lines.first().is_some_and(|line| line.trim().is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    Ok((lines.join("\n"), position + 3))
+}
+
+/// A value as written in a document, which may refer to variables
+enum Literal {
+    Variable(String),
+    Value(Value),
+    List(Vec<Literal>),
+    Object(Vec<(String, Literal)>),
+}
+
+impl Literal {
+    fn resolve(&self, variables: &Map<String, Value>) -> Value {
+        match self {
+            Literal::Variable(name) => variables.get(name).cloned().unwrap_or(Value::Null),
+            Literal::Value(value) => value.clone(),
+            Literal::List(literals) => literals
+                .iter()
+                .map(|literal| literal.resolve(variables))
+                .collect(),
+            Literal::Object(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(name, literal)| (name.clone(), literal.resolve(variables)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+struct VariableDefinition {
+    name: String,
+    is_required: bool,
+    default: Option<Literal>,
+}
+
+struct Operation {
+    kind: OperationKind,
+    name: Option<String>,
+    variables: Vec<VariableDefinition>,
+    selections: Vec<Selection>,
+}
+
+struct Fragment {
+    type_condition: String,
+    selections: Vec<Selection>,
+}
+
+struct Directive {
+    name: String,
+    arguments: Vec<(String, Literal)>,
+}
+
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Literal)>,
+    directives: Vec<Directive>,
+    selections: Vec<Selection>,
+}
+
+enum Selection {
+    Field(Field),
+    FragmentSpread {
+        name: String,
+        directives: Vec<Directive>,
+    },
+    InlineFragment {
+        type_condition: Option<String>,
+        directives: Vec<Directive>,
+        selections: Vec<Selection>,
+    },
+}
+
+struct Document {
+    operations: Vec<Operation>,
+    fragments: HashMap<String, Fragment>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// How many selection sets, lists and the like enclose the position
+    depth: usize,
+}
+
+impl Parser {
+    fn parse(input: &str) -> Result<Document, String> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+            depth: 0,
+        };
+
+        let mut document = Document {
+            operations: Vec::new(),
+            fragments: HashMap::new(),
+        };
+        while let Some(token) = parser.peek().cloned() {
+            match token {
+                Token::Punctuator('{') => document.operations.push(Operation {
+                    kind: OperationKind::Query,
+                    name: None,
+                    variables: Vec::new(),
+                    selections: parser.selection_set()?,
+                }),
+                Token::Name(keyword) if keyword == "fragment" => {
+                    parser.position += 1;
+                    let name = parser.name()?;
+                    if parser.name()? != "on" {
+                        return Err(format!("Fragment \"{}\" must have a type condition", name));
+                    }
+                    let type_condition = parser.name()?;
+                    parser.directives()?;
+                    let selections = parser.selection_set()?;
+                    let fragment = Fragment {
+                        type_condition,
+                        selections,
+                    };
+                    if document.fragments.insert(name.clone(), fragment).is_some() {
+                        return Err(format!("Fragment \"{}\" is defined twice", name));
+                    }
+                }
+                Token::Name(keyword) => {
+                    let kind = match keyword.as_str() {
+                        "query" => OperationKind::Query,
+                        "mutation" => OperationKind::Mutation,
+                        "subscription" => OperationKind::Subscription,
+                        _ => return Err(format!("Unexpected \"{}\"", keyword)),
+                    };
+                    parser.position += 1;
+                    let name = match parser.peek() {
+                        Some(Token::Name(_)) => Some(parser.name()?),
+                        _ => None,
+                    };
+                    let variables = parser.variable_definitions()?;
+                    parser.directives()?;
+                    document.operations.push(Operation {
+                        kind,
+                        name,
+                        variables,
+                        selections: parser.selection_set()?,
+                    });
+                }
+                token => return Err(format!("Unexpected {}", token.describe())),
+            }
+        }
+
+        if document.operations.is_empty() {
+            return Err("Document has no operation".to_owned());
+        }
+        Ok(document)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of document".to_owned())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn is_next(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punctuator(c))
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Punctuator(next) if next == c => Ok(()),
+            token => Err(format!("Expected \"{}\", found {}", c, token.describe())),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(format!("Expected a name, found {}", token.describe())),
+        }
+    }
+
+    fn variable_definitions(&mut self) -> Result<Vec<VariableDefinition>, String> {
+        let mut definitions = Vec::new();
+        if !self.is_next('(') {
+            return Ok(definitions);
+        }
+
+        self.position += 1;
+        while !self.is_next(')') {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let is_non_null = self.variable_type()?;
+            let default = match self.is_next('=') {
+                true => {
+                    self.position += 1;
+                    Some(self.value(true)?)
+                }
+                false => None,
+            };
+            self.directives()?;
+            definitions.push(VariableDefinition {
+                name,
+                is_required: is_non_null && default.is_none(),
+                default,
+            });
+        }
+        self.position += 1;
+
+        Ok(definitions)
+    }
+
+    /// Skips over a variable's type, telling whether it is non-null.
+    /// Arguments are checked against the schema's types instead.
+    fn variable_type(&mut self) -> Result<bool, String> {
+        match self.is_next('[') {
+            true => {
+                self.position += 1;
+                self.nested(Parser::variable_type)?;
+                self.expect(']')?;
+            }
+            false => {
+                self.name()?;
+            }
+        }
+
+        match self.is_next('!') {
+            true => {
+                self.position += 1;
+                Ok(true)
+            }
+            false => Ok(false),
+        }
+    }
+
+    fn directives(&mut self) -> Result<Vec<Directive>, String> {
+        let mut directives = Vec::new();
+        while self.is_next('@') {
+            self.position += 1;
+            directives.push(Directive {
+                name: self.name()?,
+                arguments: self.arguments()?,
+            });
+        }
+        Ok(directives)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, Literal)>, String> {
+        let mut arguments = Vec::new();
+        if !self.is_next('(') {
+            return Ok(arguments);
+        }
+
+        self.position += 1;
+        while !self.is_next(')') {
+            let name = self.name()?;
+            self.expect(':')?;
+            arguments.push((name, self.value(false)?));
+        }
+        self.position += 1;
+
+        Ok(arguments)
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.expect('{')?;
+        let selections = self.nested(|parser| {
+            let mut selections = Vec::new();
+            while !parser.is_next('}') {
+                selections.push(parser.selection()?);
+            }
+            Ok(selections)
+        })?;
+        self.position += 1;
+
+        if selections.is_empty() {
+            return Err("Selection sets can't be empty".to_owned());
+        }
+        Ok(selections)
+    }
+
+    fn selection(&mut self) -> Result<Selection, String> {
+        if self.peek() == Some(&Token::Spread) {
+            self.position += 1;
+            return match self.peek() {
+                Some(Token::Name(name)) if name != "on" => Ok(Selection::FragmentSpread {
+                    name: self.name()?,
+                    directives: self.directives()?,
+                }),
+                _ => {
+                    let type_condition = match self.peek() {
+                        Some(Token::Name(_)) => {
+                            self.position += 1;
+                            Some(self.name()?)
+                        }
+                        _ => None,
+                    };
+                    Ok(Selection::InlineFragment {
+                        type_condition,
+                        directives: self.directives()?,
+                        selections: self.selection_set()?,
+                    })
+                }
+            };
+        }
+
+        let name = self.name()?;
+        let (alias, name) = match self.is_next(':') {
+            true => {
+                self.position += 1;
+                (Some(name), self.name()?)
+            }
+            false => (None, name),
+        };
+        let arguments = self.arguments()?;
+        let directives = self.directives()?;
+        let selections = match self.is_next('{') {
+            true => self.selection_set()?,
+            false => Vec::new(),
+        };
+
+        Ok(Selection::Field(Field {
+            alias,
+            name,
+            arguments,
+            directives,
+            selections,
+        }))
+    }
+
+    /// A value; constant ones (e.g. variable defaults) can't use variables
+    fn value(&mut self, is_const: bool) -> Result<Literal, String> {
+        Ok(match self.next()? {
+            Token::Punctuator('$') if !is_const => Literal::Variable(self.name()?),
+            Token::Int(number) => Literal::Value(Value::from(number.parse::<i64>().unwrap_or(0))),
+            Token::Float(number) => Literal::Value(
+                Number::from_f64(number.parse().unwrap_or(0.0))
+                    .map(Value::Number)
+                    .unwrap_or(Value::Null),
+            ),
+            Token::Str(text) => Literal::Value(Value::String(text)),
+            Token::Name(name) => Literal::Value(match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // Enum values
+                _ => Value::String(name),
+            }),
+            Token::Punctuator('[') => {
+                let literals = self.nested(|parser| {
+                    let mut literals = Vec::new();
+                    while !parser.is_next(']') {
+                        literals.push(parser.value(is_const)?);
+                    }
+                    Ok(literals)
+                })?;
+                self.position += 1;
+                Literal::List(literals)
+            }
+            Token::Punctuator('{') => {
+                let entries = self.nested(|parser| {
+                    let mut entries = Vec::new();
+                    while !parser.is_next('}') {
+                        let name = parser.name()?;
+                        parser.expect(':')?;
+                        entries.push((name, parser.value(is_const)?));
+                    }
+                    Ok(entries)
+                })?;
+                self.position += 1;
+                Literal::Object(entries)
+            }
+            token => return Err(format!("Expected a value, found {}", token.describe())),
+        })
+    }
+
+    /// Parses one level deeper, so a hostile document can't exhaust the
+    /// stack
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Parser) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(format!("Document nests over {} deep", MAX_NESTING_DEPTH));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+}
+
+/// The type of a field or argument
+#[derive(Clone)]
+enum TypeRef {
+    Named(&'static str),
+    /// A type generated from a pile
+    Generated(String),
+    List(Box<TypeRef>),
+    NonNull(Box<TypeRef>),
+}
+
+impl TypeRef {
+    fn list(self) -> TypeRef {
+        TypeRef::List(Box::new(self))
+    }
+
+    fn non_null(self) -> TypeRef {
+        TypeRef::NonNull(Box::new(self))
+    }
+
+    fn base_name(&self) -> &str {
+        match self {
+            TypeRef::Named(name) => name,
+            TypeRef::Generated(name) => name,
+            TypeRef::List(type_ref) | TypeRef::NonNull(type_ref) => type_ref.base_name(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            TypeRef::Named(_) | TypeRef::Generated(_) => self.base_name().to_owned(),
+            TypeRef::List(type_ref) => format!("[{}]", type_ref.describe()),
+            TypeRef::NonNull(type_ref) => format!("{}!", type_ref.describe()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TypeKind {
+    Scalar,
+    Object,
+    InputObject,
+}
+
+struct InputDef {
+    name: String,
+    type_ref: TypeRef,
+}
+
+struct FieldDef {
+    name: String,
+    description: Option<String>,
+    arguments: Vec<InputDef>,
+    type_ref: TypeRef,
+}
+
+struct TypeDef {
+    name: String,
+    kind: TypeKind,
+    description: Option<String>,
+    fields: Vec<FieldDef>,
+    input_fields: Vec<InputDef>,
+}
+
+/// What a root field does, on which pile
+enum RootField {
+    Read(String),
+    Find(String),
+    Create(String),
+    Update(String),
+}
+
+struct Schema {
+    types: Vec<TypeDef>,
+    roots: HashMap<String, RootField>,
+    /// The piles the schema covers
+    piles: Vec<String>,
+}
+
+const SCALARS: [(&str, &str); 6] = [
+    ("ID", "A document ID"),
+    ("String", "UTF-8 text"),
+    ("Int", "A whole number"),
+    ("Float", "A floating point number"),
+    ("Boolean", "true or false"),
+    ("JSON", "Any JSON value"),
+];
+
+impl Schema {
+    fn load() -> Result<Schema, io::Error> {
+        let mut schema = Schema {
+            types: SCALARS
+                .iter()
+                .map(|(name, description)| TypeDef {
+                    name: name.to_string(),
+                    kind: TypeKind::Scalar,
+                    description: Some(description.to_string()),
+                    fields: Vec::new(),
+                    input_fields: Vec::new(),
+                })
+                .collect(),
+            roots: HashMap::new(),
+            piles: Vec::new(),
+        };
+        let mut query_fields = Vec::new();
+        let mut mutation_fields = Vec::new();
+
+        // System piles (e.g. `.users`) are never exposed
+        for pile_name in pile_names()?
+            .into_iter()
+            .filter(|pile_name| !pile_name.starts_with('.'))
+        {
+            let pile_meta = PileMeta::load(&pile_name)?;
+            let json_schema = match pile_meta.schema() {
+                Some(json_schema) => json_schema,
+                None => continue,
+            };
+            let properties = match json_schema.get("properties").and_then(Value::as_object) {
+                Some(properties) => properties,
+                None => continue,
+            };
+            let required: Vec<&str> = json_schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|required| required.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+
+            // Piles whose names only differ in punctuation would collide, as
+            // would e.g. the find of `orders` with the read of `find_orders`
+            let type_name = type_name(&pile_name);
+            if type_name.is_empty() {
+                continue;
+            }
+            let field_name = format!("{}{}", type_name[..1].to_lowercase(), &type_name[1..]);
+            let root_names = [
+                field_name.clone(),
+                format!("find{}", type_name),
+                format!("create{}", type_name),
+                format!("update{}", type_name),
+            ];
+            if schema.type_def(&type_name).is_some()
+                || root_names
+                    .iter()
+                    .any(|name| schema.roots.contains_key(name))
+            {
+                continue;
+            }
+            let properties: Vec<(&String, TypeRef)> = properties
+                .iter()
+                .filter(|(name, _)| is_name(name) && name.as_str() != "_id")
+                .map(|(name, property)| (name, property_type(property)))
+                .collect();
+
+            let mut fields = vec![FieldDef {
+                name: "_id".to_owned(),
+                description: Some("The document's ID".to_owned()),
+                arguments: Vec::new(),
+                type_ref: TypeRef::Named("ID").non_null(),
+            }];
+            // Documents stored before the schema may lack required properties
+            fields.extend(properties.iter().map(|(name, type_ref)| FieldDef {
+                name: name.to_string(),
+                description: None,
+                arguments: Vec::new(),
+                type_ref: type_ref.clone(),
+            }));
+            schema.types.push(TypeDef {
+                name: type_name.clone(),
+                kind: TypeKind::Object,
+                description: Some(format!("A document of the pile \"{}\"", pile_name)),
+                fields,
+                input_fields: Vec::new(),
+            });
+
+            let input_name = format!("{}Input", type_name);
+            schema.types.push(TypeDef {
+                name: input_name.clone(),
+                kind: TypeKind::InputObject,
+                description: Some(format!("A new document of the pile \"{}\"", pile_name)),
+                fields: Vec::new(),
+                input_fields: properties
+                    .iter()
+                    .map(|(name, type_ref)| InputDef {
+                        name: name.to_string(),
+                        type_ref: match required.contains(&name.as_str()) {
+                            true => type_ref.clone().non_null(),
+                            false => type_ref.clone(),
+                        },
+                    })
+                    .collect(),
+            });
+            let patch_name = format!("{}Patch", type_name);
+            schema.types.push(TypeDef {
+                name: patch_name.clone(),
+                kind: TypeKind::InputObject,
+                description: Some(format!(
+                    "Changes to a document of the pile \"{}\", null removing a property",
+                    pile_name
+                )),
+                fields: Vec::new(),
+                input_fields: properties
+                    .iter()
+                    .map(|(name, type_ref)| InputDef {
+                        name: name.to_string(),
+                        type_ref: type_ref.clone(),
+                    })
+                    .collect(),
+            });
+
+            let id_argument = || InputDef {
+                name: "id".to_owned(),
+                type_ref: TypeRef::Named("ID").non_null(),
+            };
+            let document_type = TypeRef::Generated(type_name.clone());
+            let mut find_arguments: Vec<InputDef> = properties
+                .iter()
+                .filter(|(name, type_ref)| {
+                    name.as_str() != "first"
+                        && matches!(type_ref, TypeRef::Named(scalar) if *scalar != "JSON")
+                })
+                .map(|(name, type_ref)| InputDef {
+                    name: name.to_string(),
+                    type_ref: type_ref.clone(),
+                })
+                .collect();
+            find_arguments.push(InputDef {
+                name: "first".to_owned(),
+                type_ref: TypeRef::Named("Int"),
+            });
+
+            query_fields.push(FieldDef {
+                name: field_name.clone(),
+                description: Some(format!("The document of \"{}\" with the ID", pile_name)),
+                arguments: vec![id_argument()],
+                type_ref: document_type.clone(),
+            });
+            query_fields.push(FieldDef {
+                name: format!("find{}", type_name),
+                description: Some(format!(
+                    "The documents of \"{}\" whose properties equal all the values given",
+                    pile_name
+                )),
+                arguments: find_arguments,
+                type_ref: document_type.clone().non_null().list(),
+            });
+            mutation_fields.push(FieldDef {
+                name: format!("create{}", type_name),
+                description: Some(format!("Stores a new document in \"{}\"", pile_name)),
+                arguments: vec![InputDef {
+                    name: "input".to_owned(),
+                    type_ref: TypeRef::Generated(input_name).non_null(),
+                }],
+                type_ref: document_type.clone(),
+            });
+            mutation_fields.push(FieldDef {
+                name: format!("update{}", type_name),
+                description: Some(format!(
+                    "Applies the changes to the document of \"{}\" with the ID",
+                    pile_name
+                )),
+                arguments: vec![
+                    id_argument(),
+                    InputDef {
+                        name: "input".to_owned(),
+                        type_ref: TypeRef::Generated(patch_name).non_null(),
+                    },
+                ],
+                type_ref: document_type,
+            });
+
+            let [read_name, find_name, create_name, update_name] = root_names;
+            schema.roots.extend([
+                (read_name, RootField::Read(pile_name.clone())),
+                (find_name, RootField::Find(pile_name.clone())),
+                (create_name, RootField::Create(pile_name.clone())),
+                (update_name, RootField::Update(pile_name.clone())),
+            ]);
+            schema.piles.push(pile_name);
+        }
+
+        schema.types.push(TypeDef {
+            name: "Query".to_owned(),
+            kind: TypeKind::Object,
+            description: None,
+            fields: query_fields,
+            input_fields: Vec::new(),
+        });
+        if !mutation_fields.is_empty() {
+            schema.types.push(TypeDef {
+                name: "Mutation".to_owned(),
+                kind: TypeKind::Object,
+                description: None,
+                fields: mutation_fields,
+                input_fields: Vec::new(),
+            });
+        }
+
+        Ok(schema)
+    }
+
+    fn type_def(&self, name: &str) -> Option<&TypeDef> {
+        self.types.iter().find(|type_def| type_def.name == name)
+    }
+
+    /// The schema as `__schema` introspects it
+    fn introspect(&self) -> Value {
+        let directive = |name: &str, description: &str| {
+            json!({
+                "__typename": "__Directive",
+                "name": name,
+                "description": description,
+                "isRepeatable": false,
+                "locations": ["FIELD", "FRAGMENT_SPREAD", "INLINE_FRAGMENT"],
+                "args": [self.introspect_input(&InputDef {
+                    name: "if".to_owned(),
+                    type_ref: TypeRef::Named("Boolean").non_null(),
+                })],
+            })
+        };
+
+        json!({
+            "__typename": "__Schema",
+            "description": null,
+            "queryType": self.introspect_type("Query"),
+            "mutationType": self.introspect_type("Mutation"),
+            "subscriptionType": null,
+            "types": self
+                .types
+                .iter()
+                .map(|type_def| self.introspect_type(&type_def.name))
+                .collect::<Vec<Value>>(),
+            "directives": [
+                directive("include", "Includes the selection only if the argument is true"),
+                directive("skip", "Skips the selection if the argument is true"),
+            ],
+        })
+    }
+
+    /// A named type as `__type` introspects it. Object types only have fields
+    /// of scalar or pile types, so expanding them always ends.
+    fn introspect_type(&self, name: &str) -> Value {
+        let type_def = match self.type_def(name) {
+            Some(type_def) => type_def,
+            None => return Value::Null,
+        };
+
+        let (kind, fields, input_fields, interfaces) = match type_def.kind {
+            TypeKind::Scalar => ("SCALAR", Value::Null, Value::Null, Value::Null),
+            TypeKind::Object => (
+                "OBJECT",
+                type_def
+                    .fields
+                    .iter()
+                    .map(|field| {
+                        json!({
+                            "__typename": "__Field",
+                            "name": field.name,
+                            "description": field.description,
+                            "args": field
+                                .arguments
+                                .iter()
+                                .map(|argument| self.introspect_input(argument))
+                                .collect::<Vec<Value>>(),
+                            "type": self.introspect_type_ref(&field.type_ref),
+                            "isDeprecated": false,
+                            "deprecationReason": null,
+                        })
+                    })
+                    .collect(),
+                Value::Null,
+                json!([]),
+            ),
+            TypeKind::InputObject => (
+                "INPUT_OBJECT",
+                Value::Null,
+                type_def
+                    .input_fields
+                    .iter()
+                    .map(|input| self.introspect_input(input))
+                    .collect(),
+                Value::Null,
+            ),
+        };
+
+        json!({
+            "__typename": "__Type",
+            "kind": kind,
+            "name": type_def.name,
+            "description": type_def.description,
+            "specifiedByURL": null,
+            "fields": fields,
+            "inputFields": input_fields,
+            "interfaces": interfaces,
+            "enumValues": null,
+            "possibleTypes": null,
+            "ofType": null,
+            "isOneOf": false,
+        })
+    }
+
+    fn introspect_type_ref(&self, type_ref: &TypeRef) -> Value {
+        let (kind, of_type) = match type_ref {
+            TypeRef::Named(_) | TypeRef::Generated(_) => {
+                return self.introspect_type(type_ref.base_name())
+            }
+            TypeRef::List(of_type) => ("LIST", of_type),
+            TypeRef::NonNull(of_type) => ("NON_NULL", of_type),
+        };
+
+        json!({
+            "__typename": "__Type",
+            "kind": kind,
+            "name": null,
+            "description": null,
+            "specifiedByURL": null,
+            "fields": null,
+            "inputFields": null,
+            "interfaces": null,
+            "enumValues": null,
+            "possibleTypes": null,
+            "ofType": self.introspect_type_ref(of_type),
+            "isOneOf": false,
+        })
+    }
+
+    fn introspect_input(&self, input: &InputDef) -> Value {
+        json!({
+            "__typename": "__InputValue",
+            "name": input.name,
+            "description": null,
+            "type": self.introspect_type_ref(&input.type_ref),
+            "defaultValue": null,
+            "isDeprecated": false,
+            "deprecationReason": null,
+        })
+    }
+
+    /// Checks an input value against its type, the way the spec coerces
+    /// inputs: a single value stands for a list of one
+    fn coerce(&self, value: &Value, type_ref: &TypeRef) -> Result<Value, String> {
+        let type_ref = match (type_ref, value) {
+            (TypeRef::NonNull(_), Value::Null) => {
+                return Err(format!("Expected a value of type {}", type_ref.describe()))
+            }
+            (TypeRef::NonNull(type_ref), _) => type_ref,
+            (_, Value::Null) => return Ok(Value::Null),
+            _ => type_ref,
+        };
+
+        match (type_ref, value) {
+            (TypeRef::List(type_ref), Value::Array(values)) => values
+                .iter()
+                .map(|value| self.coerce(value, type_ref))
+                .collect(),
+            (TypeRef::List(type_ref), _) => Ok(Value::Array(vec![self.coerce(value, type_ref)?])),
+            (TypeRef::Named("ID"), Value::String(_)) => Ok(value.clone()),
+            (TypeRef::Named("ID"), Value::Number(number)) if number.is_i64() => {
+                Ok(Value::String(number.to_string()))
+            }
+            (TypeRef::Named("String"), Value::String(_))
+            | (TypeRef::Named("Boolean"), Value::Bool(_))
+            | (TypeRef::Named("Float"), Value::Number(_))
+            | (TypeRef::Named("JSON"), _) => Ok(value.clone()),
+            (TypeRef::Named("Int"), Value::Number(number)) if number.is_i64() => Ok(value.clone()),
+            (TypeRef::Generated(name), Value::Object(entries)) => {
+                let input_fields = match self.type_def(name) {
+                    Some(type_def) => &type_def.input_fields,
+                    None => return Err(format!("Unknown type {}", name)),
+                };
+                if let Some(unknown) = entries
+                    .keys()
+                    .find(|key| !input_fields.iter().any(|input| &input.name == *key))
+                {
+                    return Err(format!(
+                        "Field \"{}\" isn't defined by type {}",
+                        unknown, name
+                    ));
+                }
+
+                // Fields left out stay out, so a patch only touches what it names
+                let mut coerced = Map::new();
+                for input in input_fields {
+                    match entries.get(&input.name) {
+                        Some(value) => {
+                            let value = self
+                                .coerce(value, &input.type_ref)
+                                .map_err(|e| format!("{} in field \"{}\"", e, input.name))?;
+                            coerced.insert(input.name.clone(), value);
+                        }
+                        None if matches!(input.type_ref, TypeRef::NonNull(_)) => {
+                            return Err(format!(
+                                "Field \"{}\" of type {} is required",
+                                input.name, name
+                            ));
+                        }
+                        None => (),
+                    }
+                }
+                Ok(Value::Object(coerced))
+            }
+            _ => Err(format!(
+                "Expected a value of type {}, found {}",
+                type_ref.describe(),
+                value
+            )),
+        }
+    }
+}
+
+/// `order_items` becomes `OrderItems`
+fn type_name(pile_name: &str) -> String {
+    let type_name: String = pile_name
+        .split(['_', '-'])
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}{}", word[..1].to_uppercase(), &word[1..]))
+        .collect();
+
+    // Names can't start with a digit
+    match type_name.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format!("Pile{}", type_name),
+        false => type_name,
+    }
+}
+
+/// Whether the text is a GraphQL name, leaving out the names reserved for
+/// introspection
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic())
+        && text.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && !text.starts_with("__")
+}
+
+/// The GraphQL type of a JSON Schema property. Everything is nullable, as
+/// documents stored before the schema may not follow it.
+fn property_type(property: &Value) -> TypeRef {
+    let type_names: Vec<&str> = match property.get("type") {
+        Some(Value::String(type_name)) => vec![type_name.as_str()],
+        Some(Value::Array(type_names)) => type_names
+            .iter()
+            .filter_map(Value::as_str)
+            .filter(|type_name| *type_name != "null")
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    match type_names.as_slice() {
+        ["string"] => TypeRef::Named("String"),
+        ["integer"] => TypeRef::Named("Int"),
+        ["number"] | ["integer", "number"] | ["number", "integer"] => TypeRef::Named("Float"),
+        ["boolean"] => TypeRef::Named("Boolean"),
+        ["array"] => match property.get("items").map(property_type) {
+            Some(TypeRef::Named(name)) if name != "JSON" => TypeRef::Named(name).list(),
+            _ => TypeRef::Named("JSON"),
+        },
+        _ => TypeRef::Named("JSON"),
+    }
+}
+
+/// Fields by response key, the fields sharing one merged
+type Collected<'a> = Vec<(String, Vec<&'a Field>)>;
+
+struct Context<'a> {
+    schema: &'a Schema,
+    fragments: &'a HashMap<String, Fragment>,
+    variables: &'a Map<String, Value>,
+}
+
+impl<'a> Context<'a> {
+    /// Collects the fields selected on an object of the type, following
+    /// fragments and `@skip`/`@include`
+    fn collect(
+        &self,
+        selections: impl Iterator<Item = &'a Selection>,
+        type_name: &str,
+        depth: usize,
+    ) -> Result<Collected<'a>, String> {
+        if depth > MAX_FRAGMENT_DEPTH {
+            return Err("Fragments are nested too deeply, or spread themselves".to_owned());
+        }
+
+        let mut collected: Collected<'a> = Vec::new();
+
+        for selection in selections {
+            match selection {
+                Selection::Field(field) => {
+                    if self.is_included(&field.directives)? {
+                        let key = field.alias.clone().unwrap_or_else(|| field.name.clone());
+                        merge_collected(&mut collected, vec![(key, vec![field])]);
+                    }
+                }
+                Selection::FragmentSpread { name, directives } => {
+                    let fragment = match self.fragments.get(name) {
+                        Some(fragment) => fragment,
+                        None => return Err(format!("Unknown fragment \"{}\"", name)),
+                    };
+                    if self.is_included(directives)? && fragment.type_condition == type_name {
+                        let more =
+                            self.collect(fragment.selections.iter(), type_name, depth + 1)?;
+                        merge_collected(&mut collected, more);
+                    }
+                }
+                Selection::InlineFragment {
+                    type_condition,
+                    directives,
+                    selections,
+                } => {
+                    let applies = type_condition
+                        .as_ref()
+                        .is_none_or(|type_condition| type_condition == type_name);
+                    if self.is_included(directives)? && applies {
+                        let more = self.collect(selections.iter(), type_name, depth + 1)?;
+                        merge_collected(&mut collected, more);
+                    }
+                }
+            }
+        }
+
+        Ok(collected)
+    }
+
+    fn is_included(&self, directives: &[Directive]) -> Result<bool, String> {
+        for directive in directives {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, literal)| literal.resolve(self.variables));
+            let condition = match condition {
+                Some(Value::Bool(condition)) => condition,
+                _ => {
+                    return Err(format!(
+                        "Directive \"@{}\" must have a Boolean \"if\" argument",
+                        directive.name
+                    ))
+                }
+            };
+            match directive.name.as_str() {
+                "skip" if condition => return Ok(false),
+                "include" if !condition => return Ok(false),
+                "skip" | "include" => (),
+                name => return Err(format!("Unknown directive \"@{}\"", name)),
+            }
+        }
+        Ok(true)
+    }
+
+    /// The sub-selections of fields sharing a response key, merged
+    fn sub_selections(
+        &self,
+        fields: &[&'a Field],
+        type_name: &str,
+    ) -> Result<Collected<'a>, String> {
+        self.collect(
+            fields.iter().flat_map(|field| field.selections.iter()),
+            type_name,
+            0,
+        )
+    }
+
+    fn arguments(&self, field: &Field, field_def: &FieldDef) -> Result<Map<String, Value>, String> {
+        let mut arguments = Map::new();
+        for (name, literal) in &field.arguments {
+            let argument_def = match field_def.arguments.iter().find(|def| &def.name == name) {
+                Some(argument_def) => argument_def,
+                None => {
+                    return Err(format!(
+                        "Unknown argument \"{}\" on field \"{}\"",
+                        name, field.name
+                    ))
+                }
+            };
+            let value = self
+                .schema
+                .coerce(&literal.resolve(self.variables), &argument_def.type_ref)
+                .map_err(|e| format!("{} for argument \"{}\" of \"{}\"", e, name, field.name))?;
+            arguments.insert(name.clone(), value);
+        }
+
+        if let Some(missing) = field_def.arguments.iter().find(|def| {
+            matches!(def.type_ref, TypeRef::NonNull(_)) && !arguments.contains_key(&def.name)
+        }) {
+            return Err(format!(
+                "Field \"{}\" must have the argument \"{}\" of type {}",
+                field.name,
+                missing.name,
+                missing.type_ref.describe()
+            ));
+        }
+
+        Ok(arguments)
+    }
+
+    /// Checks the selected fields against the object type, before anything
+    /// runs
+    fn validate(&self, type_name: &str, fields: &Collected<'a>, errors: &mut Vec<String>) {
+        let type_def = match self.schema.type_def(type_name) {
+            Some(type_def) => type_def,
+            None => return,
+        };
+
+        for (_, group) in fields {
+            let field = group[0];
+            match field.name.as_str() {
+                "__typename" => continue,
+                // Introspection results are only ever read
+                "__schema" | "__type" if type_name == "Query" => continue,
+                _ => (),
+            }
+
+            let field_def = match type_def.fields.iter().find(|def| def.name == field.name) {
+                Some(field_def) => field_def,
+                None => {
+                    errors.push(format!(
+                        "Cannot query field \"{}\" on type \"{}\"",
+                        field.name, type_name
+                    ));
+                    continue;
+                }
+            };
+            for field in group {
+                if let Err(e) = self.arguments(field, field_def) {
+                    errors.push(e);
+                }
+            }
+
+            let base_name = field_def.type_ref.base_name();
+            let has_selections = group.iter().any(|field| !field.selections.is_empty());
+            match self.schema.type_def(base_name).map(|def| def.kind) {
+                Some(TypeKind::Object) if !has_selections => errors.push(format!(
+                    "Field \"{}\" of type {} must have a selection of subfields",
+                    field.name,
+                    field_def.type_ref.describe()
+                )),
+                Some(TypeKind::Object) => match self.sub_selections(group, base_name) {
+                    Ok(sub_fields) => self.validate(base_name, &sub_fields, errors),
+                    Err(e) => errors.push(e),
+                },
+                _ if has_selections => errors.push(format!(
+                    "Field \"{}\" of type {} must not have a selection",
+                    field.name,
+                    field_def.type_ref.describe()
+                )),
+                _ => (),
+            }
+        }
+    }
+
+    fn resolve_root(&self, root_type: &str, group: &[&'a Field]) -> Result<Value, String> {
+        let field = group[0];
+        match field.name.as_str() {
+            "__typename" => return Ok(Value::from(root_type)),
+            "__schema" => return self.project(&self.schema.introspect(), group),
+            "__type" => {
+                let name = field
+                    .arguments
+                    .iter()
+                    .find(|(name, _)| name == "name")
+                    .map(|(_, literal)| literal.resolve(self.variables));
+                return match name {
+                    Some(Value::String(name)) => {
+                        self.project(&self.schema.introspect_type(&name), group)
+                    }
+                    _ => Err("Field \"__type\" must have a String \"name\" argument".to_owned()),
+                };
+            }
+            _ => (),
+        }
+
+        // Validated already, so the field and its arguments are known
+        let field_def = self
+            .schema
+            .type_def(root_type)
+            .and_then(|type_def| type_def.fields.iter().find(|def| def.name == field.name))
+            .ok_or_else(|| format!("Unknown field \"{}\"", field.name))?;
+        let arguments = self.arguments(field, field_def)?;
+        let id = arguments.get("id").and_then(Value::as_str);
+        let type_name = field_def.type_ref.base_name();
+
+        let resolved = match self.schema.roots.get(&field.name) {
+            Some(RootField::Read(pile_name)) => read_document(pile_name, id.unwrap_or_default()),
+            Some(RootField::Find(pile_name)) => find_documents(pile_name, &arguments),
+            Some(RootField::Create(pile_name)) => {
+                let input = arguments.get("input").cloned().unwrap_or_default();
+                crate::create(pile_name, None, &input.to_string(), Encoding::Json)
+                    .and_then(|uuid| read_document(pile_name, &uuid))
+            }
+            Some(RootField::Update(pile_name)) => {
+                let uuid = id.unwrap_or_default();
+                let input = arguments.get("input").cloned().unwrap_or_default();
+                check_document_id(uuid)
+                    .and_then(|_| {
+                        crate::patch_document(
+                            pile_name,
+                            uuid,
+                            &input.to_string(),
+                            None,
+                            Encoding::Json,
+                        )
+                    })
+                    .and_then(|_| read_document(pile_name, uuid))
+            }
+            None => return Err(format!("Unknown field \"{}\"", field.name)),
+        };
+
+        let resolved = resolved.map_err(|e| e.to_string())?;
+        let sub_fields = self.sub_selections(group, type_name)?;
+        Ok(match resolved {
+            Value::Array(documents) => documents
+                .iter()
+                .map(|document| complete_document(document, type_name, &sub_fields))
+                .collect(),
+            Value::Null => Value::Null,
+            document => complete_document(&document, type_name, &sub_fields),
+        })
+    }
+
+    /// Picks the selected fields out of an introspection result, whose
+    /// objects carry their type as `__typename`
+    fn project(&self, value: &Value, group: &[&'a Field]) -> Result<Value, String> {
+        match value {
+            Value::Array(values) => values
+                .iter()
+                .map(|value| self.project(value, group))
+                .collect(),
+            Value::Object(object) => {
+                let type_name = object
+                    .get("__typename")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let mut projected = Map::new();
+                for (key, fields) in self.sub_selections(group, type_name)? {
+                    let value = object.get(&fields[0].name).unwrap_or(&Value::Null);
+                    let value = match fields.iter().all(|field| field.selections.is_empty()) {
+                        true => value.clone(),
+                        false => self.project(value, &fields)?,
+                    };
+                    projected.insert(key, value);
+                }
+                Ok(Value::Object(projected))
+            }
+            _ => Ok(value.clone()),
+        }
+    }
+}
+
+fn merge_collected<'a>(collected: &mut Collected<'a>, more: Collected<'a>) {
+    for (key, fields) in more {
+        match collected.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, existing)) => existing.extend(fields),
+            None => collected.push((key, fields)),
+        }
+    }
+}
+
+/// Picks the selected fields out of a document
+fn complete_document(document: &Value, type_name: &str, fields: &Collected) -> Value {
+    let mut completed = Map::new();
+    for (key, group) in fields {
+        let value = match group[0].name.as_str() {
+            "__typename" => Value::from(type_name),
+            name => document.get(name).cloned().unwrap_or(Value::Null),
+        };
+        completed.insert(key.clone(), value);
+    }
+    Value::Object(completed)
+}
+
+fn check_document_id(uuid: &str) -> Result<(), io::Error> {
+    if !is_valid_document_id(uuid) {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid document ID: \"{}\"", uuid);
+        return Err(io::Error::new(e_kind, e));
+    }
+    Ok(())
+}
+
+/// The document with its `_id`, or null if there is none
+fn read_document(pile_name: &str, uuid: &str) -> Result<Value, io::Error> {
+    check_document_id(uuid)?;
+    let file_path = document_file_path(&pile_path(pile_name)?, uuid);
+    match document_with_id(Path::new(&file_path)) {
+        Ok(document) => Ok(document.unwrap_or_default()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Value::Null),
+        Err(e) => Err(e),
+    }
+}
+
+/// The documents whose properties equal the arguments (except `first`), in
+/// document order
+fn find_documents(pile_name: &str, arguments: &Map<String, Value>) -> Result<Value, io::Error> {
+    let first = match arguments.get("first").and_then(Value::as_i64) {
+        Some(first) => first.max(0) as usize,
+        None => DEFAULT_FIRST,
+    };
+
+    let conditions: Vec<String> = arguments
+        .iter()
+        .filter(|(name, _)| name.as_str() != "first")
+        .map(|(name, value)| match value {
+            Value::Null => format!("{} IS NULL", name),
+            // JSON literals keep their type, strings are quoted
+            value => format!("{} = {}", name, value),
+        })
+        .collect();
+    let file_paths = match conditions.is_empty() {
+        true => document_paths(pile_name)?,
+        false => {
+            let predicate = Predicate::parse(&conditions.join(" AND ")).map_err(|e| {
+                let e_kind = io::ErrorKind::InvalidInput;
+                io::Error::new(e_kind, e)
+            })?;
+            let pile_path = pile_path(pile_name)?;
+            matching_ids(pile_name, &predicate)?
+                .iter()
+                .map(|uuid| document_file_path(&pile_path, uuid).into())
+                .collect()
+        }
+    };
+
+    let mut documents = Vec::new();
+    for file_path in file_paths.iter().take(first) {
+        match document_with_id(file_path) {
+            Ok(Some(document)) => documents.push(document),
+            Ok(None) => (),
+            // Deleted since the pile was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(Value::Array(documents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let list = format!("{{ x(a: {}) }}", "[".repeat(200_000));
+        assert!(Parser::parse(&list).is_err());
+        let object = format!("{{ x(a: {}) }}", "{a: ".repeat(200_000));
+        assert!(Parser::parse(&object).is_err());
+        let selections = format!("{}x{}", "{ x ".repeat(200_000), "}".repeat(200_000));
+        assert!(Parser::parse(&selections).is_err());
+        let variable_type = format!("query ($a: {}Int) {{ x }}", "[".repeat(200_000));
+        assert!(Parser::parse(&variable_type).is_err());
+    }
+
+    #[test]
+    fn allows_shallow_nesting() {
+        let depth = MAX_NESTING_DEPTH - 1;
+        let list = format!("{{ x(a: {}1{}) }}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Parser::parse(&list).is_ok());
+        let selections = format!("{}x{}", "{ x ".repeat(depth), "}".repeat(depth));
+        assert!(Parser::parse(&selections).is_ok());
+    }
+}
@@ -0,0 +1,471 @@
+/// The HTTP listener.
+///
+/// A listener with the `HTTP` flag (see listeners.rs) speaks HTTP/1.1 instead
+/// of the line protocol, for clients such as browsers that can't open a raw
+/// TCP connection:
+///
+/// GET /piles/<pile>/events  the pile's change events as server-sent events
+///                           (https://html.spec.whatwg.org/multipage/server-sent-events.html)
+/// POST /graphql             a GraphQL request over the piles' schemas (see
+///                           graphql.rs)
+///
+/// Once users exist, requests authenticate with HTTP Basic authentication,
+/// and need the same rights as the line protocol's equivalent command (for
+/// the change feed, WATCH). Each connection serves a single request, and
+/// request bodies must have a `Content-Length` of at most `MAX_BODY_BYTES`.
+///
+/// The change feed sends every committed write to the pile as an event
+/// named `created`, `updated` or `deleted`, whose data is the change as
+/// webhooks deliver it (see webhooks.rs):
+///
+/// event: updated
+/// data: {"pile":"orders","op":"UPDATE","id":"cd8abd45-...","document":{..},"timestamp":"..."}
+///
+/// A quiet feed is sent a comment every `DUST_SSE_KEEPALIVE_SECS` (default
+/// 15), so proxies don't time it out. A client that falls too far behind
+/// (see events.rs) is sent an `error` event and disconnected; browsers
+/// reconnect on their own, and can catch up with SCAN. With
+/// `DUST_HTTP_ALLOW_ORIGIN` set, pages from that origin may read responses
+/// across origins (CORS), credentials included.
+use crate::listeners::ListenerFlags;
+use crate::logging::{self, Level};
+use crate::webhooks::WebhookEvent;
+use crate::{authorize, env_or, events, graphql, pile, Request};
+use base64ct::{Base64, Encoding as _};
+use serde_json::{json, Value};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tracing::Instrument;
+
+/// Requests with a longer request line and headers are refused
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Requests with a longer body are refused
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+struct Head {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The user name and password of HTTP Basic authentication
+    fn credentials(&self) -> Option<(String, String)> {
+        let encoded = self.header("Authorization")?.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(Base64::decode_vec(encoded.trim()).ok()?).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        Some((name.to_owned(), password.to_owned()))
+    }
+}
+
+struct HttpError {
+    status: &'static str,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: &'static str, message: &str) -> HttpError {
+        HttpError {
+            status,
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// Accepts the connections of one HTTP listener, for as long as the server
+/// runs
+pub async fn serve(listener: TcpListener, flags: ListenerFlags) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, socket_addr)) => {
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = handle_connection(socket, flags).await {
+                            logging::diagnostic(
+                                Level::Debug,
+                                &format!("Error serving HTTP connection: {:?}", e),
+                            );
+                        }
+                    }
+                    .instrument(tracing::info_span!("http_connection", peer = %socket_addr)),
+                );
+            }
+            Err(e) => {
+                logging::diagnostic(Level::Error, &format!("Error accepting socket: {:?}", e))
+            }
+        }
+    }
+}
+
+async fn handle_connection(socket: TcpStream, flags: ListenerFlags) -> Result<(), io::Error> {
+    let idle_timeout = Duration::from_secs(env_or("DUST_IDLE_TIMEOUT_SECS", 300));
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let head = match tokio::time::timeout(idle_timeout, read_head(&mut reader)).await {
+        Ok(Ok(Some(head))) => head,
+        Ok(Ok(None)) | Err(_) => return Ok(()),
+        Ok(Err(e)) => {
+            let error = HttpError::new("400 Bad Request", &e.to_string());
+            return send_error(&mut writer, &error).await;
+        }
+    };
+
+    // CORS preflights, see `common_headers`
+    if head.method == "OPTIONS" {
+        let headers = "Allow: GET, POST, OPTIONS\r\nAccess-Control-Allow-Methods: GET, POST, OPTIONS\r\nAccess-Control-Allow-Headers: Authorization, Content-Type\r\nAccess-Control-Max-Age: 600\r\n";
+        return send_response(&mut writer, "204 No Content", headers, None).await;
+    }
+
+    let path = head.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let routed = match segments.as_slice() {
+        ["piles", pile_name, "events"] => match head.method.as_str() {
+            "GET" => {
+                let pile_name = pile_name.to_lowercase();
+                let request = Request::Watch {
+                    pile: pile_name.clone(),
+                };
+                match check_access(&head, request, flags).await {
+                    Ok(()) => match pile::pile_path(&pile_name) {
+                        Ok(_) => return stream_changes(reader, writer, &pile_name).await,
+                        Err(e) => HttpError::new("404 Not Found", &e.to_string()),
+                    },
+                    Err(error) => error,
+                }
+            }
+            _ => HttpError::new("405 Method Not Allowed", "Only GET is allowed here"),
+        },
+        ["graphql"] => match head.method.as_str() {
+            "POST" => return run_graphql(reader, writer, &head, flags, idle_timeout).await,
+            _ => HttpError::new("405 Method Not Allowed", "Only POST is allowed here"),
+        },
+        _ => HttpError::new("404 Not Found", "No such resource"),
+    };
+
+    send_error(&mut writer, &routed).await
+}
+
+/// Reads the request line and headers, or nothing if the client hung up
+/// before sending a request
+async fn read_head(reader: &mut BufReader<OwnedReadHalf>) -> Result<Option<Head>, io::Error> {
+    let mut lines = Vec::new();
+    let mut head_bytes = 0;
+    loop {
+        let mut line = String::new();
+        let read = (&mut *reader)
+            .take((MAX_HEAD_BYTES - head_bytes) as u64)
+            .read_line(&mut line)
+            .await?;
+        head_bytes += read;
+        if !line.ends_with('\n') {
+            if head_bytes >= MAX_HEAD_BYTES {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Request head is over {} bytes", MAX_HEAD_BYTES);
+                return Err(io::Error::new(e_kind, e));
+            }
+            // The client hung up before finishing its request
+            return Ok(None);
+        }
+
+        let line = line.trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().unwrap_or_default();
+    let (method, path) = match request_line.split(' ').collect::<Vec<&str>>().as_slice() {
+        [method, path, version] if version.starts_with("HTTP/1.") => {
+            (method.to_string(), path.to_string())
+        }
+        _ => {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = format!("Malformed request line: \"{}\"", request_line);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let headers = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect();
+
+    Ok(Some(Head {
+        method,
+        path,
+        headers,
+    }))
+}
+
+/// Checks that the request's credentials may make the equivalent request of
+/// the line protocol. Verifying a password is slow on purpose and reads the
+/// user from disk, so it's kept off the threads that drive connections.
+async fn check_access(
+    head: &Head,
+    request: Request,
+    flags: ListenerFlags,
+) -> Result<(), HttpError> {
+    let credentials = head.credentials();
+    let has_credentials = credentials.is_some();
+    let authorized = tokio::task::spawn_blocking(move || {
+        let credentials_ref = credentials
+            .as_ref()
+            .map(|(name, password)| (name.as_str(), password.as_str()));
+        authorize(&request, credentials_ref, flags)
+    })
+    .await;
+
+    match authorized {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(access_error(has_credentials, &e)),
+        Err(e) => Err(HttpError::new("500 Internal Server Error", &e.to_string())),
+    }
+}
+
+fn access_error(has_credentials: bool, e: &io::Error) -> HttpError {
+    match (e.kind(), has_credentials) {
+        (io::ErrorKind::PermissionDenied, false) => {
+            HttpError::new("401 Unauthorized", &e.to_string())
+        }
+        (io::ErrorKind::PermissionDenied, true) => HttpError::new("403 Forbidden", &e.to_string()),
+        _ => HttpError::new("500 Internal Server Error", &e.to_string()),
+    }
+}
+
+/// Reads the request's body, as long as its `Content-Length` says
+async fn read_body(
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+    head: &Head,
+) -> Result<Vec<u8>, HttpError> {
+    if head.header("Transfer-Encoding").is_some() {
+        return Err(HttpError::new(
+            "411 Length Required",
+            "Chunked bodies aren't supported, send a Content-Length",
+        ));
+    }
+    let length = match head.header("Content-Length").map(str::parse::<usize>) {
+        Some(Ok(length)) if length <= MAX_BODY_BYTES => length,
+        Some(Ok(_)) => {
+            let e = format!("Request body is over {} bytes", MAX_BODY_BYTES);
+            return Err(HttpError::new("413 Content Too Large", &e));
+        }
+        Some(Err(_)) => return Err(HttpError::new("400 Bad Request", "Invalid Content-Length")),
+        None => {
+            return Err(HttpError::new(
+                "411 Length Required",
+                "Content-Length is missing",
+            ))
+        }
+    };
+
+    // Clients like curl wait for the go-ahead before sending a larger body
+    let io_error = |e: io::Error| HttpError::new("400 Bad Request", &e.to_string());
+    if head
+        .header("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        writer
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .map_err(io_error)?;
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await.map_err(io_error)?;
+    Ok(body)
+}
+
+/// Example:
+/// in: POST /graphql {"query":"{ orders(id: \"cd8abd45-...\") { _id status } }"}
+/// out: HTTP/1.1 200 OK
+/// out: Content-Type: application/json
+/// out:
+/// out: {"data":{"orders":{"_id":"cd8abd45-...","status":"shipped"}}}
+async fn run_graphql(
+    mut reader: BufReader<OwnedReadHalf>,
+    mut writer: OwnedWriteHalf,
+    head: &Head,
+    flags: ListenerFlags,
+    idle_timeout: Duration,
+) -> Result<(), io::Error> {
+    let body =
+        match tokio::time::timeout(idle_timeout, read_body(&mut reader, &mut writer, head)).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(error)) => return send_error(&mut writer, &error).await,
+            Err(_) => return Ok(()),
+        };
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = HttpError::new("400 Bad Request", &format!("Invalid JSON body: {}", e));
+            return send_error(&mut writer, &error).await;
+        }
+    };
+
+    // Resolving reads and writes documents, which is blocking filesystem IO
+    let credentials = head.credentials();
+    let has_credentials = credentials.is_some();
+    let executed = tokio::task::spawn_blocking(move || {
+        let credentials_ref = credentials
+            .as_ref()
+            .map(|(name, password)| (name.as_str(), password.as_str()));
+        graphql::execute(&request, credentials_ref, flags)
+    })
+    .await;
+
+    match executed {
+        Ok(Ok(response)) => {
+            let body = response.to_string();
+            send_response(&mut writer, "200 OK", "", Some(("application/json", &body))).await
+        }
+        Ok(Err(e)) => send_error(&mut writer, &access_error(has_credentials, &e)).await,
+        Err(e) => {
+            let error = HttpError::new("500 Internal Server Error", &e.to_string());
+            send_error(&mut writer, &error).await
+        }
+    }
+}
+
+/// Headers every response carries
+fn common_headers() -> String {
+    let mut headers = format!("Server: dustdb/{}\r\n", env!("CARGO_PKG_VERSION"));
+    if let Ok(origin) = std::env::var("DUST_HTTP_ALLOW_ORIGIN") {
+        headers.push_str(&format!(
+            "Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Credentials: true\r\nVary: Origin\r\n",
+            origin
+        ));
+    }
+    headers
+}
+
+async fn send_error(writer: &mut OwnedWriteHalf, error: &HttpError) -> Result<(), io::Error> {
+    let body = json!({ "error": error.message }).to_string();
+    let authenticate = match error.status.starts_with("401") {
+        true => "WWW-Authenticate: Basic realm=\"dustdb\"\r\n",
+        false => "",
+    };
+    send_response(
+        writer,
+        error.status,
+        authenticate,
+        Some(("application/json", &body)),
+    )
+    .await
+}
+
+/// Sends a complete response with the `(content type, body)`, if any, and
+/// closes the connection
+async fn send_response(
+    writer: &mut OwnedWriteHalf,
+    status: &str,
+    headers: &str,
+    content: Option<(&str, &str)>,
+) -> Result<(), io::Error> {
+    let (content_type, body) = content.unwrap_or_default();
+    let content_headers = match content {
+        Some(_) => format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            body.len()
+        ),
+        None => String::new(),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}{}{}Connection: close\r\n\r\n{}",
+        status,
+        common_headers(),
+        headers,
+        content_headers,
+        body
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+/// Example:
+/// in: GET /piles/orders/events
+/// out: HTTP/1.1 200 OK
+/// out: Content-Type: text/event-stream
+/// out:
+/// out: event: created
+/// out: data: {"pile":"orders","op":"CREATE","id":"cd8abd45-...","document":{..},"timestamp":"..."}
+async fn stream_changes(
+    mut reader: BufReader<OwnedReadHalf>,
+    mut writer: OwnedWriteHalf,
+    pile_name: &str,
+) -> Result<(), io::Error> {
+    use broadcast::error::RecvError;
+
+    // Subscribe before the response starts, so no change made in between
+    // goes missing
+    let mut receiver = events::subscribe_changes();
+    writer
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\n{}Content-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                common_headers()
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let keepalive_secs = env_or("DUST_SSE_KEEPALIVE_SECS", 15_u64).max(1);
+    let mut keepalive = tokio::time::interval(Duration::from_secs(keepalive_secs));
+    keepalive.reset();
+    let mut discarded = [0u8; 1024];
+
+    loop {
+        // The client hanging up ends the feed
+        let received = tokio::select! {
+            received = receiver.recv() => Some(received),
+            _ = keepalive.tick() => None,
+            read = reader.read(&mut discarded) => match read {
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(_) => continue,
+            },
+        };
+
+        let message = match received {
+            None => ": keepalive\n\n".to_owned(),
+            Some(Ok(change)) if change.pile == pile_name => {
+                let event_name = match change.event {
+                    WebhookEvent::Create => "created",
+                    WebhookEvent::Update => "updated",
+                    WebhookEvent::Delete => "deleted",
+                };
+                format!("event: {}\ndata: {}\n\n", event_name, change.payload)
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(RecvError::Lagged(missed))) => {
+                let error = format!("Change feed fell behind, {} change(s) missed", missed);
+                let data = json!({ "error": error });
+                writer
+                    .write_all(format!("event: error\ndata: {}\n\n", data).as_bytes())
+                    .await?;
+                return writer.shutdown().await;
+            }
+            Some(Err(RecvError::Closed)) => return Ok(()),
+        };
+        keepalive.reset();
+
+        writer.write_all(message.as_bytes()).await?;
+    }
+}
@@ -0,0 +1,109 @@
+/// Document ID generation.
+///
+/// Each pile picks the scheme its document IDs are generated with:
+///
+/// UUIDV4    random UUID (the default)
+/// UUIDV7    UUID starting with its creation time in milliseconds
+/// ULID      26 character Crockford base32 ID, also starting with the time
+/// SEQUENCE  monotonic counter kept in the pile's metadata, zero padded
+///
+/// Documents are listed in ID order, so with any scheme but UUIDV4 a pile's
+/// documents come back oldest first and the newest ones are found at the end
+/// without reading anything else.
+use crate::pile::PileMeta;
+use chrono::Utc;
+use dustcfg::generate_v4_uuid;
+use rand::Rng;
+use serde_json::Value;
+use std::io;
+
+const CROCKFORD_BASE32: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum IdScheme {
+    UuidV4,
+    UuidV7,
+    Ulid,
+    Sequence,
+}
+
+impl IdScheme {
+    pub fn parse(input: &str) -> Option<IdScheme> {
+        match input {
+            "UUIDV4" => Some(IdScheme::UuidV4),
+            "UUIDV7" => Some(IdScheme::UuidV7),
+            "ULID" => Some(IdScheme::Ulid),
+            "SEQUENCE" => Some(IdScheme::Sequence),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdScheme::UuidV4 => "UUIDV4",
+            IdScheme::UuidV7 => "UUIDV7",
+            IdScheme::Ulid => "ULID",
+            IdScheme::Sequence => "SEQUENCE",
+        }
+    }
+}
+
+/// Generates the next ID for a pile. Must be called with the pile's lock
+/// held, as `SEQUENCE` reads and bumps the counter in the pile's metadata.
+pub fn generate(scheme: IdScheme, pile_name: &str) -> Result<String, io::Error> {
+    match scheme {
+        IdScheme::UuidV4 => Ok(generate_v4_uuid()),
+        IdScheme::UuidV7 => Ok(generate_v7_uuid()),
+        IdScheme::Ulid => Ok(generate_ulid()),
+        IdScheme::Sequence => next_in_sequence(pile_name),
+    }
+}
+
+fn generate_v7_uuid() -> String {
+    let millis = Utc::now().timestamp_millis() as u128 & 0xFFFF_FFFF_FFFF;
+    let random: u128 = rand::thread_rng().gen();
+
+    // 48 bits of time, the version, 12 random bits, the variant, 62 random bits
+    let uuid = (millis << 80)
+        | (0x7 << 76)
+        | ((random >> 64) & 0xFFF) << 64
+        | (0b10 << 62)
+        | (random & 0x3FFF_FFFF_FFFF_FFFF);
+
+    let hex = format!("{:032x}", uuid);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn generate_ulid() -> String {
+    let millis = Utc::now().timestamp_millis() as u128 & 0xFFFF_FFFF_FFFF;
+    let random: u128 = rand::thread_rng().gen::<u128>() & ((1 << 80) - 1);
+    let ulid = (millis << 80) | random;
+
+    // 128 bits in 26 characters of 5 bits each, most significant first
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD_BASE32[((ulid >> (i * 5)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+fn next_in_sequence(pile_name: &str) -> Result<String, io::Error> {
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let next = pile_meta
+        .get("id_sequence")
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+        + 1;
+
+    pile_meta.set("id_sequence", Value::from(next));
+    pile_meta.save()?;
+
+    // Padded to the width of u64::MAX so IDs sort numerically as file names
+    Ok(format!("{:020}", next))
+}
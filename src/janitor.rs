@@ -0,0 +1,131 @@
+/// Background cleanup of the storage root.
+///
+/// Removes what interrupted or abandoned work leaves behind:
+///
+/// - pile directories that hold nothing at all (no documents, tombstones or
+///   metadata), e.g. after every document of a pile was deleted
+/// - temp files of metadata writes that never got renamed into place
+/// - deleted documents past the recycle bin's retention (see trash.rs)
+/// - bloom filters and ordered indexes of piles that no longer exist
+///
+/// It also saves the ordered indexes changed since they were last saved, so
+/// they're loaded rather than rebuilt after a restart (see ordered.rs).
+use crate::logging::{self, Level};
+use crate::pile::{self, pile_names, pile_path};
+use crate::users::USERS_PILE;
+use crate::{bloom, env_or, ordered, trash};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Temp files younger than this may belong to a write still in progress
+const TEMP_FILE_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Starts the janitor on the tokio runtime. The interval is read from
+/// `DUST_CLEANUP_INTERVAL_SECS` (default 3600 seconds, 0 disables the worker).
+pub fn spawn_worker() {
+    let interval_secs: u64 = env_or("DUST_CLEANUP_INTERVAL_SECS", 3600);
+    if interval_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+
+            match tokio::task::spawn_blocking(cleanup).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => logging::diagnostic(
+                    Level::Error,
+                    &format!("Error cleaning up storage: {:?}", e),
+                ),
+                Err(e) => logging::diagnostic(
+                    Level::Error,
+                    &format!("Error joining storage cleanup: {:?}", e),
+                ),
+            }
+        }
+    });
+}
+
+/// Example:
+/// in: CLEANUP
+/// out: {"empty_piles":2,"temp_files":1,"trash_purged":0,"bloom_filters":0,"ordered_indexes":0,"ordered_indexes_saved":3}
+pub fn cleanup() -> Result<Value, io::Error> {
+    let mut empty_piles = 0;
+    let mut temp_files = 0;
+    let mut trash_purged = 0;
+
+    // System piles (e.g. `.users`) have temp files too, but are never removed
+    let mut all_pile_names = pile_names()?;
+    all_pile_names.push(USERS_PILE.to_owned());
+
+    for pile_name in &all_pile_names {
+        let pile_path = pile_path(pile_name)?;
+        if !Path::new(&pile_path).is_dir() {
+            continue;
+        }
+
+        temp_files += remove_temp_files(&pile_path, TEMP_FILE_MIN_AGE)?;
+        trash_purged += trash::purge(pile_name)?;
+
+        if !pile_name.starts_with('.') && remove_if_empty(pile_name, &pile_path)? {
+            empty_piles += 1;
+        }
+    }
+
+    let bloom_filters = bloom::retain_piles(&pile_names()?);
+    let ordered_indexes = ordered::retain_piles(&pile_names()?);
+    let ordered_indexes_saved = ordered::save_all()?;
+
+    Ok(json!({
+        "empty_piles": empty_piles,
+        "temp_files": temp_files,
+        "trash_purged": trash_purged,
+        "bloom_filters": bloom_filters,
+        "ordered_indexes": ordered_indexes,
+        "ordered_indexes_saved": ordered_indexes_saved,
+    }))
+}
+
+/// Removes the pile's temp files that are at least `min_age` old
+pub fn remove_temp_files(pile_path: &str, min_age: Duration) -> Result<usize, io::Error> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+
+    for entry in fs::read_dir(pile_path)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if !file_name.starts_with('.') || !file_name.ends_with(".tmp") {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() >= min_age {
+            match fs::remove_file(entry.path()) {
+                Ok(_) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Holds the pile's write lock, so a write can't recreate the pile's first
+/// document between the check and the removal
+fn remove_if_empty(pile_name: &str, pile_path: &str) -> Result<bool, io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    if fs::read_dir(pile_path)?.next().is_some() {
+        return Ok(false);
+    }
+
+    fs::remove_dir(pile_path)?;
+    Ok(true)
+}
@@ -0,0 +1,261 @@
+/// Background map-reduce jobs.
+///
+/// A job maps every document of a pile to a key (the value of a field) and
+/// reduces the documents sharing a key with one operation:
+///
+/// COUNT          number of documents
+/// SUM, MIN, MAX  over a numeric value field
+/// AVG            mean of a numeric value field
+///
+/// Jobs run on their own thread, so they can take as long as a pile needs,
+/// and write one result document per key into a target pile:
+///
+/// {"_job":"<job id>","key":"paid","value":42}
+///
+/// Progress is kept in memory and reported by JOB STATUS until the server
+/// restarts.
+use crate::pile::document_paths;
+use crate::{cache, store_document, timestamp_now, DocumentId};
+use dustcfg::generate_v4_uuid;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReduceOp {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl ReduceOp {
+    pub fn parse(input: &str) -> Option<ReduceOp> {
+        match input {
+            "COUNT" => Some(ReduceOp::Count),
+            "SUM" => Some(ReduceOp::Sum),
+            "MIN" => Some(ReduceOp::Min),
+            "MAX" => Some(ReduceOp::Max),
+            "AVG" => Some(ReduceOp::Avg),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReduceOp::Count => "COUNT",
+            ReduceOp::Sum => "SUM",
+            ReduceOp::Min => "MIN",
+            ReduceOp::Max => "MAX",
+            ReduceOp::Avg => "AVG",
+        }
+    }
+}
+
+pub struct JobSpec {
+    pub pile: String,
+    pub target_pile: String,
+    pub key_field: String,
+    pub value_field: Option<String>,
+    pub reduce: ReduceOp,
+}
+
+struct JobStatus {
+    spec: JobSpec,
+    state: &'static str,
+    processed: usize,
+    total: usize,
+    started_at: String,
+    finished_at: Option<String>,
+    error: Option<String>,
+}
+
+/// Running totals of one key
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    numeric_count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: Option<f64>) {
+        self.count += 1;
+        if let Some(value) = value {
+            self.numeric_count += 1;
+            self.sum += value;
+            self.min = Some(self.min.map_or(value, |min| min.min(value)));
+            self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        }
+    }
+
+    fn result(&self, reduce: ReduceOp) -> Value {
+        match reduce {
+            ReduceOp::Count => json!(self.count),
+            ReduceOp::Sum => json!(self.sum),
+            ReduceOp::Min => json!(self.min),
+            ReduceOp::Max => json!(self.max),
+            // Documents without a numeric value count for COUNT only
+            ReduceOp::Avg => match self.numeric_count {
+                0 => Value::Null,
+                numeric_count => json!(self.sum / numeric_count as f64),
+            },
+        }
+    }
+}
+
+fn jobs() -> &'static Mutex<HashMap<String, JobStatus>> {
+    static JOBS: OnceLock<Mutex<HashMap<String, JobStatus>>> = OnceLock::new();
+    JOBS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Example:
+/// in: JOB SUBMIT orders order_totals MAP status total REDUCE SUM
+/// out: 2f1c4e0a-8a4b-4a8e-9d51-3f2b8f0e6c7d
+pub fn submit(spec: JobSpec) -> Result<String, io::Error> {
+    if spec.reduce != ReduceOp::Count && spec.value_field.is_none() {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("{} needs a value field to reduce", spec.reduce.as_str());
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let job_id = generate_v4_uuid();
+    jobs().lock().unwrap_or_else(|e| e.into_inner()).insert(
+        job_id.clone(),
+        JobStatus {
+            spec,
+            state: "running",
+            processed: 0,
+            total: 0,
+            started_at: timestamp_now(),
+            finished_at: None,
+            error: None,
+        },
+    );
+
+    let thread_job_id = job_id.clone();
+    thread::spawn(move || {
+        let result = run(&thread_job_id);
+
+        let mut jobs = jobs().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(status) = jobs.get_mut(&thread_job_id) {
+            status.finished_at = Some(timestamp_now());
+            match result {
+                Ok(_) => status.state = "done",
+                Err(e) => {
+                    status.state = "failed";
+                    status.error = Some(e.to_string());
+                }
+            }
+        }
+    });
+
+    Ok(job_id)
+}
+
+/// Example:
+/// in: JOB STATUS 2f1c4e0a-8a4b-4a8e-9d51-3f2b8f0e6c7d
+/// out: {"state":"running","processed":5000,"total":120000,...}
+pub fn status(job_id: &str) -> Result<Value, io::Error> {
+    let jobs = jobs().lock().unwrap_or_else(|e| e.into_inner());
+    let status = match jobs.get(job_id) {
+        Some(status) => status,
+        None => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = format!("Could not find job: \"{}\"", job_id);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    Ok(json!({
+        "state": status.state,
+        "processed": status.processed,
+        "total": status.total,
+        "pile": status.spec.pile,
+        "target_pile": status.spec.target_pile,
+        "reduce": status.spec.reduce.as_str(),
+        "started_at": status.started_at,
+        "finished_at": status.finished_at,
+        "error": status.error,
+    }))
+}
+
+fn run(job_id: &str) -> Result<(), io::Error> {
+    let (pile_name, target_pile, key_field, value_field, reduce) = {
+        let jobs = jobs().lock().unwrap_or_else(|e| e.into_inner());
+        let spec = &jobs[job_id].spec;
+        (
+            spec.pile.clone(),
+            spec.target_pile.clone(),
+            spec.key_field.clone(),
+            spec.value_field.clone(),
+            spec.reduce,
+        )
+    };
+
+    let file_paths = document_paths(&pile_name)?;
+    update_progress(job_id, 0, file_paths.len());
+
+    // Keys are JSON values, grouped by their serialized form
+    let mut groups: HashMap<String, (Value, Accumulator)> = HashMap::new();
+    for (index, file_path) in file_paths.iter().enumerate() {
+        // Documents deleted while the job runs are skipped
+        let document = match cache::read_document(file_path) {
+            Ok(document) => document,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let key = document
+            .json
+            .get(&key_field)
+            .cloned()
+            .unwrap_or(Value::Null);
+        let value = value_field
+            .as_ref()
+            .and_then(|value_field| document.json.get(value_field))
+            .and_then(Value::as_f64);
+
+        groups
+            .entry(key.to_string())
+            .or_insert_with(|| (key, Accumulator::default()))
+            .1
+            .add(value);
+
+        if index % 1000 == 999 {
+            update_progress(job_id, index + 1, file_paths.len());
+        }
+    }
+    update_progress(job_id, file_paths.len(), file_paths.len());
+
+    for (key, accumulator) in groups.into_values() {
+        let mut result = Map::new();
+        result.insert("_job".to_owned(), Value::from(job_id));
+        result.insert("key".to_owned(), key);
+        result.insert("value".to_owned(), accumulator.result(reduce));
+
+        store_document(
+            &target_pile,
+            DocumentId::Generated,
+            &Value::Object(result).to_string(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn update_progress(job_id: &str, processed: usize, total: usize) {
+    if let Some(status) = jobs()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_mut(job_id)
+    {
+        status.processed = processed;
+        status.total = total;
+    }
+}
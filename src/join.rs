@@ -0,0 +1,177 @@
+/// Server side joins for FIND.
+///
+/// A FIND may end in one or more JOIN clauses, each enriching the found
+/// document with the documents of another pile that match it:
+///
+/// FIND orders status paid JOIN users ON orders.user_id = users._id
+///
+/// The matches are added to the document under `_joined`, keyed by pile:
+///
+/// {"status":"paid","user_id":"cd8a...","_joined":{"users":[{"_id":"cd8a...",...}]}}
+///
+/// A field holding an array (e.g. a list of UUIDs) joins on each of its
+/// elements. Joining on `_id` reads the referenced documents directly, any
+/// other field is matched by scanning the joined pile.
+use crate::pile::{document_paths, pile_path};
+use crate::{document_file_path, document_with_id, is_valid_document_id, scan};
+use serde_json::{Map, Value};
+use std::io;
+use std::path::Path;
+
+const JOIN_KEYWORD: &str = " JOIN ";
+
+pub struct Join {
+    pub pile: String,
+    left_field: String,
+    right_field: String,
+}
+
+impl Join {
+    /// The join as a canonical string, e.g. to key cached results with
+    pub fn describe(&self) -> String {
+        format!("{} ON {}={}", self.pile, self.left_field, self.right_field)
+    }
+
+    /// Example:
+    /// in: users ON orders.user_id = users._id
+    /// out: Join { pile: "users", left_field: "user_id", right_field: "_id" }
+    ///
+    /// The sides of the `=` may come in either order, as long as one names
+    /// the FIND's pile and the other the joined pile.
+    fn parse(clause: &str, find_pile: &str) -> Result<Join, String> {
+        let (pile, condition) = match clause.split_once(" ON ") {
+            Some((pile, condition)) if !pile.trim().is_empty() => {
+                (pile.trim().to_lowercase(), condition)
+            }
+            _ => return Err("JOIN must look like JOIN <pile> ON <condition>".to_owned()),
+        };
+
+        let qualified_field = |side: &str| -> Result<(String, String), String> {
+            match side.trim().split_once('.') {
+                Some((side_pile, field)) if !field.is_empty() => {
+                    Ok((side_pile.to_lowercase(), field.to_owned()))
+                }
+                _ => Err(format!(
+                    "JOIN condition must use <pile>.<field>, got: {}",
+                    side
+                )),
+            }
+        };
+
+        let (left, right) = match condition.split_once('=') {
+            Some((left, right)) => (qualified_field(left)?, qualified_field(right)?),
+            None => {
+                return Err("JOIN condition must look like <a>.<field> = <b>.<field>".to_owned())
+            }
+        };
+
+        let (left_field, right_field) = match (left, right) {
+            ((left_pile, left_field), (right_pile, right_field))
+                if left_pile == find_pile && right_pile == pile =>
+            {
+                (left_field, right_field)
+            }
+            ((right_pile, right_field), (left_pile, left_field))
+                if left_pile == find_pile && right_pile == pile =>
+            {
+                (left_field, right_field)
+            }
+            _ => {
+                return Err(format!(
+                    "JOIN condition must compare a field of {} with a field of {}",
+                    find_pile, pile
+                ))
+            }
+        };
+
+        Ok(Join {
+            pile,
+            left_field,
+            right_field,
+        })
+    }
+}
+
+/// Splits the JOIN clauses off the end of a FIND's compare value
+pub fn parse_joins(compare: &str, find_pile: &str) -> Result<(String, Vec<Join>), String> {
+    let mut clauses = compare.split(JOIN_KEYWORD);
+    let compare = clauses.next().unwrap_or_default().to_owned();
+
+    let joins = clauses
+        .map(|clause| Join::parse(clause, find_pile))
+        .collect::<Result<Vec<Join>, String>>()?;
+
+    Ok((compare, joins))
+}
+
+/// Adds the documents matched by each join to `document` under `_joined`
+pub fn apply(joins: &[Join], document: &mut Value) -> Result<(), io::Error> {
+    let mut joined = Map::new();
+
+    for join in joins {
+        let left_values = match document.get(&join.left_field) {
+            Some(Value::Array(values)) => values.clone(),
+            Some(value) => vec![value.clone()],
+            None => Vec::new(),
+        };
+
+        let matches = match join.right_field.as_str() {
+            "_id" => find_by_id(&join.pile, &left_values)?,
+            right_field => find_by_field(&join.pile, right_field, &left_values)?,
+        };
+
+        joined.insert(join.pile.clone(), Value::Array(matches));
+    }
+
+    if let Some(json_object) = document.as_object_mut() {
+        json_object.insert("_joined".to_owned(), Value::Object(joined));
+    }
+
+    Ok(())
+}
+
+fn find_by_id(pile_name: &str, uuids: &[Value]) -> Result<Vec<Value>, io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let mut matches = Vec::new();
+
+    for uuid in uuids.iter().filter_map(Value::as_str) {
+        let file_path = document_file_path(&pile_path, uuid);
+        if is_valid_document_id(uuid) && Path::new(&file_path).is_file() {
+            if let Some(document) = document_with_id(Path::new(&file_path))? {
+                matches.push(document);
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+fn find_by_field(
+    pile_name: &str,
+    field_name: &str,
+    values: &[Value],
+) -> Result<Vec<Value>, io::Error> {
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let file_paths = document_paths(pile_name)?;
+    let matches = scan::scan_field(
+        &file_paths,
+        field_name,
+        false,
+        |file_path, value| match values.contains(&value?) {
+            true => Some(file_path.to_path_buf()),
+            false => None,
+        },
+    )?;
+
+    let mut documents = Vec::new();
+    for (_, file_path) in matches {
+        if let Some(document) = document_with_id(&file_path)? {
+            documents.push(document);
+        }
+    }
+
+    Ok(documents)
+}
@@ -0,0 +1,66 @@
+/// Listen addresses.
+///
+/// The server listens on `DUST_DB_ADDR`:`DUST_DB_PORT`, or on every address
+/// in `DUST_LISTEN` if that is set: a comma separated list of addresses
+/// (IPv6 in brackets), each optionally followed by flags restricting what
+/// its connections may do:
+///
+/// DUST_LISTEN="[::]:7777 READONLY,127.0.0.1:7778 ADMINONLY"
+///
+/// READONLY   only commands that need no more than READ rights
+/// ADMINONLY  only users with ADMIN rights on every pile (`*`), once users
+///            exist (see users.rs)
+/// HTTP       speaks HTTP instead of the line protocol (see http.rs)
+///
+/// Each listener gets its own accept loop.
+use dustcfg::get_env_var;
+
+#[derive(Clone, Copy, Default)]
+pub struct ListenerFlags {
+    pub read_only: bool,
+    pub admin_only: bool,
+    pub http: bool,
+}
+
+pub struct ListenerConfig {
+    pub addr: String,
+    pub flags: ListenerFlags,
+}
+
+pub fn configured() -> Result<Vec<ListenerConfig>, String> {
+    let listen = match std::env::var("DUST_LISTEN") {
+        Ok(listen) if !listen.trim().is_empty() => listen,
+        _ => {
+            return Ok(vec![ListenerConfig {
+                addr: format!(
+                    "{}:{}",
+                    get_env_var("DUST_DB_ADDR"),
+                    get_env_var("DUST_DB_PORT")
+                ),
+                flags: ListenerFlags::default(),
+            }])
+        }
+    };
+
+    listen.split(',').map(parse_listener).collect()
+}
+
+fn parse_listener(entry: &str) -> Result<ListenerConfig, String> {
+    let mut parts = entry.split_whitespace();
+    let addr = match parts.next() {
+        Some(addr) => addr.to_owned(),
+        None => return Err("DUST_LISTEN has an empty entry".to_owned()),
+    };
+
+    let mut flags = ListenerFlags::default();
+    for flag in parts {
+        match flag {
+            "READONLY" => flags.read_only = true,
+            "ADMINONLY" => flags.admin_only = true,
+            "HTTP" => flags.http = true,
+            _ => return Err(format!("Unknown flag for listener {}: {}", addr, flag)),
+        }
+    }
+
+    Ok(ListenerConfig { addr, flags })
+}
@@ -0,0 +1,105 @@
+/// Log verbosity.
+///
+/// dustlog writes whatever it is handed, so the server decides what to hand
+/// it. Entries below the current level are dropped:
+///
+/// DEBUG  everything, plus debug entries (e.g. the files a FIND touched)
+/// INFO   requests and responses (the default)
+/// ERROR  failed requests and error responses only
+///
+/// The level starts at `DUST_LOG_LEVEL` and can be changed at runtime with
+/// `CONFIG SET loglevel <level>`.
+///
+/// Server diagnostics (startup, background workers, connection errors) are
+/// printed to stdout, unless they are redirected into dustlog too, as
+/// a daemon does (see daemon.rs).
+use crate::env_or;
+use chrono::Utc;
+use dustlog::{write_to_log, DBRequestLog, LogLevel};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Debug = 0,
+    Info = 1,
+    Error = 2,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Level, String> {
+        match input {
+            "DEBUG" => Ok(Level::Debug),
+            "INFO" => Ok(Level::Info),
+            "ERROR" => Ok(Level::Error),
+            _ => Err(format!("Unknown log level: {}", input)),
+        }
+    }
+}
+
+impl Level {
+    /// The level of an entry dustlog is asked to write
+    pub fn of(log_level: &LogLevel) -> Level {
+        match *log_level {
+            LogLevel::ERROR => Level::Error,
+            _ => Level::Info,
+        }
+    }
+}
+
+fn current_level() -> &'static AtomicU8 {
+    static LEVEL: OnceLock<AtomicU8> = OnceLock::new();
+    LEVEL.get_or_init(|| AtomicU8::new(env_or("DUST_LOG_LEVEL", Level::Info) as u8))
+}
+
+pub fn set_level(level: Level) {
+    current_level().store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= current_level().load(Ordering::Relaxed)
+}
+
+/// Writes a debug entry, if the level is DEBUG. dustlog has no debug level of
+/// its own, so the entry is an INFO request entry marked `DEBUG`.
+pub fn debug(message: impl FnOnce() -> String) {
+    if enabled(Level::Debug) {
+        write_server_entry(LogLevel::INFO, format!("DEBUG {}", message()));
+    }
+}
+
+static REDIRECTS_DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+
+/// Sends every following diagnostic to dustlog instead of stdout/stderr
+pub fn redirect_diagnostics() {
+    REDIRECTS_DIAGNOSTICS.store(true, Ordering::Relaxed);
+}
+
+/// Reports something about the server itself, rather than about a request
+pub fn diagnostic(level: Level, message: &str) {
+    match (REDIRECTS_DIAGNOSTICS.load(Ordering::Relaxed), level) {
+        (false, _) => println!("{}", message),
+        (true, Level::Error) => write_server_entry(LogLevel::ERROR, message.to_owned()),
+        (true, level) if enabled(level) => write_server_entry(LogLevel::INFO, message.to_owned()),
+        (true, _) => (),
+    }
+}
+
+/// Entries not tied to a request are written as request entries without a
+/// client address
+fn write_server_entry(log_level: LogLevel, command: String) {
+    let log = DBRequestLog {
+        timestamp: Utc::now(),
+        log_level,
+        socket_addr: String::new(),
+        command,
+        payload_size_in_bytes: None,
+    };
+
+    if let Err(e) = write_to_log(log.as_log_str(), log.get_log_distinction()) {
+        eprintln!("{:?}", e);
+    }
+}
@@ -7,23 +7,115 @@
 /// 2. [R]ead from storage.
 /// 3. [U]pdate data already in storage.
 /// 4. [D]elete from storage.
-use chrono::Utc;
+///
+/// Usage:
+///
+/// dustdb                        Start the server
+/// dustdb --daemon               Start the server under a process supervisor
+///                               (see daemon.rs)
+/// dustdb --restore <timestamp>  Replay the WAL (up to an RFC 3339 timestamp)
+///                               onto the storage root and exit
+/// dustdb --restore-backup <name>
+///                               Restore a full backup plus its chain of
+///                               incremental backups and exit
+/// dustdb bench <addr> [flags]   Benchmark a running server (see bench.rs)
+///
+/// Once users exist (see users.rs), every command must be prefixed with
+/// `AUTH <user> <password>`. Payloads are hex encoded unless the command is
+/// prefixed with another encoding (see payload.rs), and responses are
+/// `<code> <message>` lines unless prefixed with `FORMAT JSON` (see
+/// `CommandOptions`). Code 0 is success, any other code is an error code (see
+/// errors.rs).
+mod archive;
+mod backup;
+mod bench;
+mod bloom;
+mod cache;
+mod capped;
+mod channels;
+mod confirm;
+mod consistency;
+mod csv;
+mod daemon;
+mod docmeta;
+mod errors;
+mod events;
+mod extract;
+mod fsck;
+mod graphql;
+mod http;
+mod ids;
+mod janitor;
+mod jobs;
+mod join;
+mod listeners;
+mod logging;
+mod memory;
+mod migrations;
+mod mongo;
+mod mqtt;
+mod ordered;
+mod payload;
+mod pile;
+mod prepared;
+mod quarantine;
+mod query;
+mod recovery;
+mod results;
+mod scan;
+mod scheduler;
+mod schema;
+mod sinks;
+mod sqlite;
+mod stats;
+mod systemd;
+mod tar;
+mod telemetry;
+mod trash;
+mod traverse;
+mod triggers;
+mod ttl;
+mod users;
+mod wal;
+mod webhooks;
+mod write_concern;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use daemon::LastShutdown;
 use dustcfg::{decode_hex_to_utf8, encode_utf8_to_hex, generate_v4_uuid, get_env_var};
 use dustlog::{write_to_log, DBRequestLog, DBResponseLog, LogLevel};
+use errors::ErrorCode;
 use futures::SinkExt;
-use serde_json::{from_str, Value};
+use ids::IdScheme;
+use listeners::ListenerFlags;
+use logging::Level;
+use payload::Encoding;
+use pile::{document_paths, pile_names, pile_path, OnDelete, PileMeta, Reference};
+use serde_json::{from_str, json, Map, Value};
+use sinks::Sink;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::mem::size_of_val;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use std::{error::Error, net::SocketAddr};
+use tokio::sync::Semaphore;
 use tokio::{io, net::TcpListener};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Framed, LinesCodec};
+use tracing::Instrument;
+use triggers::{Trigger, TriggerAction, TriggerEvent};
+use users::{Right, ALL_PILES};
+use wal::{WalEntry, WalOp};
+use webhooks::{Webhook, WebhookEvent};
+use write_concern::WriteConcern;
 
 /// Possible requests our clients can send us
 enum Request {
     Create {
         pile: String,
+        id: Option<String>,
         data: String,
     },
     Ping {},
@@ -31,6 +123,284 @@ enum Request {
         pile: String,
         field: String,
         compare: String,
+        joins: Vec<join::Join>,
+        case_insensitive: bool,
+        with_meta: bool,
+    },
+    Export {
+        pile: String,
+    },
+    CopyPile {
+        source_pile: String,
+        pile: String,
+        predicate: Option<query::Predicate>,
+    },
+    ExportSqlite {
+        pile: String,
+        file_name: String,
+    },
+    Archive {
+        pile: String,
+        file_name: String,
+    },
+    Unarchive {
+        file_name: String,
+        pile: Option<String>,
+    },
+    Count {
+        pile: String,
+        predicate: Option<query::Predicate>,
+    },
+    Scan {
+        pile: String,
+        cursor: Option<String>,
+        predicate: Option<query::Predicate>,
+    },
+    Sample {
+        pile: String,
+        sample_size: usize,
+    },
+    Watch {
+        pile: String,
+    },
+    Publish {
+        channel: String,
+        grant: String,
+        payload: String,
+    },
+    Subscribe {
+        channel: String,
+        grant: String,
+    },
+    Traverse {
+        pile: String,
+        uuid: String,
+        field: String,
+        depth: usize,
+    },
+    JobSubmit {
+        spec: jobs::JobSpec,
+    },
+    JobStatus {
+        job_id: String,
+    },
+    Migrate {
+        pile: String,
+        name: String,
+        step: migrations::Step,
+        dry_run: bool,
+    },
+    Migrations {
+        pile: String,
+    },
+    CreateMany {
+        pile: String,
+        data: String,
+    },
+    Import {
+        pile: String,
+        data: String,
+    },
+    ImportCsv {
+        pile: String,
+        data: String,
+        type_hints: Option<String>,
+    },
+    ImportMongo {
+        pile: String,
+        data: String,
+    },
+    Backup {
+        incremental: bool,
+    },
+    SchemaSet {
+        pile: String,
+        schema: String,
+    },
+    Describe {
+        pile: String,
+    },
+    DescriptionSet {
+        pile: String,
+        description: String,
+    },
+    SchemaGet {
+        pile: String,
+    },
+    DefaultsSet {
+        pile: String,
+        defaults: String,
+    },
+    DefaultsGet {
+        pile: String,
+    },
+    Timestamps {
+        pile: String,
+        enabled: bool,
+    },
+    AppendOnly {
+        pile: String,
+    },
+    Ids {
+        pile: String,
+        scheme: IdScheme,
+    },
+    MaxSize {
+        pile: String,
+        max_bytes: Option<usize>,
+    },
+    Delete {
+        pile: String,
+        uuid: String,
+    },
+    DeleteWhere {
+        pile: String,
+        predicate: query::Predicate,
+        /// The predicate as given, which a confirmation token is bound to
+        predicate_source: String,
+        dry_run: bool,
+        token: Option<String>,
+    },
+    Update {
+        pile: String,
+        uuid: String,
+        data: String,
+        condition: Option<query::Predicate>,
+    },
+    Patch {
+        pile: String,
+        uuid: String,
+        patch: String,
+        condition: Option<query::Predicate>,
+    },
+    FindAndModify {
+        pile: String,
+        predicate: query::Predicate,
+        action: FindAndModifyAction,
+        return_new: bool,
+    },
+    Incr {
+        pile: String,
+        uuid: String,
+        field: String,
+        delta: String,
+    },
+    Expire {
+        pile: String,
+        uuid: String,
+        seconds: u64,
+    },
+    Persist {
+        pile: String,
+        uuid: String,
+    },
+    ArrayUpdate {
+        pile: String,
+        uuid: String,
+        field: String,
+        op: ArrayOp,
+        value: String,
+    },
+    UpdateWhere {
+        pile: String,
+        predicate: query::Predicate,
+        patch: String,
+        dry_run: bool,
+    },
+    Restore {
+        pile: String,
+        uuid: String,
+    },
+    Undelete {
+        pile: String,
+        uuid: String,
+    },
+    SoftDelete {
+        pile: String,
+        enabled: bool,
+        purge_after_days: Option<u64>,
+    },
+    Capped {
+        pile: String,
+        max_documents: Option<u64>,
+        max_bytes: Option<u64>,
+    },
+    Unique {
+        pile: String,
+        field: String,
+    },
+    Reference {
+        pile: String,
+        field: String,
+        target_pile: String,
+        on_delete: OnDelete,
+    },
+    Trigger {
+        pile: String,
+        trigger: Trigger,
+    },
+    WebhookAdd {
+        pile: String,
+        webhook: Webhook,
+    },
+    WebhookRemove {
+        pile: String,
+        url: String,
+    },
+    SinkAdd {
+        pile: String,
+        sink: Sink,
+    },
+    SinkRemove {
+        pile: String,
+        url: String,
+        subject: String,
+    },
+    Explain {
+        query: Box<Request>,
+    },
+    Prepare {
+        name: String,
+        template: String,
+        /// The query with every placeholder filled in, to check it with
+        query: Box<Request>,
+    },
+    Stats {},
+    PileStats {
+        pile: String,
+    },
+    Cleanup {},
+    Info {},
+    Fsck {
+        pile: Option<String>,
+        repair: bool,
+    },
+    Repair {
+        pile: String,
+    },
+    SetLogLevel {
+        level: logging::Level,
+    },
+    Bloom {
+        pile: String,
+        field: String,
+    },
+    Ordered {
+        pile: String,
+        field: String,
+    },
+    CreateUser {
+        name: String,
+        password: String,
+    },
+    Grant {
+        user: String,
+        pile: String,
+        right: Right,
+    },
+    Revoke {
+        user: String,
+        pile: String,
     },
 }
 
@@ -40,6 +410,17 @@ impl Request {
         match parts.next() {
             Some("CREATE") => {
                 let split_input = parts.next().unwrap();
+                if let Some(user_input) = split_input.strip_prefix("USER ") {
+                    return match user_input.split_once(' ') {
+                        Some((name, password)) if !name.is_empty() && !password.is_empty() => {
+                            Ok(Request::CreateUser {
+                                name: name.to_string(),
+                                password: password.to_string(),
+                            })
+                        }
+                        _ => Err("CREATE USER must have a user name and a password".to_owned()),
+                    };
+                }
                 parts = split_input.splitn(2, ' ');
 
                 let pile = match parts.next() {
@@ -47,19 +428,34 @@ impl Request {
                     None => return Err("CREATE must have a pile name specified".to_owned()),
                 };
 
-                let data = match parts.next() {
-                    Some(data) => data,
+                let (id, data) = match parts.next() {
+                    Some(id_input) if id_input.starts_with("ID ") => {
+                        match id_input["ID ".len()..].split_once(' ') {
+                            Some((id, data)) if !id.is_empty() => (Some(id), data),
+                            _ => return Err("CREATE ID must have an ID and data".to_owned()),
+                        }
+                    }
+                    Some(data) => (None, data),
                     None => return Err("CREATE must have data after the pile name".to_owned()),
                 };
 
                 Ok(Request::Create {
                     pile: pile.to_string().to_lowercase(),
+                    id: id.map(|id| id.to_string()),
                     data: data.to_string(),
                 })
             }
             Some("PING") => Ok(Request::Ping {}),
             Some("FIND") => {
                 let split_input = parts.next().unwrap();
+                let (case_insensitive, split_input) = match split_input.strip_prefix("NOCASE ") {
+                    Some(split_input) => (true, split_input),
+                    None => (false, split_input),
+                };
+                let (with_meta, split_input) = match split_input.strip_prefix("META ") {
+                    Some(split_input) => (true, split_input),
+                    None => (false, split_input),
+                };
                 parts = split_input.splitn(3, ' ');
 
                 let pile = match parts.next() {
@@ -81,276 +477,5580 @@ impl Request {
                     }
                 };
 
+                let pile = pile.to_string().to_lowercase();
+                let (compare, joins) = join::parse_joins(compare, &pile)?;
+                query::FindCompare::parse(&compare)?;
+
                 Ok(Request::Find {
-                    pile: pile.to_string().to_lowercase(),
+                    pile,
                     field: field.to_string(),
-                    compare: compare.to_string(),
+                    compare,
+                    joins,
+                    case_insensitive,
+                    with_meta,
                 })
             }
-            Some(cmd) => Err(format!("Error parsing request, unknown command: {}", cmd)),
-            None => Err("Error parsing request, empty request".to_owned()),
-        }
-    }
-}
+            Some("EXPLAIN") => match Request::parse(parts.next().unwrap_or_default())? {
+                query @ Request::Find { .. } => Ok(Request::Explain {
+                    query: Box::new(query),
+                }),
+                _ => Err("EXPLAIN must be followed by a FIND".to_owned()),
+            },
+            Some("PREPARE") => {
+                let (name, template) = match parts.next().and_then(|rest| rest.split_once(' ')) {
+                    Some((name, template)) if !template.is_empty() => (name, template),
+                    _ => return Err("PREPARE must have a name and a query".to_owned()),
+                };
 
-/// Responses to the `Request` commands above
-enum Response {
-    Ok {
-        exit_code: u8,
-        message: Option<String>,
-    },
-    Error {
-        exit_code: u8,
-        error: String,
-    },
-}
+                // Prepared queries running one another could loop forever
+                if matches!(template.split(' ').next(), Some("PREPARE" | "EXEC")) {
+                    return Err("PREPARE can't prepare PREPARE or EXEC".to_owned());
+                }
 
-impl Response {
-    fn serialize(&self) -> String {
-        match *self {
-            Response::Ok {
-                ref exit_code,
-                ref message,
-            } => format!(
-                "{} {}",
-                exit_code,
-                match message {
-                    None => "",
-                    Some(message) => message,
+                let placeholder_args = vec!["0"; prepared::placeholder_count(template)];
+                let query = Request::parse(&prepared::expand(template, &placeholder_args)?)?;
+                query.check_is_query()?;
+
+                Ok(Request::Prepare {
+                    name: name.to_string(),
+                    template: template.to_string(),
+                    query: Box::new(query),
+                })
+            }
+            Some("EXEC") => {
+                let mut args = parts.next().unwrap_or_default().split_whitespace();
+                let name = match args.next() {
+                    Some(name) => name,
+                    None => return Err("EXEC must have the name of a prepared query".to_owned()),
+                };
+
+                let query =
+                    Request::parse(&prepared::expand_named(name, &args.collect::<Vec<_>>())?)?;
+                query.check_is_query()?;
+
+                Ok(query)
+            }
+            Some("EXPORT") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile.to_string().to_lowercase(),
+                    _ => return Err("EXPORT must have a pile name specified".to_owned()),
+                };
+
+                match (parts.next(), parts.next()) {
+                    (None, _) => Ok(Request::Export { pile }),
+                    (Some("SQLITE"), Some(file_name)) if !file_name.is_empty() => {
+                        Ok(Request::ExportSqlite {
+                            pile,
+                            file_name: file_name.to_string(),
+                        })
+                    }
+                    (Some("SQLITE"), _) => {
+                        Err("EXPORT SQLITE must have a file name after SQLITE".to_owned())
+                    }
+                    _ => Err("EXPORT can only be followed by SQLITE <file name>".to_owned()),
                 }
-            ),
-            Response::Error {
-                ref exit_code,
-                ref error,
-            } => format!("{} Error: {}", exit_code, error),
-        }
-    }
-}
+            }
+            Some("COPY") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
 
-//https://github.com/tokio-rs/tokio/blob/master/examples/tinydb.rs
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let addr = format!(
-        "{}:{}",
-        get_env_var("DUST_DB_ADDR"),
-        get_env_var("DUST_DB_PORT")
-    );
-    let listener = TcpListener::bind(&addr).await?;
-    println!("dustdb successfully started, listening on: {}", addr);
+                let (source_pile, pile) = match (parts.next(), parts.next()) {
+                    (Some(source_pile), Some(pile))
+                        if !source_pile.is_empty() && !pile.is_empty() =>
+                    {
+                        (source_pile.to_lowercase(), pile.to_lowercase())
+                    }
+                    _ => {
+                        return Err("COPY must have a source and a target pile specified".to_owned())
+                    }
+                };
 
-    loop {
-        match listener.accept().await {
-            Ok((socket, socket_addr)) => {
-                // Like with other small servers, we'll `spawn` this client to ensure it
-                // runs concurrently with all other clients. The `move` keyword is used
-                // here to move ownership of our db handle into the async closure.
-                tokio::spawn(async move {
-                    // Since our protocol is line-based we use `tokio_codecs`'s `LineCodec`
-                    // to convert our stream of bytes, `socket`, into a `Stream` of lines
-                    // as well as convert our line based responses into a stream of bytes.
-                    let mut lines = Framed::new(socket, LinesCodec::new());
-
-                    // Here for every line we get back from the `Framed` decoder,
-                    // we parse the request, and if it's valid we generate a response
-                    // based on the values in the database.
-                    while let Some(result) = lines.next().await {
-                        match result {
-                            Ok(line) => {
-                                let response = handle_request(&line, &socket_addr);
-                                let response = response.serialize();
-
-                                if let Err(e) = lines.send(response.as_str()).await {
-                                    println!("Error sending response: {:?}", e);
-                                }
+                let predicate = match parts.next().map(|rest| rest.strip_prefix("WHERE ")) {
+                    None => None,
+                    Some(Some(predicate)) => Some(query::Predicate::parse(predicate)?),
+                    Some(None) => {
+                        return Err("COPY can only be followed by WHERE <predicate>".to_owned())
+                    }
+                };
 
-                                // We only accept once command at a time -- never a persistent connection
-                                break;
-                            }
-                            Err(e) => {
-                                println!("Error decoding from socket: {:?}", e);
-                            }
-                        }
+                Ok(Request::CopyPile {
+                    source_pile,
+                    pile,
+                    predicate,
+                })
+            }
+            Some("ARCHIVE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                match (parts.next(), parts.next()) {
+                    (Some(pile), Some(file_name)) if !pile.is_empty() && !file_name.is_empty() => {
+                        Ok(Request::Archive {
+                            pile: pile.to_lowercase(),
+                            file_name: file_name.to_string(),
+                        })
                     }
+                    _ => Err("ARCHIVE must have a pile name and a file name specified".to_owned()),
+                }
+            }
+            Some("UNARCHIVE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
 
-                    // The connection will be closed at this point as `lines.next()` has returned `None`.
-                });
+                let file_name = match parts.next() {
+                    Some(file_name) if !file_name.is_empty() => file_name.to_string(),
+                    _ => return Err("UNARCHIVE must have a file name specified".to_owned()),
+                };
+
+                match (parts.next(), parts.next()) {
+                    (None, _) => Ok(Request::Unarchive {
+                        file_name,
+                        pile: None,
+                    }),
+                    (Some("AS"), Some(pile)) if !pile.is_empty() => Ok(Request::Unarchive {
+                        file_name,
+                        pile: Some(pile.to_lowercase()),
+                    }),
+                    _ => Err("UNARCHIVE can only be followed by AS <pile>".to_owned()),
+                }
             }
-            Err(e) => println!("Error accepting socket: {:?}", e),
-        }
-    }
-}
+            Some("COUNT") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
 
-fn handle_request(line: &str, socket_addr: &SocketAddr) -> Response {
-    let request = match Request::parse(line) {
-        Ok(req) => {
-            capture_request_log(
-                LogLevel::INFO,
-                socket_addr,
-                String::from(line),
-                Some(size_of_val(&*line)),
-            );
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("COUNT must have a pile name specified".to_owned()),
+                };
 
-            req
-        }
-        Err(e) => {
-            capture_request_log(
-                LogLevel::ERROR,
-                socket_addr,
-                String::from(line),
-                Some(size_of_val(&*line)),
-            );
+                let predicate = match parts.next() {
+                    Some(predicate) => Some(query::Predicate::parse(predicate)?),
+                    None => None,
+                };
 
-            return response_handler(Response::Error {
-                exit_code: 1,
-                error: e,
-            });
-        }
-    };
+                Ok(Request::Count {
+                    pile: pile.to_string().to_lowercase(),
+                    predicate,
+                })
+            }
+            Some("SAMPLE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
 
-    match request {
-        Request::Create { pile, data } => match create(&pile, &data) {
-            Ok(generated_uuid) => response_handler(Response::Ok {
-                exit_code: 0,
-                message: Some(generated_uuid),
-            }),
-            Err(e) => response_handler(Response::Error {
-                exit_code: 1,
-                error: format!("Error creating database entry: {}", e),
-            }),
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("SAMPLE must have a pile name specified".to_owned()),
+                };
+
+                let sample_size = match parts.next().map(str::parse::<usize>) {
+                    Some(Ok(sample_size)) => sample_size,
+                    _ => {
+                        return Err("SAMPLE must have a whole number after the pile name".to_owned())
+                    }
+                };
+
+                Ok(Request::Sample {
+                    pile: pile.to_string().to_lowercase(),
+                    sample_size,
+                })
+            }
+            Some("SCAN") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("SCAN must have a pile name specified".to_owned()),
+                };
+
+                let (cursor, predicate) = match parts.next() {
+                    Some(rest) => match rest.strip_prefix("WHERE ") {
+                        Some(predicate) => (None, Some(predicate)),
+                        None => match rest.split_once(" WHERE ") {
+                            Some((cursor, predicate)) => (Some(cursor), Some(predicate)),
+                            None => (Some(rest), None),
+                        },
+                    },
+                    None => (None, None),
+                };
+
+                Ok(Request::Scan {
+                    pile: pile.to_string().to_lowercase(),
+                    cursor: cursor.map(|cursor| cursor.to_string()),
+                    predicate: predicate.map(query::Predicate::parse).transpose()?,
+                })
+            }
+            Some("WATCH") => {
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("WATCH must have a pile name specified".to_owned()),
+                };
+
+                Ok(Request::Watch {
+                    pile: pile.to_string().to_lowercase(),
+                })
+            }
+            Some("PUBLISH") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let channel = match parts.next() {
+                    Some(channel) if !channel.is_empty() => channel,
+                    _ => return Err("PUBLISH must have a channel name specified".to_owned()),
+                };
+
+                let payload = match parts.next() {
+                    Some(payload) if !payload.is_empty() => payload,
+                    _ => {
+                        return Err("PUBLISH must have a payload after the channel name".to_owned())
+                    }
+                };
+
+                let channel = channel.to_string().to_lowercase();
+
+                Ok(Request::Publish {
+                    grant: channels::grant_name(&channel),
+                    channel,
+                    payload: payload.to_string(),
+                })
+            }
+            Some("SUBSCRIBE") => {
+                let channel = match parts.next() {
+                    Some(channel) if !channel.is_empty() => channel,
+                    _ => return Err("SUBSCRIBE must have a channel name specified".to_owned()),
+                };
+
+                let channel = channel.to_string().to_lowercase();
+
+                Ok(Request::Subscribe {
+                    grant: channels::grant_name(&channel),
+                    channel,
+                })
+            }
+            Some("TRAVERSE") => {
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.split(' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("TRAVERSE must have a pile name specified".to_owned()),
+                };
+
+                let uuid = match parts.next() {
+                    Some(uuid) => uuid,
+                    None => return Err("TRAVERSE must have a UUID after the pile name".to_owned()),
+                };
+
+                let field = match parts.next() {
+                    Some(field) => field,
+                    None => return Err("TRAVERSE must have a field name after the UUID".to_owned()),
+                };
+
+                let depth = match (parts.next(), parts.next(), parts.next()) {
+                    (Some("DEPTH"), Some(depth), None) => match depth.parse::<usize>() {
+                        Ok(depth) => depth,
+                        Err(_) => return Err("TRAVERSE depth must be a whole number".to_owned()),
+                    },
+                    _ => return Err("TRAVERSE must have DEPTH <n> after the field name".to_owned()),
+                };
+
+                Ok(Request::Traverse {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                    field: field.to_string(),
+                    depth,
+                })
+            }
+            Some("JOB") => {
+                let split_input = parts.next().unwrap_or_default();
+                let words: Vec<&str> = split_input.split(' ').collect();
+
+                match words.as_slice() {
+                    ["STATUS", job_id] => Ok(Request::JobStatus {
+                        job_id: job_id.to_string(),
+                    }),
+                    ["SUBMIT", pile, target_pile, "MAP", key_field, rest @ ..] => {
+                        let (value_field, reduce) = match rest {
+                            ["REDUCE", reduce] => (None, reduce),
+                            [value_field, "REDUCE", reduce] => (Some(value_field), reduce),
+                            _ => return Err("JOB SUBMIT must end in REDUCE <op>".to_owned()),
+                        };
+
+                        let reduce = match jobs::ReduceOp::parse(reduce) {
+                            Some(reduce) => reduce,
+                            None => {
+                                return Err(
+                                    "JOB reduce must be COUNT, SUM, MIN, MAX or AVG".to_owned()
+                                )
+                            }
+                        };
+
+                        Ok(Request::JobSubmit {
+                            spec: jobs::JobSpec {
+                                pile: pile.to_lowercase(),
+                                target_pile: target_pile.to_lowercase(),
+                                key_field: key_field.to_string(),
+                                value_field: value_field.map(|value_field| value_field.to_string()),
+                                reduce,
+                            },
+                        })
+                    }
+                    _ => Err("JOB must be followed by SUBMIT or STATUS".to_owned()),
+                }
+            }
+            Some("MIGRATE") => {
+                let split_input = parts.next().unwrap_or_default();
+                let words: Vec<&str> = split_input.split(' ').collect();
+
+                let (dry_run, words) = match words.as_slice() {
+                    [pile, "DRYRUN", rest @ ..] => (true, [&[*pile], rest].concat()),
+                    _ => (false, words),
+                };
+
+                match words.as_slice() {
+                    [pile, name, step @ ..] if !pile.is_empty() => {
+                        match migrations::Step::parse(step) {
+                            Some(step) => Ok(Request::Migrate {
+                                pile: pile.to_lowercase(),
+                                name: name.to_string(),
+                                step,
+                                dry_run,
+                            }),
+                            None => Err("MIGRATE step must be RENAME <field> <new field>, \
+                                 CAST <field> <string|number|boolean> or \
+                                 SPLIT <field> <separator> <field>,<field>,..."
+                                .to_owned()),
+                        }
+                    }
+                    _ => Err(
+                        "MIGRATE must have a pile name and a migration name specified".to_owned(),
+                    ),
+                }
+            }
+            Some("MIGRATIONS") => match parts.next() {
+                Some(pile) if !pile.is_empty() => Ok(Request::Migrations {
+                    pile: pile.to_string().to_lowercase(),
+                }),
+                _ => Err("MIGRATIONS must have a pile name specified".to_owned()),
+            },
+            Some("CREATEMANY") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("CREATEMANY must have a pile name specified".to_owned()),
+                };
+
+                let data = match parts.next() {
+                    Some(data) => data,
+                    None => return Err("CREATEMANY must have data after the pile name".to_owned()),
+                };
+
+                Ok(Request::CreateMany {
+                    pile: pile.to_string().to_lowercase(),
+                    data: data.to_string(),
+                })
+            }
+            Some("IMPORT") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("IMPORT must have a pile name specified".to_owned()),
+                };
+
+                let data = match parts.next() {
+                    Some(data) => data,
+                    None => return Err("IMPORT must have data after the pile name".to_owned()),
+                };
+
+                Ok(Request::Import {
+                    pile: pile.to_string().to_lowercase(),
+                    data: data.to_string(),
+                })
+            }
+            Some("IMPORTCSV") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("IMPORTCSV must have a pile name specified".to_owned()),
+                };
+
+                let data = match parts.next() {
+                    Some(data) => data,
+                    None => return Err("IMPORTCSV must have data after the pile name".to_owned()),
+                };
+
+                Ok(Request::ImportCsv {
+                    pile: pile.to_string().to_lowercase(),
+                    data: data.to_string(),
+                    type_hints: parts.next().map(|type_hints| type_hints.to_string()),
+                })
+            }
+            Some("IMPORTMONGO") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("IMPORTMONGO must have a pile name specified".to_owned()),
+                };
+
+                let data = match parts.next() {
+                    Some(data) => data,
+                    None => return Err("IMPORTMONGO must have data after the pile name".to_owned()),
+                };
+
+                Ok(Request::ImportMongo {
+                    pile: pile.to_string().to_lowercase(),
+                    data: data.to_string(),
+                })
+            }
+            Some("BACKUP") => match parts.next() {
+                Some("FULL") => Ok(Request::Backup { incremental: false }),
+                Some("INCREMENTAL") => Ok(Request::Backup { incremental: true }),
+                _ => Err("BACKUP must be followed by FULL or INCREMENTAL".to_owned()),
+            },
+            Some("SCHEMA") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let action = parts.next().unwrap_or_default();
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile.to_string().to_lowercase(),
+                    _ => return Err("SCHEMA must have a pile name specified".to_owned()),
+                };
+
+                match action {
+                    "SET" => match parts.next() {
+                        Some(schema) => Ok(Request::SchemaSet {
+                            pile,
+                            schema: schema.to_string(),
+                        }),
+                        None => Err("SCHEMA SET must have a schema after the pile name".to_owned()),
+                    },
+                    "GET" => Ok(Request::SchemaGet { pile }),
+                    _ => Err("SCHEMA must be followed by SET or GET".to_owned()),
+                }
+            }
+            Some("DESCRIBE") => match parts.next() {
+                Some(pile) if !pile.is_empty() => Ok(Request::Describe {
+                    pile: pile.to_string().to_lowercase(),
+                }),
+                _ => Err("DESCRIBE must have a pile name specified".to_owned()),
+            },
+            Some("DESCRIPTION") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let action = parts.next().unwrap_or_default();
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile.to_string().to_lowercase(),
+                    _ => return Err("DESCRIPTION must have a pile name specified".to_owned()),
+                };
+
+                match (action, parts.next()) {
+                    ("SET", Some(description)) => Ok(Request::DescriptionSet {
+                        pile,
+                        description: description.to_string(),
+                    }),
+                    ("SET", None) => Err(
+                        "DESCRIPTION SET must have a description after the pile name".to_owned(),
+                    ),
+                    _ => Err("DESCRIPTION must be followed by SET".to_owned()),
+                }
+            }
+            Some("DEFAULTS") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let action = parts.next().unwrap_or_default();
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile.to_string().to_lowercase(),
+                    _ => return Err("DEFAULTS must have a pile name specified".to_owned()),
+                };
+
+                match action {
+                    "SET" => match parts.next() {
+                        Some(defaults) => Ok(Request::DefaultsSet {
+                            pile,
+                            defaults: defaults.to_string(),
+                        }),
+                        None => {
+                            Err("DEFAULTS SET must have defaults after the pile name".to_owned())
+                        }
+                    },
+                    "GET" => Ok(Request::DefaultsGet { pile }),
+                    _ => Err("DEFAULTS must be followed by SET or GET".to_owned()),
+                }
+            }
+            Some("TIMESTAMPS") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("TIMESTAMPS must have a pile name specified".to_owned()),
+                };
+
+                let enabled = match parts.next() {
+                    Some("ON") => true,
+                    Some("OFF") => false,
+                    _ => {
+                        return Err("TIMESTAMPS must have ON or OFF after the pile name".to_owned())
+                    }
+                };
+
+                Ok(Request::Timestamps {
+                    pile: pile.to_string().to_lowercase(),
+                    enabled,
+                })
+            }
+            Some("APPENDONLY") => match parts.next() {
+                Some(pile) if !pile.is_empty() && !pile.contains(' ') => Ok(Request::AppendOnly {
+                    pile: pile.to_string().to_lowercase(),
+                }),
+                _ => Err("APPENDONLY must be APPENDONLY <pile>".to_owned()),
+            },
+            Some("IDS") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("IDS must have a pile name specified".to_owned()),
+                };
+
+                let scheme = match parts.next().and_then(IdScheme::parse) {
+                    Some(scheme) => scheme,
+                    None => return Err("IDS must have an ID scheme after the pile name".to_owned()),
+                };
+
+                Ok(Request::Ids {
+                    pile: pile.to_string().to_lowercase(),
+                    scheme,
+                })
+            }
+            Some("MAXSIZE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("MAXSIZE must have a pile name specified".to_owned()),
+                };
+
+                let max_bytes = match parts.next() {
+                    Some("DEFAULT") => None,
+                    Some(max_bytes) => match max_bytes.parse::<usize>() {
+                        Ok(max_bytes) => Some(max_bytes),
+                        Err(_) => return Err("MAXSIZE bytes must be a whole number".to_owned()),
+                    },
+                    None => {
+                        return Err(
+                            "MAXSIZE must have bytes or DEFAULT after the pile name".to_owned()
+                        )
+                    }
+                };
+
+                Ok(Request::MaxSize {
+                    pile: pile.to_string().to_lowercase(),
+                    max_bytes,
+                })
+            }
+            Some("DELETE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("DELETE must have a pile name specified".to_owned()),
+                };
+
+                let uuid = match parts.next() {
+                    Some(uuid) => uuid,
+                    None => return Err("DELETE must have a UUID after the pile name".to_owned()),
+                };
+
+                Ok(Request::Delete {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                })
+            }
+            Some("DELETEWHERE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("DELETEWHERE must have a pile name specified".to_owned()),
+                };
+
+                let (dry_run, token, predicate) = match parts.next() {
+                    Some(predicate) => match predicate.strip_prefix("DRYRUN ") {
+                        Some(predicate) => (true, None, predicate),
+                        None => match predicate
+                            .strip_prefix("TOKEN ")
+                            .and_then(|rest| rest.split_once(' '))
+                        {
+                            Some((token, predicate)) => (false, Some(token), predicate),
+                            None => (false, None, predicate),
+                        },
+                    },
+                    None => {
+                        return Err(
+                            "DELETEWHERE must have a predicate after the pile name".to_owned()
+                        )
+                    }
+                };
+
+                Ok(Request::DeleteWhere {
+                    pile: pile.to_string().to_lowercase(),
+                    predicate: query::Predicate::parse(predicate)?,
+                    predicate_source: predicate.to_string(),
+                    dry_run,
+                    token: token.map(str::to_owned),
+                })
+            }
+            Some(command @ ("UPDATE" | "PATCH")) => {
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.splitn(3, ' ');
+
+                let (pile, uuid, data) = match (parts.next(), parts.next(), parts.next()) {
+                    (Some(pile), Some(uuid), Some(data)) if !pile.is_empty() => (pile, uuid, data),
+                    _ => {
+                        return Err(format!(
+                            "{} must be {} <pile> <uuid> <data> [IF <predicate>]",
+                            command, command
+                        ))
+                    }
+                };
+
+                // The data is taken whole unless what follows its last IF is
+                // a predicate, so a JSON payload may itself contain " IF "
+                let (data, condition) = match data.rsplit_once(" IF ") {
+                    Some((payload, predicate)) => match query::Predicate::parse(predicate) {
+                        Ok(condition) => (payload, Some(condition)),
+                        Err(_) => (data, None),
+                    },
+                    None => (data, None),
+                };
+
+                let pile = pile.to_string().to_lowercase();
+                let uuid = uuid.to_string();
+                match command {
+                    "UPDATE" => Ok(Request::Update {
+                        pile,
+                        uuid,
+                        data: data.to_string(),
+                        condition,
+                    }),
+                    _ => Ok(Request::Patch {
+                        pile,
+                        uuid,
+                        patch: data.to_string(),
+                        condition,
+                    }),
+                }
+            }
+            Some("FINDANDMODIFY") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("FINDANDMODIFY must have a pile name specified".to_owned()),
+                };
+
+                let (return_new, rest) = match parts.next() {
+                    Some(rest) => match rest.strip_prefix("NEW ") {
+                        Some(rest) => (true, rest),
+                        None => (false, rest),
+                    },
+                    None => {
+                        return Err(
+                            "FINDANDMODIFY must have a predicate after the pile name".to_owned()
+                        )
+                    }
+                };
+
+                let (predicate, action) =
+                    match (rest.strip_suffix(" REMOVE"), rest.split_once(" SET ")) {
+                        (Some(predicate), _) => (predicate, FindAndModifyAction::Remove),
+                        (None, Some((predicate, patch))) => {
+                            (predicate, FindAndModifyAction::Patch(patch.to_string()))
+                        }
+                        (None, None) => {
+                            return Err(
+                                "FINDANDMODIFY must end with SET <data> or REMOVE".to_owned()
+                            )
+                        }
+                    };
+
+                Ok(Request::FindAndModify {
+                    pile: pile.to_string().to_lowercase(),
+                    predicate: query::Predicate::parse(predicate)?,
+                    action,
+                    return_new,
+                })
+            }
+            Some("INCR") => {
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.split(' ');
+
+                let (pile, uuid, field, delta) = match (
+                    parts.next(),
+                    parts.next(),
+                    parts.next(),
+                    parts.next(),
+                    parts.next(),
+                ) {
+                    (Some(pile), Some(uuid), Some(field), Some(delta), None)
+                        if !pile.is_empty() =>
+                    {
+                        (pile, uuid, field, delta)
+                    }
+                    _ => return Err("INCR must be INCR <pile> <uuid> <field> <delta>".to_owned()),
+                };
+
+                Ok(Request::Incr {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                    field: field.to_string(),
+                    delta: delta.to_string(),
+                })
+            }
+            Some("EXPIRE") => {
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.split(' ');
+
+                let (pile, uuid, seconds) =
+                    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                        (Some(pile), Some(uuid), Some(seconds), None) if !pile.is_empty() => {
+                            (pile, uuid, seconds)
+                        }
+                        _ => return Err("EXPIRE must be EXPIRE <pile> <uuid> <seconds>".to_owned()),
+                    };
+
+                let seconds = match seconds.parse() {
+                    Ok(seconds) => seconds,
+                    Err(_) => return Err("EXPIRE must have a whole number of seconds".to_owned()),
+                };
+
+                Ok(Request::Expire {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                    seconds,
+                })
+            }
+            Some("PERSIST") => {
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.split(' ');
+
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(pile), Some(uuid), None) if !pile.is_empty() => Ok(Request::Persist {
+                        pile: pile.to_string().to_lowercase(),
+                        uuid: uuid.to_string(),
+                    }),
+                    _ => Err("PERSIST must be PERSIST <pile> <uuid>".to_owned()),
+                }
+            }
+            Some(command @ ("PUSH" | "PULL" | "ADDTOSET")) => {
+                let op = match command {
+                    "PUSH" => ArrayOp::Push,
+                    "PULL" => ArrayOp::Pull,
+                    _ => ArrayOp::AddToSet,
+                };
+
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.splitn(4, ' ');
+
+                let (pile, uuid, field, value) =
+                    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                        (Some(pile), Some(uuid), Some(field), Some(value)) if !pile.is_empty() => {
+                            (pile, uuid, field, value)
+                        }
+                        _ => {
+                            return Err(format!(
+                                "{} must be {} <pile> <uuid> <field> <data>",
+                                command, command
+                            ))
+                        }
+                    };
+
+                Ok(Request::ArrayUpdate {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                    field: field.to_string(),
+                    op,
+                    value: value.to_string(),
+                })
+            }
+            Some("UPDATEWHERE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("UPDATEWHERE must have a pile name specified".to_owned()),
+                };
+
+                let (predicate, patch) =
+                    match parts.next().and_then(|rest| rest.split_once(" SET ")) {
+                        Some((predicate, patch)) => (predicate, patch),
+                        None => return Err(
+                            "UPDATEWHERE must have a predicate and SET <data> after the pile name"
+                                .to_owned(),
+                        ),
+                    };
+                let (dry_run, predicate) = match predicate.strip_prefix("DRYRUN ") {
+                    Some(predicate) => (true, predicate),
+                    None => (false, predicate),
+                };
+
+                Ok(Request::UpdateWhere {
+                    pile: pile.to_string().to_lowercase(),
+                    predicate: query::Predicate::parse(predicate)?,
+                    patch: patch.to_string(),
+                    dry_run,
+                })
+            }
+            Some("RESTORE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("RESTORE must have a pile name specified".to_owned()),
+                };
+
+                let uuid = match parts.next() {
+                    Some(uuid) => uuid,
+                    None => return Err("RESTORE must have a UUID after the pile name".to_owned()),
+                };
+
+                Ok(Request::Restore {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                })
+            }
+            Some("UNDELETE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("UNDELETE must have a pile name specified".to_owned()),
+                };
+
+                let uuid = match parts.next() {
+                    Some(uuid) if is_valid_document_id(uuid) => uuid,
+                    _ => return Err("UNDELETE must have a UUID after the pile name".to_owned()),
+                };
+
+                Ok(Request::Undelete {
+                    pile: pile.to_string().to_lowercase(),
+                    uuid: uuid.to_string(),
+                })
+            }
+            Some("SOFTDELETE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("SOFTDELETE must have a pile name specified".to_owned()),
+                };
+
+                let enabled = match parts.next() {
+                    Some("ON") => true,
+                    Some("OFF") => false,
+                    _ => {
+                        return Err("SOFTDELETE must have ON or OFF after the pile name".to_owned())
+                    }
+                };
+
+                let purge_after_days = match parts.next() {
+                    Some(days) => match days.parse::<u64>() {
+                        Ok(days) => Some(days),
+                        Err(_) => {
+                            return Err("SOFTDELETE purge days must be a whole number".to_owned())
+                        }
+                    },
+                    None => None,
+                };
+
+                Ok(Request::SoftDelete {
+                    pile: pile.to_string().to_lowercase(),
+                    enabled,
+                    purge_after_days,
+                })
+            }
+            Some("CAPPED") => {
+                const CAPPED_USAGE: &str =
+                    "CAPPED must be followed by DOCUMENTS <n> and/or BYTES <n>, or OFF";
+
+                let split_input = parts.next().unwrap_or_default();
+                let mut parts = split_input.split(' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile.to_string().to_lowercase(),
+                    _ => return Err("CAPPED must have a pile name specified".to_owned()),
+                };
+
+                let mut max_documents = None;
+                let mut max_bytes = None;
+                let mut off = false;
+                while let Some(setting) = parts.next() {
+                    let max = match setting {
+                        "OFF" => {
+                            off = true;
+                            continue;
+                        }
+                        "DOCUMENTS" => &mut max_documents,
+                        "BYTES" => &mut max_bytes,
+                        _ => return Err(CAPPED_USAGE.to_owned()),
+                    };
+
+                    match parts.next().map(str::parse::<u64>) {
+                        Some(Ok(limit)) if limit > 0 => *max = Some(limit),
+                        _ => {
+                            return Err(format!(
+                                "CAPPED {} must be a whole number above 0",
+                                setting
+                            ))
+                        }
+                    }
+                }
+
+                match (off, max_documents, max_bytes) {
+                    (true, None, None) | (false, Some(_), _) | (false, _, Some(_)) => {
+                        Ok(Request::Capped {
+                            pile,
+                            max_documents,
+                            max_bytes,
+                        })
+                    }
+                    _ => Err(CAPPED_USAGE.to_owned()),
+                }
+            }
+            Some("UNIQUE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("UNIQUE must have a pile name specified".to_owned()),
+                };
+
+                let field = match parts.next() {
+                    Some(field) => field,
+                    None => {
+                        return Err("UNIQUE must have a field name after the pile name".to_owned())
+                    }
+                };
+
+                Ok(Request::Unique {
+                    pile: pile.to_string().to_lowercase(),
+                    field: field.to_string(),
+                })
+            }
+            Some("REFERENCE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(4, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("REFERENCE must have a pile name specified".to_owned()),
+                };
+
+                let field = match parts.next() {
+                    Some(field) => field,
+                    None => {
+                        return Err(
+                            "REFERENCE must have a field name after the pile name".to_owned()
+                        )
+                    }
+                };
+
+                let target_pile = match parts.next() {
+                    Some(target_pile) => target_pile,
+                    None => {
+                        return Err(
+                            "REFERENCE must have a target pile after the field name".to_owned()
+                        )
+                    }
+                };
+
+                let on_delete = match parts.next() {
+                    Some(on_delete) => match OnDelete::parse(on_delete) {
+                        Some(on_delete) => on_delete,
+                        None => {
+                            return Err("REFERENCE on delete must be IGNORE, RESTRICT or CASCADE"
+                                .to_owned())
+                        }
+                    },
+                    None => OnDelete::Ignore,
+                };
+
+                Ok(Request::Reference {
+                    pile: pile.to_string().to_lowercase(),
+                    field: field.to_string(),
+                    target_pile: target_pile.to_string().to_lowercase(),
+                    on_delete,
+                })
+            }
+            Some("TRIGGER") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(4, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("TRIGGER must have a pile name specified".to_owned()),
+                };
+
+                let event = match parts.next().and_then(TriggerEvent::parse) {
+                    Some(event) => event,
+                    None => {
+                        return Err(
+                            "TRIGGER must have CREATE or DELETE after the pile name".to_owned()
+                        )
+                    }
+                };
+
+                let action = match (parts.next(), parts.next()) {
+                    (Some("COPY"), Some(target_pile)) => TriggerAction::Copy {
+                        target_pile: target_pile.to_string().to_lowercase(),
+                    },
+                    (Some("AUDIT"), Some(target_pile)) => TriggerAction::Audit {
+                        target_pile: target_pile.to_string().to_lowercase(),
+                    },
+                    _ => {
+                        return Err("TRIGGER action must be COPY <pile> or AUDIT <pile>".to_owned())
+                    }
+                };
+
+                Ok(Request::Trigger {
+                    pile: pile.to_string().to_lowercase(),
+                    trigger: Trigger { event, action },
+                })
+            }
+            Some("WEBHOOK") => {
+                let split_input = parts.next().unwrap_or_default();
+                let words: Vec<&str> = split_input.split(' ').collect();
+
+                let (pile, words) = match words.split_first() {
+                    Some((pile, words)) if !pile.is_empty() => (pile.to_lowercase(), words),
+                    _ => return Err("WEBHOOK must have a pile name specified".to_owned()),
+                };
+
+                match words {
+                    ["REMOVE", url] => Ok(Request::WebhookRemove {
+                        pile,
+                        url: url.to_string(),
+                    }),
+                    [url, options @ ..] if !url.is_empty() && options.len() % 2 == 0 => {
+                        let mut webhook = Webhook {
+                            url: url.to_string(),
+                            events: vec![
+                                WebhookEvent::Create,
+                                WebhookEvent::Update,
+                                WebhookEvent::Delete,
+                            ],
+                            secret: None,
+                        };
+                        for option in options.chunks(2) {
+                            match option {
+                                ["EVENTS", events] => {
+                                    webhook.events = events
+                                        .split(',')
+                                        .map(|event| {
+                                            WebhookEvent::parse(event)
+                                                .ok_or(format!("Unknown webhook event: {}", event))
+                                        })
+                                        .collect::<Result<Vec<WebhookEvent>, String>>()?
+                                }
+                                ["SECRET", secret] => webhook.secret = Some(secret.to_string()),
+                                _ => {
+                                    return Err(
+                                        "WEBHOOK options must be EVENTS <events> or SECRET <secret>"
+                                            .to_owned(),
+                                    )
+                                }
+                            }
+                        }
+
+                        Ok(Request::WebhookAdd { pile, webhook })
+                    }
+                    _ => Err("WEBHOOK must look like WEBHOOK <pile> <url> [EVENTS <events>] [SECRET <secret>] or WEBHOOK <pile> REMOVE <url>".to_owned()),
+                }
+            }
+            Some("SINK") => {
+                let split_input = parts.next().unwrap_or_default();
+                match split_input.split(' ').collect::<Vec<&str>>().as_slice() {
+                    [pile, "REMOVE", url, subject] if !pile.is_empty() => Ok(Request::SinkRemove {
+                        pile: pile.to_lowercase(),
+                        url: url.to_string(),
+                        subject: subject.to_string(),
+                    }),
+                    [pile, url, subject] if !pile.is_empty() => Ok(Request::SinkAdd {
+                        pile: pile.to_lowercase(),
+                        sink: Sink {
+                            url: url.to_string(),
+                            subject: subject.to_string(),
+                        },
+                    }),
+                    _ => Err("SINK must look like SINK <pile> <url> <subject> or SINK <pile> REMOVE <url> <subject>".to_owned()),
+                }
+            }
+            Some("STATS") => match parts.next() {
+                Some(pile) if !pile.is_empty() => Ok(Request::PileStats {
+                    pile: pile.to_string().to_lowercase(),
+                }),
+                _ => Ok(Request::Stats {}),
+            },
+            Some("CLEANUP") => Ok(Request::Cleanup {}),
+            Some("INFO") => Ok(Request::Info {}),
+            Some("FSCK") => {
+                let split_input = parts.next().unwrap_or_default();
+                match split_input.split(' ').collect::<Vec<&str>>().as_slice() {
+                    [""] => Ok(Request::Fsck {
+                        pile: None,
+                        repair: false,
+                    }),
+                    ["REPAIR"] => Ok(Request::Fsck {
+                        pile: None,
+                        repair: true,
+                    }),
+                    [pile] => Ok(Request::Fsck {
+                        pile: Some(pile.to_string().to_lowercase()),
+                        repair: false,
+                    }),
+                    [pile, "REPAIR"] => Ok(Request::Fsck {
+                        pile: Some(pile.to_string().to_lowercase()),
+                        repair: true,
+                    }),
+                    _ => {
+                        Err("FSCK must be followed by an optional pile name and REPAIR".to_owned())
+                    }
+                }
+            }
+            Some("REPAIR") => match parts.next() {
+                Some(pile) if !pile.is_empty() && !pile.contains(' ') => Ok(Request::Repair {
+                    pile: pile.to_string().to_lowercase(),
+                }),
+                _ => Err("REPAIR must have a pile name specified".to_owned()),
+            },
+            Some("CONFIG") => {
+                let split_input = parts.next().unwrap_or_default();
+                match split_input.split(' ').collect::<Vec<&str>>().as_slice() {
+                    ["SET", "loglevel", level] => Ok(Request::SetLogLevel {
+                        level: level.parse()?,
+                    }),
+                    ["SET", setting, _] => Err(format!("Unknown setting: {}", setting)),
+                    _ => Err("CONFIG must look like CONFIG SET <setting> <value>".to_owned()),
+                }
+            }
+            Some("BLOOM") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("BLOOM must have a pile name specified".to_owned()),
+                };
+
+                let field = match parts.next() {
+                    Some(field) => field,
+                    None => {
+                        return Err("BLOOM must have a field name after the pile name".to_owned())
+                    }
+                };
+
+                Ok(Request::Bloom {
+                    pile: pile.to_string().to_lowercase(),
+                    field: field.to_string(),
+                })
+            }
+            Some("ORDERED") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let pile = match parts.next() {
+                    Some(pile) if !pile.is_empty() => pile,
+                    _ => return Err("ORDERED must have a pile name specified".to_owned()),
+                };
+
+                let field = match parts.next() {
+                    Some(field) => field,
+                    None => {
+                        return Err("ORDERED must have a field name after the pile name".to_owned())
+                    }
+                };
+
+                Ok(Request::Ordered {
+                    pile: pile.to_string().to_lowercase(),
+                    field: field.to_string(),
+                })
+            }
+            Some("GRANT") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(3, ' ');
+
+                let user = match parts.next() {
+                    Some(user) if !user.is_empty() => user,
+                    _ => return Err("GRANT must have a user name specified".to_owned()),
+                };
+
+                let pile = match parts.next() {
+                    Some(pile) => pile,
+                    None => {
+                        return Err("GRANT must have a pile name after the user name".to_owned())
+                    }
+                };
+
+                let right = match parts.next().and_then(Right::parse) {
+                    Some(right) => right,
+                    None => {
+                        return Err(
+                            "GRANT must have READ, WRITE or ADMIN after the pile name".to_owned()
+                        )
+                    }
+                };
+
+                Ok(Request::Grant {
+                    user: user.to_string(),
+                    pile: pile.to_string().to_lowercase(),
+                    right,
+                })
+            }
+            Some("REVOKE") => {
+                let split_input = parts.next().unwrap_or_default();
+                parts = split_input.splitn(2, ' ');
+
+                let user = match parts.next() {
+                    Some(user) if !user.is_empty() => user,
+                    _ => return Err("REVOKE must have a user name specified".to_owned()),
+                };
+
+                let pile = match parts.next() {
+                    Some(pile) => pile,
+                    None => {
+                        return Err("REVOKE must have a pile name after the user name".to_owned())
+                    }
+                };
+
+                Ok(Request::Revoke {
+                    user: user.to_string(),
+                    pile: pile.to_string().to_lowercase(),
+                })
+            }
+            Some(cmd) => Err(format!("Error parsing request, unknown command: {}", cmd)),
+            None => Err("Error parsing request, empty request".to_owned()),
+        }
+    }
+}
+
+impl Request {
+    /// Fails unless the request only reads (see prepared.rs)
+    fn check_is_query(&self) -> Result<(), String> {
+        match self
+            .required_rights()
+            .iter()
+            .all(|(_, right)| *right == Right::Read)
+        {
+            true => Ok(()),
+            false => {
+                Err("Only queries (needing no more than READ rights) can be prepared".to_owned())
+            }
+        }
+    }
+
+    /// The rights a user needs to run this request, each with the pile it is
+    /// needed on (`*` for server wide requests). Empty if anyone may run it.
+    fn required_rights(&self) -> Vec<(&str, Right)> {
+        match *self {
+            Request::Ping {} => Vec::new(),
+            Request::Publish { ref grant, .. } => vec![(grant, Right::Write)],
+            Request::Subscribe { ref grant, .. } => vec![(grant, Right::Read)],
+            Request::Find {
+                ref pile,
+                ref joins,
+                ..
+            } => std::iter::once(pile)
+                .chain(joins.iter().map(|join| &join.pile))
+                .map(|pile| (pile.as_str(), Right::Read))
+                .collect(),
+            Request::Explain { ref query } | Request::Prepare { ref query, .. } => {
+                query.required_rights()
+            }
+            Request::Export { ref pile }
+            | Request::Count { ref pile, .. }
+            | Request::Scan { ref pile, .. }
+            | Request::Sample { ref pile, .. }
+            | Request::PileStats { ref pile }
+            | Request::Watch { ref pile }
+            | Request::Traverse { ref pile, .. }
+            | Request::SchemaGet { ref pile }
+            | Request::Describe { ref pile }
+            | Request::DefaultsGet { ref pile }
+            | Request::Migrations { ref pile } => vec![(pile, Right::Read)],
+            Request::JobSubmit { ref spec } => vec![
+                (spec.pile.as_str(), Right::Read),
+                (spec.target_pile.as_str(), Right::Write),
+            ],
+            Request::Create { ref pile, .. }
+            | Request::CreateMany { ref pile, .. }
+            | Request::Import { ref pile, .. }
+            | Request::ImportCsv { ref pile, .. }
+            | Request::ImportMongo { ref pile, .. }
+            | Request::Delete { ref pile, .. }
+            | Request::DeleteWhere { ref pile, .. }
+            | Request::UpdateWhere { ref pile, .. }
+            | Request::Update { ref pile, .. }
+            | Request::Patch { ref pile, .. }
+            | Request::FindAndModify { ref pile, .. }
+            | Request::Incr { ref pile, .. }
+            | Request::Expire { ref pile, .. }
+            | Request::Persist { ref pile, .. }
+            | Request::ArrayUpdate { ref pile, .. }
+            | Request::Restore { ref pile, .. }
+            | Request::Undelete { ref pile, .. } => vec![(pile, Right::Write)],
+            Request::SchemaSet { ref pile, .. }
+            | Request::DescriptionSet { ref pile, .. }
+            | Request::DefaultsSet { ref pile, .. }
+            | Request::Timestamps { ref pile, .. }
+            | Request::AppendOnly { ref pile }
+            | Request::Ids { ref pile, .. }
+            | Request::MaxSize { ref pile, .. }
+            | Request::SoftDelete { ref pile, .. }
+            | Request::Capped { ref pile, .. }
+            | Request::Repair { ref pile }
+            | Request::Unique { ref pile, .. }
+            | Request::Reference { ref pile, .. }
+            | Request::Trigger { ref pile, .. }
+            | Request::WebhookAdd { ref pile, .. }
+            | Request::WebhookRemove { ref pile, .. }
+            | Request::SinkAdd { ref pile, .. }
+            | Request::SinkRemove { ref pile, .. }
+            | Request::Bloom { ref pile, .. }
+            | Request::Ordered { ref pile, .. }
+            | Request::Migrate { ref pile, .. } => vec![(pile, Right::Admin)],
+            Request::Fsck {
+                pile: Some(ref pile),
+                ..
+            } => vec![(pile, Right::Admin)],
+            Request::CopyPile {
+                ref source_pile,
+                ref pile,
+                ..
+            } => vec![(source_pile, Right::Read), (pile, Right::Admin)],
+            Request::Backup { .. }
+            | Request::ExportSqlite { .. }
+            | Request::Archive { .. }
+            | Request::Unarchive { .. }
+            | Request::Fsck { pile: None, .. }
+            | Request::Stats {}
+            | Request::Cleanup {}
+            | Request::Info {}
+            | Request::SetLogLevel { .. }
+            | Request::JobStatus { .. }
+            | Request::CreateUser { .. }
+            | Request::Grant { .. }
+            | Request::Revoke { .. } => vec![(ALL_PILES, Right::Admin)],
+        }
+    }
+}
+
+/// Responses to the `Request` commands above
+enum Response {
+    Ok {
+        exit_code: u8,
+        message: Option<String>,
+    },
+    Error {
+        exit_code: u8,
+        error: String,
+    },
+}
+
+/// How responses are written back: `<code> <message>` lines, or one JSON
+/// envelope per line
+///
+/// {"code":0,"request_id":"...","data":"cd8abd45-...","error":null}
+/// {"code":3,"request_id":"...","data":null,"error":"Could not find job: \"...\""}
+#[derive(Clone, Copy, PartialEq)]
+enum ResponseFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for ResponseFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<ResponseFormat, String> {
+        match input {
+            "TEXT" => Ok(ResponseFormat::Text),
+            "JSON" => Ok(ResponseFormat::Json),
+            _ => Err(format!("Unknown response format: {}", input)),
+        }
+    }
+}
+
+/// Options a command can be prefixed with (after any AUTH), in any order:
+///
+/// ENCODING <HEX|BASE64|JSON>  payload encoding, see payload.rs
+/// FORMAT <TEXT|JSON>          response format, defaults to
+///                             `DUST_RESPONSE_FORMAT` (or TEXT)
+/// REQUEST <id>                the client's own request ID, see `request_id`
+/// WRITECONCERN <BUFFERED|WRITTEN|FSYNC>
+///                             how durable writes are once acknowledged, see
+///                             write_concern.rs
+/// AFTER <seq>                 wait for a write to land first, see
+///                             consistency.rs
+#[derive(Clone)]
+struct CommandOptions {
+    encoding: Encoding,
+    format: ResponseFormat,
+    request_id: Option<String>,
+    write_concern: WriteConcern,
+    after: Option<u64>,
+}
+
+impl CommandOptions {
+    fn server_default() -> CommandOptions {
+        CommandOptions {
+            encoding: payload::default_encoding(),
+            format: env_or("DUST_RESPONSE_FORMAT", ResponseFormat::Text),
+            request_id: None,
+            write_concern: WriteConcern::server_default(),
+            after: None,
+        }
+    }
+
+    /// Every command is logged and answered under a request ID, so a failed
+    /// request can be traced from client to server logs. The client's own ID
+    /// (`REQUEST <id>`, at most 128 characters) is used if it sent one,
+    /// otherwise a UUID is generated.
+    fn request_id(&self) -> String {
+        self.request_id.clone().unwrap_or_else(generate_v4_uuid)
+    }
+}
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The options `split_options` knows, which take one value each
+const OPTION_PREFIXES: [&str; 5] = ["ENCODING", "FORMAT", "WRITECONCERN", "AFTER", "REQUEST"];
+
+/// Splits the option prefixes off a command
+fn split_options(mut command: &str) -> Result<(CommandOptions, &str), String> {
+    let mut options = CommandOptions::server_default();
+    loop {
+        let (option, rest) = command.split_once(' ').unwrap_or((command, ""));
+        let (value, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        match option {
+            "ENCODING" => options.encoding = value.parse()?,
+            "FORMAT" => options.format = value.parse()?,
+            "WRITECONCERN" => options.write_concern = value.parse()?,
+            "AFTER" => match value.parse() {
+                Ok(seq) => options.after = Some(seq),
+                Err(_) => return Err("AFTER must have a commit sequence number".to_owned()),
+            },
+            "REQUEST" if !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH => {
+                options.request_id = Some(value.to_owned())
+            }
+            "REQUEST" => {
+                return Err(format!(
+                    "REQUEST must have an ID of at most {} characters",
+                    MAX_REQUEST_ID_LENGTH
+                ))
+            }
+            _ => return Ok((options, command)),
+        }
+        command = rest;
+    }
+}
+
+impl Response {
+    /// Text responses only echo the request ID when the client sent its own,
+    /// as a `REQUEST <id>` prefix; JSON envelopes always carry it
+    fn serialize(&self, options: &CommandOptions, request_id: &str) -> String {
+        if options.format == ResponseFormat::Json {
+            return match *self {
+                Response::Ok {
+                    ref exit_code,
+                    ref message,
+                } => json!({
+                    "code": exit_code,
+                    "request_id": request_id,
+                    "data": message,
+                    "error": null,
+                    "commit_seq": consistency::committed(),
+                }),
+                Response::Error {
+                    ref exit_code,
+                    ref error,
+                } => json!({
+                    "code": exit_code,
+                    "request_id": request_id,
+                    "data": null,
+                    "error": error,
+                    "commit_seq": consistency::committed(),
+                }),
+            }
+            .to_string();
+        }
+
+        let response = match *self {
+            Response::Ok {
+                ref exit_code,
+                ref message,
+            } => format!(
+                "{} {}",
+                exit_code,
+                match message {
+                    None => "",
+                    Some(message) => message,
+                }
+            ),
+            Response::Error {
+                ref exit_code,
+                ref error,
+            } => format!("{} Error: {}", exit_code, error),
+        };
+
+        match options.request_id {
+            Some(_) => format!("REQUEST {} {}", request_id, response),
+            None => response,
+        }
+    }
+}
+
+//https://github.com/tokio-rs/tokio/blob/master/examples/tinydb.rs
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "--restore" {
+        let until = match args.get(2) {
+            Some(timestamp) => DateTime::parse_from_rfc3339(timestamp)?.with_timezone(&Utc),
+            None => return Err("--restore must have an RFC 3339 timestamp specified".into()),
+        };
+
+        let storage_lock = daemon::lock_storage()?;
+        let replayed = restore(&until)?;
+        storage_lock.release()?;
+        println!(
+            "dustdb successfully restored {} operation(s) up to: {}",
+            replayed, until
+        );
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "bench" {
+        return bench::run(&args[2..]).await;
+    }
+
+    if args.len() > 1 && args[1] == "--restore-backup" {
+        let backup_name = match args.get(2) {
+            Some(backup_name) => backup_name,
+            None => return Err("--restore-backup must have a backup name specified".into()),
+        };
+
+        let storage_lock = daemon::lock_storage()?;
+        let replayed = restore_backup(backup_name)?;
+        storage_lock.release()?;
+        println!(
+            "dustdb successfully restored backup {} ({} WAL operation(s) replayed)",
+            backup_name, replayed
+        );
+        return Ok(());
+    }
+
+    let mut storage_lock = daemon::lock_storage()?;
+    let is_daemon = args.len() > 1 && args[1] == "--daemon";
+    if is_daemon {
+        daemon::write_pid_file()?;
+        logging::redirect_diagnostics();
+    }
+
+    if let LastShutdown::Unclean { wal_offset } = *storage_lock.last_shutdown() {
+        recovery::recover(wal_offset)?;
+        storage_lock.checkpoint(wal::end_offset()?)?;
+    }
+
+    // With socket activation, systemd owns the addresses
+    let mut listeners = Vec::new();
+    match systemd::inherited_listener()? {
+        Some(listener) => {
+            listeners.push((TcpListener::from_std(listener)?, ListenerFlags::default()))
+        }
+        None => {
+            for config in listeners::configured()? {
+                listeners.push((TcpListener::bind(&config.addr).await?, config.flags));
+            }
+        }
+    }
+
+    telemetry::init()?;
+    ttl::spawn_worker();
+    janitor::spawn_worker();
+    scheduler::spawn_worker()?;
+    mqtt::spawn_worker();
+    migrations::resume_all()?;
+
+    for (listener, flags) in listeners {
+        logging::diagnostic(
+            Level::Info,
+            &format!(
+                "dustdb successfully started, listening on: {}",
+                listener.local_addr()?
+            ),
+        );
+        match flags.http {
+            true => tokio::spawn(http::serve(listener, flags)),
+            false => tokio::spawn(serve(listener, flags)),
+        };
+    }
+    systemd::notify("READY=1");
+
+    systemd::terminated().await?;
+    systemd::notify("STOPPING=1");
+    logging::diagnostic(Level::Info, "dustdb shutting down");
+    storage_lock.release()?;
+    Ok(())
+}
+
+/// Accepts the connections of one listener, for as long as the server runs.
+/// A connection that sends nothing for `DUST_IDLE_TIMEOUT_SECS` (default 300)
+/// is closed, so silent clients don't hold on to their tasks forever.
+async fn serve(listener: TcpListener, flags: ListenerFlags) {
+    let idle_timeout = Duration::from_secs(env_or("DUST_IDLE_TIMEOUT_SECS", 300));
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, socket_addr)) => {
+                // Like with other small servers, we'll `spawn` this client to ensure it
+                // runs concurrently with all other clients. The `move` keyword is used
+                // here to move ownership of our db handle into the async closure.
+                tokio::spawn(
+                    async move {
+                        // Since our protocol is line-based we use `tokio_codecs`'s `LineCodec`
+                        // to convert our stream of bytes, `socket`, into a `Stream` of lines
+                        // as well as convert our line based responses into a stream of bytes.
+                        let mut lines = Framed::new(socket, LinesCodec::new());
+
+                        // Here for every line we get back from the `Framed` decoder,
+                        // we parse the request, and if it's valid we generate a response
+                        // based on the values in the database.
+                        while let Ok(Some(result)) =
+                            tokio::time::timeout(idle_timeout, lines.next()).await
+                        {
+                            match result {
+                                Ok(line) => {
+                                    let (options, command) = split_options(split_auth(&line).1)
+                                        .unwrap_or_else(|_| (CommandOptions::server_default(), ""));
+                                    let request_id = options.request_id();
+
+                                    // Subscribe before the WATCH or SUBSCRIBE is acknowledged,
+                                    // so nothing sent in between goes missing
+                                    let stream = match Request::parse(command) {
+                                        Ok(Request::Watch { pile }) => {
+                                            Some(Stream::Watch(pile, events::subscribe()))
+                                        }
+                                        Ok(Request::Subscribe { channel, .. })
+                                            if channels::check_channel_name(&channel).is_ok() =>
+                                        {
+                                            Some(Stream::Channel(channels::subscribe(&channel)))
+                                        }
+                                        _ => None,
+                                    };
+
+                                    let _payload = memory::track_payload(line.len());
+                                    let response = handle_request_blocking(
+                                        line,
+                                        socket_addr,
+                                        flags,
+                                        request_id.clone(),
+                                    )
+                                    .await;
+                                    let is_ok = matches!(response, Response::Ok { .. });
+                                    let response = tracing::info_span!("serialize")
+                                        .in_scope(|| response.serialize(&options, &request_id));
+
+                                    if let Err(e) = lines.send(response.as_str()).await {
+                                        logging::diagnostic(
+                                            Level::Error,
+                                            &format!("Error sending response: {:?}", e),
+                                        );
+                                    }
+
+                                    // WATCH and SUBSCRIBE keep their connection open, every
+                                    // other command is answered once -- never a persistent connection
+                                    match (stream, is_ok) {
+                                        (Some(Stream::Watch(pile_name, receiver)), true) => {
+                                            watch(
+                                                &mut lines,
+                                                &pile_name,
+                                                &options,
+                                                &request_id,
+                                                receiver,
+                                            )
+                                            .await
+                                        }
+                                        (Some(Stream::Channel(receiver)), true) => {
+                                            subscribe(&mut lines, &options, &request_id, receiver)
+                                                .await
+                                        }
+                                        _ => (),
+                                    }
+                                    break;
+                                }
+                                Err(e) => {
+                                    logging::diagnostic(
+                                        Level::Error,
+                                        &format!("Error decoding from socket: {:?}", e),
+                                    );
+                                }
+                            }
+                        }
+
+                        // The connection will be closed at this point as `lines.next()` has returned `None`
+                        // (or timed out).
+                    }
+                    .instrument(tracing::info_span!("connection", peer = %socket_addr)),
+                );
+            }
+            Err(e) => {
+                logging::diagnostic(Level::Error, &format!("Error accepting socket: {:?}", e))
+            }
+        }
+    }
+}
+
+/// What a streaming command's connection is subscribed to
+enum Stream {
+    Watch(
+        String,
+        tokio::sync::broadcast::Receiver<Arc<events::CreatedDocument>>,
+    ),
+    Channel(tokio::sync::broadcast::Receiver<Arc<String>>),
+}
+
+/// Example:
+/// in: WATCH jobs
+/// out: 0
+/// out: 0 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: 0 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// After the acknowledgement, every document created in the pile is pushed
+/// as its own line (encoded like the WATCH, with its `_id`) until the
+/// client hangs up.
+/// A watcher that falls too far behind gets an error line and is
+/// disconnected, and can catch up with SCAN.
+///
+/// With `DUST_WATCH_KEEPALIVE_SECS` set, a quiet WATCH is sent a `PING`
+/// message that often, so the connection of a crashed client fails and is
+/// closed instead of lingering half-open.
+async fn watch(
+    lines: &mut Framed<tokio::net::TcpStream, LinesCodec>,
+    pile_name: &str,
+    options: &CommandOptions,
+    request_id: &str,
+    mut receiver: tokio::sync::broadcast::Receiver<Arc<events::CreatedDocument>>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let keepalive_secs = env_or("DUST_WATCH_KEEPALIVE_SECS", 0_u64);
+    let mut keepalive = tokio::time::interval(Duration::from_secs(keepalive_secs.max(1)));
+    keepalive.reset();
+
+    loop {
+        // Anything the client sends (or hanging up) ends the WATCH
+        let received = tokio::select! {
+            received = receiver.recv() => Some(received),
+            _ = keepalive.tick(), if keepalive_secs > 0 => None,
+            _ = lines.next() => return,
+        };
+
+        let response = match received {
+            None => Response::Ok {
+                exit_code: 0,
+                message: Some("PING".to_owned()),
+            },
+            Some(Ok(created)) if created.pile == pile_name => Response::Ok {
+                exit_code: 0,
+                message: Some(options.encoding.encode(&created.document.to_string())),
+            },
+            Some(Ok(_)) => continue,
+            Some(Err(RecvError::Lagged(missed))) => Response::Error {
+                exit_code: ErrorCode::Internal as u8,
+                error: format!("WATCH fell behind, {} document(s) missed", missed),
+            },
+            Some(Err(RecvError::Closed)) => return,
+        };
+        keepalive.reset();
+        let is_lagged = matches!(response, Response::Error { .. });
+
+        if lines
+            .send(response.serialize(options, request_id).as_str())
+            .await
+            .is_err()
+            || is_lagged
+        {
+            return;
+        }
+    }
+}
+
+/// Example:
+/// in: SUBSCRIBE deploys
+/// out: 0
+/// out: 0 7B2276657273696F6E223A22312E342E30227D
+///
+/// After the acknowledgement, every message published to the channel is
+/// pushed as its own line (encoded like the SUBSCRIBE) until the client
+/// hangs up. As with WATCH, a subscriber that falls too far behind gets an
+/// error line and is disconnected, and `DUST_WATCH_KEEPALIVE_SECS` has a
+/// quiet subscription sent `PING` messages.
+async fn subscribe(
+    lines: &mut Framed<tokio::net::TcpStream, LinesCodec>,
+    options: &CommandOptions,
+    request_id: &str,
+    mut receiver: tokio::sync::broadcast::Receiver<Arc<String>>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let keepalive_secs = env_or("DUST_WATCH_KEEPALIVE_SECS", 0_u64);
+    let mut keepalive = tokio::time::interval(Duration::from_secs(keepalive_secs.max(1)));
+    keepalive.reset();
+
+    loop {
+        // Anything the client sends (or hanging up) ends the SUBSCRIBE
+        let received = tokio::select! {
+            received = receiver.recv() => Some(received),
+            _ = keepalive.tick(), if keepalive_secs > 0 => None,
+            _ = lines.next() => return,
+        };
+
+        let response = match received {
+            None => Response::Ok {
+                exit_code: 0,
+                message: Some("PING".to_owned()),
+            },
+            Some(Ok(payload)) => Response::Ok {
+                exit_code: 0,
+                message: Some(options.encoding.encode(&payload)),
+            },
+            Some(Err(RecvError::Lagged(missed))) => Response::Error {
+                exit_code: ErrorCode::Internal as u8,
+                error: format!("SUBSCRIBE fell behind, {} message(s) missed", missed),
+            },
+            Some(Err(RecvError::Closed)) => return,
+        };
+        keepalive.reset();
+        let is_lagged = matches!(response, Response::Error { .. });
+
+        if lines
+            .send(response.serialize(options, request_id).as_str())
+            .await
+            .is_err()
+            || is_lagged
+        {
+            return;
+        }
+    }
+}
+
+/// Storage operations are blocking filesystem IO, so requests are handled on
+/// tokio's blocking pool instead of the threads driving client connections.
+/// At most `DUST_MAX_BLOCKING_OPS` (default 64) run at once; further requests
+/// wait for a permit, so a burst of slow scans can't tie up the whole pool.
+async fn handle_request_blocking(
+    line: String,
+    socket_addr: SocketAddr,
+    flags: ListenerFlags,
+    request_id: String,
+) -> Response {
+    static BLOCKING_OPS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+    let blocking_ops = BLOCKING_OPS
+        .get_or_init(|| Arc::new(Semaphore::new(env_or("DUST_MAX_BLOCKING_OPS", 64))))
+        .clone();
+
+    let permit = match blocking_ops.acquire_owned().await {
+        Ok(permit) => permit,
+        Err(e) => {
+            return response_handler(
+                &request_id,
+                Response::Error {
+                    exit_code: ErrorCode::Internal as u8,
+                    error: format!("Error scheduling request: {}", e),
+                },
+            )
+        }
+    };
+
+    let blocking_request_id = request_id.clone();
+    let span = tracing::info_span!("request", request_id = %request_id);
+    let handled = tokio::task::spawn_blocking(move || {
+        let _entered = span.entered();
+        let response = handle_request(&line, &socket_addr, flags, &blocking_request_id);
+        drop(permit);
+        response
+    })
+    .await;
+
+    match handled {
+        Ok(response) => response,
+        Err(e) => response_handler(
+            &request_id,
+            Response::Error {
+                exit_code: ErrorCode::Internal as u8,
+                error: format!("Error handling request: {}", e),
+            },
+        ),
+    }
+}
+
+fn handle_request(
+    line: &str,
+    socket_addr: &SocketAddr,
+    flags: ListenerFlags,
+    request_id: &str,
+) -> Response {
+    let respond = |response| response_handler(request_id, response);
+    let (user, command) = split_auth(line);
+    let parsed = tracing::info_span!("parse").in_scope(|| {
+        split_options(command)
+            .and_then(|(options, command)| Ok((options, Request::parse(command)?)))
+    });
+    let (options, request) = match parsed {
+        Ok(parsed) => {
+            capture_request_log(
+                LogLevel::INFO,
+                socket_addr,
+                format!("[{}] {}", request_id, users::redact(line)),
+                Some(size_of_val(&*line)),
+            );
+
+            parsed
+        }
+        Err(e) => {
+            capture_request_log(
+                LogLevel::ERROR,
+                socket_addr,
+                format!("[{}] {}", request_id, users::redact(line)),
+                Some(size_of_val(&*line)),
+            );
+
+            return respond(Response::Error {
+                exit_code: ErrorCode::ParseError as u8,
+                error: e,
+            });
+        }
+    };
+
+    let encoding = options.encoding;
+    let _write_concern = write_concern::scoped(options.write_concern);
+    if let Err(e) = authorize(&request, user, flags) {
+        return respond(Response::Error {
+            exit_code: ErrorCode::Unauthorized as u8,
+            error: format!("Error authorizing request: {}", e),
+        });
+    }
+
+    if let Some(seq) = options.after {
+        if let Err(e) = consistency::wait_for(seq) {
+            return respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error waiting for commit: {}", e),
+            });
+        }
+    }
+
+    match request {
+        Request::Create { pile, id, data } => match create(&pile, id.as_deref(), &data, encoding) {
+            Ok(generated_uuid) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(generated_uuid),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error creating database entry: {}", e),
+            }),
+        },
+        Request::Ping {} => respond(Response::Ok {
+            exit_code: 0,
+            message: None,
+        }),
+        Request::Find {
+            pile,
+            field,
+            compare,
+            joins,
+            case_insensitive,
+            with_meta,
+        } => match find(
+            &pile,
+            &field,
+            &compare,
+            &joins,
+            case_insensitive,
+            with_meta,
+            encoding,
+        ) {
+            Ok(encoded_json_data) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(encoded_json_data),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error finding database entry: {}", e),
+            }),
+        },
+        Request::Export { pile } => match export(&pile, encoding) {
+            Ok(encoded_jsonl_data) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(encoded_jsonl_data),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error exporting pile: {}", e),
+            }),
+        },
+        Request::CopyPile {
+            source_pile,
+            pile,
+            predicate,
+        } => match copy_pile(&source_pile, &pile, predicate.as_ref()) {
+            Ok(copied_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(copied_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error copying pile: {}", e),
+            }),
+        },
+        Request::ExportSqlite { pile, file_name } => match export_sqlite(&pile, &file_name) {
+            Ok(exported_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(exported_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error exporting pile to SQLite: {}", e),
+            }),
+        },
+        Request::Archive { pile, file_name } => match archive::archive(&pile, &file_name) {
+            Ok(archived_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(archived_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error archiving pile: {}", e),
+            }),
+        },
+        Request::Unarchive { file_name, pile } => {
+            match archive::unarchive(&file_name, pile.as_deref()) {
+                Ok(loaded_count) => respond(Response::Ok {
+                    exit_code: 0,
+                    message: Some(loaded_count.to_string()),
+                }),
+                Err(e) => respond(Response::Error {
+                    exit_code: ErrorCode::of(&e) as u8,
+                    error: format!("Error loading pile archive: {}", e),
+                }),
+            }
+        }
+        Request::Count { pile, predicate } => match count(&pile, predicate.as_ref()) {
+            Ok(matched_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(matched_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error counting documents: {}", e),
+            }),
+        },
+        Request::Sample { pile, sample_size } => match sample(&pile, sample_size, encoding) {
+            Ok(encoded_jsonl_data) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(encoded_jsonl_data),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error sampling pile: {}", e),
+            }),
+        },
+        Request::Scan {
+            pile,
+            cursor,
+            predicate,
+        } => match scan_pile(&pile, cursor.as_deref(), predicate.as_ref(), encoding) {
+            Ok(batch) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(batch),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error scanning pile: {}", e),
+            }),
+        },
+        Request::Publish {
+            channel, payload, ..
+        } => match channels::publish(&channel, &payload, encoding) {
+            Ok(subscriber_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(subscriber_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error publishing to channel: {}", e),
+            }),
+        },
+        // The connection itself streams the messages, see `subscribe`
+        Request::Subscribe { channel, .. } => match channels::check_channel_name(&channel) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error subscribing to channel: {}", e),
+            }),
+        },
+        // The connection itself streams the documents, see `watch`
+        Request::Watch { pile } => match pile::pile_path(&pile) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error watching pile: {}", e),
+            }),
+        },
+        Request::Traverse {
+            pile,
+            uuid,
+            field,
+            depth,
+        } => match traverse::traverse(&pile, &uuid, &field, depth, encoding) {
+            Ok(encoded_jsonl_data) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(encoded_jsonl_data),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error traversing references: {}", e),
+            }),
+        },
+        Request::JobSubmit { spec } => match jobs::submit(spec) {
+            Ok(job_id) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(job_id),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error submitting job: {}", e),
+            }),
+        },
+        Request::JobStatus { job_id } => match jobs::status(&job_id) {
+            Ok(status) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(status.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error getting job status: {}", e),
+            }),
+        },
+        Request::Migrate {
+            pile,
+            step,
+            dry_run: true,
+            ..
+        } => match migrations::dry_run(&pile, &step) {
+            Ok(report) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(report.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error trying migration: {}", e),
+            }),
+        },
+        Request::Migrate {
+            pile,
+            name,
+            step,
+            dry_run: false,
+        } => match migrations::start(&pile, &name, step) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error starting migration: {}", e),
+            }),
+        },
+        Request::Migrations { pile } => match migrations::history(&pile) {
+            Ok(history) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(history.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error getting migrations: {}", e),
+            }),
+        },
+        Request::CreateMany { pile, data } => match create_many(&pile, &data, encoding) {
+            Ok(generated_uuids) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(json!(generated_uuids).to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error creating database entries: {}", e),
+            }),
+        },
+        Request::Import { pile, data } => match import(&pile, &data, encoding) {
+            Ok(imported_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(imported_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error importing into pile: {}", e),
+            }),
+        },
+        Request::ImportCsv {
+            pile,
+            data,
+            type_hints,
+        } => match import_csv(&pile, &data, type_hints.as_deref(), encoding) {
+            Ok(imported_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(imported_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error importing CSV into pile: {}", e),
+            }),
+        },
+        Request::ImportMongo { pile, data } => match import_mongo(&pile, &data, encoding) {
+            Ok(imported_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(imported_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error importing MongoDB export into pile: {}", e),
+            }),
+        },
+        Request::Backup { incremental } => {
+            let backup_result = match incremental {
+                true => backup::create_incremental(),
+                false => backup::create_full(),
+            };
+
+            match backup_result {
+                Ok(backup_name) => respond(Response::Ok {
+                    exit_code: 0,
+                    message: Some(backup_name),
+                }),
+                Err(e) => respond(Response::Error {
+                    exit_code: ErrorCode::of(&e) as u8,
+                    error: format!("Error creating backup: {}", e),
+                }),
+            }
+        }
+        Request::SchemaSet { pile, schema } => match set_schema(&pile, &schema, encoding) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile schema: {}", e),
+            }),
+        },
+        Request::Describe { pile } => match describe(&pile) {
+            Ok(manifest) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(manifest.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error describing pile: {}", e),
+            }),
+        },
+        Request::DescriptionSet { pile, description } => {
+            match set_description(&pile, &description) {
+                Ok(_) => respond(Response::Ok {
+                    exit_code: 0,
+                    message: None,
+                }),
+                Err(e) => respond(Response::Error {
+                    exit_code: ErrorCode::of(&e) as u8,
+                    error: format!("Error setting pile description: {}", e),
+                }),
+            }
+        }
+        Request::SchemaGet { pile } => match get_schema(&pile, encoding) {
+            Ok(encoded_schema) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(encoded_schema),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error getting pile schema: {}", e),
+            }),
+        },
+        Request::DefaultsSet { pile, defaults } => match set_defaults(&pile, &defaults, encoding) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile defaults: {}", e),
+            }),
+        },
+        Request::DefaultsGet { pile } => match get_defaults(&pile, encoding) {
+            Ok(encoded_defaults) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(encoded_defaults),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error getting pile defaults: {}", e),
+            }),
+        },
+        Request::Timestamps { pile, enabled } => match set_timestamps(&pile, enabled) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile timestamps: {}", e),
+            }),
+        },
+        Request::AppendOnly { pile } => match set_append_only(&pile) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error making pile append-only: {}", e),
+            }),
+        },
+        Request::Ids { pile, scheme } => match set_id_scheme(&pile, scheme) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile ID scheme: {}", e),
+            }),
+        },
+        Request::MaxSize { pile, max_bytes } => match set_max_document_bytes(&pile, max_bytes) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile maximum document size: {}", e),
+            }),
+        },
+        Request::Delete { pile, uuid } => match delete(&pile, &uuid) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error deleting database entry: {}", e),
+            }),
+        },
+        Request::DeleteWhere {
+            pile,
+            predicate,
+            dry_run: true,
+            ..
+        } => match matching_ids(&pile, &predicate) {
+            Ok(matched_uuids) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(json!(matched_uuids).to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error finding database entries: {}", e),
+            }),
+        },
+        Request::DeleteWhere {
+            pile,
+            predicate,
+            predicate_source,
+            dry_run: false,
+            token,
+        } => match delete_where(&pile, &predicate, &predicate_source, token.as_deref()) {
+            Ok(deleted_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(deleted_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error deleting database entries: {}", e),
+            }),
+        },
+        Request::Update {
+            pile,
+            uuid,
+            data,
+            condition,
+        } => match update(&pile, &uuid, &data, condition.as_ref(), encoding) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error updating database entry: {}", e),
+            }),
+        },
+        Request::Patch {
+            pile,
+            uuid,
+            patch,
+            condition,
+        } => match patch_document(&pile, &uuid, &patch, condition.as_ref(), encoding) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error patching database entry: {}", e),
+            }),
+        },
+        Request::FindAndModify {
+            pile,
+            predicate,
+            action,
+            return_new,
+        } => match find_and_modify(&pile, &predicate, &action, return_new, encoding) {
+            Ok(encoded_data) => respond(Response::Ok {
+                exit_code: 0,
+                message: encoded_data,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error modifying database entry: {}", e),
+            }),
+        },
+        Request::Incr {
+            pile,
+            uuid,
+            field,
+            delta,
+        } => match incr(&pile, &uuid, &field, &delta) {
+            Ok(new_value) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(new_value.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error incrementing field: {}", e),
+            }),
+        },
+        Request::Expire {
+            pile,
+            uuid,
+            seconds,
+        } => match ttl::expire(&pile, &uuid, seconds) {
+            Ok(expires_at) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(expires_at),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting document expiry: {}", e),
+            }),
+        },
+        Request::Persist { pile, uuid } => match ttl::persist(&pile, &uuid) {
+            Ok(persisted_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(persisted_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error removing document expiry: {}", e),
+            }),
+        },
+        Request::ArrayUpdate {
+            pile,
+            uuid,
+            field,
+            op,
+            value,
+        } => match update_array(&pile, &uuid, &field, op, &value, encoding) {
+            Ok(array_length) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(array_length.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error updating array field: {}", e),
+            }),
+        },
+        Request::UpdateWhere {
+            pile,
+            predicate,
+            patch,
+            dry_run: true,
+        } => match update_where_dry_run(&pile, &predicate, &patch, encoding) {
+            Ok(changed_uuids) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(json!(changed_uuids).to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error finding database entries: {}", e),
+            }),
+        },
+        Request::UpdateWhere {
+            pile,
+            predicate,
+            patch,
+            dry_run: false,
+        } => match update_where(&pile, &predicate, &patch, encoding) {
+            Ok(updated_count) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(updated_count.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error updating database entries: {}", e),
+            }),
+        },
+        Request::Restore { pile, uuid } => match restore_document(&pile, &uuid) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error restoring database entry: {}", e),
+            }),
+        },
+        Request::Undelete { pile, uuid } => match trash::undelete(&pile, &uuid) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error undeleting database entry: {}", e),
+            }),
+        },
+        Request::SoftDelete {
+            pile,
+            enabled,
+            purge_after_days,
+        } => match set_soft_delete(&pile, enabled, purge_after_days) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile soft delete: {}", e),
+            }),
+        },
+        Request::Capped {
+            pile,
+            max_documents,
+            max_bytes,
+        } => match set_capped(&pile, max_documents, max_bytes) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error setting pile cap: {}", e),
+            }),
+        },
+        Request::Unique { pile, field } => match add_unique_field(&pile, &field) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding unique constraint: {}", e),
+            }),
+        },
+        Request::Reference {
+            pile,
+            field,
+            target_pile,
+            on_delete,
+        } => match add_reference(&pile, &field, &target_pile, on_delete) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding reference: {}", e),
+            }),
+        },
+        Request::Trigger { pile, trigger } => match add_trigger(&pile, trigger) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding trigger: {}", e),
+            }),
+        },
+        Request::WebhookAdd { pile, webhook } => match webhooks::add(&pile, webhook) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding webhook: {}", e),
+            }),
+        },
+        Request::WebhookRemove { pile, url } => match webhooks::remove(&pile, &url) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error removing webhook: {}", e),
+            }),
+        },
+        Request::SinkAdd { pile, sink } => match sinks::add(&pile, sink) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding sink: {}", e),
+            }),
+        },
+        Request::SinkRemove { pile, url, subject } => match sinks::remove(&pile, &url, &subject) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error removing sink: {}", e),
+            }),
+        },
+        Request::Explain { query } => match explain(&query) {
+            Ok(plan) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(plan.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error explaining query: {}", e),
+            }),
+        },
+        Request::Prepare { name, template, .. } => match prepared::register(&name, &template) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::InvalidInput as u8,
+                error: format!("Error preparing query: {}", e),
+            }),
+        },
+        Request::Stats {} => respond(Response::Ok {
+            exit_code: 0,
+            message: Some(stats().to_string()),
+        }),
+        Request::PileStats { pile } => match stats::get(&pile) {
+            Ok(pile_stats) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(pile_stats.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error reading pile statistics: {}", e),
+            }),
+        },
+        Request::SetLogLevel { level } => {
+            logging::set_level(level);
+            respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            })
+        }
+        Request::Info {} => respond(Response::Ok {
+            exit_code: 0,
+            message: Some(info().to_string()),
+        }),
+        Request::Cleanup {} => match janitor::cleanup() {
+            Ok(report) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(report.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error cleaning up storage: {}", e),
+            }),
+        },
+        Request::Repair { pile } => match quarantine::repair(&pile) {
+            Ok(report) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(report.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error repairing pile: {}", e),
+            }),
+        },
+        Request::Fsck { pile, repair } => match fsck::check(pile.as_deref(), repair) {
+            Ok(report) => respond(Response::Ok {
+                exit_code: 0,
+                message: Some(report.to_string()),
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error checking storage: {}", e),
+            }),
+        },
+        Request::Bloom { pile, field } => match add_bloom_field(&pile, &field) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding bloom filter: {}", e),
+            }),
+        },
+        Request::Ordered { pile, field } => match add_ordered_field(&pile, &field) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error adding ordered index: {}", e),
+            }),
+        },
+        Request::CreateUser { name, password } => match users::create(&name, &password) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error creating user: {}", e),
+            }),
+        },
+        Request::Grant { user, pile, right } => match users::grant(&user, &pile, right) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error granting right: {}", e),
+            }),
+        },
+        Request::Revoke { user, pile } => match users::revoke(&user, &pile) {
+            Ok(_) => respond(Response::Ok {
+                exit_code: 0,
+                message: None,
+            }),
+            Err(e) => respond(Response::Error {
+                exit_code: ErrorCode::of(&e) as u8,
+                error: format!("Error revoking right: {}", e),
+            }),
+        },
+    }
+}
+
+/// Splits the optional `AUTH <user> <password>` prefix off a request line,
+/// returning the credentials (if any) and the command itself
+fn split_auth(line: &str) -> (Option<(&str, &str)>, &str) {
+    let mut parts = line.splitn(4, ' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("AUTH"), Some(name), Some(password), command) => {
+            (Some((name, password)), command.unwrap_or_default())
+        }
+        _ => (None, line),
+    }
+}
+
+/// Checks that the request's credentials grant what the request needs, and
+/// that the listener it came in on allows it. Until the first user is
+/// created, the server is open to everyone.
+fn authorize(
+    request: &Request,
+    credentials: Option<(&str, &str)>,
+    flags: ListenerFlags,
+) -> Result<(), io::Error> {
+    let mut required_rights = request.required_rights();
+
+    // Where a traversal leads depends on the piles' references
+    let reachable_piles = match *request {
+        Request::Traverse {
+            ref pile,
+            ref field,
+            depth,
+            ..
+        } => traverse::reachable_piles(pile, field, depth)?,
+        _ => Vec::new(),
+    };
+    required_rights.extend(
+        reachable_piles
+            .iter()
+            .map(|pile_name| (pile_name.as_str(), Right::Read)),
+    );
+
+    authorize_rights(required_rights, credentials, flags)
+}
+
+/// Checks that the credentials grant the rights, as `authorize` does for a
+/// request
+fn authorize_rights(
+    mut required_rights: Vec<(&str, Right)>,
+    credentials: Option<(&str, &str)>,
+    flags: ListenerFlags,
+) -> Result<(), io::Error> {
+    if flags.read_only
+        && required_rights
+            .iter()
+            .any(|(_, right)| *right > Right::Read)
+    {
+        let e_kind = io::ErrorKind::PermissionDenied;
+        let e = "This listener is read-only".to_owned();
+        return Err(io::Error::new(e_kind, e));
+    }
+    if flags.admin_only {
+        required_rights.push((ALL_PILES, Right::Admin));
+    }
+
+    if required_rights.is_empty() {
+        return Ok(());
+    }
+
+    // System piles (e.g. `.users`) are only ever touched by the server itself
+    if let Some((pile_name, _)) = required_rights
+        .iter()
+        .find(|(pile_name, _)| pile_name.starts_with('.'))
+    {
+        let e_kind = io::ErrorKind::PermissionDenied;
+        let e = format!("Pile \"{}\" is reserved", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    if !users::any_exist()? {
+        return Ok(());
+    }
+
+    let user = match credentials {
+        Some((name, password)) => users::authenticate(name, password)?,
+        None => {
+            let e_kind = io::ErrorKind::PermissionDenied;
+            let e = "Authentication required".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    match required_rights
+        .iter()
+        .find(|(pile_name, right)| !user.has_right(pile_name, *right))
+    {
+        None => Ok(()),
+        Some((pile_name, _)) => {
+            let e_kind = io::ErrorKind::PermissionDenied;
+            let e = format!(
+                "User \"{}\" may not do this on \"{}\"",
+                user.name, pile_name
+            );
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+fn response_handler(request_id: &str, response: Response) -> Response {
+    match response {
+        Response::Ok { .. } if !logging::enabled(logging::Level::Info) => response,
+        Response::Ok {
+            ref exit_code,
+            ref message,
+        } => {
+            let log = DBResponseLog {
+                timestamp: Utc::now(),
+                log_level: LogLevel::INFO,
+                exit_code: exit_code.clone(),
+                message: Some(format!(
+                    "[{}] {}",
+                    request_id,
+                    message.as_deref().unwrap_or_default()
+                )),
+            };
+
+            match write_to_log(log.as_log_str(), log.get_log_distinction()) {
+                Ok(_) => (),
+                Err(e) => eprintln!("{:?}", e),
+            };
+
+            response
+        }
+        Response::Error {
+            ref exit_code,
+            ref error,
+        } => {
+            let log = DBResponseLog {
+                timestamp: Utc::now(),
+                log_level: LogLevel::ERROR,
+                exit_code: exit_code.clone(),
+                message: Some(format!("[{}] {}", request_id, error)),
+            };
+
+            match write_to_log(log.as_log_str(), log.get_log_distinction()) {
+                Ok(_) => (),
+                Err(e) => eprintln!("{:?}", e),
+            };
+
+            response
+        }
+    }
+}
+
+// Example:
+/// in: FIND users email matthew@saplink.io
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// The found document carries its UUID as the `_id` field (like EXPORT), so
+/// it can be addressed by DELETE and friends. The value is typed like in a
+/// predicate (see query.rs), so `FIND users age 18` finds the number 18 and
+/// fields of other types simply don't match. With `NOCASE` before the pile
+/// name, the value is compared case insensitively, e.g.
+/// `FIND NOCASE users email Matthew@Saplink.io`. `IN (<value>,...)` finds a
+/// document matching any of the values in the same pass, e.g.
+/// `FIND users status IN (active,trial,beta)`, and `CONTAINS <value>` one whose
+/// array field holds the value, e.g. `FIND posts tags CONTAINS rust`. Trailing
+/// JOIN clauses pull in matching documents of other piles (see join.rs).
+/// With `META` before the pile name (after `NOCASE`, if both), the document
+/// also carries its size, checksum, last modification and revision as
+/// `_meta` (see docmeta.rs). Recent results are served from a cache until one
+/// of their piles is written to (see results.rs).
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn find(
+    pile_name: &str,
+    field_name: &str,
+    compare_name: &str,
+    joins: &[join::Join],
+    case_insensitive: bool,
+    with_meta: bool,
+    encoding: Encoding,
+) -> Result<String, io::Error> {
+    // Results are cached as found, so one entry serves every encoding
+    let query = match case_insensitive {
+        true => format!("NOCASE {} {} {}", pile_name, field_name, compare_name),
+        false => format!("{} {} {}", pile_name, field_name, compare_name),
+    };
+    let cache_key = std::iter::once(query)
+        .chain(joins.iter().map(join::Join::describe))
+        .collect::<Vec<_>>()
+        .join(" JOIN ");
+    let json_content = match results::get(&cache_key) {
+        Some(json_content) => json_content,
+        None => {
+            let piles: Vec<String> = std::iter::once(pile_name.to_owned())
+                .chain(joins.iter().map(|join| join.pile.clone()))
+                .collect();
+            let generations = results::generations(&piles);
+            let json_content =
+                find_uncached(pile_name, field_name, compare_name, joins, case_insensitive)?;
+            results::insert(cache_key, piles, &generations, json_content.clone());
+            json_content
+        }
+    };
+
+    // The metadata is read fresh, as only the document itself is cached
+    match json_content {
+        Some(mut json_content) => {
+            if with_meta {
+                add_meta(pile_name, &mut json_content)?;
+            }
+            Ok(encoding.encode(&json_content.to_string()))
+        }
+        None => Ok(String::new()),
+    }
+}
+
+/// Adds the metadata of a document found by its `_id` as `_meta`
+fn add_meta(pile_name: &str, json_content: &mut Value) -> Result<(), io::Error> {
+    let uuid = match json_content.get("_id").and_then(Value::as_str) {
+        Some(uuid) => uuid.to_owned(),
+        None => return Ok(()),
+    };
+
+    let file_path = document_file_path(&pile_path(pile_name)?, &uuid);
+    let meta = docmeta::load(Path::new(&file_path))?;
+    if let Some(json_object) = json_content.as_object_mut() {
+        json_object.insert("_meta".to_owned(), meta);
+    }
+    Ok(())
+}
+
+fn find_uncached(
+    pile_name: &str,
+    field_name: &str,
+    compare_name: &str,
+    joins: &[join::Join],
+    case_insensitive: bool,
+) -> Result<Option<Value>, io::Error> {
+    let find_compare = parse_find_compare(compare_name)?;
+
+    // Bloom filters hold values as stored, so can't rule out other casings
+    let pile_meta = PileMeta::load(pile_name)?;
+    if !case_insensitive
+        && pile_meta
+            .bloom_fields()
+            .iter()
+            .any(|field| field == field_name)
+        && !might_contain_any(pile_name, field_name, find_compare.literals())?
+    {
+        return Ok(None);
+    }
+
+    // Bloom filters and ordered indexes alike hold values as stored
+    let indexed_paths = match find_compare.range().filter(|_| !case_insensitive) {
+        Some(range) => indexed_paths(
+            pile_name,
+            &pile_meta.ordered_fields(),
+            &[(field_name, range)],
+        )?,
+        None => None,
+    };
+    let file_paths = match indexed_paths {
+        Some(file_paths) => file_paths,
+        None => document_paths(pile_name)?,
+    };
+    let touched_paths = Mutex::new(Vec::new());
+    let matches = scan::scan_field(&file_paths, field_name, true, |file_path, value| {
+        if logging::enabled(logging::Level::Debug) {
+            touched_paths
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(file_path.display().to_string());
+        }
+
+        match find_compare.matches(&value?, case_insensitive) {
+            true => Some(file_path.to_path_buf()),
+            false => None,
+        }
+    })?;
+
+    logging::debug(|| {
+        let touched_paths = touched_paths
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        format!(
+            "FIND {} {} touched {} file(s): {}",
+            pile_name,
+            field_name,
+            touched_paths.len(),
+            touched_paths.join(", ")
+        )
+    });
+
+    // Only the match is fully parsed, to add its UUID as `_id` and anything
+    // joined onto it
+    if let Some((_, file_path)) = matches.into_iter().next() {
+        if let Some(mut json_content) = document_with_id(&file_path)? {
+            join::apply(joins, &mut json_content)?;
+            return Ok(Some(json_content));
+        }
+    }
+    // Do not want an error if pile doesn't exist, this was for testing only.
+    // If the pile doesn't exist, no data to return!
+    // else {
+    //     let e_kind = io::ErrorKind::NotFound;
+    //     let e = format!("Could not find pile: \"{}\"", pile_name).to_owned();
+    //     let error = io::Error::new(e_kind, e);
+    //     return Err(error);
+    // }
+    Ok(None)
+}
+
+/// The paths of the documents (in document order) that ordered indexes on
+/// the fields narrow a query down to, or `None` if no field has one
+fn indexed_paths(
+    pile_name: &str,
+    ordered_fields: &[String],
+    ranges: &[(&str, query::Range)],
+) -> Result<Option<Vec<PathBuf>>, io::Error> {
+    let mut candidates: Option<BTreeSet<String>> = None;
+    for (field_name, range) in ranges {
+        if !ordered_fields.iter().any(|field| field == field_name) {
+            continue;
+        }
+
+        let uuids: BTreeSet<String> = ordered::lookup(pile_name, field_name, range)?
+            .into_iter()
+            .collect();
+        candidates = Some(match candidates {
+            Some(candidates) => candidates.intersection(&uuids).cloned().collect(),
+            None => uuids,
+        });
+    }
+
+    let pile_path = pile_path(pile_name)?;
+    Ok(candidates.map(|uuids| {
+        uuids
+            .iter()
+            .map(|uuid| PathBuf::from(document_file_path(&pile_path, uuid)))
+            .collect()
+    }))
+}
+
+fn parse_find_compare(compare_name: &str) -> Result<query::FindCompare, io::Error> {
+    query::FindCompare::parse(compare_name).map_err(|e| {
+        let e_kind = io::ErrorKind::InvalidInput;
+        io::Error::new(e_kind, e)
+    })
+}
+
+fn might_contain_any(
+    pile_name: &str,
+    field_name: &str,
+    literals: &[query::Literal],
+) -> Result<bool, io::Error> {
+    for literal in literals {
+        if bloom::might_contain(pile_name, field_name, literal.text())? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Example:
+/// in: EXPLAIN FIND users email matthew@saplink.io
+/// out: {"pile":"users","predicate":{"field":"email","equals":"matthew@saplink.io"},"joins":[],"index":"bloom","ruled_out":false,"estimated_documents":1204}
+///
+/// Reports how FIND would go about the query without running it: the query as
+/// parsed, the index it consults (the pile's ordered index or bloom filter on
+/// the field, if any), whether the bloom filter already rules out every
+/// document, and how many documents the scan reads at most (those the ordered
+/// index narrows it down to, or else the pile's document count, see
+/// stats.rs). FIND stops at its first match, so it usually reads fewer.
+fn explain(query: &Request) -> Result<Value, io::Error> {
+    let (pile_name, field_name, compare_name, joins, case_insensitive) = match *query {
+        Request::Find {
+            ref pile,
+            ref field,
+            ref compare,
+            ref joins,
+            case_insensitive,
+            ..
+        } => (pile, field, compare, joins, case_insensitive),
+        _ => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = "Only FIND can be explained".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    let has_bloom_filter = !case_insensitive
+        && pile_meta
+            .bloom_fields()
+            .iter()
+            .any(|field| field == field_name);
+    let find_compare = parse_find_compare(compare_name)?;
+    let ruled_out =
+        has_bloom_filter && !might_contain_any(pile_name, field_name, find_compare.literals())?;
+
+    let ordered_fields = pile_meta.ordered_fields();
+    let indexed_paths = match find_compare.range().filter(|_| !case_insensitive) {
+        Some(range) if !ruled_out => {
+            indexed_paths(pile_name, &ordered_fields, &[(field_name, range)])?
+        }
+        _ => None,
+    };
+    let index = match indexed_paths {
+        Some(_) => Some("ordered"),
+        None => has_bloom_filter.then_some("bloom"),
+    };
+
+    let estimated_documents = match (ruled_out, indexed_paths) {
+        (true, _) => 0,
+        (false, Some(indexed_paths)) => indexed_paths.len() as u64,
+        (false, None) => stats::get(pile_name)?
+            .get("documents")
+            .and_then(Value::as_u64)
+            .unwrap_or(0),
+    };
+
+    Ok(json!({
+        "pile": pile_name,
+        "predicate": match find_compare {
+            query::FindCompare::Equals(_) => json!({
+                "field": field_name,
+                "equals": compare_name,
+                "case_insensitive": case_insensitive,
+            }),
+            query::FindCompare::In(ref literals) => json!({
+                "field": field_name,
+                "in": literals.iter().map(query::Literal::text).collect::<Vec<_>>(),
+                "case_insensitive": case_insensitive,
+            }),
+            query::FindCompare::Contains(ref literal) => json!({
+                "field": field_name,
+                "contains": literal.text(),
+                "case_insensitive": case_insensitive,
+            }),
+        },
+        "joins": joins.iter().map(|join| &join.pile).collect::<Vec<_>>(),
+        "index": index,
+        "ruled_out": ruled_out,
+        "estimated_documents": estimated_documents,
+    }))
+}
+
+/// Example:
+/// in: CREATE users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: cd8abd45-ad36-4cf6-a520-c1c5d0671d96
+///
+/// in: CREATE users ID matthew 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: matthew
+///
+/// With `ID`, the document is stored under the client's own ID instead of a
+/// generated one. Creating an ID that is already taken fails, so a retried
+/// CREATE can never store the same document twice.
+///
+/// NOTE: We are writing the PLAIN TEXT DATA to the file! This makes it easier
+/// for future viewing via filesystem/other ops. This is a security trade-off:
+/// the logic here is that if a potential, bad actor already has access to the
+/// filesystem, then the data being encoded as plaintext vs. hex does not really
+/// make a difference in the grand scheme of security. :)
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn create(
+    pile_name: &str,
+    id: Option<&str>,
+    data: &str,
+    encoding: Encoding,
+) -> Result<String, io::Error> {
+    // STEP 1: Check the client's own ID, if any
+    let document_id = match id {
+        Some(id) if is_valid_document_id(id) => DocumentId::New(id),
+        Some(id) => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Invalid document ID: \"{}\"", id);
+            return Err(io::Error::new(e_kind, e));
+        }
+        None => DocumentId::Generated,
+    };
+
+    // STEP 2: Decode the data back into plaintext (from hex, unless sent as
+    // plain JSON)
+    let decoded_data_result = match encoding.decode(data) {
+        Ok(utf8_string) => Ok(utf8_string),
+        Err(e) => Err(e),
+    }?;
+
+    // STEP 3: Store the decoded data in the pile under the client's ID or a
+    // freshly generated, collision checked UUID to be used for future ops
+    let uuid = store_document(pile_name, document_id, &decoded_data_result)?;
+
+    Ok(uuid)
+}
+
+/// Example:
+/// in: CREATEMANY users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: ["cd8abd45-ad36-4cf6-a520-c1c5d0671d96","0b7e5c1a-8d2f-4b53-9a4e-6f1d2c3b4a59"]
+///
+/// The decoded data is a JSON array of documents, stored under generated IDs
+/// in one go: if any document is rejected (e.g. by the pile's schema),
+/// nothing is stored. The IDs come back in the order of the documents.
+fn create_many(pile_name: &str, data: &str, encoding: Encoding) -> Result<Vec<String>, io::Error> {
+    let documents = match from_str(&encoding.decode(data)?)? {
+        Value::Array(documents) => documents,
+        _ => {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = "Expected a JSON array of documents".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let documents: Vec<String> = documents.iter().map(Value::to_string).collect();
+    store_documents(pile_name, &documents)
+}
+
+/// Example:
+/// in: EXPORT users
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// The decoded output is JSONL: every document in the pile on its own line,
+/// with its UUID added as the `_id` field so the export can be re-imported
+/// into another environment without losing document identity.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn export(pile_name: &str, encoding: Encoding) -> Result<String, io::Error> {
+    let mut jsonl_lines: Vec<String> = Vec::new();
+    for file_path in document_paths(pile_name)? {
+        if let Some(json_content) = document_with_id(&file_path)? {
+            jsonl_lines.push(json_content.to_string());
+        }
+    }
+
+    Ok(encoding.encode_jsonl(&jsonl_lines.join("\n")))
+}
+
+/// Example:
+/// in: COPY users users_staging WHERE country = NL
+/// out: 318
+///
+/// Clones the pile into a new one on the server: its documents (only those
+/// matching the predicate, if given) under the same IDs, and its settings,
+/// indexes included (built on first use). Triggers aren't copied, so writes
+/// to the copy don't reach the piles the original's triggers write to, nor
+/// are statistics or the migration history. Returns how many documents were
+/// copied; the target pile must not exist yet.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn copy_pile(
+    source_pile_name: &str,
+    pile_name: &str,
+    predicate: Option<&query::Predicate>,
+) -> Result<usize, io::Error> {
+    const COPY_BATCH_SIZE: usize = 1000;
+
+    let source_path = pile_path(source_pile_name)?;
+    let target_path = pile_path(pile_name)?;
+    if !Path::new(&source_path).is_dir() {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Could not find pile: \"{}\"", source_pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+    if Path::new(&target_path).exists() {
+        let e_kind = io::ErrorKind::AlreadyExists;
+        let e = format!("Pile \"{}\" already exists", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let source_meta = PileMeta::load(source_pile_name)?;
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    for key in source_meta.keys() {
+        if !["created_at", "stats", "migrations", "triggers"].contains(&key.as_str()) {
+            pile_meta.set(&key, source_meta.get(&key).cloned().unwrap_or(Value::Null));
+        }
+    }
+    pile_meta.save()?;
+
+    let uuids: Vec<String> = match predicate {
+        Some(predicate) => matching_ids(source_pile_name, predicate)?,
+        None => document_paths(source_pile_name)?
+            .iter()
+            .filter_map(|file_path| file_path.file_stem()?.to_str().map(str::to_owned))
+            .collect(),
+    };
+
+    // Written like a replayed WAL, so the copy survives a crash like any write
+    let mut copied_count = 0;
+    for batch in uuids.chunks(COPY_BATCH_SIZE) {
+        let mut wal_entries = Vec::new();
+        for uuid in batch {
+            match fs::read_to_string(document_file_path(&source_path, uuid)) {
+                Ok(data) => wal_entries.push(WalEntry::new(WalOp::Create {
+                    pile: pile_name.to_owned(),
+                    uuid: uuid.clone(),
+                    data,
+                })),
+                // Deleted since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let _applying = wal::append_all(&wal_entries)?;
+        apply_wal_entries(&wal_entries)?;
+        copied_count += wal_entries.len();
+    }
+
+    Ok(copied_count)
+}
+
+/// Example:
+/// in: EXPORT users SQLITE users.db
+/// out: 1204
+///
+/// Writes every document in the pile into a new SQLite database (see
+/// sqlite.rs) in the directory `DUST_EXPORT_PATH` (default `exports`) on the
+/// server, and returns how many were written. The database has one table
+/// named after the pile, with a row per document: its UUID as `_id`, the
+/// document as JSON text as `_json`, and a column per field the pile has a
+/// bloom filter, ordered index or unique constraint on. Objects and arrays in
+/// those columns are JSON text, booleans are 0 or 1. As the file lands on the
+/// server, it takes admin rights over every pile.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn export_sqlite(pile_name: &str, file_name: &str) -> Result<usize, io::Error> {
+    if !is_valid_document_id(file_name) {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid export file name: \"{}\"", file_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let export_dir = PathBuf::from(env_or("DUST_EXPORT_PATH", "exports".to_owned()));
+    let file_path = export_dir.join(file_name);
+    if file_path.exists() {
+        let e_kind = io::ErrorKind::AlreadyExists;
+        let e = format!("Export file already exists: \"{}\"", file_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    let mut field_names: Vec<String> = [
+        pile_meta.bloom_fields(),
+        pile_meta.ordered_fields(),
+        pile_meta.unique_fields(),
+    ]
+    .concat();
+    field_names.sort();
+    field_names.dedup();
+    field_names.retain(|field_name| field_name != "_id" && field_name != "_json");
+
+    let mut rows = Vec::new();
+    for file_path in document_paths(pile_name)? {
+        let uuid = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(uuid) => uuid.to_owned(),
+            None => continue,
+        };
+        let json_content = &cache::read_document(&file_path)?.json;
+
+        let mut row = vec![
+            sqlite::SqlValue::Text(uuid),
+            sqlite::SqlValue::Text(json_content.to_string()),
+        ];
+        for field_name in &field_names {
+            row.push(match json_content.get(field_name) {
+                None | Some(Value::Null) => sqlite::SqlValue::Null,
+                Some(Value::Bool(boolean)) => sqlite::SqlValue::Integer(*boolean as i64),
+                Some(Value::Number(number)) => match number.as_i64() {
+                    Some(integer) => sqlite::SqlValue::Integer(integer),
+                    None => sqlite::SqlValue::Real(number.as_f64().unwrap_or_default()),
+                },
+                Some(Value::String(string)) => sqlite::SqlValue::Text(string.clone()),
+                Some(value) => sqlite::SqlValue::Text(value.to_string()),
+            });
+        }
+        rows.push(row);
+    }
+
+    let column_names: Vec<String> = ["_id".to_owned(), "_json".to_owned()]
+        .into_iter()
+        .chain(field_names)
+        .collect();
+
+    // Written aside and renamed into place, so a half-written database is
+    // never mistaken for an export
+    fs::create_dir_all(&export_dir)?;
+    let tmp_path = export_dir.join(format!(".{}.tmp", file_name));
+    sqlite::write_table(&tmp_path, pile_name, &column_names, &rows)?;
+    fs::rename(tmp_path, file_path)?;
+
+    Ok(rows.len())
+}
+
+/// Example:
+/// in: COUNT users status active AND age >= 18
+/// out: 42
+///
+/// Without a predicate (see query.rs) every document is counted. Only the
+/// fields the predicate looks at are read from each document, and a pile
+/// whose bloom filter rules out a required equality isn't read at all. An
+/// ordered index on a field the predicate bounds narrows the documents read
+/// down to those within the bounds.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn count(pile_name: &str, predicate: Option<&query::Predicate>) -> Result<usize, io::Error> {
+    match predicate {
+        Some(predicate) => Ok(matching_ids(pile_name, predicate)?.len()),
+        None => Ok(document_paths(pile_name)?.len()),
+    }
+}
+
+/// The IDs of the documents matching the predicate, in document order
+fn matching_ids(pile_name: &str, predicate: &query::Predicate) -> Result<Vec<String>, io::Error> {
+    let pile_meta = PileMeta::load(pile_name)?;
+    let bloom_fields = pile_meta.bloom_fields();
+    for (field_name, value) in predicate.required_equalities() {
+        if bloom_fields.iter().any(|field| field == field_name)
+            && !bloom::might_contain(pile_name, field_name, value)?
+        {
+            return Ok(Vec::new());
+        }
+    }
+
+    let ranges = predicate.required_ranges();
+    let file_paths = match indexed_paths(pile_name, &pile_meta.ordered_fields(), &ranges)? {
+        Some(file_paths) => file_paths,
+        None => document_paths(pile_name)?,
+    };
+
+    let matches = scan::scan_fields(
+        &file_paths,
+        &predicate.fields(),
+        false,
+        |file_path, fields| match predicate.matches(&fields) {
+            true => file_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(str::to_owned),
+            false => None,
+        },
+    )?;
+
+    Ok(matches.into_iter().map(|(_, uuid)| uuid).collect())
+}
+
+/// Example:
+/// in: SAMPLE users 2
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// Returns that many documents picked uniformly at random (or the whole pile,
+/// if it is smaller), encoded like EXPORT. Only the picked documents are read;
+/// the rest of the pile is only listed.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn sample(pile_name: &str, sample_size: usize, encoding: Encoding) -> Result<String, io::Error> {
+    let file_paths = document_paths(pile_name)?;
+    let sample_size = sample_size.min(file_paths.len());
+
+    let mut jsonl_lines: Vec<String> = Vec::new();
+    for index in rand::seq::index::sample(&mut rand::thread_rng(), file_paths.len(), sample_size) {
+        match document_with_id(&file_paths[index]) {
+            Ok(Some(json_content)) => jsonl_lines.push(json_content.to_string()),
+            Ok(None) => (),
+            // Deleted since the pile was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(encoding.encode_jsonl(&jsonl_lines.join("\n")))
+}
+
+/// How many batches' worth of documents a filtered SCAN looks at, at most,
+/// before it returns what it found so far
+const MAX_SCAN_BATCHES_SEARCHED: usize = 100;
+
+/// Example:
+/// in: SCAN users 0
+/// out: 63643861626434352D616433362D346366362D613532302D633163356430363731643936 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// Returns the next cursor followed by a batch of at most
+/// `DUST_SCAN_BATCH_SIZE` (default 100) documents, encoded like EXPORT. Start
+/// without a cursor (or with `0`) and pass each returned cursor back until it
+/// comes back as `0`. Documents are visited in UUID order and the cursor is
+/// the last UUID looked at, so only one batch is ever read into memory and
+/// documents created or deleted mid-iteration don't shift the others: every
+/// document present for the whole iteration is returned exactly once.
+///
+/// in: SCAN users 0 WHERE age >= 18
+///
+/// With `WHERE` and a predicate (see query.rs) after the cursor, only the
+/// matching documents are returned, in batches the same way. A batch may then
+/// come back empty with a cursor other than `0`, when the rest of the pile
+/// was too far to search in one go.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn scan_pile(
+    pile_name: &str,
+    cursor: Option<&str>,
+    predicate: Option<&query::Predicate>,
+    encoding: Encoding,
+) -> Result<String, io::Error> {
+    let after_uuid = match cursor {
+        None | Some("0") => None,
+        Some(cursor) => match decode_hex_to_utf8(cursor) {
+            Ok(uuid) if is_valid_document_id(&uuid) => Some(uuid),
+            _ => {
+                let e_kind = io::ErrorKind::InvalidInput;
+                let e = format!("Invalid cursor: {}", cursor);
+                return Err(io::Error::new(e_kind, e));
+            }
+        },
+    };
+
+    let batch_size = env_or("DUST_SCAN_BATCH_SIZE", 100_usize).max(1);
+    let mut file_paths = document_paths(pile_name)?.into_iter().filter(|file_path| {
+        let uuid = file_path.file_stem().and_then(|stem| stem.to_str());
+        after_uuid
+            .as_deref()
+            .is_none_or(|after_uuid| uuid > Some(after_uuid))
+    });
+
+    let mut jsonl_lines: Vec<String> = Vec::new();
+    let mut last_uuid = None;
+    let mut scanned = 0;
+    for file_path in file_paths.by_ref() {
+        last_uuid = file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(str::to_owned);
+        scanned += 1;
+
+        // Documents deleted since the pile was listed are skipped
+        let json_content = match scanned_document(&file_path, predicate) {
+            Ok(json_content) => json_content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(json_content) = json_content {
+            jsonl_lines.push(json_content.to_string());
+        }
+
+        if jsonl_lines.len() >= batch_size || scanned >= batch_size * MAX_SCAN_BATCHES_SEARCHED {
+            break;
+        }
+    }
+
+    let next_cursor = match (file_paths.next(), last_uuid) {
+        (Some(_), Some(last_uuid)) => encode_utf8_to_hex(&last_uuid),
+        _ => "0".to_owned(),
+    };
+
+    Ok(format!(
+        "{} {}",
+        next_cursor,
+        encoding.encode_jsonl(&jsonl_lines.join("\n"))
+    ))
+}
+
+/// The document for a SCAN batch, if it matches the predicate (if any)
+fn scanned_document(
+    file_path: &Path,
+    predicate: Option<&query::Predicate>,
+) -> Result<Option<Value>, io::Error> {
+    if let Some(predicate) = predicate {
+        if !predicate.matches(&cache::probe_fields(file_path, &predicate.fields())?) {
+            return Ok(None);
+        }
+    }
+
+    document_with_id(file_path)
+}
+
+/// A document as returned to clients (e.g. a line of EXPORT output), with its
+/// UUID added as `_id`
+fn document_with_id(file_path: &Path) -> Result<Option<Value>, io::Error> {
+    let uuid = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(uuid) => uuid.to_owned(),
+        None => return Ok(None),
+    };
+
+    let mut json_content = cache::read_document(file_path)?.json.clone();
+    if let Some(json_object) = json_content.as_object_mut() {
+        json_object.insert("_id".to_owned(), Value::String(uuid));
+    }
+
+    Ok(Some(json_content))
+}
+
+/// Example:
+/// in: IMPORT users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: 2
+///
+/// The decoded input is JSONL, one document per line. A document carrying a
+/// string `_id` field is stored under that id (the field itself is stripped,
+/// as the id lives in the file name), otherwise a fresh ID is generated
+/// following the pile's ID scheme.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn import(pile_name: &str, data: &str, encoding: Encoding) -> Result<usize, io::Error> {
+    let decoded_data = encoding.decode_jsonl(data)?;
+
+    // Parse everything up front so a bad line doesn't leave a half-done import
+    let mut documents: Vec<(Option<String>, String)> = Vec::new();
+    for (line_number, line) in decoded_data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut json_content: Value = from_str(line)?;
+        let uuid = match json_content
+            .as_object_mut()
+            .and_then(|json_object| json_object.remove("_id"))
+        {
+            Some(Value::String(uuid)) if is_valid_document_id(&uuid) => Some(uuid),
+            Some(_) => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Invalid \"_id\" on line {}", line_number + 1);
+                return Err(io::Error::new(e_kind, e));
+            }
+            None => None,
+        };
+
+        documents.push((uuid, json_content.to_string()));
+    }
+
+    for (uuid, data) in &documents {
+        let document_id = match uuid {
+            Some(uuid) => DocumentId::Replace(uuid),
+            None => DocumentId::Generated,
+        };
+        store_document(pile_name, document_id, data)?;
+    }
+
+    Ok(documents.len())
+}
+
+/// Example:
+/// in: IMPORTCSV users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0 age:int,born:date
+/// out: 2
+///
+/// The decoded input is CSV with a header row; every following row becomes a
+/// document with one field per header. The optional type hints pin a column to
+/// int, float, bool, date or string, other columns have their type inferred.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn import_csv(
+    pile_name: &str,
+    data: &str,
+    type_hints: Option<&str>,
+    encoding: Encoding,
+) -> Result<usize, io::Error> {
+    let decoded_data = encoding.decode_text(data)?;
+    let type_hints = csv::parse_type_hints(type_hints.unwrap_or_default())?;
+    let documents = csv::parse_documents(&decoded_data, &type_hints)?;
+
+    for document in &documents {
+        store_document(pile_name, DocumentId::Generated, &document.to_string())?;
+    }
+
+    Ok(documents.len())
+}
+
+/// Example:
+/// in: IMPORTMONGO users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: 2
+///
+/// The decoded input is `mongoexport` output, one document per line or a JSON
+/// array. Extended JSON types become plain JSON and an ObjectId `_id` becomes
+/// the document's ID (see mongo.rs), replacing any document stored under it,
+/// so importing the same export twice leaves one copy.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn import_mongo(pile_name: &str, data: &str, encoding: Encoding) -> Result<usize, io::Error> {
+    let decoded_data = encoding.decode_jsonl(data)?;
+
+    // Everything is converted up front so a bad document doesn't leave a
+    // half-done import
+    let documents = mongo::parse_documents(&decoded_data, is_valid_document_id)?;
+
+    for document in &documents {
+        let document_id = match document.id {
+            Some(ref uuid) => DocumentId::Replace(uuid),
+            None => DocumentId::Generated,
+        };
+        store_document(pile_name, document_id, &document.json.to_string())?;
+    }
+
+    Ok(documents.len())
+}
+
+/// Example:
+/// in: SCHEMA SET users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out:
+///
+/// Every document written to the pile afterwards must satisfy the schema.
+/// Documents already in the pile are left as they are.
+fn set_schema(pile_name: &str, schema: &str, encoding: Encoding) -> Result<(), io::Error> {
+    let pile_schema: Value = from_str(&encoding.decode(schema)?)?;
+    schema::check_schema(&pile_schema)?;
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    pile_meta.set("schema", pile_schema);
+    pile_meta.save()
+}
+
+/// Example:
+/// in: SCHEMA GET users
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+fn get_schema(pile_name: &str, encoding: Encoding) -> Result<String, io::Error> {
+    match PileMeta::load(pile_name)?.schema() {
+        Some(pile_schema) => Ok(encoding.encode(&pile_schema.to_string())),
+        None => Ok(String::new()),
+    }
+}
+
+/// Example:
+/// in: DESCRIBE users
+/// out: {"pile":"users","created_at":"2024-05-01T12:00:00.000Z","description":"Everyone who signed up","schema":true,"indexes":{"bloom":["email"],"ordered":["age"],"unique":["email"]},"id_scheme":"UUIDV4","storage":{"soft_delete":{"purge_after_days":30},"capped":null,"append_only":false,"max_document_bytes":null,"timestamps":true}}
+///
+/// Reports what the pile's manifest (see pile.rs) says about it: whether
+/// documents must satisfy a schema (SCHEMA GET returns it), the indexes and
+/// constraints declared on its fields, how IDs are generated, and how
+/// documents are stored.
+fn describe(pile_name: &str) -> Result<Value, io::Error> {
+    if !Path::new(&pile_path(pile_name)?).is_dir() {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Could not find pile: \"{}\"", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    let soft_delete = pile_meta
+        .soft_delete()
+        .map(|purge_after_days| json!({ "purge_after_days": purge_after_days }));
+
+    Ok(json!({
+        "pile": pile_name,
+        "created_at": pile_meta.get("created_at"),
+        "description": pile_meta.get("description"),
+        "schema": pile_meta.schema().is_some(),
+        "indexes": {
+            "bloom": pile_meta.bloom_fields(),
+            "ordered": pile_meta.ordered_fields(),
+            "unique": pile_meta.unique_fields(),
         },
-        Request::Ping {} => response_handler(Response::Ok {
-            exit_code: 0,
-            message: None,
-        }),
-        Request::Find {
-            pile,
-            field,
-            compare,
-        } => match find(&pile, &field, &compare) {
-            Ok(encoded_json_data) => response_handler(Response::Ok {
-                exit_code: 0,
-                message: Some(encoded_json_data),
+        "id_scheme": pile_meta.id_scheme().as_str(),
+        "storage": {
+            "soft_delete": soft_delete,
+            "capped": pile_meta.get("capped"),
+            "append_only": pile_meta.append_only(),
+            "max_document_bytes": pile_meta.max_document_bytes(),
+            "timestamps": pile_meta.timestamps(),
+        },
+    }))
+}
+
+/// Example:
+/// in: DESCRIPTION SET users Everyone who signed up
+/// out:
+fn set_description(pile_name: &str, description: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    pile_meta.set("description", json!(description));
+    pile_meta.save()
+}
+
+/// Example:
+/// in: DEFAULTS SET users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out:
+///
+/// The decoded defaults are a JSON object, e.g. {"status":"pending","score":0},
+/// whose fields are added to every new document that doesn't set them itself.
+fn set_defaults(pile_name: &str, defaults: &str, encoding: Encoding) -> Result<(), io::Error> {
+    let defaults: Value = from_str(&encoding.decode(defaults)?)?;
+    if !defaults.is_object() {
+        let e_kind = io::ErrorKind::InvalidData;
+        let e = "Defaults must be a JSON object".to_owned();
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    pile_meta.set("defaults", defaults);
+    pile_meta.save()
+}
+
+/// Example:
+/// in: DEFAULTS GET users
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+fn get_defaults(pile_name: &str, encoding: Encoding) -> Result<String, io::Error> {
+    match PileMeta::load(pile_name)?.defaults() {
+        Some(defaults) => Ok(encoding.encode(&Value::Object(defaults.clone()).to_string())),
+        None => Ok(String::new()),
+    }
+}
+
+/// Example:
+/// in: TIMESTAMPS users ON
+/// out:
+///
+/// With timestamps on, the server stamps `_created_at` and `_updated_at` onto
+/// every new document (overriding whatever the client sent), and refreshes
+/// `_updated_at` whenever a document is modified.
+fn set_timestamps(pile_name: &str, enabled: bool) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    pile_meta.set("timestamps", Value::Bool(enabled));
+    pile_meta.save()
+}
+
+/// Example:
+/// in: APPENDONLY audit
+/// out:
+///
+/// From now on documents can be added to the pile, but never updated (in
+/// whole or in part), replaced on import or deleted, by clients and the
+/// server alike (no expiry, migration or cap applies). Once set it can't be
+/// unset, so the pile's history can be trusted. A capped pile can't be made
+/// append-only, as its cap would have to delete documents.
+fn set_append_only(pile_name: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    if pile_meta
+        .get("capped")
+        .is_some_and(|capped| !capped.is_null())
+    {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Pile \"{}\" is capped", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    pile_meta.set("append_only", Value::Bool(true));
+    pile_meta.save()
+}
+
+/// Fails if the pile is append-only (see `set_append_only`). Every path that
+/// changes or removes a stored document checks this, so the rule holds no
+/// matter which command got there.
+fn check_mutable(pile_meta: &PileMeta, pile_name: &str) -> Result<(), io::Error> {
+    match pile_meta.append_only() {
+        true => {
+            let e_kind = io::ErrorKind::PermissionDenied;
+            let e = format!("Pile \"{}\" is append-only", pile_name);
+            Err(io::Error::new(e_kind, e))
+        }
+        false => Ok(()),
+    }
+}
+
+/// Example:
+/// in: IDS events ULID
+/// out:
+///
+/// Picks how IDs of new documents in the pile are generated (see ids.rs).
+/// Documents already in the pile keep their IDs.
+fn set_id_scheme(pile_name: &str, scheme: IdScheme) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    pile_meta.set("id_scheme", Value::from(scheme.as_str()));
+    pile_meta.save()
+}
+
+/// Example:
+/// in: MAXSIZE users 1048576
+/// out:
+///
+/// Caps the size of documents written to the pile, overriding the server wide
+/// `DUST_MAX_DOCUMENT_BYTES` (default 16 MiB); `DEFAULT` drops the override.
+/// Documents already in the pile are left alone.
+fn set_max_document_bytes(pile_name: &str, max_bytes: Option<usize>) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    pile_meta.set("max_document_bytes", Value::from(max_bytes));
+    pile_meta.save()
+}
+
+/// Example:
+/// in: DELETE users cd8abd45-ad36-4cf6-a520-c1c5d0671d96
+/// out:
+///
+/// In piles with soft delete on, the document is only tombstoned: it is hidden
+/// from every query but can be brought back with RESTORE until it is purged.
+/// Elsewhere it goes to the pile's recycle bin (see trash.rs), from which
+/// UNDELETE brings it back.
+fn delete(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    delete_if(pile_name, uuid, |_| true).map(|_| ())
+}
+
+/// Deletes the document like DELETE if `should_delete` accepts it as stored
+/// (None if it isn't valid JSON), telling whether it did. The cascade is
+/// worked out first, then each document's check and removal happen under its
+/// pile's lock (so a concurrent write can't undo them or be undone), and its
+/// triggers and notifications run once the lock is released, as they may
+/// write to the same pile.
+fn delete_if<F>(pile_name: &str, uuid: &str, should_delete: F) -> Result<bool, io::Error>
+where
+    F: FnOnce(Option<&Value>) -> bool,
+{
+    let pile_path = pile_path(pile_name)?;
+    if !is_valid_document_id(uuid) || !Path::new(&document_file_path(&pile_path, uuid)).is_file() {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Could not find document: \"{}\"", uuid);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    // Work out everything the delete cascades to before touching anything, so
+    // a RESTRICT further down the chain blocks the whole delete
+    let mut to_delete = vec![(pile_name.to_owned(), uuid.to_owned())];
+    let mut visited = HashSet::from([(pile_name.to_owned(), uuid.to_owned())]);
+    let mut index = 0;
+    while index < to_delete.len() {
+        let (ref target_pile, ref target_uuid) = to_delete[index].clone();
+        for (referencing_pile, referencing_uuid, on_delete) in
+            referencing_documents(target_pile, target_uuid)?
+        {
+            match on_delete {
+                OnDelete::Restrict => {
+                    let e_kind = io::ErrorKind::PermissionDenied;
+                    let e = format!(
+                        "Document {} in pile \"{}\" still references it",
+                        referencing_uuid, referencing_pile
+                    );
+                    return Err(io::Error::new(e_kind, e));
+                }
+                OnDelete::Cascade => {
+                    let key = (referencing_pile, referencing_uuid);
+                    if visited.insert(key.clone()) {
+                        to_delete.push(key);
+                    }
+                }
+                OnDelete::Ignore => (),
+            }
+        }
+        index += 1;
+    }
+
+    // An append-only pile anywhere in the cascade blocks the whole delete
+    for (pile_name, _) in &to_delete {
+        check_mutable(&PileMeta::load(pile_name)?, pile_name)?;
+    }
+
+    let mut should_delete = Some(should_delete);
+    for (pile_name, uuid) in to_delete {
+        let pile_meta = PileMeta::load(&pile_name)?;
+        let file_path = document_file_path(&pile::pile_path(&pile_name)?, &uuid);
+
+        let json_content = {
+            let pile_lock = pile::lock(&pile_name);
+            let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+            let json_content: Option<Value> = match fs::read_to_string(&file_path) {
+                Ok(file_content) => from_str(&file_content).ok(),
+                // Deleted since the check above, by another client or (for
+                // the documents cascaded to) an earlier step of the cascade
+                Err(e) if e.kind() == io::ErrorKind::NotFound => match should_delete {
+                    Some(_) => {
+                        let e_kind = io::ErrorKind::NotFound;
+                        let e = format!("Could not find document: \"{}\"", uuid);
+                        return Err(io::Error::new(e_kind, e));
+                    }
+                    None => continue,
+                },
+                Err(e) => return Err(e),
+            };
+            // Only the document asked for is checked, the first one
+            if let Some(should_delete) = should_delete.take() {
+                if !should_delete(json_content.as_ref()) {
+                    return Ok(false);
+                }
+            }
+
+            match pile_meta.soft_delete() {
+                Some(_) => tombstone_document(&pile_name, &uuid)?,
+                None => {
+                    trash::keep(&pile_name, &uuid)?;
+                    delete_document(&pile_name, &uuid)?
+                }
+            }
+            json_content
+        };
+
+        triggers::run(
+            &pile_meta.triggers(),
+            TriggerEvent::Delete,
+            &pile_name,
+            &uuid,
+            json_content.as_ref(),
+        );
+        notify_change(
+            &pile_meta,
+            WebhookEvent::Delete,
+            &pile_name,
+            &uuid,
+            json_content.as_ref(),
+        );
+    }
+
+    Ok(true)
+}
+
+/// Example:
+/// in: DELETEWHERE sessions expires_at < 2024-01-01
+/// out: 1204
+///
+/// Deletes every document matching the predicate (see query.rs), one at a time
+/// like DELETE, and returns how many were deleted. Stops at the first document
+/// that can't be deleted (e.g. still referenced with RESTRICT), leaving the
+/// documents before it deleted. With `DRYRUN` before the predicate, nothing is
+/// deleted and the IDs of the matching documents are returned instead:
+///
+/// in: DELETEWHERE sessions DRYRUN expires_at < 2024-01-01
+/// out: ["cd8abd45-ad36-4cf6-a520-c1c5d0671d96","0b7e5c1a-8d2f-4b53-9a4e-6f1d2c3b4a59"]
+///
+/// Deleting many documents takes a confirmation token (see confirm.rs).
+fn delete_where(
+    pile_name: &str,
+    predicate: &query::Predicate,
+    predicate_source: &str,
+    token: Option<&str>,
+) -> Result<usize, io::Error> {
+    let matched_uuids = matching_ids(pile_name, predicate)?;
+    confirm::check(
+        &format!("DELETEWHERE {} {}", pile_name, predicate_source),
+        matched_uuids.len(),
+        token,
+    )?;
+
+    for (deleted_count, uuid) in matched_uuids.iter().enumerate() {
+        match delete(pile_name, uuid) {
+            Ok(()) => (),
+            // Gone already, e.g. by a cascade from an earlier match
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                let e_kind = e.kind();
+                let e = format!("{} (after deleting {} documents)", e, deleted_count);
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+    }
+
+    Ok(matched_uuids.len())
+}
+
+/// Example:
+/// in: UPDATE orders cd8abd45-ad36-4cf6-a520-c1c5d0671d96 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0 IF status = pending
+/// out:
+///
+/// Replaces the stored document with the decoded data. With `IF` and a
+/// predicate (see query.rs), the update only applies if the document as stored
+/// matches it, and fails with a conflict otherwise; the check and the write
+/// happen under the pile's lock, so two clients can't both make the same
+/// transition (e.g. pending to shipped).
+fn update(
+    pile_name: &str,
+    uuid: &str,
+    data: &str,
+    condition: Option<&query::Predicate>,
+    encoding: Encoding,
+) -> Result<(), io::Error> {
+    let data: Value = from_str(&encoding.decode(data)?)?;
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    modify_document(&pile_meta, pile_name, uuid, |json_content| {
+        check_condition(condition, json_content)?;
+        *json_content = data;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Example:
+/// in: PATCH users cd8abd45-ad36-4cf6-a520-c1c5d0671d96 7B22656D61696C223A6E756C6C7D
+/// out:
+///
+/// Applies the decoded JSON merge patch (RFC 7386) to the stored document:
+/// fields in the patch are set, nested objects are merged, and fields set to
+/// null are removed. Only the patch travels over the wire, and the document is
+/// read and written back under the pile's lock. Takes an `IF` condition like
+/// UPDATE.
+fn patch_document(
+    pile_name: &str,
+    uuid: &str,
+    patch: &str,
+    condition: Option<&query::Predicate>,
+    encoding: Encoding,
+) -> Result<(), io::Error> {
+    let patch = decode_merge_patch(patch, encoding)?;
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    modify_document(&pile_meta, pile_name, uuid, |json_content| {
+        check_condition(condition, json_content)?;
+        merge_patch(json_content, &patch);
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Fails with a conflict unless the document matches the condition (if any)
+fn check_condition(
+    condition: Option<&query::Predicate>,
+    json_content: &Value,
+) -> Result<(), io::Error> {
+    let empty_object = Map::new();
+    let fields = json_content.as_object().unwrap_or(&empty_object);
+
+    match condition {
+        Some(condition) if !condition.matches(fields) => {
+            let e_kind = io::ErrorKind::AlreadyExists;
+            let e = "Document doesn't match the IF condition".to_owned();
+            Err(io::Error::new(e_kind, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Documents are JSON objects, so only an object patch keeps them one
+fn decode_merge_patch(patch: &str, encoding: Encoding) -> Result<Value, io::Error> {
+    let patch: Value = from_str(&encoding.decode(patch)?)?;
+    if !patch.is_object() {
+        let e_kind = io::ErrorKind::InvalidData;
+        let e = "Expected a JSON object as the merge patch".to_owned();
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    Ok(patch)
+}
+
+enum FindAndModifyAction {
+    Patch(String),
+    Remove,
+}
+
+/// Example:
+/// in: FINDANDMODIFY jobs status = queued SET 7B22737461747573223A2272756E6E696E67227D
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// Takes the first document matching the predicate (see query.rs), applies the
+/// decoded merge patch to it (like PATCH) or deletes it with `REMOVE` instead
+/// of `SET <data>`, and returns it as it was before, with its `_id` like FIND.
+/// With `NEW` before the predicate, the document is returned as it is after
+/// the patch. The predicate is checked again under the pile's lock, so of
+/// two clients racing for the same document only one gets it, and the other
+/// moves on to the next match. Returns nothing if no document matches.
+fn find_and_modify(
+    pile_name: &str,
+    predicate: &query::Predicate,
+    action: &FindAndModifyAction,
+    return_new: bool,
+    encoding: Encoding,
+) -> Result<Option<String>, io::Error> {
+    let patch = match action {
+        FindAndModifyAction::Patch(patch) => Some(decode_merge_patch(patch, encoding)?),
+        FindAndModifyAction::Remove => None,
+    };
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    for uuid in matching_ids(pile_name, predicate)? {
+        let mut old_content = None;
+        let modified = match patch {
+            Some(ref patch) => modify_document(&pile_meta, pile_name, &uuid, |json_content| {
+                if check_condition(Some(predicate), json_content).is_ok() {
+                    old_content = Some(json_content.clone());
+                    merge_patch(json_content, patch);
+                }
+                Ok(())
             }),
-            Err(e) => response_handler(Response::Error {
-                exit_code: 1,
-                error: format!("Error finding database entry: {}", e),
+            None => remove_document_if(pile_name, &uuid, |json_content| {
+                if check_condition(Some(predicate), json_content).is_ok() {
+                    old_content = Some(json_content.clone());
+                }
+                old_content.is_some()
             }),
-        },
+        };
+
+        let new_content = match modified {
+            Ok(new_content) => new_content,
+            // Deleted since it matched
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        // Taken by someone else since it matched
+        let old_content = match old_content {
+            Some(old_content) => old_content,
+            None => continue,
+        };
+
+        let mut json_content = match return_new {
+            true => new_content.unwrap_or(old_content),
+            false => old_content,
+        };
+        if let Some(json_object) = json_content.as_object_mut() {
+            json_object.insert("_id".to_owned(), Value::String(uuid));
+        }
+
+        return Ok(Some(encoding.encode(&json_content.to_string())));
+    }
+
+    Ok(None)
+}
+
+/// Deletes a document (like DELETE) if `should_remove` accepts it, checked
+/// under the pile's lock. Always returns None, as nothing is left to return.
+fn remove_document_if<F>(
+    pile_name: &str,
+    uuid: &str,
+    should_remove: F,
+) -> Result<Option<Value>, io::Error>
+where
+    F: FnOnce(&Value) -> bool,
+{
+    delete_if(pile_name, uuid, |json_content| {
+        json_content.is_some_and(should_remove)
+    })?;
+
+    Ok(None)
+}
+
+/// Example:
+/// in: INCR products cd8abd45-ad36-4cf6-a520-c1c5d0671d96 stock -3
+/// out: 17
+///
+/// Adds the delta (negative to decrement) to a numeric field and returns the
+/// new value. The read and the write happen under the pile's lock, so
+/// concurrent increments never lose one another. A missing field counts as 0;
+/// integers stay integers unless the delta is fractional.
+fn incr(pile_name: &str, uuid: &str, field: &str, delta: &str) -> Result<Value, io::Error> {
+    let delta = match from_str(delta) {
+        Ok(Value::Number(delta)) => delta,
+        _ => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Invalid delta: {}", delta);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    let mut new_value = Value::Null;
+    modify_document(&pile_meta, pile_name, uuid, |json_content| {
+        let json_object = match json_content.as_object_mut() {
+            Some(json_object) => json_object,
+            None => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = "Document is not a JSON object".to_owned();
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+
+        let sum = match json_object.get(field).unwrap_or(&Value::from(0)) {
+            Value::Number(current) => match (current.as_i64(), delta.as_i64()) {
+                (Some(current), Some(delta)) => current.checked_add(delta).map(Value::from),
+                _ => current
+                    .as_f64()
+                    .zip(delta.as_f64())
+                    .and_then(|(current, delta)| serde_json::Number::from_f64(current + delta))
+                    .map(Value::Number),
+            },
+            _ => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Field \"{}\" is not a number", field);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+
+        new_value = match sum {
+            Some(sum) => sum,
+            None => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Field \"{}\" would overflow", field);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+        json_object.insert(field.to_owned(), new_value.clone());
+        Ok(())
+    })?;
+
+    Ok(new_value)
+}
+
+#[derive(Clone, Copy)]
+enum ArrayOp {
+    Push,
+    Pull,
+    AddToSet,
+}
+
+/// Example:
+/// in: PUSH posts cd8abd45-ad36-4cf6-a520-c1c5d0671d96 tags 227275737422
+/// out: 4
+///
+/// Changes an array field in place, under the pile's lock like INCR, and
+/// returns the array's new length. The decoded data is one JSON value:
+///
+/// PUSH      appends it
+/// PULL      removes every element equal to it
+/// ADDTOSET  appends it, unless an equal element is already there
+///
+/// A missing field counts as an empty array.
+fn update_array(
+    pile_name: &str,
+    uuid: &str,
+    field: &str,
+    op: ArrayOp,
+    value: &str,
+    encoding: Encoding,
+) -> Result<usize, io::Error> {
+    let value: Value = from_str(&encoding.decode(value)?)?;
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    let mut array_length = 0;
+    modify_document(&pile_meta, pile_name, uuid, |json_content| {
+        let json_object = match json_content.as_object_mut() {
+            Some(json_object) => json_object,
+            None => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = "Document is not a JSON object".to_owned();
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+
+        let array = match json_object
+            .entry(field.to_owned())
+            .or_insert_with(|| Value::Array(Vec::new()))
+        {
+            Value::Array(array) => array,
+            _ => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Field \"{}\" is not an array", field);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+
+        match op {
+            ArrayOp::Push => array.push(value),
+            ArrayOp::Pull => array.retain(|element| *element != value),
+            ArrayOp::AddToSet if array.contains(&value) => (),
+            ArrayOp::AddToSet => array.push(value),
+        }
+
+        array_length = array.len();
+        Ok(())
+    })?;
+
+    Ok(array_length)
+}
+
+/// Example:
+/// in: UPDATEWHERE users status = trial SET 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: 318
+///
+/// Applies the decoded partial document to every document matching the
+/// predicate (see query.rs), as a JSON merge patch: fields in the patch are set,
+/// nested objects are merged, and fields set to null are removed. Renaming a
+/// field is `{"new_name": <value>, "old_name": null}` wherever the value is the
+/// same, e.g. with a predicate on it. Each document is updated on its own under
+/// the pile's rules (schema, unique fields, ...), so a document that breaks
+/// them stops the update with the documents before it updated. Returns how
+/// many documents actually changed. With `DRYRUN` before the predicate,
+/// nothing is updated and the IDs of the documents the patch would change are
+/// returned instead (the pile's rules aren't checked):
+///
+/// in: UPDATEWHERE users DRYRUN status = trial SET 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+/// out: ["cd8abd45-ad36-4cf6-a520-c1c5d0671d96"]
+fn update_where(
+    pile_name: &str,
+    predicate: &query::Predicate,
+    patch: &str,
+    encoding: Encoding,
+) -> Result<usize, io::Error> {
+    let patch = decode_merge_patch(patch, encoding)?;
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    let mut updated_count = 0;
+    for uuid in matching_ids(pile_name, predicate)? {
+        let modified = modify_document(&pile_meta, pile_name, &uuid, |json_content| {
+            merge_patch(json_content, &patch);
+            Ok(())
+        });
+        match modified {
+            Ok(Some(_)) => updated_count += 1,
+            Ok(None) => (),
+            // Deleted since it matched
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                let e_kind = e.kind();
+                let e = format!(
+                    "Document {}: {} (after updating {} documents)",
+                    uuid, e, updated_count
+                );
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+    }
+
+    Ok(updated_count)
+}
+
+fn update_where_dry_run(
+    pile_name: &str,
+    predicate: &query::Predicate,
+    patch: &str,
+    encoding: Encoding,
+) -> Result<Vec<String>, io::Error> {
+    let patch = decode_merge_patch(patch, encoding)?;
+    let pile_path = pile_path(pile_name)?;
+
+    let mut changed_uuids = Vec::new();
+    for uuid in matching_ids(pile_name, predicate)? {
+        let file_path = document_file_path(&pile_path, &uuid);
+        let document = match cache::read_document(Path::new(&file_path)) {
+            Ok(document) => document,
+            // Deleted since it matched
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut json_content = document.json.clone();
+        merge_patch(&mut json_content, &patch);
+        if json_content != document.json {
+            changed_uuids.push(uuid);
+        }
+    }
+
+    Ok(changed_uuids)
+}
+
+/// Reads, modifies and writes back a document under the pile's lock, so no
+/// other write to the pile can slip in between. The modified document goes
+/// through the pile's rules like a new one. Returns the document as stored,
+/// or None, without writing anything, if `modify` left it as it was.
+fn modify_document<F>(
+    pile_meta: &PileMeta,
+    pile_name: &str,
+    uuid: &str,
+    modify: F,
+) -> Result<Option<Value>, io::Error>
+where
+    F: FnOnce(&mut Value) -> Result<(), io::Error>,
+{
+    check_mutable(pile_meta, pile_name)?;
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let pile_path = pile_path(pile_name)?;
+    let file_path = document_file_path(&pile_path, uuid);
+    if !is_valid_document_id(uuid) || !Path::new(&file_path).is_file() {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Could not find document: \"{}\"", uuid);
+        return Err(io::Error::new(e_kind, e));
+    }
+    let current_content: Value = from_str(&fs::read_to_string(file_path)?)?;
+
+    let mut json_content = current_content.clone();
+    modify(&mut json_content)?;
+    if json_content == current_content {
+        return Ok(None);
+    }
+
+    let mut document = prepare_new_document(
+        pile_meta,
+        pile_name,
+        DocumentId::Replace(uuid),
+        &json_content.to_string(),
+    )?;
+
+    // An update keeps the document's original creation time
+    let created_at = current_content.get("_created_at");
+    if let (true, Some(created_at)) = (pile_meta.timestamps(), created_at) {
+        if let Some(json_object) = document.json_content.as_object_mut() {
+            json_object.insert("_created_at".to_owned(), created_at.clone());
+            document.data = document.json_content.to_string();
+        }
+    }
+
+    check_unique_fields(
+        pile_name,
+        &pile_meta.unique_fields(),
+        &[(uuid, &document.json_content)],
+    )?;
+
+    let _applying = wal::append(&document.wal_entry(pile_name))?;
+    commit_new_document(pile_meta, pile_name, &document)?;
+    capped::enforce(pile_meta, pile_name, &[uuid]);
+    notify_change(
+        pile_meta,
+        WebhookEvent::Update,
+        pile_name,
+        uuid,
+        Some(&document.json_content),
+    );
+
+    Ok(Some(document.json_content))
+}
+
+/// Applies a JSON merge patch (RFC 7386) to the target
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch_object = match patch.as_object() {
+        Some(patch_object) => patch_object,
+        None => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    if let Some(target_object) = target.as_object_mut() {
+        for (field, value) in patch_object {
+            match value {
+                Value::Null => {
+                    target_object.remove(field);
+                }
+                value => merge_patch(
+                    target_object.entry(field.clone()).or_insert(Value::Null),
+                    value,
+                ),
+            }
+        }
+    }
+}
+
+/// Finds every document (in any pile) whose RESTRICT or CASCADE reference
+/// points at the given document
+fn referencing_documents(
+    pile_name: &str,
+    uuid: &str,
+) -> Result<Vec<(String, String, OnDelete)>, io::Error> {
+    let mut referencing = Vec::new();
+
+    for referencing_pile in pile_names()? {
+        let references = PileMeta::load(&referencing_pile)?
+            .references()
+            .into_iter()
+            .filter(|reference| reference.pile == pile_name)
+            .filter(|reference| reference.on_delete != OnDelete::Ignore)
+            .collect::<Vec<Reference>>();
+
+        if references.is_empty() {
+            continue;
+        }
+
+        for file_path in document_paths(&referencing_pile)? {
+            let document = cache::read_document(&file_path)?;
+            for reference in &references {
+                if reference.referenced_uuids(&document.json).contains(&uuid) {
+                    let referencing_uuid = file_path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .unwrap_or_default();
+                    referencing.push((
+                        referencing_pile.clone(),
+                        referencing_uuid.to_owned(),
+                        reference.on_delete,
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(referencing)
+}
+
+/// Example:
+/// in: RESTORE users cd8abd45-ad36-4cf6-a520-c1c5d0671d96
+/// out:
+fn restore_document(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    // So the expiry sweep can't purge the tombstone between the check and the
+    // rename (see ttl.rs)
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+    if !is_valid_document_id(uuid) || !Path::new(&tombstone_file_path(&pile_path, uuid)).is_file() {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Could not find deleted document: \"{}\"", uuid);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let _applying = wal::append(&WalEntry::new(WalOp::Restore {
+        pile: pile_name.to_owned(),
+        uuid: uuid.to_owned(),
+    }))?;
+
+    mark_restored(pile_name, uuid)
+}
+
+/// Swaps a tombstone back for the document. Restoring a document that isn't
+/// tombstoned (any more) is not an error, which keeps WAL replay idempotent.
+fn mark_restored(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let file_path = document_file_path(&pile_path, uuid);
+    ordered::before_write(pile_name)?;
+
+    match fs::rename(tombstone_file_path(&pile_path, uuid), &file_path) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
     }
+    write_concern::sync_document(Path::new(&file_path))?;
+    ordered::record(pile_name, uuid, || {
+        fs::read_to_string(&file_path)
+            .ok()
+            .and_then(|data| from_str(&data).ok())
+    });
+
+    let document_size = fs::metadata(&file_path)?.len();
+    stats::record(pile_name, 1, document_size as i64);
+    results::invalidate(pile_name);
+    consistency::commit();
+    Ok(())
 }
 
-fn response_handler(response: Response) -> Response {
-    match response {
-        Response::Ok {
-            ref exit_code,
-            ref message,
-        } => {
-            let log = DBResponseLog {
-                timestamp: Utc::now(),
-                log_level: LogLevel::INFO,
-                exit_code: exit_code.clone(),
-                message: message.clone(),
-            };
+/// Example:
+/// in: SOFTDELETE users ON 30
+/// out:
+///
+/// Turns soft delete on (tombstones purged after 30 days, or kept forever if
+/// no days are given) or OFF. Turning it off leaves existing tombstones alone.
+fn set_soft_delete(
+    pile_name: &str,
+    enabled: bool,
+    purge_after_days: Option<u64>,
+) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
 
-            match write_to_log(log.as_log_str(), log.get_log_distinction()) {
-                Ok(_) => (),
-                Err(e) => eprintln!("{:?}", e),
-            };
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    match enabled {
+        true => pile_meta.set(
+            "soft_delete",
+            json!({ "purge_after_days": purge_after_days }),
+        ),
+        false => pile_meta.set("soft_delete", Value::Null),
+    }
+    pile_meta.save()
+}
 
-            response
-        }
-        Response::Error {
-            ref exit_code,
-            ref error,
-        } => {
-            let log = DBResponseLog {
-                timestamp: Utc::now(),
-                log_level: LogLevel::ERROR,
-                exit_code: exit_code.clone(),
-                message: Some(error.clone()),
-            };
+/// Example:
+/// in: CAPPED activity DOCUMENTS 1000 BYTES 1048576
+/// out:
+///
+/// Caps the pile at the given number of documents and/or bytes (see
+/// capped.rs), or lifts the cap with OFF. An append-only pile can't be capped.
+/// A pile already over its new cap is trimmed by the next document written to
+/// it.
+fn set_capped(
+    pile_name: &str,
+    max_documents: Option<u64>,
+    max_bytes: Option<u64>,
+) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
 
-            match write_to_log(log.as_log_str(), log.get_log_distinction()) {
-                Ok(_) => (),
-                Err(e) => eprintln!("{:?}", e),
-            };
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    if pile_meta.append_only() {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Pile \"{}\" is append-only", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
 
-            response
-        }
+    match (max_documents, max_bytes) {
+        (None, None) => pile_meta.set("capped", Value::Null),
+        _ => pile_meta.set(
+            "capped",
+            json!({ "max_documents": max_documents, "max_bytes": max_bytes }),
+        ),
     }
+    pile_meta.save()
 }
 
-// Example:
-/// in: FIND users email matthew@saplink.io
-/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
-fn find(pile_name: &str, field_name: &str, compare_name: &str) -> Result<String, io::Error> {
-    let pile_path = format!("{}{}", get_env_var("DUST_DATA_STORAGE_PATH"), &pile_name);
-    let dir_path = Path::new(&pile_path);
-    if dir_path.is_dir() {
-        for entry in fs::read_dir(dir_path)? {
-            let entry = entry?;
-            let file_content = fs::read_to_string(entry.path())?;
-            let json_content: Value = from_str(&file_content)?;
-            if let Some(value) = json_content.get(field_name) {
-                if value.as_str().unwrap() == compare_name {
-                    let encoded_json_data = encode_utf8_to_hex(&file_content);
-                    return Ok(encoded_json_data);
+/// Example:
+/// in: UNIQUE users email
+/// out:
+///
+/// Declaring the constraint fails if the pile already holds duplicates.
+fn add_unique_field(pile_name: &str, field_name: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut unique_fields = pile_meta.unique_fields();
+    if unique_fields.iter().any(|field| field == field_name) {
+        return Ok(());
+    }
+
+    let mut seen: HashMap<String, String> = HashMap::new();
+    for file_path in document_paths(pile_name)? {
+        let document = cache::read_document(&file_path)?;
+        let uuid = file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+
+        match document.json.get(field_name) {
+            None | Some(Value::Null) => (),
+            Some(value) => match seen.get(&value.to_string()) {
+                Some(seen_uuid) => {
+                    let e_kind = io::ErrorKind::AlreadyExists;
+                    let e = format!(
+                        "Documents {} and {} share \"{}\" = {}",
+                        seen_uuid, uuid, field_name, value
+                    );
+                    return Err(io::Error::new(e_kind, e));
                 }
-            }
+                None => {
+                    seen.insert(value.to_string(), uuid.to_owned());
+                }
+            },
         }
     }
-    // Do not want an error if pile doesn't exist, this was for testing only.
-    // If the pile doesn't exist, no data to return!
-    // else {
-    //     let e_kind = io::ErrorKind::NotFound;
-    //     let e = format!("Could not find pile: \"{}\"", pile_name).to_owned();
-    //     let error = io::Error::new(e_kind, e);
-    //     return Err(error);
-    // }
-    Ok(String::new())
+
+    unique_fields.push(field_name.to_owned());
+    pile_meta.set("unique", json!(unique_fields));
+    pile_meta.save()
 }
 
 /// Example:
-/// in: CREATE users 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
-/// out: cd8abd45-ad36-4cf6-a520-c1c5d0671d96
+/// in: REFERENCE orders user_id users CASCADE
+/// out:
 ///
-/// NOTE: We are writing the PLAIN TEXT DATA to the file! This makes it easier
-/// for future viewing via filesystem/other ops. This is a security trade-off:
-/// the logic here is that if a potential, bad actor already has access to the
-/// filesystem, then the data being encoded as plaintext vs. hex does not really
-/// make a difference in the grand scheme of security. :)
-fn create(pile_name: &str, data_as_hex_string: &str) -> Result<String, io::Error> {
-    // STEP 1: Generate a UUID to be used for future ops
-    // TODO: Check for uuid collision ?
-    let generated_uuid: String = generate_v4_uuid();
+/// New documents in `orders` must have a `user_id` naming an existing document
+/// in `users`. Deleting a user is blocked (RESTRICT), deletes their orders too
+/// (CASCADE) or leaves them be (IGNORE, the default). Re-declaring a reference
+/// for the same field replaces it.
+fn add_reference(
+    pile_name: &str,
+    field_name: &str,
+    target_pile: &str,
+    on_delete: OnDelete,
+) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
 
-    // STEP 2: Decode the data back into plaintext (from hex)
-    let decoded_data_result = match decode_hex_to_utf8(&data_as_hex_string) {
-        Ok(utf8_string) => Ok(utf8_string),
-        Err(e) => Err(e),
-    }?;
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut references = pile_meta
+        .references()
+        .into_iter()
+        .filter(|reference| reference.field != field_name)
+        .collect::<Vec<Reference>>();
+
+    references.push(Reference {
+        field: field_name.to_owned(),
+        pile: target_pile.to_owned(),
+        on_delete,
+    });
+
+    pile_meta.set(
+        "references",
+        Value::Array(references.iter().map(Reference::to_json).collect()),
+    );
+    pile_meta.save()
+}
+
+/// Example:
+/// in: TRIGGER orders CREATE AUDIT orders_audit
+/// out:
+fn add_trigger(pile_name: &str, trigger: Trigger) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut triggers = pile_meta.triggers();
+    triggers.push(trigger);
+
+    pile_meta.set(
+        "triggers",
+        Value::Array(triggers.iter().map(Trigger::to_json).collect()),
+    );
+    pile_meta.save()
+}
+
+/// Example:
+/// in: BLOOM users email
+/// out:
+///
+/// FINDs on `users.email` first check an in-memory bloom filter, and return
+/// no match straight away for values the pile definitely doesn't hold.
+fn add_bloom_field(pile_name: &str, field_name: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut bloom_fields = pile_meta.bloom_fields();
+    if !bloom_fields.iter().any(|field| field == field_name) {
+        bloom_fields.push(field_name.to_owned());
+        pile_meta.set("bloom", json!(bloom_fields));
+        pile_meta.save()?;
+    }
+
+    Ok(())
+}
+
+/// Example:
+/// in: ORDERED users age
+/// out:
+///
+/// Keeps `users` sorted by `age` (see ordered.rs), so queries bounding it
+/// (`age >= 18`, `age 30`, ...) read only the documents within the bounds.
+fn add_ordered_field(pile_name: &str, field_name: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut ordered_fields = pile_meta.ordered_fields();
+    if !ordered_fields.iter().any(|field| field == field_name) {
+        ordered_fields.push(field_name.to_owned());
+        pile_meta.set("ordered", json!(ordered_fields));
+        pile_meta.save()?;
+    }
+
+    Ok(())
+}
+
+/// Example:
+/// in: STATS
+/// out: {"cache":{"hits":10,"misses":4,...},"memory":{"budget":268435456,"used":2048,...}}
+///
+/// `STATS <pile>` reports on a single pile instead (see stats.rs).
+fn stats() -> Value {
+    json!({
+        "cache": cache::stats(),
+        "results": results::stats(),
+        "memory": memory::stats(),
+    })
+}
+
+/// Example:
+/// in: INFO
+/// out: {"version":"0.1.0","scheduled_tasks":[{"task":"backup","schedule":"0 2 * * *","running":false,"runs":3,"last_started_at":"...","last_duration_ms":5120,"last_error":null,"next_run_at":"..."}]}
+fn info() -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "scheduled_tasks": scheduler::status(),
+    })
+}
+
+/// Document ids become file names, so they must not be able to name anything
+/// outside of their pile
+fn is_valid_document_id(id: &str) -> bool {
+    !id.is_empty() && !id.starts_with('.') && !id.contains(['/', '\\'])
+}
+
+/// Which ID a new document is stored under
+pub enum DocumentId<'a> {
+    /// A fresh ID following the pile's ID scheme
+    Generated,
+    /// The client's own ID, which must not be taken yet
+    New(&'a str),
+    /// The client's own ID, replacing any document stored under it
+    Replace(&'a str),
+}
+
+/// Applies the pile's defaults and validates the document against the pile's
+/// schema (if any), creates the pile (if not exists), records the write in the
+/// WAL and then writes the plaintext document into the pile. Once the write
+/// is committed, the pile's CREATE triggers run.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn store_document(
+    pile_name: &str,
+    document_id: DocumentId,
+    data: &str,
+) -> Result<String, io::Error> {
+    let pile_meta = PileMeta::load(pile_name)?;
+    let (uuid, json_content) = write_new_document(&pile_meta, pile_name, document_id, data)?;
+
+    triggers::run(
+        &pile_meta.triggers(),
+        TriggerEvent::Create,
+        pile_name,
+        &uuid,
+        Some(&json_content),
+    );
+    notify_change(
+        &pile_meta,
+        WebhookEvent::Create,
+        pile_name,
+        &uuid,
+        Some(&json_content),
+    );
+
+    Ok(uuid)
+}
+
+/// Stores a batch of documents like `store_document`, under generated IDs
+/// (see `write_new_documents`), returning the IDs in order
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+fn store_documents(pile_name: &str, data: &[String]) -> Result<Vec<String>, io::Error> {
+    let pile_meta = PileMeta::load(pile_name)?;
+    let documents = write_new_documents(&pile_meta, pile_name, data)?;
 
-    // STEP 3: Create the path for the desired pile (if not exists)
-    let pile_path = format!("{}{}", get_env_var("DUST_DATA_STORAGE_PATH"), &pile_name);
+    let triggers = pile_meta.triggers();
+    for document in &documents {
+        triggers::run(
+            &triggers,
+            TriggerEvent::Create,
+            pile_name,
+            &document.uuid,
+            Some(&document.json_content),
+        );
+        notify_change(
+            &pile_meta,
+            WebhookEvent::Create,
+            pile_name,
+            &document.uuid,
+            Some(&document.json_content),
+        );
+    }
+
+    Ok(documents
+        .into_iter()
+        .map(|document| document.uuid)
+        .collect())
+}
+
+/// Writes a document under the ID picked by `document_id`, returning the ID
+/// it was stored under
+fn write_new_document(
+    pile_meta: &PileMeta,
+    pile_name: &str,
+    document_id: DocumentId,
+    data: &str,
+) -> Result<(String, Value), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let document = prepare_new_document(pile_meta, pile_name, document_id, data)?;
+    check_unique_fields(
+        pile_name,
+        &pile_meta.unique_fields(),
+        &[(&document.uuid, &document.json_content)],
+    )?;
+
+    let pile_path = pile_path(pile_name)?;
     match fs::create_dir_all(&pile_path) {
         Ok(_) => Ok(()),
         Err(e) => Err(e),
     }?;
 
-    // STEP 4: Write the decoded data into the pile
-    let file_path = format!(
-        "{}/{}.{}",
-        pile_path,
-        generated_uuid,
-        get_env_var("DUST_DATA_FMT")
+    let _applying = wal::append(&document.wal_entry(pile_name))?;
+    commit_new_document(pile_meta, pile_name, &document)?;
+    events::publish(pile_name, &document.uuid, &document.json_content);
+    capped::enforce(pile_meta, pile_name, &[&document.uuid]);
+
+    Ok((document.uuid, document.json_content))
+}
+
+/// Like `write_new_document`, for a batch of documents with generated IDs.
+/// Every document is checked before the first one is written, so a batch
+/// either goes in whole or not at all, and the batch is one WAL append.
+fn write_new_documents(
+    pile_meta: &PileMeta,
+    pile_name: &str,
+    data: &[String],
+) -> Result<Vec<NewDocument>, io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut documents: Vec<NewDocument> = Vec::new();
+    let mut uuids = HashSet::new();
+    for (index, data) in data.iter().enumerate() {
+        let document = prepare_new_document(pile_meta, pile_name, DocumentId::Generated, data)
+            .map_err(|e| io::Error::new(e.kind(), format!("Document {}: {}", index, e)))?;
+
+        // Generated IDs are only checked against the documents on disk
+        if !uuids.insert(document.uuid.clone()) {
+            let e_kind = io::ErrorKind::AlreadyExists;
+            let e = format!("Generated ID \"{}\" twice", document.uuid);
+            return Err(io::Error::new(e_kind, e));
+        }
+        documents.push(document);
+    }
+
+    let unique_values: Vec<(&str, &Value)> = documents
+        .iter()
+        .map(|document| (document.uuid.as_str(), &document.json_content))
+        .collect();
+    check_unique_fields(pile_name, &pile_meta.unique_fields(), &unique_values)?;
+
+    let pile_path = pile_path(pile_name)?;
+    fs::create_dir_all(&pile_path)?;
+
+    let wal_entries: Vec<WalEntry> = documents
+        .iter()
+        .map(|document| document.wal_entry(pile_name))
+        .collect();
+    let _applying = wal::append_all(&wal_entries)?;
+
+    for document in &documents {
+        commit_new_document(pile_meta, pile_name, document)?;
+        events::publish(pile_name, &document.uuid, &document.json_content);
+    }
+    let uuids: Vec<&str> = documents
+        .iter()
+        .map(|document| document.uuid.as_str())
+        .collect();
+    capped::enforce(pile_meta, pile_name, &uuids);
+
+    Ok(documents)
+}
+
+/// A new document that passed the pile's rules, ready to be written
+struct NewDocument {
+    uuid: String,
+    data: String,
+    json_content: Value,
+}
+
+impl NewDocument {
+    fn wal_entry(&self, pile_name: &str) -> WalEntry {
+        WalEntry::new(WalOp::Create {
+            pile: pile_name.to_owned(),
+            uuid: self.uuid.clone(),
+            data: self.data.clone(),
+        })
+    }
+}
+
+/// Resolves the new document's ID and applies the pile's rules, except for
+/// unique fields (which depend on everything else being written). Must be
+/// called with the pile's lock held.
+fn prepare_new_document(
+    pile_meta: &PileMeta,
+    pile_name: &str,
+    document_id: DocumentId,
+    data: &str,
+) -> Result<NewDocument, io::Error> {
+    // Checked under the pile lock, so a free ID stays free until written
+    let uuid = match document_id {
+        DocumentId::Generated => generate_free_id(pile_meta.id_scheme(), pile_name)?,
+        DocumentId::New(uuid) if is_document_id_taken(pile_name, uuid)? => {
+            let e_kind = io::ErrorKind::AlreadyExists;
+            let e = format!("Document \"{}\" already exists", uuid);
+            return Err(io::Error::new(e_kind, e));
+        }
+        DocumentId::Replace(uuid)
+            if pile_meta.append_only() && is_document_id_taken(pile_name, uuid)? =>
+        {
+            let e_kind = io::ErrorKind::PermissionDenied;
+            let e = format!(
+                "Pile \"{}\" is append-only, document \"{}\" can't be replaced",
+                pile_name, uuid
+            );
+            return Err(io::Error::new(e_kind, e));
+        }
+        DocumentId::New(uuid) | DocumentId::Replace(uuid) => uuid.to_owned(),
+    };
+
+    // Checked before the document is parsed, and again once the pile's rules
+    // (e.g. defaults) had their say in what is actually stored
+    check_document_size(pile_meta, data)?;
+    let data = prepare_document(pile_meta, data)?;
+    check_document_size(pile_meta, &data)?;
+
+    let json_content: Value = from_str(&data)?;
+    check_references(&pile_meta.references(), &json_content)?;
+
+    Ok(NewDocument {
+        uuid,
+        data,
+        json_content,
+    })
+}
+
+/// Writes a document whose creation is already in the WAL
+fn commit_new_document(
+    pile_meta: &PileMeta,
+    pile_name: &str,
+    document: &NewDocument,
+) -> Result<(), io::Error> {
+    write_document(pile_name, &document.uuid, &document.data)?;
+    bloom::record(pile_name, &pile_meta.bloom_fields(), &document.json_content);
+
+    Ok(())
+}
+
+/// Tells whatever follows the pile's changes (webhooks, event sinks, MQTT, the
+/// HTTP change feed) about a committed write. `document` is the document as
+/// stored, or as it was before a delete.
+fn notify_change(
+    pile_meta: &PileMeta,
+    event: WebhookEvent,
+    pile_name: &str,
+    uuid: &str,
+    document: Option<&Value>,
+) {
+    webhooks::notify(pile_meta, event, pile_name, uuid, document);
+    sinks::publish(pile_meta, event, pile_name, uuid, document);
+    mqtt::publish(event, pile_name, uuid, document);
+    events::publish_change(event, pile_name, uuid, document);
+}
+
+/// Generates an ID that no document (live or soft deleted) in the pile uses
+/// yet, so a collision can never silently overwrite another document. Gives
+/// up after `MAX_ID_ATTEMPTS` collisions in a row, which means the ID
+/// generator itself is broken.
+fn generate_free_id(scheme: IdScheme, pile_name: &str) -> Result<String, io::Error> {
+    const MAX_ID_ATTEMPTS: usize = 8;
+
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let uuid = ids::generate(scheme, pile_name)?;
+        if !is_document_id_taken(pile_name, &uuid)? {
+            return Ok(uuid);
+        }
+    }
+
+    let e_kind = io::ErrorKind::AlreadyExists;
+    let e = format!(
+        "Could not generate a free ID in {} attempts",
+        MAX_ID_ATTEMPTS
     );
+    Err(io::Error::new(e_kind, e))
+}
 
-    match fs::write(&file_path, decoded_data_result) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
-    }?;
+fn check_document_size(pile_meta: &PileMeta, data: &str) -> Result<(), io::Error> {
+    let max_bytes = match pile_meta.max_document_bytes() {
+        Some(max_bytes) => max_bytes,
+        None => env_or("DUST_MAX_DOCUMENT_BYTES", 16 * 1024 * 1024),
+    };
+
+    match data.len() <= max_bytes {
+        true => Ok(()),
+        false => {
+            let e_kind = io::ErrorKind::FileTooLarge;
+            let e = format!(
+                "Document is {} bytes, the pile allows at most {} bytes",
+                data.len(),
+                max_bytes
+            );
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+/// Whether a live or soft deleted document uses the ID
+fn is_document_id_taken(pile_name: &str, uuid: &str) -> Result<bool, io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    Ok(Path::new(&document_file_path(&pile_path, uuid)).exists()
+        || Path::new(&tombstone_file_path(&pile_path, uuid)).exists())
+}
+
+/// Runs a new document through the pile's metadata rules. The document is
+/// only re-serialized when a rule changes it, so otherwise the plain text is
+/// stored exactly as the client sent it.
+fn prepare_document(pile_meta: &PileMeta, data: &str) -> Result<String, io::Error> {
+    let mut data = data.to_owned();
+
+    if let Some(defaults) = pile_meta.defaults() {
+        let mut json_content: Value = from_str(&data)?;
+        if let Some(json_object) = json_content.as_object_mut() {
+            let missing_fields = defaults
+                .iter()
+                .filter(|(field, _)| !json_object.contains_key(*field))
+                .collect::<Vec<_>>();
+
+            if !missing_fields.is_empty() {
+                for (field, default_value) in missing_fields {
+                    json_object.insert(field.clone(), default_value.clone());
+                }
+                data = json_content.to_string();
+            }
+        }
+    }
+
+    if pile_meta.timestamps() {
+        let mut json_content: Value = from_str(&data)?;
+        if let Some(json_object) = json_content.as_object_mut() {
+            let now = Value::String(timestamp_now());
+            json_object.insert("_created_at".to_owned(), now.clone());
+            json_object.insert("_updated_at".to_owned(), now);
+            data = json_content.to_string();
+        }
+    }
+
+    if let Some(pile_schema) = pile_meta.schema() {
+        schema::validate(pile_schema, &from_str(&data)?)?;
+    }
+
+    Ok(data)
+}
+
+/// Fails with a conflict if another document in the pile already holds the
+/// same value for one of the unique fields, or two of the documents being
+/// written do. Documents without the field (or with null) never conflict.
+fn check_unique_fields(
+    pile_name: &str,
+    unique_fields: &[String],
+    documents: &[(&str, &Value)],
+) -> Result<(), io::Error> {
+    // Who holds each unique value being written, keyed by field and value
+    let mut holders: HashMap<(&str, String), &str> = HashMap::new();
+    for (uuid, json_content) in documents {
+        for field in unique_fields {
+            let value = match json_content.get(field) {
+                None | Some(Value::Null) => continue,
+                Some(value) => value.to_string(),
+            };
+
+            if let Some(holder) = holders.insert((field, value.clone()), uuid) {
+                let e_kind = io::ErrorKind::AlreadyExists;
+                let e = format!(
+                    "Unique constraint violated: \"{}\" = {} is set by both {} and {}",
+                    field, value, holder, uuid
+                );
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+    }
+
+    if holders.is_empty() {
+        return Ok(());
+    }
+
+    for file_path in document_paths(pile_name)? {
+        let existing_uuid = file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        if documents.iter().any(|(uuid, _)| *uuid == existing_uuid) {
+            continue;
+        }
+
+        let existing_document = cache::read_document(&file_path)?;
+        for field in unique_fields {
+            let value = match existing_document.json.get(field) {
+                None | Some(Value::Null) => continue,
+                Some(value) => value.to_string(),
+            };
+
+            if holders.contains_key(&(field.as_str(), value.clone())) {
+                let e_kind = io::ErrorKind::AlreadyExists;
+                let e = format!(
+                    "Unique constraint violated: \"{}\" = {} already exists in document {}",
+                    field, value, existing_uuid
+                );
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_references(references: &[Reference], json_content: &Value) -> Result<(), io::Error> {
+    for reference in references {
+        let target_path = pile_path(&reference.pile)?;
+        for target_uuid in reference.referenced_uuids(json_content) {
+            let target_exists = is_valid_document_id(target_uuid)
+                && Path::new(&document_file_path(&target_path, target_uuid)).is_file();
+
+            if !target_exists {
+                let e_kind = io::ErrorKind::NotFound;
+                let e = format!(
+                    "\"{}\" references missing document {} in pile \"{}\"",
+                    reference.field, target_uuid, reference.pile
+                );
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Server-side timestamps are always UTC with millisecond precision, so they
+/// compare correctly as plain strings
+fn timestamp_now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn document_file_path(pile_path: &str, uuid: &str) -> String {
+    format!("{}/{}.{}", pile_path, uuid, get_env_var("DUST_DATA_FMT"))
+}
+
+fn tombstone_file_path(pile_path: &str, uuid: &str) -> String {
+    format!("{}/{}.{}", pile_path, uuid, pile::TOMBSTONE_EXTENSION)
+}
+
+fn write_document(pile_name: &str, uuid: &str, data: &str) -> Result<(), io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let file_path = document_file_path(&pile_path, uuid);
+    cache::invalidate(&file_path);
+    ordered::before_write(pile_name)?;
+
+    // Swapped in with a rename rather than rewritten in place, so a reader
+    // never sees half a document and a hot backup's hard link to the old
+    // file (see backup.rs) keeps its content
+    let replaced_size = fs::metadata(&file_path).ok().map(|metadata| metadata.len());
+    let tmp_path = format!("{}/.{}.{}.tmp", pile_path, uuid, generate_v4_uuid());
+    fs::write(&tmp_path, data)?;
+    fs::rename(tmp_path, &file_path)?;
+    write_concern::sync_document(Path::new(&file_path))?;
+    ordered::record(pile_name, uuid, || from_str(data).ok());
+    docmeta::record(&pile_path, uuid, data.as_bytes());
+
+    match replaced_size {
+        Some(replaced_size) => {
+            stats::record(pile_name, 0, data.len() as i64 - replaced_size as i64)
+        }
+        None => stats::record(pile_name, 1, data.len() as i64),
+    }
+    results::invalidate(pile_name);
+    consistency::commit();
+    Ok(())
+}
+
+/// Records the deletion in the WAL and then removes the document's file
+fn delete_document(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    check_mutable(&PileMeta::load(pile_name)?, pile_name)?;
+    let _applying = wal::append(&WalEntry::new(WalOp::Delete {
+        pile: pile_name.to_owned(),
+        uuid: uuid.to_owned(),
+    }))?;
+
+    remove_document(pile_name, uuid)
+}
+
+/// Removes the document and its tombstone (if any). Removing a document that
+/// is already gone is not an error, which keeps WAL replay idempotent.
+fn remove_document(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let file_path = document_file_path(&pile_path, uuid);
+    let removed_size = fs::metadata(&file_path).ok().map(|metadata| metadata.len());
+    ordered::before_write(pile_name)?;
 
-    Ok(generated_uuid)
+    for file_path in [file_path, tombstone_file_path(&pile_path, uuid)] {
+        cache::invalidate(&file_path);
+        match fs::remove_file(&file_path) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        write_concern::sync_document(Path::new(&file_path))?;
+    }
+    ordered::record(pile_name, uuid, || None);
+    docmeta::remove(&pile_path, uuid)?;
+
+    // Tombstones aren't counted, only the live document
+    if let Some(removed_size) = removed_size {
+        stats::record(pile_name, -1, -(removed_size as i64));
+    }
+    results::invalidate(pile_name);
+    consistency::commit();
+    Ok(())
+}
+
+/// Records the soft delete in the WAL and then swaps the document for a
+/// tombstone, which is hidden from every query
+fn tombstone_document(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    check_mutable(&PileMeta::load(pile_name)?, pile_name)?;
+    let _applying = wal::append(&WalEntry::new(WalOp::Tombstone {
+        pile: pile_name.to_owned(),
+        uuid: uuid.to_owned(),
+    }))?;
+
+    mark_tombstoned(pile_name, uuid)
+}
+
+/// The tombstone's modification time records when the document was deleted,
+/// which is what the purge policy goes by
+fn mark_tombstoned(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let tombstone_path = tombstone_file_path(&pile_path, uuid);
+    let file_path = document_file_path(&pile_path, uuid);
+    cache::invalidate(&file_path);
+
+    let document_size = fs::metadata(&file_path).map(|metadata| metadata.len());
+    ordered::before_write(pile_name)?;
+    match fs::rename(file_path, &tombstone_path) {
+        Ok(_) => {
+            ordered::record(pile_name, uuid, || None);
+            stats::record(pile_name, -1, -(document_size.unwrap_or(0) as i64));
+            results::invalidate(pile_name);
+            consistency::commit();
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    fs::File::options()
+        .write(true)
+        .open(&tombstone_path)?
+        .set_modified(std::time::SystemTime::now())?;
+    write_concern::sync_document(Path::new(&tombstone_path))
+}
+
+/// Reads an optional setting from the environment, falling back to `default`
+/// when it is unset or can't be parsed
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value.parse().unwrap_or(default),
+        Err(_) => default,
+    }
+}
+
+/// Replays every WAL operation up to `until` onto the storage root, which is
+/// expected to hold a base snapshot taken before the point being recovered to.
+/// Replaying is idempotent: documents already present in the snapshot are
+/// simply rewritten with the same content.
+fn restore(until: &DateTime<Utc>) -> Result<usize, io::Error> {
+    let entries = wal::read_until(until)?;
+    apply_wal_entries(&entries)?;
+
+    Ok(entries.len())
+}
+
+/// Copies a full backup back into the storage root, then replays the WAL
+/// segments of every incremental backup chained on top of it
+fn restore_backup(backup_name: &str) -> Result<usize, io::Error> {
+    let chain = backup::resolve_chain(backup_name)?;
+    backup::copy_dir_all(
+        &chain.data_dir,
+        Path::new(&get_env_var("DUST_DATA_STORAGE_PATH")),
+    )?;
+
+    let mut replayed = 0;
+    for wal_segment in &chain.wal_segments {
+        let entries = wal::parse_entries(&fs::read_to_string(wal_segment)?, None)?;
+        apply_wal_entries(&entries)?;
+        replayed += entries.len();
+    }
+
+    Ok(replayed)
+}
+
+fn apply_wal_entries(entries: &[WalEntry]) -> Result<(), io::Error> {
+    for entry in entries {
+        match entry.op {
+            WalOp::Create {
+                ref pile,
+                ref uuid,
+                ref data,
+            } => {
+                fs::create_dir_all(pile_path(pile)?)?;
+                write_document(pile, uuid, data)?;
+            }
+            WalOp::Delete { ref pile, ref uuid } => remove_document(pile, uuid)?,
+            WalOp::Tombstone { ref pile, ref uuid } => mark_tombstoned(pile, uuid)?,
+            WalOp::Restore { ref pile, ref uuid } => mark_restored(pile, uuid)?,
+        }
+    }
+
+    Ok(())
 }
 
 fn capture_request_log(
@@ -359,6 +6059,10 @@ fn capture_request_log(
     command: String,
     payload_size_in_bytes: Option<usize>,
 ) {
+    if !logging::enabled(logging::Level::of(&log_level)) {
+        return;
+    }
+
     let log = DBRequestLog {
         timestamp: Utc::now(),
         log_level,
@@ -0,0 +1,75 @@
+/// Global memory budget.
+///
+/// Caches, bloom filters, ordered indexes and the payloads of requests being
+/// handled all grow with load. `DUST_MEMORY_BUDGET_BYTES` (default 256 MiB, 0
+/// disables it) caps their combined size: whenever the total goes over, the
+/// document cache is shrunk first (it's the cheapest to refill), then bloom
+/// filters and ordered indexes are dropped (they're rebuilt or reloaded on
+/// demand). In-flight payloads are counted but can't be evicted.
+use crate::{bloom, cache, env_or, ordered};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+static PAYLOAD_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+fn budget() -> usize {
+    static BUDGET: OnceLock<usize> = OnceLock::new();
+    *BUDGET.get_or_init(|| env_or("DUST_MEMORY_BUDGET_BYTES", 256 * 1024 * 1024))
+}
+
+/// Accounts for a request payload for as long as the guard lives
+pub struct PayloadGuard {
+    bytes: usize,
+}
+
+impl Drop for PayloadGuard {
+    fn drop(&mut self) {
+        PAYLOAD_BYTES.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+pub fn track_payload(bytes: usize) -> PayloadGuard {
+    PAYLOAD_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    enforce();
+    PayloadGuard { bytes }
+}
+
+/// Evicts until usage is back under budget (or nothing evictable is left)
+pub fn enforce() {
+    let budget = budget();
+    if budget == 0 {
+        return;
+    }
+
+    let payload_bytes = PAYLOAD_BYTES.load(Ordering::Relaxed);
+    let index_bytes = bloom::bytes() + ordered::bytes();
+    let cache_bytes = cache::bytes();
+    if payload_bytes + index_bytes + cache_bytes <= budget {
+        return;
+    }
+
+    let cache_allowance = budget.saturating_sub(payload_bytes + index_bytes);
+    cache::shrink_to(cache_allowance);
+
+    if payload_bytes + index_bytes > budget {
+        bloom::clear();
+        ordered::clear();
+    }
+}
+
+pub fn stats() -> Value {
+    let payload_bytes = PAYLOAD_BYTES.load(Ordering::Relaxed);
+    let bloom_bytes = bloom::bytes();
+    let ordered_bytes = ordered::bytes();
+    let cache_bytes = cache::bytes();
+
+    json!({
+        "budget": budget(),
+        "used": payload_bytes + bloom_bytes + ordered_bytes + cache_bytes,
+        "cache": cache_bytes,
+        "bloom": bloom_bytes,
+        "ordered": ordered_bytes,
+        "payloads": payload_bytes,
+    })
+}
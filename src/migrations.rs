@@ -0,0 +1,487 @@
+/// Migrations of a pile's documents.
+///
+/// `MIGRATE <pile> <name> <step>` rewrites every document of the pile with
+/// one transformation, on its own thread:
+///
+/// RENAME <field> <new field>              moves the field's value to a new name
+/// CAST <field> <string|number|boolean>    converts the field's value
+/// SPLIT <field> <separator> <a>,<b>,...   splits a string field into several
+///
+/// (a separator can't hold spaces). Every migration a pile ran is recorded
+/// in its metadata, under `migrations`, and listed by `MIGRATIONS <pile>`:
+///
+/// name         the client's name for it, applied at most once per pile
+/// step         the transformation, as given
+/// state        running, done or failed
+/// processed    documents gone through so far, out of total
+/// cursor       the last document gone through (they go in ID order)
+/// started_at   when it was first started
+/// finished_at  when it was done, or failed
+/// error        why it failed
+///
+/// Documents go through the pile's rules (schema, unique fields, ...) like
+/// any update, and one that breaks them fails the migration where it
+/// stands. Migrating again under the same name picks up after the cursor,
+/// and so does the server for migrations still running when it stopped.
+/// Steps leave documents they already transformed as they are, so going
+/// over the documents since the last saved cursor again is harmless.
+/// Documents written while a migration runs are expected in the new shape.
+///
+/// `MIGRATE <pile> DRYRUN <name> <step>` goes through the documents without
+/// writing anything or recording the migration, and reports which documents
+/// the step would change and which it would fail on.
+use crate::cache::read_document;
+use crate::logging::{self, Level};
+use crate::pile::{self, document_paths, pile_names, PileMeta};
+use crate::{modify_document, query, timestamp_now};
+use serde_json::{json, Map, Number, Value};
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Progress is saved to the pile's metadata every this many documents
+const PROGRESS_INTERVAL: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub enum CastType {
+    String,
+    Number,
+    Boolean,
+}
+
+pub enum Step {
+    Rename {
+        field: String,
+        to: String,
+    },
+    Cast {
+        field: String,
+        to: CastType,
+    },
+    Split {
+        field: String,
+        separator: String,
+        into: Vec<String>,
+    },
+}
+
+impl Step {
+    pub fn parse(words: &[&str]) -> Option<Step> {
+        match *words {
+            ["RENAME", field, to] => Some(Step::Rename {
+                field: field.to_owned(),
+                to: to.to_owned(),
+            }),
+            ["CAST", field, to] => {
+                let to = match to {
+                    "string" => CastType::String,
+                    "number" => CastType::Number,
+                    "boolean" => CastType::Boolean,
+                    _ => return None,
+                };
+                Some(Step::Cast {
+                    field: field.to_owned(),
+                    to,
+                })
+            }
+            ["SPLIT", field, separator, into]
+                if !separator.is_empty() && !into.split(',').any(str::is_empty) =>
+            {
+                Some(Step::Split {
+                    field: field.to_owned(),
+                    separator: separator.to_owned(),
+                    into: into.split(',').map(str::to_owned).collect(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The step as given, which is how the history records it
+    fn describe(&self) -> String {
+        match self {
+            Step::Rename { field, to } => format!("RENAME {} {}", field, to),
+            Step::Cast { field, to } => {
+                let to = match to {
+                    CastType::String => "string",
+                    CastType::Number => "number",
+                    CastType::Boolean => "boolean",
+                };
+                format!("CAST {} {}", field, to)
+            }
+            Step::Split {
+                field,
+                separator,
+                into,
+            } => format!("SPLIT {} {} {}", field, separator, into.join(",")),
+        }
+    }
+
+    fn apply(&self, json_content: &mut Value) -> Result<(), io::Error> {
+        let json_object = match json_content.as_object_mut() {
+            Some(json_object) => json_object,
+            None => return Ok(()),
+        };
+
+        match self {
+            Step::Rename { field, to } => {
+                if let Some(value) = json_object.remove(field) {
+                    json_object.insert(to.clone(), value);
+                }
+            }
+            Step::Cast { field, to } => {
+                if let Some(value) = json_object.get_mut(field) {
+                    *value = cast(field, value, *to)?;
+                }
+            }
+            Step::Split {
+                field,
+                separator,
+                into,
+            } => {
+                let text = match json_object.get(field) {
+                    None => return Ok(()),
+                    Some(Value::String(text)) => text.clone(),
+                    Some(_) => {
+                        let e_kind = io::ErrorKind::InvalidData;
+                        let e = format!("Field \"{}\" is not a string", field);
+                        return Err(io::Error::new(e_kind, e));
+                    }
+                };
+
+                json_object.remove(field);
+                let mut parts = text.splitn(into.len(), separator.as_str());
+                for target in into {
+                    let part = parts.next().map_or(Value::Null, Value::from);
+                    json_object.insert(target.clone(), part);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Nulls are left alone, as are values already of the type
+fn cast(field: &str, value: &Value, to: CastType) -> Result<Value, io::Error> {
+    let cast_value = match (to, value) {
+        (_, Value::Null) => Some(Value::Null),
+        (CastType::String, value) => Some(Value::String(query::text_of(value))),
+        (CastType::Number, Value::Number(_)) | (CastType::Boolean, Value::Bool(_)) => {
+            Some(value.clone())
+        }
+        (CastType::Number, Value::String(text)) => match text.trim().parse::<i64>() {
+            Ok(integer) => Some(Value::from(integer)),
+            Err(_) => text
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(Number::from_f64)
+                .map(Value::Number),
+        },
+        (CastType::Number, Value::Bool(boolean)) => Some(Value::from(*boolean as u8)),
+        (CastType::Boolean, Value::String(text)) => match text.trim() {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (CastType::Boolean, Value::Number(number)) => match number.as_f64() {
+            Some(0.0) => Some(Value::Bool(false)),
+            Some(1.0) => Some(Value::Bool(true)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match cast_value {
+        Some(cast_value) => Ok(cast_value),
+        None => {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = format!("Can't convert \"{}\" = {} to the new type", field, value);
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+/// Migrations with a thread working on them, by pile and name
+fn running() -> &'static Mutex<HashSet<(String, String)>> {
+    static RUNNING: OnceLock<Mutex<HashSet<(String, String)>>> = OnceLock::new();
+    RUNNING.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+fn history_of(pile_meta: &PileMeta) -> Vec<Value> {
+    match pile_meta.get("migrations").and_then(Value::as_array) {
+        Some(migrations) => migrations.clone(),
+        None => Vec::new(),
+    }
+}
+
+/// Adjusts the named migration's entry in the pile's history
+fn update<F>(pile_name: &str, name: &str, adjust: F) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut Map<String, Value>),
+{
+    // The history is read, adjusted and saved with the rest of the manifest
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut migrations = history_of(&pile_meta);
+
+    if let Some(migration) = migrations
+        .iter_mut()
+        .filter_map(Value::as_object_mut)
+        .find(|migration| migration.get("name").and_then(Value::as_str) == Some(name))
+    {
+        adjust(migration);
+    }
+
+    pile_meta.set("migrations", Value::Array(migrations));
+    pile_meta.save()
+}
+
+/// Example:
+/// in: MIGRATE users split_names SPLIT name _ first_name,last_name
+/// out:
+///
+/// Starts the migration (or picks a failed one up where it stopped) and
+/// returns right away; MIGRATIONS reports how it's doing.
+pub fn start(pile_name: &str, name: &str, step: Step) -> Result<(), io::Error> {
+    let is_valid_name = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !is_valid_name {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid migration name: \"{}\"", name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let key = (pile_name.to_owned(), name.to_owned());
+    {
+        let pile_lock = pile::lock(pile_name);
+        let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pile_meta = PileMeta::load(pile_name)?;
+        let mut migrations = history_of(&pile_meta);
+
+        let existing = migrations
+            .iter_mut()
+            .filter_map(Value::as_object_mut)
+            .find(|migration| migration.get("name").and_then(Value::as_str) == Some(name));
+        match existing {
+            Some(migration) => {
+                let state = migration.get("state").and_then(Value::as_str);
+                if state == Some("done") {
+                    let e_kind = io::ErrorKind::AlreadyExists;
+                    let e = format!("Migration \"{}\" was already applied", name);
+                    return Err(io::Error::new(e_kind, e));
+                }
+                if running()
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .contains(&key)
+                {
+                    let e_kind = io::ErrorKind::AlreadyExists;
+                    let e = format!("Migration \"{}\" is already running", name);
+                    return Err(io::Error::new(e_kind, e));
+                }
+                if migration.get("step").and_then(Value::as_str) != Some(&step.describe()) {
+                    let e_kind = io::ErrorKind::InvalidInput;
+                    let e = format!(
+                        "Migration \"{}\" was started as: {}",
+                        name,
+                        migration.get("step").unwrap_or(&Value::Null)
+                    );
+                    return Err(io::Error::new(e_kind, e));
+                }
+
+                migration.insert("state".to_owned(), json!("running"));
+                migration.insert("finished_at".to_owned(), Value::Null);
+                migration.insert("error".to_owned(), Value::Null);
+            }
+            None => migrations.push(json!({
+                "name": name,
+                "step": step.describe(),
+                "state": "running",
+                "processed": 0,
+                "total": 0,
+                "cursor": null,
+                "started_at": timestamp_now(),
+                "finished_at": null,
+                "error": null,
+            })),
+        }
+
+        pile_meta.set("migrations", Value::Array(migrations));
+        pile_meta.save()?;
+    }
+
+    spawn(pile_name, name, step);
+    Ok(())
+}
+
+/// Resumes the migrations that were still running when the server stopped
+pub fn resume_all() -> Result<(), io::Error> {
+    for pile_name in pile_names()? {
+        for migration in history_of(&PileMeta::load(&pile_name)?) {
+            if migration.get("state").and_then(Value::as_str) != Some("running") {
+                continue;
+            }
+
+            let name = migration.get("name").and_then(Value::as_str);
+            let step = migration
+                .get("step")
+                .and_then(Value::as_str)
+                .and_then(|step| Step::parse(&step.split(' ').collect::<Vec<&str>>()));
+            if let (Some(name), Some(step)) = (name, step) {
+                logging::diagnostic(
+                    Level::Info,
+                    &format!("Resuming migration \"{}\" of pile \"{}\"", name, pile_name),
+                );
+                spawn(&pile_name, name, step);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn(pile_name: &str, name: &str, step: Step) {
+    let key = (pile_name.to_owned(), name.to_owned());
+    running()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key.clone());
+
+    thread::spawn(move || {
+        let (pile_name, name) = &key;
+        let result = run(pile_name, name, &step);
+
+        let finished = update(pile_name, name, |migration| {
+            migration.insert("finished_at".to_owned(), json!(timestamp_now()));
+            match result {
+                Ok(_) => {
+                    migration.insert("state".to_owned(), json!("done"));
+                }
+                Err(ref e) => {
+                    migration.insert("state".to_owned(), json!("failed"));
+                    migration.insert("error".to_owned(), json!(e.to_string()));
+                }
+            }
+        });
+        if let Err(e) = finished {
+            logging::diagnostic(
+                Level::Error,
+                &format!(
+                    "Error recording the end of migration \"{}\" of pile \"{}\": {:?}",
+                    name, pile_name, e
+                ),
+            );
+        }
+
+        running()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&key);
+    });
+}
+
+fn run(pile_name: &str, name: &str, step: &Step) -> Result<(), io::Error> {
+    let pile_meta = PileMeta::load(pile_name)?;
+    let cursor = history_of(&pile_meta)
+        .iter()
+        .find(|migration| migration.get("name").and_then(Value::as_str) == Some(name))
+        .and_then(|migration| migration.get("cursor"))
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+
+    let uuids: Vec<String> = document_paths(pile_name)?
+        .iter()
+        .filter_map(|file_path| file_path.file_stem()?.to_str().map(str::to_owned))
+        .collect();
+    let total = uuids.len();
+    let mut processed = match cursor {
+        Some(ref cursor) => uuids.partition_point(|uuid| uuid <= cursor),
+        None => 0,
+    };
+
+    let save_progress = |processed: usize, cursor: Option<&str>| {
+        update(pile_name, name, |migration| {
+            migration.insert("processed".to_owned(), json!(processed));
+            migration.insert("total".to_owned(), json!(total));
+            migration.insert("cursor".to_owned(), json!(cursor));
+        })
+    };
+    save_progress(processed, cursor.as_deref())?;
+
+    let mut cursor = cursor;
+    for uuid in &uuids[processed..] {
+        match modify_document(&pile_meta, pile_name, uuid, |json_content| {
+            step.apply(json_content)
+        }) {
+            Ok(_) => (),
+            // Documents deleted while the migration runs are skipped
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                let e_kind = e.kind();
+                let e = format!("Document {}: {}", uuid, e);
+                return Err(io::Error::new(e_kind, e));
+            }
+        }
+
+        processed += 1;
+        cursor = Some(uuid.clone());
+        if processed % PROGRESS_INTERVAL == 0 {
+            save_progress(processed, cursor.as_deref())?;
+        }
+    }
+
+    save_progress(processed, cursor.as_deref())
+}
+
+/// Example:
+/// in: MIGRATE users DRYRUN split_names SPLIT name _ first_name,last_name
+/// out: {"documents":120000,"changed":["cd8abd45-..."],"failed":[{"document":"0b7e5c1a-...","error":"Field \"name\" is not a string"}]}
+///
+/// Unlike a migration, runs to the end before it returns. The pile's rules
+/// aren't checked.
+pub fn dry_run(pile_name: &str, step: &Step) -> Result<Value, io::Error> {
+    let file_paths = document_paths(pile_name)?;
+    let mut changed = Vec::new();
+    let mut failed = Vec::new();
+    for file_path in &file_paths {
+        let uuid = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(uuid) => uuid,
+            None => continue,
+        };
+        let document = match read_document(file_path) {
+            Ok(document) => document,
+            // Documents deleted since they were listed are skipped
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut json_content = document.json.clone();
+        match step.apply(&mut json_content) {
+            Ok(_) if json_content != document.json => changed.push(json!(uuid)),
+            Ok(_) => (),
+            Err(e) => failed.push(json!({ "document": uuid, "error": e.to_string() })),
+        }
+    }
+
+    Ok(json!({
+        "documents": file_paths.len(),
+        "changed": changed,
+        "failed": failed,
+    }))
+}
+
+/// Example:
+/// in: MIGRATIONS users
+/// out: [{"name":"split_names","step":"SPLIT name _ first_name,last_name","state":"running","processed":5000,"total":120000,...}]
+pub fn history(pile_name: &str) -> Result<Value, io::Error> {
+    Ok(Value::Array(history_of(&PileMeta::load(pile_name)?)))
+}
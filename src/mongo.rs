@@ -0,0 +1,175 @@
+/// Support for loading documents exported from MongoDB.
+///
+/// Takes `mongoexport` output, either one document per line (the default) or
+/// a single array (`--jsonArray`), in relaxed or canonical Extended JSON.
+/// The BSON types Extended JSON wraps in `$` objects become plain JSON:
+///
+/// $oid                         its 24 hex digits
+/// $date                        an RFC 3339 timestamp (UTC, milliseconds)
+/// $numberInt, $numberLong      a number
+/// $numberDouble                a number, or null for NaN and infinities
+/// $numberDecimal               a number, or a string if it doesn't fit one
+/// $binary, $uuid               the base64 payload or the UUID string
+/// $timestamp                   its seconds since the epoch
+///
+/// Other `$` objects (e.g. `$regularExpression`) are kept as they are. A
+/// document's `_id` becomes its dustdb ID if it's an ObjectId or a string
+/// that is a valid ID, otherwise it's kept as the `_mongo_id` field and the
+/// document gets a fresh ID.
+use chrono::{DateTime, SecondsFormat};
+use serde_json::{Deserializer, Map, Number, Value};
+use std::io;
+
+/// A converted document, with the ID it should be stored under (if any)
+pub struct MongoDocument {
+    pub id: Option<String>,
+    pub json: Value,
+}
+
+/// Parses every exported document, failing on the first one that isn't a
+/// JSON object
+pub fn parse_documents(
+    input: &str,
+    is_valid_id: impl Fn(&str) -> bool,
+) -> Result<Vec<MongoDocument>, io::Error> {
+    let mut values = Vec::new();
+    for value in Deserializer::from_str(input).into_iter::<Value>() {
+        match value? {
+            Value::Array(array) => values.extend(array),
+            value => values.push(value),
+        }
+    }
+
+    let mut documents = Vec::new();
+    for (index, value) in values.into_iter().enumerate() {
+        let mut json_object = match convert(value)? {
+            Value::Object(json_object) => json_object,
+            _ => {
+                return Err(invalid_data(format!(
+                    "Document {} is not a JSON object",
+                    index + 1
+                )))
+            }
+        };
+
+        let id = match json_object.remove("_id") {
+            Some(Value::String(id)) if is_valid_id(&id) => Some(id),
+            Some(mongo_id) => {
+                json_object.insert("_mongo_id".to_owned(), mongo_id);
+                None
+            }
+            None => None,
+        };
+
+        documents.push(MongoDocument {
+            id,
+            json: Value::Object(json_object),
+        });
+    }
+
+    Ok(documents)
+}
+
+/// Example:
+/// in: {"_id":{"$oid":"5f1d7a3e9d1c2b0017a4e0b1"},"born":{"$date":{"$numberLong":"0"}}}
+/// out: {"_id":"5f1d7a3e9d1c2b0017a4e0b1","born":"1970-01-01T00:00:00.000Z"}
+fn convert(value: Value) -> Result<Value, io::Error> {
+    match value {
+        Value::Array(array) => Ok(Value::Array(
+            array.into_iter().map(convert).collect::<Result<_, _>>()?,
+        )),
+        Value::Object(json_object) => match json_object.len() {
+            1 | 2 => match convert_wrapper(&json_object)? {
+                Some(value) => Ok(value),
+                None => convert_object(json_object),
+            },
+            _ => convert_object(json_object),
+        },
+        value => Ok(value),
+    }
+}
+
+fn convert_object(json_object: Map<String, Value>) -> Result<Value, io::Error> {
+    let mut converted = Map::new();
+    for (key, value) in json_object {
+        converted.insert(key, convert(value)?);
+    }
+    Ok(Value::Object(converted))
+}
+
+/// The plain value of an Extended JSON type wrapper, or `None` if the object
+/// isn't one
+fn convert_wrapper(json_object: &Map<String, Value>) -> Result<Option<Value>, io::Error> {
+    // Only `$binary` in its legacy form has a second key (`$type`)
+    if json_object.len() == 2 {
+        return match (json_object.get("$binary"), json_object.get("$type")) {
+            (Some(Value::String(base64)), Some(_)) => Ok(Some(Value::String(base64.clone()))),
+            _ => Ok(None),
+        };
+    }
+
+    let (key, value) = match json_object.iter().next() {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    let converted = match (key.as_str(), value) {
+        ("$oid", Value::String(oid)) => Value::String(oid.clone()),
+        ("$uuid", Value::String(uuid)) => Value::String(uuid.clone()),
+        ("$date", date) => Value::String(convert_date(date)?),
+        ("$numberInt" | "$numberLong", Value::String(number)) => match number.parse::<i64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => return Err(invalid_data(format!("Invalid {}: {}", key, number))),
+        },
+        ("$numberDouble", Value::String(number)) => match number.parse::<f64>() {
+            Ok(number) => Number::from_f64(number).map_or(Value::Null, Value::Number),
+            Err(_) => return Err(invalid_data(format!("Invalid {}: {}", key, number))),
+        },
+        ("$numberDecimal", Value::String(number)) => match number.parse::<f64>() {
+            Ok(float) if float.is_finite() => Value::from(float),
+            _ => Value::String(number.clone()),
+        },
+        ("$binary", Value::Object(binary)) => match binary.get("base64") {
+            Some(Value::String(base64)) => Value::String(base64.clone()),
+            _ => return Ok(None),
+        },
+        ("$timestamp", Value::Object(timestamp)) => match timestamp.get("t") {
+            Some(seconds @ Value::Number(_)) => seconds.clone(),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(converted))
+}
+
+/// `$date` holds an ISO 8601 string (relaxed), milliseconds since the epoch
+/// as `$numberLong` (canonical) or, from older exports, as a plain number
+fn convert_date(date: &Value) -> Result<String, io::Error> {
+    let millis = match date {
+        Value::String(date) => match DateTime::parse_from_rfc3339(date) {
+            Ok(date) => date.timestamp_millis(),
+            Err(_) => return Err(invalid_data(format!("Invalid $date: {}", date))),
+        },
+        Value::Number(millis) => match millis.as_i64() {
+            Some(millis) => millis,
+            None => return Err(invalid_data(format!("Invalid $date: {}", millis))),
+        },
+        Value::Object(wrapper) => match wrapper.get("$numberLong").and_then(Value::as_str) {
+            Some(millis) => millis
+                .parse()
+                .map_err(|_| invalid_data(format!("Invalid $date: {}", millis)))?,
+            None => return Err(invalid_data(format!("Invalid $date: {}", date))),
+        },
+        _ => return Err(invalid_data(format!("Invalid $date: {}", date))),
+    };
+
+    match DateTime::from_timestamp_millis(millis) {
+        Some(date) => Ok(date.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        None => Err(invalid_data(format!("$date out of range: {}", millis))),
+    }
+}
+
+fn invalid_data(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,495 @@
+/// MQTT bridge.
+///
+/// With `DUST_MQTT_BROKER` set (`<host>[:<port>]`, 1883 by default), the
+/// server connects to that MQTT broker as a client (MQTT 3.1.1,
+/// https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/mqtt-v3.1.1.html) and maps
+/// topics under `DUST_MQTT_TOPIC_PREFIX` (default `dustdb`) onto piles:
+///
+/// dustdb/<pile>/created        change events the server publishes, as
+/// dustdb/<pile>/updated        {"pile":..,"op":..,"id":..,"document":{..},
+/// dustdb/<pile>/deleted        "timestamp":..}
+/// dustdb/<pile>/create         a JSON document to store, as CREATE
+/// dustdb/<pile>/update/<uuid>  a JSON document to replace it with, as UPDATE
+/// dustdb/<pile>/delete/<uuid>  deletes it (the payload is ignored), as DELETE
+///
+/// Change events are published for the piles in `DUST_MQTT_PILES` (a comma
+/// separated list, default `*` for every pile but the users'), at QoS 0 and
+/// not retained; events of writes made while the broker is unreachable are
+/// dropped. Writes are only accepted for the piles in
+/// `DUST_MQTT_WRITABLE_PILES` (none by default), as MQTT clients don't
+/// authenticate against dustdb; they go through the same rules as the
+/// commands, and a rejected one is reported to the server's diagnostics.
+/// The server subscribes at QoS 1 in a persistent session, so writes sent
+/// while it's down are delivered once it's back, and acknowledges each once
+/// it's stored.
+///
+/// `DUST_MQTT_CLIENT_ID` (default `dustdb`), `DUST_MQTT_USERNAME` and
+/// `DUST_MQTT_PASSWORD` are used to connect, and the keep-alive interval is
+/// `DUST_MQTT_KEEP_ALIVE_SECS` (default 60). A lost connection is retried
+/// every 5 seconds.
+use crate::logging::{self, Level};
+use crate::payload::Encoding;
+use crate::users::USERS_PILE;
+use crate::webhooks::WebhookEvent;
+use crate::{create, delete, env_or, timestamp_now, update};
+use serde_json::{json, Value};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long a read waits for the broker before pending events go out
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xC0;
+
+/// Change events waiting to be published, as topic and payload. Only set
+/// while the bridge runs.
+static EVENTS: OnceLock<Mutex<mpsc::Sender<(String, String)>>> = OnceLock::new();
+
+struct Config {
+    addr: String,
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    keep_alive_secs: u16,
+    prefix: String,
+    writable_piles: Vec<String>,
+}
+
+fn pile_list(key: &str, default: &str) -> Vec<String> {
+    env_or(key, default.to_owned())
+        .split(',')
+        .map(|pile| pile.trim().to_lowercase())
+        .filter(|pile| !pile.is_empty())
+        .collect()
+}
+
+fn lists_pile(piles: &[String], pile_name: &str) -> bool {
+    pile_name != USERS_PILE && piles.iter().any(|pile| pile == "*" || pile == pile_name)
+}
+
+/// Starts the bridge on its own thread, if a broker is configured
+pub fn spawn_worker() {
+    let broker = match std::env::var("DUST_MQTT_BROKER") {
+        Ok(broker) if !broker.trim().is_empty() => broker.trim().to_owned(),
+        _ => return,
+    };
+    let addr = match broker.rsplit_once(':') {
+        Some((_, port)) if !port.contains(']') => broker,
+        _ => format!("{}:{}", broker, DEFAULT_MQTT_PORT),
+    };
+
+    let config = Config {
+        addr,
+        client_id: env_or("DUST_MQTT_CLIENT_ID", "dustdb".to_owned()),
+        username: std::env::var("DUST_MQTT_USERNAME").ok(),
+        password: std::env::var("DUST_MQTT_PASSWORD").ok(),
+        keep_alive_secs: env_or("DUST_MQTT_KEEP_ALIVE_SECS", 60u16).max(1),
+        prefix: env_or("DUST_MQTT_TOPIC_PREFIX", "dustdb".to_owned()),
+        writable_piles: pile_list("DUST_MQTT_WRITABLE_PILES", ""),
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    if EVENTS.set(Mutex::new(sender)).is_err() {
+        return;
+    }
+
+    thread::spawn(move || loop {
+        if let Err(e) = run_session(&config, &receiver) {
+            logging::diagnostic(
+                Level::Error,
+                &format!("MQTT connection to {} lost: {}", config.addr, e),
+            );
+        }
+
+        // Events of writes made while disconnected are dropped
+        thread::sleep(RECONNECT_DELAY);
+        while receiver.try_recv().is_ok() {}
+    });
+}
+
+/// Queues the write's change event, if the bridge runs and publishes the
+/// pile's events
+pub fn publish(event: WebhookEvent, pile_name: &str, uuid: &str, document: Option<&Value>) {
+    let events = match EVENTS.get() {
+        Some(events) => events,
+        None => return,
+    };
+    if !lists_pile(&pile_list("DUST_MQTT_PILES", "*"), pile_name) {
+        return;
+    }
+
+    let topic = format!(
+        "{}/{}/{}",
+        env_or("DUST_MQTT_TOPIC_PREFIX", "dustdb".to_owned()),
+        pile_name,
+        match event {
+            WebhookEvent::Create => "created",
+            WebhookEvent::Update => "updated",
+            WebhookEvent::Delete => "deleted",
+        }
+    );
+    let payload = json!({
+        "pile": pile_name,
+        "op": event.as_str(),
+        "id": uuid,
+        "document": document,
+        "timestamp": timestamp_now(),
+    })
+    .to_string();
+
+    // Failing only means the bridge thread is gone
+    let _ = events
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .send((topic, payload));
+}
+
+fn run_session(
+    config: &Config,
+    events: &mpsc::Receiver<(String, String)>,
+) -> Result<(), io::Error> {
+    let addr = match config.addr.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = format!("Could not resolve MQTT broker: \"{}\"", config.addr);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+    let keep_alive = Duration::from_secs(config.keep_alive_secs as u64);
+    let mut stream = TcpStream::connect_timeout(&addr, keep_alive)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_write_timeout(Some(keep_alive))?;
+
+    // CONNECT, keeping the session (and with it the subscriptions and the
+    // writes queued for them) across reconnects
+    let mut flags = 0u8;
+    let mut payload = mqtt_string(&config.client_id);
+    if let Some(ref username) = config.username {
+        flags |= 0x80;
+        payload.extend(mqtt_string(username));
+    }
+    if let Some(ref password) = config.password {
+        flags |= 0x40;
+        payload.extend(mqtt_string(password));
+    }
+    let mut body = mqtt_string("MQTT");
+    body.extend([4, flags]);
+    body.extend(config.keep_alive_secs.to_be_bytes());
+    body.extend(payload);
+    stream.write_all(&packet(CONNECT, &body))?;
+
+    let mut buffer = Vec::new();
+    let (header, body) = read_packet(&mut stream, &mut buffer, keep_alive)?;
+    if header & 0xF0 != CONNACK || body.get(1) != Some(&0) {
+        let e_kind = io::ErrorKind::ConnectionRefused;
+        let e = format!("MQTT broker refused the connection (CONNACK {:?})", body);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let filters = ["+/create", "+/update/+", "+/delete/+"];
+    let mut body = 1u16.to_be_bytes().to_vec();
+    for filter in filters {
+        body.extend(mqtt_string(&format!("{}/{}", config.prefix, filter)));
+        body.push(1);
+    }
+    stream.write_all(&packet(SUBSCRIBE, &body))?;
+    logging::diagnostic(
+        Level::Info,
+        &format!("MQTT bridge connected to {}", config.addr),
+    );
+
+    let mut last_sent = Instant::now();
+    let mut last_received = Instant::now();
+    loop {
+        while let Ok((topic, payload)) = events.try_recv() {
+            let mut body = mqtt_string(&topic);
+            body.extend(payload.as_bytes());
+            stream.write_all(&packet(PUBLISH, &body))?;
+            last_sent = Instant::now();
+        }
+
+        if let Some((header, body)) = try_read_packet(&mut stream, &mut buffer)? {
+            last_received = Instant::now();
+            if header & 0xF0 == PUBLISH {
+                let qos = (header >> 1) & 0x03;
+                let (topic, packet_id, payload) = parse_publish(&body, qos)?;
+                apply_write(config, &topic, &payload);
+                if let (1, Some(packet_id)) = (qos, packet_id) {
+                    stream.write_all(&packet(PUBACK, &packet_id.to_be_bytes()))?;
+                    last_sent = Instant::now();
+                }
+            }
+        }
+
+        if last_sent.elapsed() >= keep_alive / 2 {
+            stream.write_all(&packet(PINGREQ, &[]))?;
+            last_sent = Instant::now();
+        }
+        if last_received.elapsed() > keep_alive * 3 / 2 {
+            let e_kind = io::ErrorKind::TimedOut;
+            let e = "MQTT broker stopped answering".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    }
+}
+
+/// Stores what a client published under a write topic
+fn apply_write(config: &Config, topic: &str, payload: &[u8]) {
+    let path = topic
+        .strip_prefix(&config.prefix)
+        .and_then(|path| path.strip_prefix('/'))
+        .unwrap_or_default();
+    let levels: Vec<&str> = path.split('/').collect();
+    let pile_name = levels.first().copied().unwrap_or_default().to_lowercase();
+    if !lists_pile(&config.writable_piles, &pile_name) {
+        logging::diagnostic(
+            Level::Error,
+            &format!("Ignoring MQTT write to non-writable pile: {}", topic),
+        );
+        return;
+    }
+
+    let data = String::from_utf8_lossy(payload);
+    let written = match levels.as_slice() {
+        [_, "create"] => create(&pile_name, None, &data, Encoding::Json).map(|_| ()),
+        [_, "update", uuid] => update(&pile_name, uuid, &data, None, Encoding::Json),
+        [_, "delete", uuid] => delete(&pile_name, uuid),
+        _ => return,
+    };
+    if let Err(e) = written {
+        logging::diagnostic(
+            Level::Error,
+            &format!("Error applying MQTT write to {}: {}", topic, e),
+        );
+    }
+}
+
+fn parse_publish(body: &[u8], qos: u8) -> Result<(String, Option<u16>, Vec<u8>), io::Error> {
+    let malformed = || {
+        let e_kind = io::ErrorKind::InvalidData;
+        let e = "Malformed MQTT PUBLISH packet".to_owned();
+        io::Error::new(e_kind, e)
+    };
+
+    let topic_length = u16::from_be_bytes([
+        *body.first().ok_or_else(malformed)?,
+        *body.get(1).ok_or_else(malformed)?,
+    ]) as usize;
+    let topic = body.get(2..2 + topic_length).ok_or_else(malformed)?;
+    let mut offset = 2 + topic_length;
+
+    let packet_id = match qos {
+        0 => None,
+        _ => {
+            let id = body.get(offset..offset + 2).ok_or_else(malformed)?;
+            offset += 2;
+            Some(u16::from_be_bytes([id[0], id[1]]))
+        }
+    };
+
+    Ok((
+        String::from_utf8_lossy(topic).into_owned(),
+        packet_id,
+        body[offset..].to_vec(),
+    ))
+}
+
+/// A length prefixed UTF-8 string
+fn mqtt_string(string: &str) -> Vec<u8> {
+    let mut bytes = (string.len() as u16).to_be_bytes().to_vec();
+    bytes.extend(string.as_bytes());
+    bytes
+}
+
+/// The fixed header (with its variable length remaining length) and body
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![header];
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+    bytes.extend(body);
+    bytes
+}
+
+/// Takes a complete packet off the front of the buffer, if there is one
+fn take_packet(buffer: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>, io::Error> {
+    let mut remaining = 0usize;
+    let mut offset = 1;
+    loop {
+        let byte = match buffer.get(offset) {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+        remaining += ((byte & 0x7F) as usize) << (7 * (offset - 1));
+        offset += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if offset > 4 {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = "Malformed MQTT packet length".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    }
+
+    if buffer.len() < offset + remaining {
+        return Ok(None);
+    }
+    let header = buffer[0];
+    let body = buffer[offset..offset + remaining].to_vec();
+    buffer.drain(..offset + remaining);
+    Ok(Some((header, body)))
+}
+
+/// Reads what the broker sent within the poll interval, returning the next
+/// complete packet if there is one
+fn try_read_packet(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<(u8, Vec<u8>)>, io::Error> {
+    if let Some(packet) = take_packet(buffer)? {
+        return Ok(Some(packet));
+    }
+
+    let mut chunk = [0u8; 4096];
+    match stream.read(&mut chunk) {
+        Ok(0) => {
+            let e_kind = io::ErrorKind::UnexpectedEof;
+            let e = "MQTT broker closed the connection".to_owned();
+            Err(io::Error::new(e_kind, e))
+        }
+        Ok(read) => {
+            buffer.extend_from_slice(&chunk[..read]);
+            take_packet(buffer)
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn read_packet(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<(u8, Vec<u8>), io::Error> {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if let Some(packet) = try_read_packet(stream, buffer)? {
+            return Ok(packet);
+        }
+    }
+
+    let e_kind = io::ErrorKind::TimedOut;
+    let e = "MQTT broker didn't answer".to_owned();
+    Err(io::Error::new(e_kind, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_remaining_lengths_of_1_to_4_bytes() {
+        let cases: [(usize, &[u8]); 7] = [
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (321, &[0xC1, 0x02]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+        ];
+        for (length, encoded) in cases {
+            let body = vec![0xAB; length];
+            let mut buffer = packet(PUBLISH, &body);
+            assert_eq!(buffer[0], PUBLISH);
+            assert_eq!(&buffer[1..1 + encoded.len()], encoded, "{}", length);
+
+            // Followed by the start of the next packet, which stays behind
+            buffer.extend([PINGREQ, 0x00]);
+            let taken = take_packet(&mut buffer).unwrap();
+            assert!(taken == Some((PUBLISH, body)), "{}", length);
+            assert_eq!(buffer, vec![PINGREQ, 0x00]);
+        }
+    }
+
+    #[test]
+    fn waits_for_incomplete_packets() {
+        for partial in [
+            vec![PUBLISH],
+            vec![PUBLISH, 0x80],
+            vec![PUBLISH, 0x03, 0x00],
+        ] {
+            let mut buffer = partial.clone();
+            assert!(take_packet(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer, partial);
+        }
+
+        // The largest length there is, 4 bytes long
+        let mut buffer = vec![PUBLISH, 0xFF, 0xFF, 0xFF, 0x7F];
+        assert!(take_packet(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_a_5_byte_remaining_length() {
+        let mut buffer = vec![PUBLISH, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        let e = take_packet(&mut buffer).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_publish_packets() {
+        let mut body = mqtt_string("dust/users");
+        body.extend(b"{\"a\":1}");
+        let (topic, packet_id, payload) = parse_publish(&body, 0).unwrap();
+        assert_eq!(topic, "dust/users");
+        assert_eq!(packet_id, None);
+        assert_eq!(payload, b"{\"a\":1}");
+
+        let mut body = mqtt_string("dust/users");
+        body.extend([0x12, 0x34]);
+        body.extend(b"{\"a\":1}");
+        let (topic, packet_id, payload) = parse_publish(&body, 1).unwrap();
+        assert_eq!(topic, "dust/users");
+        assert_eq!(packet_id, Some(0x1234));
+        assert_eq!(payload, b"{\"a\":1}");
+
+        // Cut off in the topic, and before a QoS 1 packet ID
+        for (body, qos) in [
+            (vec![0x00], 0),
+            (vec![0x00, 0x05, b'd'], 0),
+            (mqtt_string("t"), 1),
+        ] {
+            let e = parse_publish(&body, qos).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,544 @@
+/// Ordered indexes over declared fields.
+///
+/// `ORDERED <pile> <field>` keeps the pile's documents sorted by the field,
+/// so a predicate (or FIND) that bounds it only reads the documents within
+/// the bounds instead of scanning the whole pile:
+///
+/// age >= 18 AND age < 65     a range seek
+/// name STARTSWITH Mat        a seek over the names starting with "Mat"
+/// status IN (active,trial)   a lookup per value, like FIND on the field
+///
+/// Numbers sort before strings, each in their natural order. A document
+/// whose field is missing or holds anything else isn't indexed, which no
+/// bound can match anyway. Case insensitive queries can't seek.
+///
+/// Each index is a sorted key file next to the pile's documents,
+/// `.ordered.<field, hex encoded>.jsonl`, holding one `[value,"<uuid>"]`
+/// per line. It's loaded (or, without one, built by a scan) the first time
+/// it's needed and from then on kept up to date in memory by every write.
+/// The first write to a pile since its key files were saved removes them
+/// before the write lands, so a key file is never behind the documents; the
+/// janitor saves them again (see janitor.rs).
+use crate::cache::read_document;
+use crate::logging::{self, Level};
+use crate::pile::{self, document_paths, pile_path, PileMeta};
+use crate::query::Range;
+use crate::{memory, quarantine};
+use serde_json::{from_str, json, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Rough footprint of one document in an index: its key and UUID, held once
+/// in the sorted entries and once more to find them again
+const ENTRY_BYTES: usize = 192;
+
+/// A field value as the index sorts it
+#[derive(Clone)]
+enum Key {
+    Number(f64),
+    String(String),
+}
+
+impl Key {
+    fn of(value: &Value) -> Option<Key> {
+        match value {
+            // Adding 0.0 turns -0.0 into 0.0, which queries compare as equal
+            Value::Number(number) => Some(Key::Number(number.as_f64()? + 0.0)),
+            Value::String(text) => Some(Key::String(text.clone())),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Key::Number(number) => json!(number),
+            Key::String(text) => json!(text),
+        }
+    }
+
+    fn is_same_type(&self, other: &Key) -> bool {
+        matches!(
+            (self, other),
+            (Key::Number(_), Key::Number(_)) | (Key::String(_), Key::String(_))
+        )
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        match (self, other) {
+            (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+            (Key::Number(_), Key::String(_)) => Ordering::Less,
+            (Key::String(_), Key::Number(_)) => Ordering::Greater,
+            (Key::String(a), Key::String(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+struct OrderedIndex {
+    entries: BTreeSet<(Key, String)>,
+    keys: HashMap<String, Key>,
+    is_saved: bool,
+}
+
+impl OrderedIndex {
+    fn new() -> OrderedIndex {
+        OrderedIndex {
+            entries: BTreeSet::new(),
+            keys: HashMap::new(),
+            is_saved: false,
+        }
+    }
+
+    /// Moves a document to its new key, or out of the index without one
+    fn set(&mut self, uuid: &str, key: Option<Key>) {
+        if let Some(old_key) = self.keys.remove(uuid) {
+            self.entries.remove(&(old_key, uuid.to_owned()));
+        }
+        if let Some(key) = key {
+            self.entries.insert((key.clone(), uuid.to_owned()));
+            self.keys.insert(uuid.to_owned(), key);
+        }
+    }
+
+    /// The documents from `from` on, for as long as their keys are within
+    fn seek(&self, from: Bound<&Key>, is_within: impl Fn(&Key) -> bool) -> Vec<String> {
+        let start = match from {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                Bound::Included((key.clone(), String::new()))
+            }
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        self.entries
+            .range((start, Bound::Unbounded))
+            .skip_while(|(key, _)| matches!(from, Bound::Excluded(from) if key == from))
+            .take_while(|(key, _)| is_within(key))
+            .map(|(_, uuid)| uuid.clone())
+            .collect()
+    }
+
+    /// The documents whose field may be in the range (queries check the
+    /// documents themselves afterwards)
+    fn lookup(&self, range: &Range) -> Vec<String> {
+        match *range {
+            Range::AnyOf(literals) => {
+                let mut uuids = Vec::new();
+                for literal in literals {
+                    // An equality also matches a string holding the value's text
+                    let mut keys = vec![Key::String(literal.text().to_owned())];
+                    keys.extend(Key::of(literal.value()).filter(|key| *key != keys[0]));
+                    for key in keys {
+                        uuids.extend(self.seek(Bound::Included(&key), |other| *other == key));
+                    }
+                }
+                uuids
+            }
+            Range::Prefix(prefix) => self.seek(
+                Bound::Included(&Key::String(prefix.to_owned())),
+                |key| matches!(key, Key::String(text) if text.starts_with(prefix)),
+            ),
+            Range::Between(lower, upper) => {
+                let lower = match lower {
+                    Bound::Included(value) => Key::of(value).map(Bound::Included),
+                    Bound::Excluded(value) => Key::of(value).map(Bound::Excluded),
+                    Bound::Unbounded => Some(Bound::Unbounded),
+                };
+                let upper = match upper {
+                    Bound::Included(value) => Key::of(value).map(Bound::Included),
+                    Bound::Excluded(value) => Key::of(value).map(Bound::Excluded),
+                    Bound::Unbounded => Some(Bound::Unbounded),
+                };
+                let (lower, upper) = match (lower, upper) {
+                    (Some(lower), Some(upper)) => (lower, upper),
+                    // Only numbers and strings can be ordered
+                    _ => return Vec::new(),
+                };
+
+                // Numbers are only ordered against numbers, strings against
+                // strings, so the range never leaves the bounds' type
+                let bound_type = match (&lower, &upper) {
+                    (Bound::Included(key) | Bound::Excluded(key), _)
+                    | (_, Bound::Included(key) | Bound::Excluded(key)) => key.clone(),
+                    _ => return self.keys.keys().cloned().collect(),
+                };
+                let floor = match bound_type {
+                    Key::Number(_) => Key::Number(f64::NEG_INFINITY),
+                    Key::String(_) => Key::String(String::new()),
+                };
+                let from = match lower {
+                    Bound::Included(ref key) => Bound::Included(key),
+                    Bound::Excluded(ref key) => Bound::Excluded(key),
+                    Bound::Unbounded => Bound::Included(&floor),
+                };
+
+                self.seek(from, |key| {
+                    key.is_same_type(&bound_type)
+                        && match upper {
+                            Bound::Included(ref upper) => key <= upper,
+                            Bound::Excluded(ref upper) => key < upper,
+                            Bound::Unbounded => true,
+                        }
+                })
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.keys.len() * ENTRY_BYTES
+    }
+}
+
+struct Indexes {
+    loaded: HashMap<(String, String), OrderedIndex>,
+    /// Piles known to have no key files on disk, which writes needn't remove
+    files_removed: HashSet<String>,
+}
+
+fn indexes() -> &'static Mutex<Indexes> {
+    static INDEXES: OnceLock<Mutex<Indexes>> = OnceLock::new();
+    INDEXES.get_or_init(|| {
+        Mutex::new(Indexes {
+            loaded: HashMap::new(),
+            files_removed: HashSet::new(),
+        })
+    })
+}
+
+/// Field names come straight from clients, so they are hex encoded to keep
+/// them from reaching outside the pile's directory
+fn key_file_path(pile_name: &str, field_name: &str) -> Result<PathBuf, io::Error> {
+    let encoded_field: String = field_name
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(Path::new(&pile_path(pile_name)?).join(format!(".ordered.{}.jsonl", encoded_field)))
+}
+
+/// The IDs of the documents whose `field_name` may be in the range, sorted.
+/// The index is loaded (or built) when missing.
+pub fn lookup(pile_name: &str, field_name: &str, range: &Range) -> Result<Vec<String>, io::Error> {
+    let key = (pile_name.to_owned(), field_name.to_owned());
+    if let Some(index) = indexes()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .loaded
+        .get(&key)
+    {
+        return Ok(sorted(index.lookup(range)));
+    }
+
+    // Hold the pile's write lock while loading so no write can slip in
+    // between reading the pile and the index going live
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut index = load(pile_name, field_name)?;
+    let uuids = sorted(index.lookup(range));
+
+    let mut indexes = indexes().lock().unwrap_or_else(|e| e.into_inner());
+    if !index.is_saved {
+        save(&mut indexes, pile_name, field_name, &mut index)?;
+    }
+    indexes.loaded.insert(key, index);
+    drop(indexes);
+    memory::enforce();
+
+    Ok(uuids)
+}
+
+fn sorted(mut uuids: Vec<String>) -> Vec<String> {
+    uuids.sort_unstable();
+    uuids.dedup();
+    uuids
+}
+
+/// Reads the key file, or builds the index by scanning the pile without one
+fn load(pile_name: &str, field_name: &str) -> Result<OrderedIndex, io::Error> {
+    let mut index = OrderedIndex::new();
+
+    match fs::read_to_string(key_file_path(pile_name, field_name)?) {
+        Ok(file_content) => {
+            for line in file_content.lines() {
+                let (value, uuid): (Value, String) = from_str(line)?;
+                index.set(&uuid, Key::of(&value));
+            }
+            index.is_saved = true;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            for file_path in document_paths(pile_name)? {
+                let uuid = file_path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or_default()
+                    .to_owned();
+                let document = match read_document(&file_path) {
+                    Ok(document) => document,
+                    Err(e) if quarantine::is_corrupt(&e) => {
+                        quarantine::report(&file_path, &e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                index.set(&uuid, document.json.get(field_name).and_then(Key::of));
+            }
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(index)
+}
+
+/// Writes the key file, swapped in with a rename like pile metadata. Must be
+/// called while holding the pile's write lock.
+fn save(
+    indexes: &mut Indexes,
+    pile_name: &str,
+    field_name: &str,
+    index: &mut OrderedIndex,
+) -> Result<(), io::Error> {
+    // An empty index is as quick to build as to load, and a key file would
+    // keep an emptied pile's directory from being cleaned up
+    if !index.entries.is_empty() {
+        let key_file_path = key_file_path(pile_name, field_name)?;
+        let mut tmp_path = key_file_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let lines: Vec<String> = index
+            .entries
+            .iter()
+            .map(|(key, uuid)| json!([key.to_json(), uuid]).to_string())
+            .collect();
+        fs::write(&tmp_path, lines.join("\n"))?;
+        fs::rename(tmp_path, key_file_path)?;
+        indexes.files_removed.remove(pile_name);
+    }
+
+    index.is_saved = true;
+    Ok(())
+}
+
+/// Removes the pile's key files, unless they are known to be gone already
+fn remove_files(indexes: &mut Indexes, pile_name: &str) -> Result<(), io::Error> {
+    if indexes.files_removed.contains(pile_name) {
+        return Ok(());
+    }
+
+    for field_name in PileMeta::load(pile_name)?.ordered_fields() {
+        match fs::remove_file(key_file_path(pile_name, &field_name)?) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    indexes.files_removed.insert(pile_name.to_owned());
+    Ok(())
+}
+
+/// Called before any document of the pile is written, removed or restored
+pub fn before_write(pile_name: &str) -> Result<(), io::Error> {
+    let mut indexes = indexes().lock().unwrap_or_else(|e| e.into_inner());
+    remove_files(&mut indexes, pile_name)
+}
+
+/// Drops the pile's loaded indexes and removes its key files (e.g. after they
+/// turned out wrong), so they're rebuilt the next time they're needed. Must
+/// be called while holding the pile's write lock.
+pub fn discard(pile_name: &str) -> Result<(), io::Error> {
+    let mut indexes = indexes().lock().unwrap_or_else(|e| e.into_inner());
+    indexes
+        .loaded
+        .retain(|(index_pile, _), _| index_pile != pile_name);
+    indexes.files_removed.remove(pile_name);
+    remove_files(&mut indexes, pile_name)
+}
+
+/// Compares the field's key file with `documents`, the fields of every
+/// document in the pile (`None` for those that couldn't be read), returning
+/// how many entries are wrong or missing. `None` if there's no key file to
+/// compare, or it was saved after `since`, when the documents were read.
+pub fn check_key_file(
+    pile_name: &str,
+    field_name: &str,
+    documents: &HashMap<String, Option<serde_json::Map<String, Value>>>,
+    since: SystemTime,
+) -> Result<Option<usize>, io::Error> {
+    let key_file_path = key_file_path(pile_name, field_name)?;
+    let file_content = {
+        // Writes remove the key file, so one older than the documents read
+        // means none of them changed since
+        let pile_lock = pile::lock(pile_name);
+        let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+        match fs::metadata(&key_file_path) {
+            Ok(metadata) if metadata.modified()? <= since => (),
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        fs::read_to_string(&key_file_path)?
+    };
+
+    let expected_key = |fields: &serde_json::Map<String, Value>| -> Option<Key> {
+        fields.get(field_name).and_then(Key::of)
+    };
+
+    let mut wrong_entries = 0;
+    let mut indexed = HashSet::new();
+    for line in file_content.lines() {
+        let (value, uuid): (Value, String) = match from_str(line) {
+            Ok(entry) => entry,
+            Err(_) => {
+                wrong_entries += 1;
+                continue;
+            }
+        };
+
+        let is_wrong = match documents.get(&uuid) {
+            None => true,
+            Some(None) => false,
+            Some(Some(fields)) => expected_key(fields) != Key::of(&value),
+        };
+        if is_wrong {
+            wrong_entries += 1;
+        }
+        indexed.insert(uuid);
+    }
+
+    for (uuid, fields) in documents {
+        let should_be_indexed = fields.as_ref().and_then(expected_key).is_some();
+        if should_be_indexed && !indexed.contains(uuid) {
+            wrong_entries += 1;
+        }
+    }
+
+    Ok(Some(wrong_entries))
+}
+
+/// Moves a document within the pile's live indexes once its write has
+/// landed; `document` gives its new content, `None` once it's gone. The
+/// content is only read if the pile has an index loaded.
+pub fn record(pile_name: &str, uuid: &str, document: impl FnOnce() -> Option<Value>) {
+    let mut indexes = indexes().lock().unwrap_or_else(|e| e.into_inner());
+
+    // The janitor may have saved the key files since `before_write`
+    if let Err(e) = remove_files(&mut indexes, pile_name) {
+        logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Error removing ordered indexes of pile \"{}\": {:?}",
+                pile_name, e
+            ),
+        );
+    }
+
+    let field_names: Vec<String> = indexes
+        .loaded
+        .keys()
+        .filter(|(index_pile, _)| index_pile == pile_name)
+        .map(|(_, field_name)| field_name.clone())
+        .collect();
+    if field_names.is_empty() {
+        return;
+    }
+
+    let document = document();
+    for field_name in field_names {
+        let key = document
+            .as_ref()
+            .and_then(|document| document.get(&field_name))
+            .and_then(Key::of);
+        if let Some(index) = indexes.loaded.get_mut(&(pile_name.to_owned(), field_name)) {
+            index.set(uuid, key);
+            index.is_saved = false;
+        }
+    }
+}
+
+/// Saves every index changed since it was last saved, returning how many
+pub fn save_all() -> Result<usize, io::Error> {
+    let unsaved: Vec<(String, String)> = indexes()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .loaded
+        .iter()
+        .filter(|(_, index)| !index.is_saved)
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    let mut saved = 0;
+    for (pile_name, field_name) in unsaved {
+        let pile_lock = pile::lock(&pile_name);
+        let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut indexes = indexes().lock().unwrap_or_else(|e| e.into_inner());
+        let key = (pile_name, field_name);
+        // Taken out while saving, as `save` needs the rest of the state too
+        if let Some(mut index) = indexes.loaded.remove(&key) {
+            let result = match index.is_saved {
+                true => Ok(()),
+                false => save(&mut indexes, &key.0, &key.1, &mut index),
+            };
+            indexes.loaded.insert(key, index);
+            result?;
+            saved += 1;
+        }
+    }
+
+    Ok(saved)
+}
+
+pub fn bytes() -> usize {
+    indexes()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .loaded
+        .values()
+        .map(OrderedIndex::size)
+        .sum()
+}
+
+/// Drops every loaded index; each is loaded again the next time it's needed
+/// (or rebuilt, if it changed since it was last saved)
+pub fn clear() {
+    indexes()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .loaded
+        .clear();
+}
+
+/// Drops the indexes of piles not in `pile_names` (e.g. piles removed since),
+/// returning how many were dropped
+pub fn retain_piles(pile_names: &[String]) -> usize {
+    let mut indexes = indexes().lock().unwrap_or_else(|e| e.into_inner());
+    indexes
+        .files_removed
+        .retain(|pile_name| pile_names.contains(pile_name));
+
+    let before = indexes.loaded.len();
+    indexes
+        .loaded
+        .retain(|(pile_name, _), _| pile_names.contains(pile_name));
+    before - indexes.loaded.len()
+}
@@ -0,0 +1,110 @@
+/// Payload encodings.
+///
+/// The protocol is line based, so payloads (documents, JSONL, CSV) have to be
+/// sent without raw newlines. They are hex encoded by default, which is safe
+/// for any text but doubles its size. A request can instead pick
+///
+/// BASE64  standard base64 (with padding), a third larger than the text
+/// JSON    documents as plain JSON, several documents as one JSON array
+///
+/// by prefixing the command (after any AUTH) with
+/// `ENCODING <HEX|BASE64|JSON>`:
+///
+/// ENCODING JSON CREATE users {"name":"matthew"}
+///
+/// The response payload uses the same encoding as the request. Without the
+/// prefix, `DUST_PAYLOAD_ENCODING` (default HEX) applies. Compact JSON never
+/// contains a raw newline, so it is line safe as is; payloads that aren't
+/// JSON (the CSV of IMPORTCSV) stay hex encoded in JSON mode.
+use crate::env_or;
+use base64ct::{Base64, Encoding as _};
+use dustcfg::{decode_hex_to_utf8, encode_utf8_to_hex};
+use serde_json::{from_str, Value};
+use std::io;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Hex,
+    Base64,
+    Json,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Encoding, String> {
+        match input {
+            "HEX" => Ok(Encoding::Hex),
+            "BASE64" => Ok(Encoding::Base64),
+            "JSON" => Ok(Encoding::Json),
+            _ => Err(format!("Unknown payload encoding: {}", input)),
+        }
+    }
+}
+
+impl Encoding {
+    /// Encodes a single JSON document
+    #[tracing::instrument(name = "serialize", skip_all)]
+    pub fn encode(&self, json: &str) -> String {
+        match self {
+            Encoding::Hex => encode_utf8_to_hex(json),
+            Encoding::Base64 => Base64::encode_string(json.as_bytes()),
+            Encoding::Json => json.to_owned(),
+        }
+    }
+
+    /// Encodes JSONL, which in JSON mode is sent as an array of its lines
+    #[tracing::instrument(name = "serialize", skip_all)]
+    pub fn encode_jsonl(&self, jsonl: &str) -> String {
+        match self {
+            Encoding::Hex => encode_utf8_to_hex(jsonl),
+            Encoding::Base64 => Base64::encode_string(jsonl.as_bytes()),
+            Encoding::Json => format!("[{}]", jsonl.lines().collect::<Vec<&str>>().join(",")),
+        }
+    }
+
+    /// Decodes a single JSON document
+    pub fn decode(&self, payload: &str) -> Result<String, io::Error> {
+        match self {
+            Encoding::Hex | Encoding::Base64 => self.decode_text(payload),
+            Encoding::Json => Ok(payload.to_owned()),
+        }
+    }
+
+    /// Decodes JSONL, accepting an array of documents in JSON mode
+    pub fn decode_jsonl(&self, payload: &str) -> Result<String, io::Error> {
+        match self {
+            Encoding::Hex | Encoding::Base64 => self.decode_text(payload),
+            Encoding::Json => match from_str(payload)? {
+                Value::Array(documents) => Ok(documents
+                    .iter()
+                    .map(Value::to_string)
+                    .collect::<Vec<String>>()
+                    .join("\n")),
+                _ => {
+                    let e_kind = io::ErrorKind::InvalidData;
+                    let e = "Expected a JSON array of documents".to_owned();
+                    Err(io::Error::new(e_kind, e))
+                }
+            },
+        }
+    }
+
+    /// Decodes a payload that isn't JSON (e.g. CSV)
+    pub fn decode_text(&self, payload: &str) -> Result<String, io::Error> {
+        match self {
+            Encoding::Base64 => {
+                let bytes = Base64::decode_vec(payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Encoding::Hex | Encoding::Json => decode_hex_to_utf8(payload),
+        }
+    }
+}
+
+/// The encoding of commands without an `ENCODING` prefix
+pub fn default_encoding() -> Encoding {
+    env_or("DUST_PAYLOAD_ENCODING", Encoding::Hex)
+}
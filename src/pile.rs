@@ -0,0 +1,393 @@
+/// Pile level metadata.
+///
+/// Each pile directory may hold a `.pile.json` file next to its documents,
+/// carrying settings that apply to the whole pile (e.g. its JSON Schema).
+/// Files starting with a `.` are never treated as documents.
+///
+/// The file is the pile's manifest, which `DESCRIBE <pile>` reports on. It is
+/// written along with the pile's first document (when its statistics are
+/// first recorded, see stats.rs), and records when that was under
+/// `created_at`. Piles from before manifests record when their metadata was
+/// first saved instead.
+use crate::ids::IdScheme;
+use crate::sinks::Sink;
+use crate::timestamp_now;
+use crate::triggers::Trigger;
+use crate::webhooks::Webhook;
+use dustcfg::{generate_v4_uuid, get_env_var};
+use serde_json::{from_str, json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+const META_FILE_NAME: &str = ".pile.json";
+pub const TOMBSTONE_EXTENSION: &str = "tombstone";
+const MAX_PILE_NAME_LENGTH: usize = 64;
+
+/// What happens to referencing documents when the referenced one is deleted
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnDelete {
+    Ignore,
+    Restrict,
+    Cascade,
+}
+
+impl OnDelete {
+    pub fn parse(input: &str) -> Option<OnDelete> {
+        match input {
+            "IGNORE" => Some(OnDelete::Ignore),
+            "RESTRICT" => Some(OnDelete::Restrict),
+            "CASCADE" => Some(OnDelete::Cascade),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            OnDelete::Ignore => "IGNORE",
+            OnDelete::Restrict => "RESTRICT",
+            OnDelete::Cascade => "CASCADE",
+        }
+    }
+}
+
+/// A field whose value (a UUID, or an array of UUIDs) must name an existing
+/// document in another pile
+pub struct Reference {
+    pub field: String,
+    pub pile: String,
+    pub on_delete: OnDelete,
+}
+
+impl Reference {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "field": self.field,
+            "pile": self.pile,
+            "on_delete": self.on_delete.as_str(),
+        })
+    }
+
+    /// The UUIDs a document refers to through this reference
+    pub fn referenced_uuids<'a>(&self, json_content: &'a Value) -> Vec<&'a str> {
+        match json_content.get(&self.field) {
+            Some(Value::String(uuid)) => vec![uuid.as_str()],
+            Some(Value::Array(uuids)) => uuids.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub struct PileMeta {
+    pile_name: String,
+    fields: Map<String, Value>,
+}
+
+impl PileMeta {
+    /// Loads the metadata of a pile, which is empty if none was ever saved
+    pub fn load(pile_name: &str) -> Result<PileMeta, io::Error> {
+        let meta_path = Path::new(&pile_path(pile_name)?).join(META_FILE_NAME);
+
+        let fields = match fs::read_to_string(meta_path) {
+            Ok(file_content) => match from_str(&file_content)? {
+                Value::Object(fields) => fields,
+                _ => {
+                    let e_kind = io::ErrorKind::InvalidData;
+                    let e = format!("Metadata of pile \"{}\" is not an object", pile_name);
+                    return Err(io::Error::new(e_kind, e));
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Map::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(PileMeta {
+            pile_name: pile_name.to_owned(),
+            fields,
+        })
+    }
+
+    /// Writes the metadata next to the pile's documents, creating the pile if
+    /// needed. The file is swapped in with a rename so concurrent readers never
+    /// see a partially written file. Callers load, change and save under the
+    /// pile's lock, so no other change is lost in between.
+    pub fn save(&self) -> Result<(), io::Error> {
+        let pile_path = pile_path(&self.pile_name)?;
+        fs::create_dir_all(&pile_path)?;
+
+        let mut fields = self.fields.clone();
+        fields
+            .entry("created_at")
+            .or_insert_with(|| json!(timestamp_now()));
+
+        let meta_path = Path::new(&pile_path).join(META_FILE_NAME);
+        let tmp_path =
+            Path::new(&pile_path).join(format!("{}.{}.tmp", META_FILE_NAME, generate_v4_uuid()));
+        fs::write(&tmp_path, Value::Object(fields).to_string())?;
+        fs::rename(tmp_path, meta_path)
+    }
+
+    /// Saves an empty manifest for a pile that has none yet. A manifest saved
+    /// in the meantime, e.g. by a SCHEMA SET, is never replaced: the file is
+    /// linked into place, which fails if one is there already.
+    pub fn create(pile_name: &str) -> Result<(), io::Error> {
+        let pile_path = pile_path(pile_name)?;
+        let meta_path = Path::new(&pile_path).join(META_FILE_NAME);
+        if meta_path.exists() {
+            return Ok(());
+        }
+
+        let tmp_path =
+            Path::new(&pile_path).join(format!("{}.{}.tmp", META_FILE_NAME, generate_v4_uuid()));
+        let fields = json!({ "created_at": timestamp_now() });
+        fs::write(&tmp_path, fields.to_string())?;
+        let linked = fs::hard_link(&tmp_path, &meta_path);
+        fs::remove_file(&tmp_path)?;
+        match linked {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.fields.get(key)
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.fields.keys().cloned().collect()
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.fields.insert(key.to_owned(), value);
+    }
+
+    pub fn schema(&self) -> Option<&Value> {
+        self.get("schema")
+    }
+
+    pub fn defaults(&self) -> Option<&Map<String, Value>> {
+        self.get("defaults").and_then(Value::as_object)
+    }
+
+    /// `Some(purge_after_days)` when DELETE only tombstones documents in this
+    /// pile, where `None` days means tombstones are kept forever
+    pub fn soft_delete(&self) -> Option<Option<u64>> {
+        let soft_delete = self.get("soft_delete").and_then(Value::as_object)?;
+        Some(soft_delete.get("purge_after_days").and_then(Value::as_u64))
+    }
+
+    /// Fields that FIND consults a bloom filter for before scanning
+    pub fn bloom_fields(&self) -> Vec<String> {
+        match self.get("bloom").and_then(Value::as_array) {
+            Some(fields) => fields
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Fields the pile keeps an ordered index on (see ordered.rs)
+    pub fn ordered_fields(&self) -> Vec<String> {
+        match self.get("ordered").and_then(Value::as_array) {
+            Some(fields) => fields
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Fields whose values must be unique across the pile's documents
+    pub fn unique_fields(&self) -> Vec<String> {
+        match self.get("unique").and_then(Value::as_array) {
+            Some(fields) => fields
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn references(&self) -> Vec<Reference> {
+        let references = match self.get("references").and_then(Value::as_array) {
+            Some(references) => references,
+            None => return Vec::new(),
+        };
+
+        references
+            .iter()
+            .filter_map(|reference| {
+                Some(Reference {
+                    field: reference.get("field")?.as_str()?.to_owned(),
+                    pile: reference.get("pile")?.as_str()?.to_owned(),
+                    on_delete: reference
+                        .get("on_delete")
+                        .and_then(Value::as_str)
+                        .and_then(OnDelete::parse)
+                        .unwrap_or(OnDelete::Ignore),
+                })
+            })
+            .collect()
+    }
+
+    pub fn triggers(&self) -> Vec<Trigger> {
+        match self.get("triggers").and_then(Value::as_array) {
+            Some(triggers) => triggers.iter().filter_map(Trigger::from_json).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn webhooks(&self) -> Vec<Webhook> {
+        match self.get("webhooks").and_then(Value::as_array) {
+            Some(webhooks) => webhooks.iter().filter_map(Webhook::from_json).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn sinks(&self) -> Vec<Sink> {
+        match self.get("sinks").and_then(Value::as_array) {
+            Some(sinks) => sinks.iter().filter_map(Sink::from_json).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// How IDs of new documents are generated when the client doesn't pick one
+    pub fn id_scheme(&self) -> IdScheme {
+        self.get("id_scheme")
+            .and_then(Value::as_str)
+            .and_then(IdScheme::parse)
+            .unwrap_or(IdScheme::UuidV4)
+    }
+
+    /// Largest document (in bytes, as stored) the pile accepts, if the pile
+    /// overrides the server wide `DUST_MAX_DOCUMENT_BYTES`
+    pub fn max_document_bytes(&self) -> Option<usize> {
+        self.get("max_document_bytes")
+            .and_then(Value::as_u64)
+            .map(|max_bytes| max_bytes as usize)
+    }
+
+    /// Whether the server maintains `_created_at` / `_updated_at`
+    pub fn timestamps(&self) -> bool {
+        self.get("timestamps")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Whether documents in the pile can only be added, never changed or
+    /// removed
+    pub fn append_only(&self) -> bool {
+        self.get("append_only")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+}
+
+/// Returns the write lock of a pile. Writers that check constraints against
+/// the pile's current documents hold it until their own write has landed.
+pub fn lock(pile_name: &str) -> Arc<Mutex<()>> {
+    static PILE_LOCKS: OnceLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> = OnceLock::new();
+
+    let mut pile_locks = PILE_LOCKS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    pile_locks
+        .entry(pile_name.to_owned())
+        .or_insert_with(|| Arc::new(Mutex::new(())))
+        .clone()
+}
+
+/// Returns the directory of a pile. Pile names come straight from clients, so
+/// this is where they are checked: a name must be 1 to `MAX_PILE_NAME_LENGTH`
+/// lowercase letters, digits, `_` or `-` (system piles add a leading `.`), and
+/// the directory must not resolve (e.g. through a symlink) to anywhere outside
+/// of the storage root.
+pub fn pile_path(pile_name: &str) -> Result<String, io::Error> {
+    let base_name = pile_name.strip_prefix('.').unwrap_or(pile_name);
+    let is_valid_name = !base_name.is_empty()
+        && pile_name.len() <= MAX_PILE_NAME_LENGTH
+        && base_name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !is_valid_name {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid pile name: \"{}\"", pile_name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let storage_path = get_env_var("DUST_DATA_STORAGE_PATH");
+    let pile_path = format!("{}{}", storage_path, pile_name);
+
+    if let Ok(canonical_pile_path) = fs::canonicalize(&pile_path) {
+        if !canonical_pile_path.starts_with(fs::canonicalize(&storage_path)?) {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Pile \"{}\" is outside of the storage root", pile_name);
+            return Err(io::Error::new(e_kind, e));
+        }
+    }
+
+    Ok(pile_path)
+}
+
+/// Lists the paths of every document in a pile (sorted by UUID), skipping the
+/// pile's metadata and anything else that isn't a document
+pub fn document_paths(pile_name: &str) -> Result<Vec<PathBuf>, io::Error> {
+    files_with_extension(pile_name, &get_env_var("DUST_DATA_FMT"))
+}
+
+/// Lists the paths of every soft deleted document in a pile
+pub fn tombstone_paths(pile_name: &str) -> Result<Vec<PathBuf>, io::Error> {
+    files_with_extension(pile_name, TOMBSTONE_EXTENSION)
+}
+
+fn files_with_extension(pile_name: &str, extension: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let dir_path = Path::new(&pile_path);
+    if !dir_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut file_paths = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        let file_path = entry.path();
+        let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let has_extension = file_path.extension().is_some_and(|ext| ext == extension);
+        if !is_hidden && has_extension && entry.file_type()?.is_file() {
+            file_paths.push(file_path);
+        }
+    }
+    file_paths.sort();
+
+    Ok(file_paths)
+}
+
+/// Lists the names of every pile in the storage root
+pub fn pile_names() -> Result<Vec<String>, io::Error> {
+    let storage_path = get_env_var("DUST_DATA_STORAGE_PATH");
+    let dir_path = Path::new(&storage_path);
+    if !dir_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut pile_names = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if !file_name.starts_with('.') && entry.file_type()?.is_dir() {
+            pile_names.push(file_name);
+        }
+    }
+    pile_names.sort();
+
+    Ok(pile_names)
+}
@@ -0,0 +1,115 @@
+/// Prepared queries.
+///
+/// A query with numbered placeholders can be registered under a name once,
+/// and then run by name with just its arguments:
+///
+/// PREPARE open_orders COUNT orders status = $1 AND total >= $2
+/// EXEC open_orders open 100
+///
+/// Arguments are separated by spaces and each takes the place of one
+/// placeholder as a single word, so an argument can never change the shape
+/// of the query. Only queries (commands needing no more than READ rights)
+/// can be prepared, and a prepared query runs with the rights of whoever
+/// runs it, like any other command.
+///
+/// Prepared queries are shared by all connections and live in memory until
+/// the server stops, at most `DUST_MAX_PREPARED_QUERIES` (default 1024) of
+/// them; preparing an existing name replaces its query.
+use crate::env_or;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+fn prepared() -> &'static Mutex<HashMap<String, String>> {
+    static PREPARED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    PREPARED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn register(name: &str, template: &str) -> Result<(), String> {
+    let is_valid_name = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !is_valid_name {
+        return Err(format!("Invalid prepared query name: \"{}\"", name));
+    }
+
+    let mut prepared = prepared().lock().unwrap_or_else(|e| e.into_inner());
+    if !prepared.contains_key(name) && prepared.len() >= env_or("DUST_MAX_PREPARED_QUERIES", 1024) {
+        return Err("Too many prepared queries".to_owned());
+    }
+
+    prepared.insert(name.to_owned(), template.to_owned());
+    Ok(())
+}
+
+/// The query prepared under the name, with the arguments filled in
+pub fn expand_named(name: &str, args: &[&str]) -> Result<String, String> {
+    let template = match prepared()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+    {
+        Some(template) => template.clone(),
+        None => return Err(format!("No prepared query named \"{}\"", name)),
+    };
+
+    expand(&template, args)
+}
+
+/// Fills `$1`, `$2`, ... in with the arguments, which must be exactly as many
+/// as the highest placeholder
+pub fn expand(template: &str, args: &[&str]) -> Result<String, String> {
+    let expected_args = placeholder_count(template);
+    if args.len() != expected_args {
+        return Err(format!(
+            "Expected {} argument(s), got {}",
+            expected_args,
+            args.len()
+        ));
+    }
+
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((before, index, after)) = next_placeholder(rest) {
+        expanded.push_str(before);
+        expanded.push_str(args[index - 1]);
+        rest = after;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+pub fn placeholder_count(template: &str) -> usize {
+    let mut highest = 0;
+    let mut rest = template;
+    while let Some((_, index, after)) = next_placeholder(rest) {
+        highest = highest.max(index);
+        rest = after;
+    }
+
+    highest
+}
+
+/// Splits off the text before the next `$<number>` placeholder, the
+/// placeholder's number and the text after it
+fn next_placeholder(input: &str) -> Option<(&str, usize, &str)> {
+    let mut search_from = 0;
+    loop {
+        let dollar = search_from + input[search_from..].find('$')?;
+        let digits = input[dollar + 1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(input.len() - dollar - 1);
+
+        match input[dollar + 1..dollar + 1 + digits].parse::<usize>() {
+            Ok(index) if index > 0 => {
+                return Some((&input[..dollar], index, &input[dollar + 1 + digits..]))
+            }
+            // A lone `$` (or `$0`) is just text
+            _ => search_from = dollar + 1,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+/// Corrupt documents.
+///
+/// A document file that no longer parses (e.g. truncated by a failing disk,
+/// or edited by hand) doesn't take its pile down with it: FIND, queries and
+/// index builds skip it and carry on with the healthy documents, reporting
+/// it once to the server's diagnostics.
+///
+/// `REPAIR <pile>` then moves every corrupt document of the pile out of the
+/// way, into the pile's directory under `DUST_QUARANTINE_PATH` (default
+/// `quarantine`), along with its sidecar (see docmeta.rs), to be inspected,
+/// fixed and imported again. The pile's indexes and statistics forget it.
+/// Quarantining isn't logged in the WAL, so a restore brings back what the
+/// document last held when it was written.
+use crate::logging::{self, Level};
+use crate::pile::{self, document_paths, pile_path};
+use crate::{cache, consistency, env_or, ordered, results, stats};
+use chrono::Utc;
+use serde_json::{from_slice, json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// Corrupt documents already reported, so a scan a second doesn't flood the
+/// diagnostics
+fn reported() -> &'static Mutex<HashSet<PathBuf>> {
+    static REPORTED: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+    REPORTED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Whether reading a document failed because its content doesn't parse
+pub fn is_corrupt(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Reports a corrupt document being skipped, the first time it is
+pub fn report(file_path: &Path, e: &io::Error) {
+    let is_new = reported()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(file_path.to_path_buf());
+    if is_new {
+        logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Skipping corrupt document \"{}\" (quarantine it with REPAIR): {}",
+                file_path.display(),
+                e
+            ),
+        );
+    }
+}
+
+/// Example:
+/// in: REPAIR users
+/// out: {"documents":1204,"quarantined":["cd8abd45-..."]}
+pub fn repair(pile_name: &str) -> Result<Value, io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    let quarantine_dir =
+        PathBuf::from(env_or("DUST_QUARANTINE_PATH", "quarantine".to_owned())).join(pile_name);
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let file_paths = document_paths(pile_name)?;
+    let mut quarantined = Vec::new();
+    for file_path in &file_paths {
+        let uuid = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(uuid) => uuid.to_owned(),
+            None => continue,
+        };
+
+        let data = fs::read(file_path)?;
+        if from_slice::<Value>(&data).is_ok() {
+            continue;
+        }
+
+        // A document quarantined before keeps its earlier copy
+        fs::create_dir_all(&quarantine_dir)?;
+        let suffix = Utc::now().timestamp_millis();
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        ordered::before_write(pile_name)?;
+        move_file(
+            file_path,
+            &quarantine_dir.join(format!("{}.{}", suffix, file_name)),
+        )?;
+
+        let sidecar_path = Path::new(&pile_path).join(format!(".{}.meta.json", uuid));
+        if sidecar_path.exists() {
+            let sidecar_name = format!("{}.{}.meta.json", suffix, uuid);
+            move_file(&sidecar_path, &quarantine_dir.join(sidecar_name))?;
+        }
+
+        cache::invalidate(&file_path.to_string_lossy());
+        ordered::record(pile_name, &uuid, || None);
+        stats::record(pile_name, -1, -(data.len() as i64));
+        reported()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(file_path);
+        quarantined.push(uuid);
+    }
+
+    if !quarantined.is_empty() {
+        results::invalidate(pile_name);
+        consistency::commit();
+        logging::diagnostic(
+            Level::Info,
+            &format!(
+                "Quarantined {} corrupt document(s) of pile \"{}\" in {}",
+                quarantined.len(),
+                pile_name,
+                quarantine_dir.display()
+            ),
+        );
+    }
+
+    Ok(json!({
+        "documents": file_paths.len(),
+        "quarantined": quarantined,
+    }))
+}
+
+/// Renames the file, or copies it over when the quarantine is on another
+/// filesystem
+fn move_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    match fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+    }
+}
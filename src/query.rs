@@ -0,0 +1,714 @@
+/// Predicates over document fields.
+///
+/// A predicate is one or more conditions joined with AND / OR, where AND binds
+/// tighter than OR. NOT negates the condition or parenthesized group after
+/// it:
+///
+/// status active
+/// status = active AND age >= 18
+/// role admin OR role owner AND verified true
+/// NOT (status deleted OR status banned) AND verified true
+///
+/// Parentheses at either end of a value group conditions, so a value that
+/// starts or ends with one has to be quoted (`name = "(unknown)"`). NOTs and
+/// parentheses nest at most `MAX_NESTING_DEPTH` (64) deep.
+///
+/// A condition is `<field> <value>` (equality, like FIND) or
+/// `<field> <op> <value>` with one of `=`, `!=`, `>`, `>=`, `<`, `<=`,
+/// `STARTSWITH` and `ENDSWITH` (which match string fields by the value's
+/// text, e.g. `name STARTSWITH Mat` for autocompletion). Values
+/// are read as JSON when they parse as such (`18`, `true`, `null`,
+/// `"two words"`), and as plain strings otherwise, so they compare with
+/// fields of their own type: `age 18` matches the number 18 and `active true`
+/// the boolean. An equality also matches a string field holding the value's
+/// text, so `zip 01234` matches "01234". Ordering compares numbers with
+/// numbers and strings with strings (which suits RFC 3339 timestamps); other
+/// values can't be ordered, and a field of another type or a missing field
+/// never matches.
+///
+/// `<field> IN (<value>,<value>,...)` matches a field equal to any of the
+/// values, which are read like any other (`plan IN (free,"pro plus",3)`).
+/// `<field> CONTAINS <value>` matches an array field with an element equal to
+/// the value (`tags CONTAINS rust`).
+///
+/// Missing fields and nulls have conditions of their own:
+///
+/// <field> IS NULL      the field is null, or missing altogether
+/// <field> IS NOT NULL  the field holds anything but null
+/// HAS <field>          the field is there, even if null
+/// NOT HAS <field>      the field is missing (e.g. to backfill a new field)
+///
+/// A predicate starting with `NOCASE` compares strings case insensitively,
+/// folding both sides to lowercase by Unicode rules (so "ÉCOLE" matches
+/// "école"):
+///
+/// NOCASE email = Matthew@Saplink.io
+use serde_json::{from_str, Map, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+/// NOTs and parentheses can nest this deep; deeper predicates are refused
+const MAX_NESTING_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+impl Op {
+    fn parse(input: &str) -> Option<Op> {
+        match input {
+            "=" => Some(Op::Eq),
+            "!=" => Some(Op::Ne),
+            ">" => Some(Op::Gt),
+            ">=" => Some(Op::Ge),
+            "<" => Some(Op::Lt),
+            "<=" => Some(Op::Le),
+            "STARTSWITH" => Some(Op::StartsWith),
+            "ENDSWITH" => Some(Op::EndsWith),
+            "CONTAINS" => Some(Op::Contains),
+            _ => None,
+        }
+    }
+}
+
+/// A value in a query, as typed by the client
+pub struct Literal {
+    value: Value,
+    text: String,
+}
+
+impl Literal {
+    pub fn parse(input: &str) -> Literal {
+        let value = from_str(input).unwrap_or_else(|_| Value::String(input.to_owned()));
+        let text = text_of(&value);
+        Literal { value, text }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// The literal as a field value would be indexed (see `text_of`)
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether a field holds this value, either as the same JSON value or as
+    /// a string of the same text
+    pub fn is_equal(&self, field_value: &Value, case_insensitive: bool) -> bool {
+        *field_value == self.value
+            || field_value
+                .as_str()
+                .is_some_and(|text| text_equals(text, &self.text, case_insensitive))
+    }
+
+    /// Whether a field is an array holding this value
+    pub fn is_element(&self, field_value: &Value, case_insensitive: bool) -> bool {
+        field_value.as_array().is_some_and(|elements| {
+            elements
+                .iter()
+                .any(|element| self.is_equal(element, case_insensitive))
+        })
+    }
+}
+
+/// What FIND compares its field with: one value, any of the values of
+/// `IN (<value>,...)`, or an array element with `CONTAINS <value>`
+pub enum FindCompare {
+    Equals(Literal),
+    In(Vec<Literal>),
+    Contains(Literal),
+}
+
+impl FindCompare {
+    pub fn parse(input: &str) -> Result<FindCompare, String> {
+        if let Some(list) = in_list(input) {
+            return Ok(FindCompare::In(parse_list(list)?));
+        }
+
+        Ok(match input.strip_prefix("CONTAINS ") {
+            Some(value) => FindCompare::Contains(Literal::parse(value)),
+            None => FindCompare::Equals(Literal::parse(input)),
+        })
+    }
+
+    /// Where an ordered index finds the documents that may match
+    pub fn range(&self) -> Option<Range<'_>> {
+        match self {
+            FindCompare::Contains(_) => None,
+            _ => any_of_range(self.literals()),
+        }
+    }
+
+    /// The values, any of which a field must hold (or, for CONTAINS, an
+    /// element of it) to match
+    pub fn literals(&self) -> &[Literal] {
+        match self {
+            FindCompare::Equals(literal) | FindCompare::Contains(literal) => {
+                std::slice::from_ref(literal)
+            }
+            FindCompare::In(literals) => literals,
+        }
+    }
+
+    pub fn matches(&self, field_value: &Value, case_insensitive: bool) -> bool {
+        match self {
+            FindCompare::Contains(literal) => literal.is_element(field_value, case_insensitive),
+            _ => self
+                .literals()
+                .iter()
+                .any(|literal| literal.is_equal(field_value, case_insensitive)),
+        }
+    }
+}
+
+/// The text of a value: strings as they are, anything else as JSON
+pub fn text_of(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Where an ordered index (see ordered.rs) finds the documents a condition
+/// may match
+pub enum Range<'a> {
+    /// Equal to any of the values
+    AnyOf(&'a [Literal]),
+    /// Between two numbers, or two strings
+    Between(Bound<&'a Value>, Bound<&'a Value>),
+    /// A string starting with the text
+    Prefix(&'a str),
+}
+
+/// Indexes only hold numbers and strings, so can't find any other value
+fn any_of_range(literals: &[Literal]) -> Option<Range<'_>> {
+    literals
+        .iter()
+        .all(|literal| matches!(literal.value, Value::Number(_) | Value::String(_)))
+        .then_some(Range::AnyOf(literals))
+}
+
+/// What a condition checks its field for
+enum Test {
+    Compare(Op, Literal),
+    In(Vec<Literal>),
+    IsNull,
+    IsNotNull,
+    Has,
+}
+
+impl Test {
+    fn range(&self) -> Option<Range<'_>> {
+        let (op, literal) = match self {
+            Test::In(literals) => return any_of_range(literals),
+            Test::Compare(op, literal) => (*op, literal),
+            _ => return None,
+        };
+
+        let value = &literal.value;
+        match op {
+            Op::Eq => any_of_range(std::slice::from_ref(literal)),
+            Op::Gt => Some(Range::Between(Bound::Excluded(value), Bound::Unbounded)),
+            Op::Ge => Some(Range::Between(Bound::Included(value), Bound::Unbounded)),
+            Op::Lt => Some(Range::Between(Bound::Unbounded, Bound::Excluded(value))),
+            Op::Le => Some(Range::Between(Bound::Unbounded, Bound::Included(value))),
+            Op::StartsWith => Some(Range::Prefix(literal.text())),
+            _ => None,
+        }
+    }
+}
+
+struct Condition {
+    field: String,
+    test: Test,
+}
+
+impl Condition {
+    fn matches(&self, fields: &Map<String, Value>, case_insensitive: bool) -> bool {
+        let field_value = fields.get(&self.field);
+        let (op, literal) = match (&self.test, field_value) {
+            (Test::IsNull, field_value) => return field_value.is_none_or(Value::is_null),
+            (Test::IsNotNull, field_value) => return field_value.is_some_and(|v| !v.is_null()),
+            (Test::Has, field_value) => return field_value.is_some(),
+            (Test::In(literals), Some(field_value)) => {
+                return literals
+                    .iter()
+                    .any(|literal| literal.is_equal(field_value, case_insensitive))
+            }
+            (Test::Compare(..) | Test::In(..), None) => return false,
+            (Test::Compare(op, literal), Some(_)) => (*op, literal),
+        };
+        let field_value = field_value.unwrap_or(&Value::Null);
+
+        let is_equal = literal.is_equal(field_value, case_insensitive);
+        match op {
+            Op::Eq => is_equal,
+            Op::Ne => !is_equal,
+            Op::StartsWith | Op::EndsWith => field_value
+                .as_str()
+                .is_some_and(|text| has_affix(text, literal.text(), op, case_insensitive)),
+            Op::Contains => literal.is_element(field_value, case_insensitive),
+            op => match compare(field_value, &literal.value, case_insensitive) {
+                Some(ordering) => match op {
+                    Op::Gt => ordering == Ordering::Greater,
+                    Op::Ge => ordering != Ordering::Less,
+                    Op::Lt => ordering == Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                },
+                None => false,
+            },
+        }
+    }
+}
+
+fn compare(a: &Value, b: &Value, case_insensitive: bool) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) if case_insensitive => {
+            Some(a.to_lowercase().cmp(&b.to_lowercase()))
+        }
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Whether a string starts (or ends) with another, ignoring case if asked to
+fn has_affix(text: &str, affix: &str, op: Op, case_insensitive: bool) -> bool {
+    let (text, affix) = match case_insensitive {
+        true => (
+            Cow::Owned(text.to_lowercase()),
+            Cow::Owned(affix.to_lowercase()),
+        ),
+        false => (Cow::Borrowed(text), Cow::Borrowed(affix)),
+    };
+
+    match op {
+        Op::StartsWith => text.starts_with(&*affix),
+        _ => text.ends_with(&*affix),
+    }
+}
+
+/// Whether two strings are equal, ignoring case if asked to
+pub fn text_equals(a: &str, b: &str, case_insensitive: bool) -> bool {
+    match case_insensitive {
+        true => a == b || a.to_lowercase() == b.to_lowercase(),
+        false => a == b,
+    }
+}
+
+/// Conditions combined with AND, OR and NOT
+enum Expr {
+    Any(Vec<Expr>),
+    All(Vec<Expr>),
+    Not(Box<Expr>),
+    Condition(Condition),
+}
+
+impl Expr {
+    fn matches(&self, fields: &Map<String, Value>, case_insensitive: bool) -> bool {
+        match self {
+            Expr::Any(exprs) => exprs
+                .iter()
+                .any(|expr| expr.matches(fields, case_insensitive)),
+            Expr::All(exprs) => exprs
+                .iter()
+                .all(|expr| expr.matches(fields, case_insensitive)),
+            Expr::Not(expr) => !expr.matches(fields, case_insensitive),
+            Expr::Condition(condition) => condition.matches(fields, case_insensitive),
+        }
+    }
+
+    fn conditions<'a>(&'a self, conditions: &mut Vec<&'a Condition>) {
+        match self {
+            Expr::Any(exprs) | Expr::All(exprs) => {
+                exprs.iter().for_each(|expr| expr.conditions(conditions))
+            }
+            Expr::Not(expr) => expr.conditions(conditions),
+            Expr::Condition(condition) => conditions.push(condition),
+        }
+    }
+
+    /// Equalities (and array elements, which indexes hold too) that hold
+    /// wherever the expression does. Only those ANDed all the way up count;
+    /// anything under an OR or a NOT may not hold.
+    fn required_equalities<'a>(&'a self, equalities: &mut Vec<(&'a str, &'a str)>) {
+        match self {
+            Expr::All(exprs) => exprs
+                .iter()
+                .for_each(|expr| expr.required_equalities(equalities)),
+            Expr::Condition(Condition {
+                field,
+                test: Test::Compare(Op::Eq | Op::Contains, literal),
+            }) => equalities.push((field, literal.text())),
+            _ => (),
+        }
+    }
+
+    /// Like `required_equalities`, for ordered indexes
+    fn required_ranges<'a>(&'a self, ranges: &mut Vec<(&'a str, Range<'a>)>) {
+        match self {
+            Expr::All(exprs) => exprs.iter().for_each(|expr| expr.required_ranges(ranges)),
+            Expr::Condition(condition) => {
+                if let Some(range) = condition.test.range() {
+                    ranges.push((&condition.field, range));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A parsed predicate
+pub struct Predicate {
+    expr: Expr,
+    case_insensitive: bool,
+}
+
+impl Predicate {
+    pub fn parse(input: &str) -> Result<Predicate, String> {
+        let mut tokens = tokenize(input)?;
+        let case_insensitive = tokens.first().is_some_and(|token| token == "NOCASE");
+        if case_insensitive {
+            tokens.remove(0);
+        }
+
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.parse_any()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected \"{}\" in predicate", token));
+        }
+
+        Ok(Predicate {
+            expr,
+            case_insensitive,
+        })
+    }
+
+    /// Every field the predicate looks at
+    pub fn fields(&self) -> Vec<&str> {
+        let mut conditions = Vec::new();
+        self.expr.conditions(&mut conditions);
+
+        let mut fields: Vec<&str> = Vec::new();
+        for condition in conditions {
+            if !fields.contains(&condition.field.as_str()) {
+                fields.push(&condition.field);
+            }
+        }
+
+        fields
+    }
+
+    pub fn matches(&self, fields: &Map<String, Value>) -> bool {
+        self.expr.matches(fields, self.case_insensitive)
+    }
+
+    /// String equalities every matching document satisfies, which an index
+    /// can rule a pile out with. Indexes hold values exactly as stored, so a
+    /// case insensitive predicate has none.
+    pub fn required_equalities(&self) -> Vec<(&str, &str)> {
+        let mut equalities = Vec::new();
+        if !self.case_insensitive {
+            self.expr.required_equalities(&mut equalities);
+        }
+
+        equalities
+    }
+
+    /// Ranges of field values every matching document is within, which an
+    /// ordered index can narrow the documents to read down with
+    pub fn required_ranges(&self) -> Vec<(&str, Range<'_>)> {
+        let mut ranges = Vec::new();
+        if !self.case_insensitive {
+            self.expr.required_ranges(&mut ranges);
+        }
+
+        ranges
+    }
+}
+
+/// Reads tokens into an expression, OR binding loosest and NOT tightest
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+    /// How many NOTs and parentheses enclose the current position
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn parse_any(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.parse_all()?];
+        while self.peek() == Some("OR") {
+            self.position += 1;
+            exprs.push(self.parse_all()?);
+        }
+
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::Any(exprs),
+        })
+    }
+
+    fn parse_all(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.parse_not()?];
+        while self.peek() == Some("AND") {
+            self.position += 1;
+            exprs.push(self.parse_not()?);
+        }
+
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::All(exprs),
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some("NOT") => {
+                self.position += 1;
+                let expr = self.nested(Parser::parse_not)?;
+                Ok(Expr::Not(Box::new(expr)))
+            }
+            Some("(") => {
+                self.position += 1;
+                let expr = self.nested(Parser::parse_any)?;
+                if self.peek() != Some(")") {
+                    return Err("Predicate is missing a closing parenthesis".to_owned());
+                }
+                self.position += 1;
+                Ok(expr)
+            }
+            _ => {
+                // Up to the next AND, OR or closing parenthesis, stepping
+                // over an IN list
+                let start = self.position;
+                let mut depth = 0;
+                while let Some(token) = self.peek() {
+                    match token {
+                        "AND" | "OR" | ")" if depth == 0 => break,
+                        "(" => depth += 1,
+                        ")" => depth -= 1,
+                        _ => (),
+                    }
+                    self.position += 1;
+                }
+
+                Ok(Expr::Condition(parse_condition(
+                    &self.tokens[start..self.position],
+                )?))
+            }
+        }
+    }
+
+    /// Parses one level deeper, so a hostile predicate can't exhaust the
+    /// stack
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(format!(
+                "Predicate nests NOT and parentheses over {} deep",
+                MAX_NESTING_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+}
+
+fn parse_condition(tokens: &[String]) -> Result<Condition, String> {
+    let (field, test) = match tokens {
+        [field, is, null] if is == "IS" && null == "NULL" => (field, Test::IsNull),
+        [field, is, not, null] if is == "IS" && not == "NOT" && null == "NULL" => {
+            (field, Test::IsNotNull)
+        }
+        [has, field] if has == "HAS" => (field, Test::Has),
+        [field, is_in, list @ ..] if is_in == "IN" && !list.is_empty() => {
+            (field, Test::In(parse_list(&list.join(" "))?))
+        }
+        _ => return parse_comparison(tokens),
+    };
+
+    Ok(Condition {
+        field: field.clone(),
+        test,
+    })
+}
+
+fn parse_comparison(tokens: &[String]) -> Result<Condition, String> {
+    let (field, op, value) = match tokens {
+        [field, value] => (field, Op::Eq, value),
+        [field, op, value] => match Op::parse(op) {
+            Some(op) => (field, op, value),
+            None => return Err(format!("Unknown operator: {}", op)),
+        },
+        _ => {
+            return Err(format!(
+                "Condition must look like <field> [<op>] <value>, got: {}",
+                tokens.join(" ")
+            ))
+        }
+    };
+
+    let literal = Literal::parse(value);
+    let is_ordering = matches!(op, Op::Gt | Op::Ge | Op::Lt | Op::Le);
+    if is_ordering && !matches!(literal.value, Value::Number(_) | Value::String(_)) {
+        return Err(format!(
+            "Type mismatch: only numbers and strings can be ordered, got: {}",
+            value
+        ));
+    }
+
+    Ok(Condition {
+        field: field.clone(),
+        test: Test::Compare(op, literal),
+    })
+}
+
+/// The list of an `IN` (or of a FIND's `IN`), if that's what the input is
+fn in_list(input: &str) -> Option<&str> {
+    input
+        .strip_prefix("IN ")
+        .map(str::trim_start)
+        .filter(|list| list.starts_with('('))
+}
+
+/// Reads `(<value>,<value>,...)`, where commas inside double quoted values
+/// don't separate them
+fn parse_list(input: &str) -> Result<Vec<Literal>, String> {
+    let items = match input
+        .trim()
+        .strip_prefix('(')
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        Some(items) => items,
+        None => {
+            return Err(format!(
+                "IN must be followed by (<value>,...), got: {}",
+                input
+            ))
+        }
+    };
+
+    let mut literals = Vec::new();
+    let mut item = String::new();
+    let mut is_quoted = false;
+    let mut is_escaped = false;
+    for c in items.chars().chain([',']) {
+        if c == ',' && !is_quoted {
+            let value = item.trim();
+            if value.is_empty() {
+                return Err(format!("IN list has an empty value: {}", input));
+            }
+            literals.push(Literal::parse(value));
+            item.clear();
+            continue;
+        }
+
+        if c == '"' && !is_escaped {
+            is_quoted = !is_quoted;
+        }
+        is_escaped = c == '\\' && !is_escaped;
+        item.push(c);
+    }
+    if is_quoted {
+        return Err("IN list ends inside a quoted value".to_owned());
+    }
+
+    Ok(literals)
+}
+
+/// Splits on spaces, keeping double quoted strings (JSON escapes included)
+/// together as one token, and parentheses opening or closing a token apart
+fn tokenize(input: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+            continue;
+        }
+
+        let mut token = String::new();
+        if c == '"' {
+            token.push(chars.next().unwrap_or_default());
+            let mut is_escaped = false;
+            loop {
+                match chars.next() {
+                    Some(c) => {
+                        token.push(c);
+                        if c == '"' && !is_escaped {
+                            break;
+                        }
+                        is_escaped = c == '\\' && !is_escaped;
+                    }
+                    None => return Err("Predicate ends inside a quoted value".to_owned()),
+                }
+            }
+        } else if c == '(' {
+            token.push(chars.next().unwrap_or_default());
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ' ' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+
+            let closing = token.len() - token.trim_end_matches(')').len();
+            token.truncate(token.len() - closing);
+            if !token.is_empty() {
+                tokens.push(token);
+            }
+            tokens.extend((0..closing).map(|_| ")".to_owned()));
+            continue;
+        }
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let nots = "NOT ".repeat(20_000);
+        assert!(Predicate::parse(&format!("{}a b", nots)).is_err());
+        let parentheses = format!("{}a b{}", "(".repeat(20_000), ")".repeat(20_000));
+        assert!(Predicate::parse(&parentheses).is_err());
+    }
+
+    #[test]
+    fn allows_shallow_nesting() {
+        let nots = "NOT ".repeat(MAX_NESTING_DEPTH);
+        assert!(Predicate::parse(&format!("{}a b", nots)).is_ok());
+        let predicate = Predicate::parse("NOT (a b OR NOT (c d))").unwrap();
+        let fields = serde_json::json!({ "a": "x", "c": "d" });
+        assert!(predicate.matches(fields.as_object().unwrap()));
+    }
+}
@@ -0,0 +1,88 @@
+/// Crash recovery.
+///
+/// When the previous server (or restore) didn't shut down cleanly, which the
+/// storage lock file tells (see daemon.rs), the server puts the storage root
+/// back in order before it accepts any connection:
+///
+/// 1. a WAL entry left half written at the end of the log is cut off
+/// 2. the WAL entries logged since the crashed process started are replayed,
+///    so every acknowledged write is on disk, whatever its write concern
+/// 3. temp files of interrupted writes are removed, whatever their age
+/// 4. ordered index key files are discarded, to be rebuilt on first use
+/// 5. every pile's statistics are recounted from its documents
+///
+/// A lock file from before WAL offsets were recorded in it doesn't say where
+/// to replay from, so step 2 is skipped then. What was done is logged as a
+/// recovery report:
+///
+/// wal_bytes_truncated      the size of the torn WAL entry, if any
+/// wal_operations_replayed  how many WAL entries were replayed
+/// temp_files_removed       how many temp files were left behind
+/// piles_checked            how many piles had their indexes and totals redone
+/// duration_ms              how long the recovery took
+use crate::logging::{self, Level};
+use crate::pile::{pile_names, pile_path};
+use crate::users::USERS_PILE;
+use crate::{apply_wal_entries, janitor, ordered, stats, wal};
+use serde_json::{json, Value};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Recovers from a crash of the process that started logging at `wal_offset`
+pub fn recover(wal_offset: Option<u64>) -> Result<Value, io::Error> {
+    let started = Instant::now();
+    logging::diagnostic(Level::Info, "dustdb was not shut down cleanly, recovering");
+
+    let wal_bytes_truncated = wal::truncate_torn_tail()?;
+
+    let wal_operations_replayed = match wal_offset {
+        Some(wal_offset) => {
+            let (wal_chunk, _) = wal::read_since(wal_offset)?;
+            let entries = wal::parse_entries(&String::from_utf8_lossy(&wal_chunk), None)?;
+            apply_wal_entries(&entries)?;
+            entries.len()
+        }
+        None => {
+            logging::diagnostic(
+                Level::Error,
+                "The storage lock file holds no WAL offset, skipping WAL replay",
+            );
+            0
+        }
+    };
+
+    // No write is in progress before the server accepts connections, so
+    // every temp file is left over
+    let mut all_pile_names = pile_names()?;
+    all_pile_names.push(USERS_PILE.to_owned());
+    let mut temp_files_removed = 0;
+    for pile_name in &all_pile_names {
+        let pile_path = pile_path(pile_name)?;
+        if Path::new(&pile_path).is_dir() {
+            temp_files_removed += janitor::remove_temp_files(&pile_path, Duration::ZERO)?;
+        }
+    }
+
+    // Key files are renamed into place without a sync, so a machine crash
+    // can leave one torn, and a rebuild is cheap next to a wrong index
+    let pile_names = pile_names()?;
+    for pile_name in &pile_names {
+        ordered::before_write(pile_name)?;
+        stats::recount(pile_name)?;
+    }
+
+    let report = json!({
+        "wal_bytes_truncated": wal_bytes_truncated,
+        "wal_operations_replayed": wal_operations_replayed,
+        "temp_files_removed": temp_files_removed,
+        "piles_checked": pile_names.len(),
+        "duration_ms": started.elapsed().as_millis() as u64,
+    });
+    logging::diagnostic(
+        Level::Info,
+        &format!("dustdb successfully recovered: {}", report),
+    );
+
+    Ok(report)
+}
@@ -0,0 +1,134 @@
+/// Cache of recent FIND results.
+///
+/// Dashboards and the like fire the same few FINDs over and over against
+/// piles that rarely change. Their results are kept, keyed by the query as
+/// parsed, for up to `DUST_RESULT_CACHE_TTL_SECS` (default 60) and at most
+/// `DUST_RESULT_CACHE_MAX_ENTRIES` (default 1024, 0 disables it) at a time,
+/// the oldest result making way for a new one.
+///
+/// Any write to a pile drops every result that read from it (including
+/// through a JOIN). Each pile has a generation that every write bumps once it
+/// has landed; a result computed while one of its piles was written to is
+/// not kept, so the cache never holds anything older than the files.
+use crate::env_or;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+struct CachedResult {
+    result: Option<Value>,
+    piles: Vec<String>,
+    cached_at: Instant,
+}
+
+struct Results {
+    entries: HashMap<String, CachedResult>,
+    generations: HashMap<String, u64>,
+    max_entries: usize,
+    ttl: Duration,
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+fn results() -> &'static Mutex<Results> {
+    static RESULTS: OnceLock<Mutex<Results>> = OnceLock::new();
+    RESULTS.get_or_init(|| {
+        Mutex::new(Results {
+            entries: HashMap::new(),
+            generations: HashMap::new(),
+            max_entries: env_or("DUST_RESULT_CACHE_MAX_ENTRIES", 1024),
+            ttl: Duration::from_secs(env_or("DUST_RESULT_CACHE_TTL_SECS", 60)),
+        })
+    })
+}
+
+/// The generations of the given piles, to be handed back to `insert`
+pub fn generations(piles: &[String]) -> Vec<u64> {
+    let results = results().lock().unwrap_or_else(|e| e.into_inner());
+    piles
+        .iter()
+        .map(|pile| results.generations.get(pile).copied().unwrap_or(0))
+        .collect()
+}
+
+/// The cached result of a query, if it is still fresh. The outer `None` is a
+/// miss, the inner one a query that found nothing.
+pub fn get(key: &str) -> Option<Option<Value>> {
+    let mut results = results().lock().unwrap_or_else(|e| e.into_inner());
+
+    let ttl = results.ttl;
+    match results.entries.get(key) {
+        Some(cached) if cached.cached_at.elapsed() < ttl => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return Some(cached.result.clone());
+        }
+        Some(_) => {
+            results.entries.remove(key);
+        }
+        None => (),
+    }
+
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    None
+}
+
+/// Keeps a query's result, unless one of its piles was written to since
+/// `generations` were taken (before the query ran)
+pub fn insert(key: String, piles: Vec<String>, generations: &[u64], result: Option<Value>) {
+    let mut results = results().lock().unwrap_or_else(|e| e.into_inner());
+    if results.max_entries == 0 {
+        return;
+    }
+
+    let is_current = piles.iter().zip(generations).all(|(pile, generation)| {
+        results.generations.get(pile).copied().unwrap_or(0) == *generation
+    });
+    if !is_current {
+        return;
+    }
+
+    if results.entries.len() >= results.max_entries && !results.entries.contains_key(&key) {
+        let oldest_key = results
+            .entries
+            .iter()
+            .min_by_key(|(_, cached)| cached.cached_at)
+            .map(|(oldest_key, _)| oldest_key.clone());
+        if let Some(oldest_key) = oldest_key {
+            results.entries.remove(&oldest_key);
+        }
+    }
+
+    results.entries.insert(
+        key,
+        CachedResult {
+            result,
+            piles,
+            cached_at: Instant::now(),
+        },
+    );
+}
+
+/// Drops every result that read from the pile; called after every write to
+/// one of its documents
+pub fn invalidate(pile_name: &str) {
+    let mut results = results().lock().unwrap_or_else(|e| e.into_inner());
+
+    *results.generations.entry(pile_name.to_owned()).or_insert(0) += 1;
+    results
+        .entries
+        .retain(|_, cached| !cached.piles.iter().any(|pile| pile == pile_name));
+}
+
+pub fn stats() -> Value {
+    let results = results().lock().unwrap_or_else(|e| e.into_inner());
+
+    json!({
+        "hits": HITS.load(Ordering::Relaxed),
+        "misses": MISSES.load(Ordering::Relaxed),
+        "entries": results.entries.len(),
+        "max_entries": results.max_entries,
+    })
+}
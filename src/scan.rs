@@ -0,0 +1,152 @@
+/// Parallel scans over a pile's documents.
+///
+/// Queries that can't be answered any other way read every document of a
+/// pile. Large piles are split into contiguous chunks that are parsed and
+/// matched on `DUST_SCAN_PARALLELISM` threads (default: one per core), and the
+/// results are merged back into document order.
+///
+/// Corrupt documents are skipped rather than failing the scan (see
+/// quarantine.rs).
+use crate::cache::probe_fields;
+use crate::{env_or, quarantine};
+use serde_json::{Map, Value};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+/// Below this many documents, spawning threads costs more than it saves
+const MIN_DOCUMENTS_PER_THREAD: usize = 256;
+
+/// Like `scan_with`, but each document only has `field_name` extracted from
+/// it (see `cache::probe_fields`) instead of being fully parsed
+pub fn scan_field<T, F>(
+    file_paths: &[PathBuf],
+    field_name: &str,
+    first_only: bool,
+    visit: F,
+) -> Result<Vec<(usize, T)>, io::Error>
+where
+    T: Send,
+    F: Fn(&Path, Option<Value>) -> Option<T> + Sync,
+{
+    scan_fields(
+        file_paths,
+        &[field_name],
+        first_only,
+        |file_path, mut fields| visit(file_path, fields.remove(field_name)),
+    )
+}
+
+/// Like `scan_field`, for visitors that look at several fields
+pub fn scan_fields<T, F>(
+    file_paths: &[PathBuf],
+    field_names: &[&str],
+    first_only: bool,
+    visit: F,
+) -> Result<Vec<(usize, T)>, io::Error>
+where
+    T: Send,
+    F: Fn(&Path, Map<String, Value>) -> Option<T> + Sync,
+{
+    let load = |file_path: &Path| probe_fields(file_path, field_names);
+    scan_with(file_paths, first_only, load, visit)
+}
+
+/// Calls `visit` on every document, as loaded by `load`, and returns, in
+/// document order, the index and result of each document it returned `Some`
+/// for. With `first_only`, only the first match (in document order) is
+/// returned, and threads stop early once a match before their position is
+/// known.
+#[tracing::instrument(name = "scan", skip_all, fields(documents = file_paths.len()))]
+fn scan_with<D, T, L, F>(
+    file_paths: &[PathBuf],
+    first_only: bool,
+    load: L,
+    visit: F,
+) -> Result<Vec<(usize, T)>, io::Error>
+where
+    T: Send,
+    L: Fn(&Path) -> Result<D, io::Error> + Sync,
+    F: Fn(&Path, D) -> Option<T> + Sync,
+{
+    let default_parallelism = thread::available_parallelism().map_or(1, |n| n.get());
+    let parallelism = env_or("DUST_SCAN_PARALLELISM", default_parallelism)
+        .min(file_paths.len() / MIN_DOCUMENTS_PER_THREAD)
+        .max(1);
+
+    // Index of the earliest match found so far, used to cut `first_only`
+    // scans short, and a flag to stop every thread after an error
+    let first_match = AtomicUsize::new(usize::MAX);
+    let failed = AtomicBool::new(false);
+
+    let scan_chunk = |offset: usize, chunk: &[PathBuf]| -> Result<Vec<(usize, T)>, io::Error> {
+        let mut matches = Vec::new();
+        for (i, file_path) in chunk.iter().enumerate() {
+            let index = offset + i;
+            if failed.load(Ordering::Relaxed)
+                || (first_only && index > first_match.load(Ordering::Relaxed))
+            {
+                break;
+            }
+
+            let document = match load(file_path) {
+                Ok(document) => document,
+                Err(e) if quarantine::is_corrupt(&e) => {
+                    quarantine::report(file_path, &e);
+                    continue;
+                }
+                Err(e) => {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            };
+
+            if let Some(result) = visit(file_path, document) {
+                matches.push((index, result));
+                if first_only {
+                    first_match.fetch_min(index, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+
+        Ok(matches)
+    };
+
+    let mut matches = if parallelism == 1 {
+        scan_chunk(0, file_paths)?
+    } else {
+        let chunk_size = file_paths.len().div_ceil(parallelism);
+        let chunk_results = thread::scope(|scope| {
+            let handles = file_paths
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let scan_chunk = &scan_chunk;
+                    scope.spawn(move || scan_chunk(i * chunk_size, chunk))
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| match handle.join() {
+                    Ok(chunk_result) => chunk_result,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut matches = Vec::new();
+        for chunk_result in chunk_results {
+            matches.extend(chunk_result?);
+        }
+        matches
+    };
+
+    if first_only {
+        matches.truncate(1);
+    }
+
+    Ok(matches)
+}
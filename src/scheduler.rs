@@ -0,0 +1,414 @@
+/// Scheduled maintenance.
+///
+/// `DUST_SCHEDULE` runs maintenance tasks on cron schedules, as a semicolon
+/// separated list of `<task>=<schedule>`:
+///
+/// DUST_SCHEDULE="cleanup=*/15 * * * *;backup=0 2 * * *;fsck=30 3 * * 0"
+///
+/// cleanup             storage cleanup, as CLEANUP (see janitor.rs)
+/// ttl                 expiry sweep (see ttl.rs)
+/// backup              full backup, as BACKUP FULL (see backup.rs)
+/// backup_incremental  incremental backup, as BACKUP INCREMENTAL
+/// fsck                integrity check of every pile, as FSCK (see fsck.rs)
+///
+/// A schedule has the five fields of a crontab (minute, hour, day of month,
+/// month, day of week with 0 for Sunday), each `*`, a number, a range `a-b`,
+/// a step `*/n` or `a-b/n`, or a comma separated list of those, in UTC. As in
+/// cron, a task whose day of month and day of week are both restricted runs
+/// on days matching either. A task still running when it's due again skips
+/// that run. The interval workers of cleanup and expiry keep running next to
+/// their schedules; set `DUST_CLEANUP_INTERVAL_SECS` or
+/// `DUST_TTL_SWEEP_INTERVAL_SECS` to 0 to leave them to the scheduler.
+///
+/// INFO reports each task's schedule, last run (start, duration and outcome)
+/// and next run.
+use crate::logging::{self, Level};
+use crate::{backup, fsck, janitor, timestamp_now, ttl};
+use chrono::{DateTime, Datelike, Duration, DurationRound, SecondsFormat, Timelike, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+/// Matching minutes are looked for up to this far ahead, which covers any
+/// schedule that runs at all (e.g. on February 29th)
+const MAX_LOOKAHEAD_DAYS: i64 = 8 * 366;
+
+/// The configured tasks, set once the scheduler starts
+static SCHEDULED: OnceLock<Vec<(String, Task, Schedule)>> = OnceLock::new();
+
+#[derive(Clone, Copy)]
+enum Task {
+    Cleanup,
+    Ttl,
+    Backup,
+    BackupIncremental,
+    Fsck,
+}
+
+impl Task {
+    fn parse(input: &str) -> Option<Task> {
+        match input {
+            "cleanup" => Some(Task::Cleanup),
+            "ttl" => Some(Task::Ttl),
+            "backup" => Some(Task::Backup),
+            "backup_incremental" => Some(Task::BackupIncremental),
+            "fsck" => Some(Task::Fsck),
+            _ => None,
+        }
+    }
+
+    fn run(self) -> Result<Value, io::Error> {
+        match self {
+            Task::Cleanup => janitor::cleanup(),
+            Task::Ttl => Ok(json!(ttl::sweep()?)),
+            Task::Backup => Ok(json!(backup::create_full()?)),
+            Task::BackupIncremental => Ok(json!(backup::create_incremental()?)),
+            Task::Fsck => fsck::check(None, false),
+        }
+    }
+}
+
+/// One field of a schedule, as the values it matches
+struct Field {
+    values: Vec<u32>,
+    is_wildcard: bool,
+}
+
+impl Field {
+    fn parse(input: &str, min: u32, max: u32) -> Option<Field> {
+        let mut values = Vec::new();
+        for part in input.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse().ok().filter(|&step| step > 0)?),
+                None => (part, 1),
+            };
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                    None => {
+                        let value = range.parse().ok()?;
+                        (value, value)
+                    }
+                },
+            };
+            if start < min || end > max || start > end {
+                return None;
+            }
+            values.extend((start..=end).step_by(step));
+        }
+
+        Some(Field {
+            values,
+            is_wildcard: input == "*",
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.values.contains(&value)
+    }
+}
+
+struct Schedule {
+    source: String,
+    minutes: Field,
+    hours: Field,
+    days_of_month: Field,
+    months: Field,
+    days_of_week: Field,
+}
+
+impl Schedule {
+    fn parse(input: &str) -> Option<Schedule> {
+        let fields: Vec<&str> = input.split_whitespace().collect();
+        match fields.as_slice() {
+            [minutes, hours, days_of_month, months, days_of_week] => Some(Schedule {
+                source: fields.join(" "),
+                minutes: Field::parse(minutes, 0, 59)?,
+                hours: Field::parse(hours, 0, 23)?,
+                days_of_month: Field::parse(days_of_month, 1, 31)?,
+                months: Field::parse(months, 1, 12)?,
+                days_of_week: Field::parse(days_of_week, 0, 7)?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, time: &DateTime<Utc>) -> bool {
+        self.matches_day(time)
+            && self.hours.matches(time.hour())
+            && self.minutes.matches(time.minute())
+    }
+
+    fn matches_day(&self, time: &DateTime<Utc>) -> bool {
+        let day_of_month = self.days_of_month.matches(time.day());
+        // Sunday is 0 or 7
+        let weekday = time.weekday().num_days_from_sunday();
+        let day_of_week =
+            self.days_of_week.matches(weekday) || (weekday == 0 && self.days_of_week.matches(7));
+        let day = match (
+            self.days_of_month.is_wildcard,
+            self.days_of_week.is_wildcard,
+        ) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+
+        day && self.months.matches(time.month())
+    }
+
+    /// The first matching minute after `time`, skipping whole days and hours
+    /// that don't match
+    fn next_after(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let until = *time + Duration::days(MAX_LOOKAHEAD_DAYS);
+        let mut minute = time.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        while minute < until {
+            let day_start = minute.duration_trunc(Duration::days(1)).ok()?;
+            let hour_start = minute.duration_trunc(Duration::hours(1)).ok()?;
+            if !self.matches_day(&minute) {
+                minute = day_start + Duration::days(1);
+            } else if !self.hours.matches(minute.hour()) {
+                minute = hour_start + Duration::hours(1);
+            } else if !self.minutes.matches(minute.minute()) {
+                minute += Duration::minutes(1);
+            } else {
+                return Some(minute);
+            }
+        }
+        None
+    }
+}
+
+/// The last run of a task, and whether one is running
+#[derive(Default)]
+struct Status {
+    running: bool,
+    runs: u64,
+    last_started_at: Option<String>,
+    last_duration_ms: Option<u128>,
+    last_error: Option<String>,
+}
+
+fn statuses() -> &'static Mutex<HashMap<String, Status>> {
+    static STATUSES: OnceLock<Mutex<HashMap<String, Status>>> = OnceLock::new();
+    STATUSES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn configured() -> Result<Vec<(String, Task, Schedule)>, io::Error> {
+    let config = match std::env::var("DUST_SCHEDULE") {
+        Ok(config) => config,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut scheduled = Vec::new();
+    for entry in config
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (task_name, schedule) = entry.split_once('=').unwrap_or((entry, ""));
+        let task_name = task_name.trim();
+        let task = match Task::parse(task_name) {
+            Some(task) => task,
+            None => {
+                let e_kind = io::ErrorKind::InvalidInput;
+                let e = format!("Unknown task in DUST_SCHEDULE: \"{}\"", task_name);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+        // A schedule that never comes around, e.g. February 30th, is a typo
+        let schedule = match Schedule::parse(schedule) {
+            Some(schedule) if schedule.next_after(&Utc::now()).is_some() => schedule,
+            _ => {
+                let e_kind = io::ErrorKind::InvalidInput;
+                let e = format!(
+                    "Invalid schedule for task \"{}\" in DUST_SCHEDULE: \"{}\"",
+                    task_name, schedule
+                );
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+        scheduled.push((task_name.to_owned(), task, schedule));
+    }
+
+    Ok(scheduled)
+}
+
+/// Starts the scheduler on the tokio runtime, failing on an invalid
+/// `DUST_SCHEDULE` so a typo doesn't silently turn backups off
+pub fn spawn_worker() -> Result<(), io::Error> {
+    let configured = configured()?;
+    if configured.is_empty() {
+        return Ok(());
+    }
+    let scheduled = SCHEDULED.get_or_init(|| configured);
+
+    tokio::spawn(async move {
+        let mut last_minute = Utc::now();
+        loop {
+            // Wake up at the start of every minute
+            let now = Utc::now();
+            let next_minute =
+                now.duration_trunc(Duration::minutes(1)).unwrap_or(now) + Duration::minutes(1);
+            let wait = (next_minute - now).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            let minute = Utc::now()
+                .duration_trunc(Duration::minutes(1))
+                .unwrap_or(next_minute);
+            if minute <= last_minute {
+                continue;
+            }
+            last_minute = minute;
+
+            for (task_name, task, schedule) in scheduled {
+                if schedule.matches(&minute) {
+                    start(task_name, *task);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Runs the task in the background, unless it's still running
+fn start(task_name: &str, task: Task) {
+    {
+        let mut statuses = statuses().lock().unwrap_or_else(|e| e.into_inner());
+        let status = statuses.entry(task_name.to_owned()).or_default();
+        if status.running {
+            logging::diagnostic(
+                Level::Error,
+                &format!(
+                    "Skipping scheduled {}, its previous run hasn't finished",
+                    task_name
+                ),
+            );
+            return;
+        }
+        status.running = true;
+        status.last_started_at = Some(timestamp_now());
+    }
+
+    let task_name = task_name.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let result = task.run();
+
+        let mut statuses = statuses().lock().unwrap_or_else(|e| e.into_inner());
+        let status = statuses.entry(task_name.clone()).or_default();
+        status.running = false;
+        status.runs += 1;
+        status.last_duration_ms = Some(started.elapsed().as_millis());
+        status.last_error = match result {
+            Ok(_) => None,
+            Err(e) => {
+                logging::diagnostic(
+                    Level::Error,
+                    &format!("Error running scheduled {}: {:?}", task_name, e),
+                );
+                Some(e.to_string())
+            }
+        };
+    });
+}
+
+/// The status of every scheduled task, for INFO
+pub fn status() -> Value {
+    let scheduled = match SCHEDULED.get() {
+        Some(scheduled) => scheduled,
+        None => return json!([]),
+    };
+    let statuses = statuses().lock().unwrap_or_else(|e| e.into_inner());
+    let now = Utc::now();
+
+    scheduled
+        .iter()
+        .map(|(task_name, _, schedule)| {
+            let status = statuses.get(task_name);
+            json!({
+                "task": task_name,
+                "schedule": schedule.source,
+                "running": status.is_some_and(|status| status.running),
+                "runs": status.map_or(0, |status| status.runs),
+                "last_started_at": status.and_then(|status| status.last_started_at.clone()),
+                "last_duration_ms": status.and_then(|status| status.last_duration_ms),
+                "last_error": status.and_then(|status| status.last_error.clone()),
+                "next_run_at": schedule
+                    .next_after(&now)
+                    .map(|next| next.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn next_after(schedule: &str, time: &str) -> Option<DateTime<Utc>> {
+        Schedule::parse(schedule).unwrap().next_after(&at(time))
+    }
+
+    #[test]
+    fn parses_fields() {
+        let values = |input, min, max| Field::parse(input, min, max).map(|field| field.values);
+        assert_eq!(values("*/15", 0, 59), Some(vec![0, 15, 30, 45]));
+        assert_eq!(values("1-9/4", 0, 59), Some(vec![1, 5, 9]));
+        assert_eq!(values("1,3,10-12", 0, 59), Some(vec![1, 3, 10, 11, 12]));
+        assert_eq!(values("7", 0, 7), Some(vec![7]));
+        for input in ["60", "5-1", "*/0", "x", "1,", "0-"] {
+            assert_eq!(values(input, 0, 59), None, "{}", input);
+        }
+        assert!(Schedule::parse("0 0 * *").is_none());
+        assert!(Schedule::parse("0 0 0 * *").is_none());
+    }
+
+    #[test]
+    fn steps_to_the_next_matching_minute() {
+        let next = next_after("*/15 * * * *", "2024-05-01T10:07:30Z");
+        assert_eq!(next, Some(at("2024-05-01T10:15:00Z")));
+        // Strictly after, even on a matching minute
+        let next = next_after("*/15 * * * *", "2024-05-01T10:15:00Z");
+        assert_eq!(next, Some(at("2024-05-01T10:30:00Z")));
+        let next = next_after("30 2 * * *", "2024-12-31T03:00:00Z");
+        assert_eq!(next, Some(at("2025-01-01T02:30:00Z")));
+    }
+
+    #[test]
+    fn takes_sunday_as_0_or_7() {
+        // 2024-05-05 is a Sunday
+        for schedule in ["0 0 * * 0", "0 0 * * 7", "0 0 * * 5-7"] {
+            let next = next_after(schedule, "2024-05-04T12:00:00Z");
+            assert_eq!(next, Some(at("2024-05-05T00:00:00Z")), "{}", schedule);
+        }
+    }
+
+    #[test]
+    fn matches_either_day_when_both_are_restricted() {
+        // The 13th or any Friday: 2024-10-11 is a Friday, the 13th a Sunday
+        let next = next_after("0 0 13 * 5", "2024-10-11T00:00:00Z");
+        assert_eq!(next, Some(at("2024-10-13T00:00:00Z")));
+        let next = next_after("0 0 13 * 5", "2024-10-13T00:00:00Z");
+        assert_eq!(next, Some(at("2024-10-18T00:00:00Z")));
+        // With either one a wildcard, both must match
+        let next = next_after("0 0 * 11 5", "2024-10-11T00:00:00Z");
+        assert_eq!(next, Some(at("2024-11-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn looks_ahead_for_rare_days_only() {
+        let next = next_after("0 0 29 2 *", "2024-03-01T00:00:00Z");
+        assert_eq!(next, Some(at("2028-02-29T00:00:00Z")));
+        assert_eq!(next_after("0 0 31 2 *", "2024-03-01T00:00:00Z"), None);
+    }
+}
@@ -0,0 +1,181 @@
+/// Validation of documents against a pile's JSON Schema.
+///
+/// Supports the commonly used subset of JSON Schema: `type`, `enum`, `const`,
+/// `properties`, `required`, `additionalProperties`, `items`, `minItems`,
+/// `maxItems`, `minLength`, `maxLength`, `minimum`, `maximum`,
+/// `exclusiveMinimum` and `exclusiveMaximum`. Unknown keywords are ignored.
+use serde_json::Value;
+use std::io;
+
+/// Checks that `schema` can be used to validate documents
+pub fn check_schema(schema: &Value) -> Result<(), io::Error> {
+    match schema {
+        Value::Object(_) | Value::Bool(_) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "JSON Schema must be an object or a boolean",
+        )),
+    }
+}
+
+/// Validates `document` against `schema`, reporting the first violation along
+/// with the JSON pointer of the offending value
+pub fn validate(schema: &Value, document: &Value) -> Result<(), io::Error> {
+    match validate_at(schema, document, "") {
+        Ok(()) => Ok(()),
+        Err(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Schema validation failed: {}", e),
+        )),
+    }
+}
+
+fn validate_at(schema: &Value, value: &Value, pointer: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{} is not allowed", display(pointer))),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let matches = match expected {
+            Value::String(type_name) => is_type(value, type_name),
+            Value::Array(type_names) => type_names
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|type_name| is_type(value, type_name)),
+            _ => true,
+        };
+
+        if !matches {
+            return Err(format!("{} must be of type {}", display(pointer), expected));
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(format!("{} must be one of {:?}", display(pointer), allowed));
+        }
+    }
+
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return Err(format!("{} must equal {}", display(pointer), constant));
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for field in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(field) {
+                        return Err(format!(
+                            "{} is missing required field \"{}\"",
+                            display(pointer),
+                            field
+                        ));
+                    }
+                }
+            }
+
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (field, field_value) in object {
+                let field_pointer = format!("{}/{}", pointer, field);
+                match properties.and_then(|properties| properties.get(field)) {
+                    Some(field_schema) => validate_at(field_schema, field_value, &field_pointer)?,
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate_at(additional, field_value, &field_pointer)?
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            let item_count = items.len() as u64;
+            if let Some(min_items) = schema.get("minItems").and_then(Value::as_u64) {
+                if item_count < min_items {
+                    return Err(format!(
+                        "{} must have at least {} item(s)",
+                        display(pointer),
+                        min_items
+                    ));
+                }
+            }
+
+            if let Some(max_items) = schema.get("maxItems").and_then(Value::as_u64) {
+                if item_count > max_items {
+                    return Err(format!(
+                        "{} must have at most {} item(s)",
+                        display(pointer),
+                        max_items
+                    ));
+                }
+            }
+
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate_at(item_schema, item, &format!("{}/{}", pointer, index))?;
+                }
+            }
+        }
+        Value::String(string) => {
+            let length = string.chars().count() as u64;
+            if let Some(min_length) = schema.get("minLength").and_then(Value::as_u64) {
+                if length < min_length {
+                    return Err(format!(
+                        "{} must be at least {} character(s) long",
+                        display(pointer),
+                        min_length
+                    ));
+                }
+            }
+
+            if let Some(max_length) = schema.get("maxLength").and_then(Value::as_u64) {
+                if length > max_length {
+                    return Err(format!(
+                        "{} must be at most {} character(s) long",
+                        display(pointer),
+                        max_length
+                    ));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or(f64::NAN);
+            let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+
+            if bound("minimum").is_some_and(|minimum| number < minimum)
+                || bound("exclusiveMinimum").is_some_and(|minimum| number <= minimum)
+                || bound("maximum").is_some_and(|maximum| number > maximum)
+                || bound("exclusiveMaximum").is_some_and(|maximum| number >= maximum)
+            {
+                return Err(format!("{} is out of range", display(pointer)));
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn is_type(value: &Value, type_name: &str) -> bool {
+    match type_name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+fn display(pointer: &str) -> String {
+    match pointer {
+        "" => "document".to_owned(),
+        pointer => format!("\"{}\"", pointer),
+    }
+}
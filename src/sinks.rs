@@ -0,0 +1,392 @@
+/// Change event sinks.
+///
+/// `SINK <pile> <url> <subject>` publishes every committed write to the pile
+/// to a NATS subject (https://docs.nats.io/reference/reference-protocols/nats-protocol),
+/// and `SINK <pile> REMOVE <url> <subject>` stops it. The URL names the NATS
+/// server, `nats://<host>[:<port>]` (4222 by default). A pile may have any
+/// number of sinks; they see the same writes webhooks do (see webhooks.rs),
+/// each published as JSON:
+///
+/// {"pile":"orders","op":"CREATE","id":"cd8abd45-...","document":{..},"timestamp":"..."}
+///
+/// Each server has a background thread of its own, publishing to it over one
+/// connection in the order the writes were committed, so an unreachable
+/// server only holds up itself; events never hold up or undo the write. The
+/// connection runs in verbose mode, so every event is acknowledged by the
+/// server; one that isn't is published again over a new connection, up to
+/// `DUST_SINK_MAX_ATTEMPTS` (default 3) times in all, and then dropped and
+/// reported. Each attempt times out after `DUST_SINK_TIMEOUT_SECS` (default
+/// 10). Events past the `DUST_SINK_QUEUE_SIZE` (default 1000) waiting for a
+/// server are dropped and reported too. As with core NATS, an event no
+/// subscriber is listening for is gone; pending events are lost on shutdown.
+use crate::logging::{self, Level};
+use crate::pile::{self, PileMeta};
+use crate::webhooks::WebhookEvent;
+use crate::{env_or, timestamp_now};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_NATS_PORT: u16 = 4222;
+
+/// A server's publishing thread stops after this long without an event, and
+/// is started again by the next one
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub struct Sink {
+    pub url: String,
+    pub subject: String,
+}
+
+impl Sink {
+    pub fn from_json(json_content: &Value) -> Option<Sink> {
+        Some(Sink {
+            url: json_content.get("url")?.as_str()?.to_owned(),
+            subject: json_content.get("subject")?.as_str()?.to_owned(),
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "url": self.url,
+            "subject": self.subject,
+        })
+    }
+}
+
+/// The `host:port` of a NATS server URL
+fn parse_url(url: &str) -> Result<(String, u16), io::Error> {
+    let authority = url
+        .strip_prefix("nats://")
+        .map(|rest| rest.trim_end_matches('/'));
+    let (host, port) = match authority {
+        Some(authority) => match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()),
+            _ => (authority, Some(DEFAULT_NATS_PORT)),
+        },
+        None => ("", None),
+    };
+
+    match port {
+        Some(port) if !host.is_empty() && !host.contains('/') => Ok((host.to_owned(), port)),
+        _ => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!(
+                "Sink URL must look like nats://<host>[:<port>]: \"{}\"",
+                url
+            );
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+/// NATS subjects are dot separated tokens without whitespace; wildcards only
+/// make sense when subscribing
+fn is_valid_subject(subject: &str) -> bool {
+    !subject.is_empty()
+        && subject.split('.').all(|token| {
+            !token.is_empty()
+                && token != "*"
+                && token != ">"
+                && !token.contains(char::is_whitespace)
+        })
+}
+
+/// Example:
+/// in: SINK orders nats://nats.internal:4222 dustdb.orders
+/// out:
+pub fn add(pile_name: &str, sink: Sink) -> Result<(), io::Error> {
+    parse_url(&sink.url)?;
+    if !is_valid_subject(&sink.subject) {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid NATS subject: \"{}\"", sink.subject);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut sinks = pile_meta.sinks();
+    if !sinks
+        .iter()
+        .any(|existing| existing.url == sink.url && existing.subject == sink.subject)
+    {
+        sinks.push(sink);
+        pile_meta.set(
+            "sinks",
+            Value::Array(sinks.iter().map(Sink::to_json).collect()),
+        );
+        pile_meta.save()?;
+    }
+
+    Ok(())
+}
+
+/// Example:
+/// in: SINK orders REMOVE nats://nats.internal:4222 dustdb.orders
+/// out:
+pub fn remove(pile_name: &str, url: &str, subject: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut sinks = pile_meta.sinks();
+    let sink_count = sinks.len();
+    sinks.retain(|sink| sink.url != url || sink.subject != subject);
+    if sinks.len() == sink_count {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!(
+            "Pile \"{}\" has no sink for \"{}\" {}",
+            pile_name, url, subject
+        );
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    pile_meta.set(
+        "sinks",
+        Value::Array(sinks.iter().map(Sink::to_json).collect()),
+    );
+    pile_meta.save()
+}
+
+struct Event {
+    url: String,
+    subject: String,
+    payload: String,
+}
+
+/// The queue of each server's publishing thread. Events are only queued with
+/// the lock held, which lets an idle thread remove its queue safely.
+fn queues() -> &'static Mutex<HashMap<String, mpsc::SyncSender<Event>>> {
+    static QUEUES: OnceLock<Mutex<HashMap<String, mpsc::SyncSender<Event>>>> = OnceLock::new();
+    QUEUES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn queue_size() -> usize {
+    env_or("DUST_SINK_QUEUE_SIZE", 1000_usize).max(1)
+}
+
+/// Queues the event for its server's thread, starting one if needed. Gives
+/// the event back if the queue is full.
+fn enqueue(
+    queues: &mut HashMap<String, mpsc::SyncSender<Event>>,
+    event: Event,
+) -> Result<(), Event> {
+    let event = match queues.get(&event.url) {
+        Some(queue) => match queue.try_send(event) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::TrySendError::Full(event)) => return Err(event),
+            // The thread is gone, e.g. it panicked
+            Err(mpsc::TrySendError::Disconnected(event)) => event,
+        },
+        None => event,
+    };
+
+    let (queue, receiver) = mpsc::sync_channel(queue_size());
+    let url = event.url.clone();
+    // Can't fail, the queue is new and empty
+    let _ = queue.try_send(event);
+    queues.insert(url.clone(), queue);
+    thread::spawn(move || publish_all(&url, receiver));
+    Ok(())
+}
+
+/// Publishes the server's events as they're queued, until it's been idle for
+/// `WORKER_IDLE_TIMEOUT`
+fn publish_all(url: &str, receiver: mpsc::Receiver<Event>) {
+    let mut connection = None;
+    loop {
+        match receiver.recv_timeout(WORKER_IDLE_TIMEOUT) {
+            Ok(event) => publish_event(&mut connection, &event),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let mut queues = queues().lock().unwrap_or_else(|e| e.into_inner());
+                // Nothing can be queued while the lock is held
+                match receiver.try_recv() {
+                    Ok(event) => {
+                        drop(queues);
+                        publish_event(&mut connection, &event);
+                    }
+                    Err(_) => {
+                        queues.remove(url);
+                        return;
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Queues the write's event for every sink of the pile. `document` is the
+/// document as stored, or as it was before a delete.
+pub fn publish(
+    pile_meta: &PileMeta,
+    event: WebhookEvent,
+    pile_name: &str,
+    uuid: &str,
+    document: Option<&Value>,
+) {
+    let sinks = pile_meta.sinks();
+    if sinks.is_empty() {
+        return;
+    }
+
+    let payload = json!({
+        "pile": pile_name,
+        "op": event.as_str(),
+        "id": uuid,
+        "document": document,
+        "timestamp": timestamp_now(),
+    })
+    .to_string();
+
+    let mut overflowed = Vec::new();
+    let mut queues = queues().lock().unwrap_or_else(|e| e.into_inner());
+    for sink in sinks {
+        let event = Event {
+            url: sink.url,
+            subject: sink.subject,
+            payload: payload.clone(),
+        };
+        if let Err(event) = enqueue(&mut queues, event) {
+            overflowed.push(event);
+        }
+    }
+    drop(queues);
+
+    for event in overflowed {
+        logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Dropping change event for {} {}: over {} events are waiting for the server",
+                event.url,
+                event.subject,
+                queue_size()
+            ),
+        );
+    }
+}
+
+fn publish_event(connection: &mut Option<NatsConnection>, event: &Event) {
+    let max_attempts: u32 = env_or("DUST_SINK_MAX_ATTEMPTS", 3u32).max(1);
+    for attempt in 1..=max_attempts {
+        let published = match connection.take() {
+            Some(connection) => Ok(connection),
+            None => NatsConnection::connect(&event.url),
+        }
+        .and_then(|mut connection| {
+            connection.publish(&event.subject, &event.payload)?;
+            Ok(connection)
+        });
+
+        match published {
+            Ok(published) => {
+                *connection = Some(published);
+                return;
+            }
+            Err(e) if attempt == max_attempts => logging::diagnostic(
+                Level::Error,
+                &format!(
+                    "Dropping change event for {} {} after {} attempt(s): {}",
+                    event.url, event.subject, attempt, e
+                ),
+            ),
+            // The connection is dropped, the next attempt opens a new one
+            Err(_) => (),
+        }
+    }
+}
+
+struct NatsConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl NatsConnection {
+    fn connect(url: &str) -> Result<NatsConnection, io::Error> {
+        let (host, port) = parse_url(url)?;
+        let timeout = Duration::from_secs(env_or("DUST_SINK_TIMEOUT_SECS", 10u64).max(1));
+
+        let addr = match (host.trim_matches(['[', ']']), port)
+            .to_socket_addrs()?
+            .next()
+        {
+            Some(addr) => addr,
+            None => {
+                let e_kind = io::ErrorKind::NotFound;
+                let e = format!("Could not resolve NATS host: \"{}\"", host);
+                return Err(io::Error::new(e_kind, e));
+            }
+        };
+        let writer = TcpStream::connect_timeout(&addr, timeout)?;
+        writer.set_read_timeout(Some(timeout))?;
+        writer.set_write_timeout(Some(timeout))?;
+
+        let mut connection = NatsConnection {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        };
+
+        // The server greets with its INFO, then acknowledges the CONNECT
+        let info = connection.read_line()?;
+        if !info.starts_with("INFO") {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = format!("Not a NATS server, it greeted with \"{}\"", info);
+            return Err(io::Error::new(e_kind, e));
+        }
+        let connect = json!({
+            "verbose": true,
+            "pedantic": false,
+            "name": "dustdb",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+        });
+        connection
+            .writer
+            .write_all(format!("CONNECT {}\r\n", connect).as_bytes())?;
+        connection.await_ok()?;
+
+        Ok(connection)
+    }
+
+    fn publish(&mut self, subject: &str, payload: &str) -> Result<(), io::Error> {
+        self.writer.write_all(
+            format!("PUB {} {}\r\n{}\r\n", subject, payload.len(), payload).as_bytes(),
+        )?;
+        self.await_ok()
+    }
+
+    /// Waits for the server to acknowledge the last command, answering its
+    /// keep-alive PINGs meanwhile
+    fn await_ok(&mut self) -> Result<(), io::Error> {
+        loop {
+            let line = self.read_line()?;
+            match line.as_str() {
+                "+OK" => return Ok(()),
+                "PING" => self.writer.write_all(b"PONG\r\n")?,
+                _ if line.starts_with("-ERR") => {
+                    let e = format!("NATS server refused: {}", line);
+                    return Err(io::Error::other(e));
+                }
+                // INFO updates about the cluster
+                _ => (),
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, io::Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            let e_kind = io::ErrorKind::UnexpectedEof;
+            let e = "NATS server closed the connection".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+        Ok(line.trim_end().to_owned())
+    }
+}
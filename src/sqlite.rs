@@ -0,0 +1,492 @@
+/// Minimal SQLite support for exporting documents.
+///
+/// Writes a database file holding a single table in the SQLite file format
+/// (https://www.sqlite.org/fileformat.html), so any SQLite tool can open it
+/// without dustdb. Only what a freshly written table needs is supported: no
+/// indexes, free pages or journals. Rows get rowids 1, 2, ... in the order
+/// given; integers are stored as 8 bytes and text as UTF-8.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const PAGE_SIZE: usize = 4096;
+
+/// The largest payload stored within a table leaf cell
+const MAX_LOCAL: usize = PAGE_SIZE - 35;
+
+/// The least payload stored within a table leaf cell that overflows
+const MIN_LOCAL: usize = (PAGE_SIZE - 12) * 32 / 255 - 23;
+
+/// The database header takes up the start of the first page
+const HEADER_SIZE: usize = 100;
+
+const LEAF_PAGE: u8 = 0x0D;
+const INTERIOR_PAGE: u8 = 0x05;
+
+/// The SQLite version claimed to have written the file (3.40.1)
+const SQLITE_VERSION_NUMBER: u32 = 3_040_001;
+
+/// A value of a table cell
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+/// Writes the database to `file_path`: one table named `table_name` with the
+/// given columns (declared without a type, so each value keeps its own) and
+/// rows. Every row must have a value per column.
+pub fn write_table(
+    file_path: &Path,
+    table_name: &str,
+    column_names: &[String],
+    rows: &[Vec<SqlValue>],
+) -> Result<(), io::Error> {
+    let mut file = DatabaseFile::default();
+
+    // The table's root is always page 2, so the schema can point at it
+    // before the tree is built
+    let root_page = file.build_table(rows);
+
+    let columns: Vec<String> = column_names.iter().map(|name| quote(name)).collect();
+    let schema_row = vec![
+        SqlValue::Text("table".to_owned()),
+        SqlValue::Text(table_name.to_owned()),
+        SqlValue::Text(table_name.to_owned()),
+        SqlValue::Integer(2),
+        SqlValue::Text(format!(
+            "CREATE TABLE {}({})",
+            quote(table_name),
+            columns.join(", ")
+        )),
+    ];
+    let schema_cell = file.leaf_cell(1, &record(&schema_row));
+    let mut first_page = match leaf_page(HEADER_SIZE, &[schema_cell]) {
+        Some(page) => page,
+        None => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = "The table's schema doesn't fit the first page".to_owned();
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let page_count = file.overflow_pages.len() as u32 + 2;
+    first_page[..HEADER_SIZE].copy_from_slice(&database_header(page_count));
+
+    let mut bytes = Vec::with_capacity(page_count as usize * PAGE_SIZE);
+    bytes.extend(first_page);
+    bytes.extend(root_page);
+    for page in file.overflow_pages {
+        bytes.extend(page);
+    }
+    fs::write(file_path, bytes)
+}
+
+/// Pages other than the first two, numbered from 3 in the order they were
+/// added
+#[derive(Default)]
+struct DatabaseFile {
+    overflow_pages: Vec<Vec<u8>>,
+}
+
+impl DatabaseFile {
+    fn add_page(&mut self, page: Vec<u8>) -> u32 {
+        self.overflow_pages.push(page);
+        self.overflow_pages.len() as u32 + 2
+    }
+
+    /// Builds the table's b-tree, adding every page but the root (which is
+    /// returned) to the file
+    fn build_table(&mut self, rows: &[Vec<SqlValue>]) -> Vec<u8> {
+        // Leaves, each with the largest rowid it holds
+        let mut level: Vec<(Vec<u8>, u64)> = Vec::new();
+        let mut cells = Vec::new();
+        for (index, row) in rows.iter().enumerate() {
+            let rowid = index as u64 + 1;
+            let cell = self.leaf_cell(rowid, &record(row));
+            cells.push(cell);
+            if !fits(8, &cells) {
+                let cell = cells.pop().unwrap_or_default();
+                level.push((leaf_page(0, &cells).unwrap_or_default(), rowid - 1));
+                cells = vec![cell];
+            }
+        }
+        if !cells.is_empty() || level.is_empty() {
+            level.push((leaf_page(0, &cells).unwrap_or_default(), rows.len() as u64));
+        }
+
+        while level.len() > 1 {
+            let children: Vec<(u32, u64)> = level
+                .into_iter()
+                .map(|(page, max_rowid)| (self.add_page(page), max_rowid))
+                .collect();
+            level = interior_pages(&children);
+        }
+
+        level.pop().map(|(page, _)| page).unwrap_or_default()
+    }
+
+    /// A table leaf cell, spilling what doesn't fit into overflow pages
+    fn leaf_cell(&mut self, rowid: u64, payload: &[u8]) -> Vec<u8> {
+        let mut cell = Vec::new();
+        put_varint(&mut cell, payload.len() as u64);
+        put_varint(&mut cell, rowid);
+
+        if payload.len() <= MAX_LOCAL {
+            cell.extend_from_slice(payload);
+            return cell;
+        }
+
+        let local = match MIN_LOCAL + (payload.len() - MIN_LOCAL) % (PAGE_SIZE - 4) {
+            local if local <= MAX_LOCAL => local,
+            _ => MIN_LOCAL,
+        };
+        cell.extend_from_slice(&payload[..local]);
+
+        // Overflow pages are chained by a next page number up front, added
+        // back to front so each knows the number of the next
+        let mut next_page = 0u32;
+        let chunks: Vec<&[u8]> = payload[local..].chunks(PAGE_SIZE - 4).collect();
+        for chunk in chunks.into_iter().rev() {
+            let mut page = vec![0u8; PAGE_SIZE];
+            page[..4].copy_from_slice(&next_page.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+            next_page = self.add_page(page);
+        }
+        cell.extend_from_slice(&next_page.to_be_bytes());
+        cell
+    }
+}
+
+/// Interior pages over the children (page number and largest rowid below
+/// it), each with the largest rowid it covers
+fn interior_pages(children: &[(u32, u64)]) -> Vec<(Vec<u8>, u64)> {
+    let mut pages = Vec::new();
+    let mut start = 0;
+    while start < children.len() {
+        // The last child is the right-most pointer rather than a cell
+        let mut end = start + 1;
+        let mut cells = Vec::new();
+        while end < children.len() {
+            let mut cell = children[end - 1].0.to_be_bytes().to_vec();
+            put_varint(&mut cell, children[end - 1].1);
+            cells.push(cell);
+            if !fits(12, &cells) {
+                cells.pop();
+                break;
+            }
+            end += 1;
+        }
+
+        // An interior page needs a cell, so never leave a lone child for the
+        // last one
+        if children.len() - end == 1 && !cells.is_empty() {
+            cells.pop();
+            end -= 1;
+        }
+
+        let (right_page, max_rowid) = children[end - 1];
+        let mut page = btree_page(INTERIOR_PAGE, 0, &cells);
+        page[8..12].copy_from_slice(&right_page.to_be_bytes());
+        pages.push((page, max_rowid));
+        start = end;
+    }
+    pages
+}
+
+/// A leaf page holding the cells, or `None` if they don't fit. `offset` is
+/// where the page's b-tree header starts (after the database header on the
+/// first page).
+fn leaf_page(offset: usize, cells: &[Vec<u8>]) -> Option<Vec<u8>> {
+    match fits(offset + 8, cells) {
+        true => Some(btree_page(LEAF_PAGE, offset, cells)),
+        false => None,
+    }
+}
+
+fn fits(header_end: usize, cells: &[Vec<u8>]) -> bool {
+    let cells_size: usize = cells.iter().map(|cell| cell.len() + 2).sum();
+    header_end + cells_size <= PAGE_SIZE
+}
+
+/// Lays out a b-tree page: its header, the cell pointer array and the cells,
+/// packed against the end of the page in pointer order
+fn btree_page(page_type: u8, offset: usize, cells: &[Vec<u8>]) -> Vec<u8> {
+    let header_size = match page_type {
+        INTERIOR_PAGE => 12,
+        _ => 8,
+    };
+
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut content_start = PAGE_SIZE;
+    for (index, cell) in cells.iter().enumerate() {
+        content_start -= cell.len();
+        page[content_start..content_start + cell.len()].copy_from_slice(cell);
+
+        let pointer = offset + header_size + index * 2;
+        page[pointer..pointer + 2].copy_from_slice(&(content_start as u16).to_be_bytes());
+    }
+
+    // A content area starting at 65536 would be written as 0
+    page[offset] = page_type;
+    page[offset + 3..offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    page[offset + 5..offset + 7].copy_from_slice(&(content_start as u16).to_be_bytes());
+    page
+}
+
+fn database_header(page_count: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..16].copy_from_slice(b"SQLite format 3\0");
+    header[16..18].copy_from_slice(&(PAGE_SIZE as u16).to_be_bytes());
+    header[18] = 1; // Legacy (rollback journal) write version
+    header[19] = 1; // Legacy read version
+    header[21] = 64; // Maximum embedded payload fraction
+    header[22] = 32; // Minimum embedded payload fraction
+    header[23] = 32; // Leaf payload fraction
+    header[24..28].copy_from_slice(&1u32.to_be_bytes()); // File change counter
+    header[28..32].copy_from_slice(&page_count.to_be_bytes());
+    header[40..44].copy_from_slice(&1u32.to_be_bytes()); // Schema cookie
+    header[44..48].copy_from_slice(&4u32.to_be_bytes()); // Schema format
+    header[56..60].copy_from_slice(&1u32.to_be_bytes()); // UTF-8
+    header[92..96].copy_from_slice(&1u32.to_be_bytes()); // Change counter it's valid for
+    header[96..100].copy_from_slice(&SQLITE_VERSION_NUMBER.to_be_bytes());
+    header
+}
+
+/// A row in the record format: a header of serial types, then the values
+fn record(values: &[SqlValue]) -> Vec<u8> {
+    let mut types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        match value {
+            SqlValue::Null => put_varint(&mut types, 0),
+            SqlValue::Integer(integer) => {
+                put_varint(&mut types, 6);
+                body.extend_from_slice(&integer.to_be_bytes());
+            }
+            SqlValue::Real(real) => {
+                put_varint(&mut types, 7);
+                body.extend_from_slice(&real.to_bits().to_be_bytes());
+            }
+            SqlValue::Text(text) => {
+                put_varint(&mut types, text.len() as u64 * 2 + 13);
+                body.extend_from_slice(text.as_bytes());
+            }
+        }
+    }
+
+    // The header's size counts itself, which can take one more byte
+    let mut header_size = types.len() as u64 + 1;
+    if varint_len(header_size) > 1 {
+        header_size = types.len() as u64 + varint_len(types.len() as u64 + 2) as u64;
+    }
+
+    let mut record = Vec::with_capacity(header_size as usize + body.len());
+    put_varint(&mut record, header_size);
+    record.extend(types);
+    record.extend(body);
+    record
+}
+
+fn varint_len(value: u64) -> usize {
+    let mut encoded = Vec::new();
+    put_varint(&mut encoded, value);
+    encoded.len()
+}
+
+/// Big-endian, 7 bits per byte with the high bit set on all but the last,
+/// except that a 9th byte carries a full 8 bits
+fn put_varint(out: &mut Vec<u8>, value: u64) {
+    if value > 0x00FF_FFFF_FFFF_FFFF {
+        let mut bytes = [0u8; 9];
+        bytes[8] = value as u8;
+        let mut rest = value >> 8;
+        for byte in bytes[..8].iter_mut().rev() {
+            *byte = (rest & 0x7F) as u8 | 0x80;
+            rest >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return;
+    }
+
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(bytes.into_iter().rev());
+}
+
+/// Quotes an identifier for the schema's CREATE TABLE
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_varint(bytes: &[u8], mut pos: usize) -> (u64, usize) {
+        let mut value = 0u64;
+        for index in 0..9 {
+            let byte = bytes[pos];
+            pos += 1;
+            if index == 8 {
+                return ((value << 8) | byte as u64, pos);
+            }
+            value = (value << 7) | (byte & 0x7F) as u64;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        (value, pos)
+    }
+
+    fn page(bytes: &[u8], number: u32) -> &[u8] {
+        let start = (number as usize - 1) * PAGE_SIZE;
+        &bytes[start..start + PAGE_SIZE]
+    }
+
+    /// Reads a table b-tree as the file format describes it, independently of
+    /// how the writer laid it out: every row's rowid and payload, in order
+    fn read_table(bytes: &[u8], number: u32, rows: &mut Vec<(u64, Vec<u8>)>) {
+        let offset = if number == 1 { HEADER_SIZE } else { 0 };
+        let page = page(bytes, number);
+        let cell_count = u16::from_be_bytes([page[offset + 3], page[offset + 4]]) as usize;
+        let header_size = if page[offset] == INTERIOR_PAGE { 12 } else { 8 };
+        let pointer = |index: usize| {
+            let at = offset + header_size + index * 2;
+            u16::from_be_bytes([page[at], page[at + 1]]) as usize
+        };
+
+        match page[offset] {
+            INTERIOR_PAGE => {
+                for index in 0..cell_count {
+                    let cell = pointer(index);
+                    let child = u32::from_be_bytes(page[cell..cell + 4].try_into().unwrap());
+                    let (key, _) = read_varint(page, cell + 4);
+                    read_table(bytes, child, rows);
+                    assert!(rows.last().is_some_and(|(rowid, _)| *rowid <= key));
+                }
+                let right = u32::from_be_bytes(page[offset + 8..offset + 12].try_into().unwrap());
+                read_table(bytes, right, rows);
+            }
+            LEAF_PAGE => {
+                for index in 0..cell_count {
+                    let (payload_size, pos) = read_varint(page, pointer(index));
+                    let (rowid, pos) = read_varint(page, pos);
+                    rows.push((rowid, read_payload(bytes, page, pos, payload_size as usize)));
+                }
+            }
+            page_type => panic!("Unexpected page type {} on page {}", page_type, number),
+        }
+    }
+
+    fn read_payload(bytes: &[u8], page: &[u8], pos: usize, size: usize) -> Vec<u8> {
+        let (usable, max_local) = (PAGE_SIZE, PAGE_SIZE - 35);
+        if size <= max_local {
+            return page[pos..pos + size].to_vec();
+        }
+
+        let min_local = (usable - 12) * 32 / 255 - 23;
+        let local = match min_local + (size - min_local) % (usable - 4) {
+            local if local <= max_local => local,
+            _ => min_local,
+        };
+        let mut payload = page[pos..pos + local].to_vec();
+        let mut next = u32::from_be_bytes(page[pos + local..pos + local + 4].try_into().unwrap());
+        while next != 0 {
+            let overflow = self::page(bytes, next);
+            let chunk = (size - payload.len()).min(usable - 4);
+            payload.extend_from_slice(&overflow[4..4 + chunk]);
+            next = u32::from_be_bytes(overflow[..4].try_into().unwrap());
+        }
+        assert_eq!(payload.len(), size);
+        payload
+    }
+
+    fn round_trip(rows: Vec<Vec<SqlValue>>) {
+        let file_path = std::env::temp_dir().join(format!(
+            "dustdb-sqlite-{}-{}.db",
+            std::process::id(),
+            rows.len()
+        ));
+        let columns = vec!["_id".to_owned(), "_json".to_owned()];
+        write_table(&file_path, "docs", &columns, &rows).unwrap();
+        let bytes = fs::read(&file_path).unwrap();
+        fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(&bytes[..16], b"SQLite format 3\0");
+        let page_count = u32::from_be_bytes(bytes[28..32].try_into().unwrap());
+        assert_eq!(bytes.len(), page_count as usize * PAGE_SIZE);
+
+        let mut schema = Vec::new();
+        read_table(&bytes, 1, &mut schema);
+        assert_eq!(schema.len(), 1);
+        let create_table = b"CREATE TABLE \"docs\"(\"_id\", \"_json\")";
+        assert!(schema[0].1.ends_with(create_table));
+
+        let mut read_rows = Vec::new();
+        read_table(&bytes, 2, &mut read_rows);
+        assert_eq!(read_rows.len(), rows.len());
+        for (index, ((rowid, payload), row)) in read_rows.iter().zip(&rows).enumerate() {
+            assert_eq!(*rowid, index as u64 + 1);
+            assert_eq!(*payload, record(row));
+        }
+    }
+
+    fn row(index: usize, text_size: usize) -> Vec<SqlValue> {
+        vec![
+            SqlValue::Integer(index as i64),
+            SqlValue::Text("x".repeat(text_size)),
+        ]
+    }
+
+    #[test]
+    fn encodes_varints_and_records() {
+        let encoded = |value| {
+            let mut out = Vec::new();
+            put_varint(&mut out, value);
+            out
+        };
+        assert_eq!(encoded(0x7F), vec![0x7F]);
+        assert_eq!(encoded(0x80), vec![0x81, 0x00]);
+        assert_eq!(encoded(0x3FFF), vec![0xFF, 0x7F]);
+        assert_eq!(encoded(u64::MAX), vec![0xFF; 9]);
+        for value in [0, 300, 1 << 40, 0x00FF_FFFF_FFFF_FFFF, 1 << 60, u64::MAX] {
+            assert_eq!(read_varint(&encoded(value), 0), (value, varint_len(value)));
+        }
+
+        let values = [
+            SqlValue::Integer(1),
+            SqlValue::Text("ab".to_owned()),
+            SqlValue::Null,
+        ];
+        let mut expected = vec![4, 6, 17, 0];
+        expected.extend_from_slice(&1i64.to_be_bytes());
+        expected.extend_from_slice(b"ab");
+        assert_eq!(record(&values), expected);
+    }
+
+    #[test]
+    fn lays_out_leaves_and_interior_pages() {
+        round_trip(Vec::new());
+        round_trip((0..1).map(|index| row(index, 10)).collect());
+        // Over a single interior page's worth of leaves
+        round_trip((0..150_000).map(|index| row(index, 10)).collect());
+    }
+
+    #[test]
+    fn spills_large_rows_into_overflow_pages() {
+        let sizes = [MAX_LOCAL - 20, MAX_LOCAL, MAX_LOCAL + 1, 10_000, 100_000];
+        round_trip(
+            sizes
+                .iter()
+                .enumerate()
+                .map(|(index, size)| row(index, *size))
+                .collect(),
+        );
+    }
+}
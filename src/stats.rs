@@ -0,0 +1,186 @@
+/// Per-pile statistics.
+///
+/// Every pile keeps running totals of its live documents in a `.stats.json`
+/// file next to them, so `STATS <pile>` never walks the pile's directory:
+///
+/// documents      how many there are (soft deleted ones don't count)
+/// bytes          their total size, as stored
+/// last_write_at  when one was last written, deleted or restored
+///
+/// The totals are adjusted by the files actually added, changed or removed,
+/// so replaying the WAL over documents already on disk leaves them as they
+/// are. They are kept apart from the pile's manifest (see pile.rs), which
+/// every write would otherwise rewrite under settings being changed at the
+/// same time. Piles from before kept them in the manifest, under `stats`,
+/// and a pile from before the totals existed is walked once, the first time
+/// it changes or its statistics are asked for.
+use crate::logging::{self, Level};
+use crate::pile::{document_paths, pile_path, PileMeta};
+use crate::timestamp_now;
+use serde_json::{from_str, json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+const STATS_FILE_NAME: &str = ".stats.json";
+
+/// Serializes updates of a pile's totals, which are read, adjusted and saved.
+/// Writers call in holding the pile's own lock or not, so this is a separate
+/// one.
+fn lock(pile_name: &str) -> Arc<Mutex<()>> {
+    static STATS_LOCKS: OnceLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> = OnceLock::new();
+
+    let mut stats_locks = STATS_LOCKS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    stats_locks
+        .entry(pile_name.to_owned())
+        .or_insert_with(|| Arc::new(Mutex::new(())))
+        .clone()
+}
+
+/// Adjusts the pile's totals after a document was written or removed. The
+/// document itself has landed by then, so a failure here is only reported.
+pub fn record(pile_name: &str, documents_delta: i64, bytes_delta: i64) {
+    if let Err(e) = try_record(pile_name, documents_delta, bytes_delta) {
+        logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Error updating statistics of pile \"{}\": {:?}",
+                pile_name, e
+            ),
+        );
+    }
+}
+
+fn try_record(pile_name: &str, documents_delta: i64, bytes_delta: i64) -> Result<(), io::Error> {
+    let stats_lock = lock(pile_name);
+    let _guard = stats_lock.lock().unwrap_or_else(|e| e.into_inner());
+    let stats = load(pile_name)?;
+
+    // A walk already sees the change being recorded
+    let (documents, bytes) = match totals(&stats) {
+        Some((documents, bytes)) => (
+            documents.saturating_add_signed(documents_delta),
+            bytes.saturating_add_signed(bytes_delta),
+        ),
+        None => walk(pile_name)?,
+    };
+
+    save(
+        pile_name,
+        &json!({
+            "documents": documents,
+            "bytes": bytes,
+            "last_write_at": timestamp_now(),
+        }),
+    )?;
+    // The pile's first write is when it was created
+    PileMeta::create(pile_name)
+}
+
+/// Example:
+/// in: STATS users
+/// out: {"documents":1204,"bytes":368420,"average_bytes":306,"last_write_at":"2024-05-01T12:00:00.000Z"}
+pub fn get(pile_name: &str) -> Result<Value, io::Error> {
+    let stats_lock = lock(pile_name);
+    let _guard = stats_lock.lock().unwrap_or_else(|e| e.into_inner());
+    let stats = load(pile_name)?;
+
+    let (documents, bytes) = match totals(&stats) {
+        Some(totals) => totals,
+        None => {
+            let (documents, bytes) = walk(pile_name)?;
+            // Saving would create an empty pile that doesn't exist yet
+            if documents > 0 {
+                save(
+                    pile_name,
+                    &json!({ "documents": documents, "bytes": bytes }),
+                )?;
+            }
+            (documents, bytes)
+        }
+    };
+
+    Ok(json!({
+        "documents": documents,
+        "bytes": bytes,
+        "average_bytes": bytes.checked_div(documents).unwrap_or(0),
+        "last_write_at": stats.get("last_write_at").cloned().unwrap_or(Value::Null),
+    }))
+}
+
+/// Replaces the pile's totals with a fresh count, e.g. after a crash left
+/// them out of step with the documents
+pub fn recount(pile_name: &str) -> Result<(), io::Error> {
+    let stats_lock = lock(pile_name);
+    let _guard = stats_lock.lock().unwrap_or_else(|e| e.into_inner());
+    let stats = load(pile_name)?;
+
+    let (documents, bytes) = walk(pile_name)?;
+    save(
+        pile_name,
+        &json!({
+            "documents": documents,
+            "bytes": bytes,
+            "last_write_at": stats.get("last_write_at").cloned().unwrap_or(Value::Null),
+        }),
+    )
+}
+
+fn stats_file_path(pile_name: &str) -> Result<PathBuf, io::Error> {
+    Ok(Path::new(&pile_path(pile_name)?).join(STATS_FILE_NAME))
+}
+
+/// The pile's saved totals, or those its manifest held before they had a
+/// file of their own, or nothing
+fn load(pile_name: &str) -> Result<Value, io::Error> {
+    match fs::read_to_string(stats_file_path(pile_name)?) {
+        Ok(file_content) => Ok(from_str(&file_content)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PileMeta::load(pile_name)?
+            .get("stats")
+            .cloned()
+            .unwrap_or(Value::Null)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Swapped in with a rename, like the manifest
+fn save(pile_name: &str, stats: &Value) -> Result<(), io::Error> {
+    let pile_path = pile_path(pile_name)?;
+    fs::create_dir_all(&pile_path)?;
+
+    let stats_path = Path::new(&pile_path).join(STATS_FILE_NAME);
+    let tmp_path = Path::new(&pile_path).join(format!("{}.tmp", STATS_FILE_NAME));
+    fs::write(&tmp_path, stats.to_string())?;
+    fs::rename(tmp_path, stats_path)
+}
+
+fn totals(stats: &Value) -> Option<(u64, u64)> {
+    Some((
+        stats.get("documents")?.as_u64()?,
+        stats.get("bytes")?.as_u64()?,
+    ))
+}
+
+/// Counts the pile's documents and their sizes from scratch
+fn walk(pile_name: &str) -> Result<(u64, u64), io::Error> {
+    let mut documents = 0;
+    let mut bytes = 0;
+    for file_path in document_paths(pile_name)? {
+        match fs::metadata(file_path) {
+            Ok(metadata) => {
+                documents += 1;
+                bytes += metadata.len();
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((documents, bytes))
+}
@@ -0,0 +1,102 @@
+/// systemd integration.
+///
+/// With socket activation (a `dustdb.socket` unit), systemd binds the port
+/// and hands the listening socket to the server as file descriptor 3,
+/// announced through `LISTEN_PID` and `LISTEN_FDS`; the server then starts
+/// on the first connection. Under a `Type=notify` service, the server tells
+/// systemd through `NOTIFY_SOCKET` once it accepts connections (`READY=1`)
+/// and when it begins to shut down (`STOPPING=1`).
+///
+/// Outside of systemd none of these variables are set and both are no-ops.
+/// Either way, SIGTERM (what systemd stops services with) shuts the server
+/// down after the notification instead of killing it outright.
+use crate::logging::{self, Level};
+use std::env;
+use std::io;
+use std::net::TcpListener;
+
+/// The listening socket passed by systemd socket activation, if any
+#[cfg(unix)]
+pub fn inherited_listener() -> Result<Option<TcpListener>, io::Error> {
+    use std::os::unix::io::FromRawFd;
+
+    /// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+    const LISTEN_FDS_START: i32 = 3;
+
+    // Variables inherited from a parent that was itself activated aren't ours
+    let is_for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let fd_count: i32 = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fd_count| fd_count.parse().ok())
+        .unwrap_or(0);
+
+    match (is_for_us, fd_count) {
+        (false, _) | (true, 0) => Ok(None),
+        (true, 1) => {
+            // SAFETY: systemd passed us this descriptor and nothing else owns it
+            let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+            listener.set_nonblocking(true)?;
+            Ok(Some(listener))
+        }
+        (true, fd_count) => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Expected one socket from systemd, got {}", fd_count);
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener() -> Result<Option<TcpListener>, io::Error> {
+    Ok(None)
+}
+
+/// Sends a state (e.g. `READY=1`) to systemd's notification socket, if the
+/// server runs under a notify service. Failures are reported and otherwise
+/// ignored, systemd will time the service out on its own.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket_path = match env::var("NOTIFY_SOCKET") {
+        Ok(socket_path) => socket_path,
+        Err(_) => return,
+    };
+
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        match socket_path.strip_prefix('@') {
+            // Abstract socket names start with a NUL byte instead
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &socket_path),
+        }
+    });
+
+    if let Err(e) = sent {
+        logging::diagnostic(
+            Level::Error,
+            &format!("Error notifying systemd ({}): {:?}", state, e),
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// Resolves once the server is asked to shut down
+#[cfg(unix)]
+pub async fn terminated() -> Result<(), io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn terminated() -> Result<(), io::Error> {
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,196 @@
+/// Minimal tar support for pile archives.
+///
+/// Reads and writes regular files in the POSIX ustar format
+/// (https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html), so
+/// archives can be listed and unpacked with any tar tool. Directories,
+/// links and extended headers aren't supported; a path longer than 100 bytes
+/// is split over the header's prefix field at a `/`.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const BLOCK_SIZE: usize = 512;
+
+/// A file in the archive
+pub struct Entry {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// Writes the entries to `file_path` as a tar archive, each with the given
+/// modification time (seconds since the epoch)
+pub fn write(file_path: &Path, entries: &[Entry], mtime: u64) -> Result<(), io::Error> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        bytes.extend_from_slice(&header(&entry.path, entry.data.len() as u64, mtime)?);
+        bytes.extend_from_slice(&entry.data);
+        bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+    }
+
+    // The archive ends with two zero blocks
+    bytes.resize(bytes.len() + 2 * BLOCK_SIZE, 0);
+    fs::write(file_path, bytes)
+}
+
+/// Reads every regular file in the tar archive at `file_path`
+pub fn read(file_path: &Path) -> Result<Vec<Entry>, io::Error> {
+    let bytes = fs::read(file_path)?;
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= bytes.len() {
+        let header = &bytes[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        if parse_octal(&header[148..156])? != checksum(header) {
+            return Err(invalid_archive("a header's checksum doesn't match"));
+        }
+
+        let size = parse_octal(&header[124..136])? as usize;
+        let data_start = offset + BLOCK_SIZE;
+        let data_end = data_start + size;
+        if data_end > bytes.len() {
+            return Err(invalid_archive("a file runs past the end of the archive"));
+        }
+
+        // Anything but a regular file is skipped
+        if matches!(header[156], b'0' | 0) {
+            let name = text_field(&header[0..100]);
+            let prefix = text_field(&header[345..500]);
+            let path = match prefix.is_empty() {
+                true => name,
+                false => format!("{}/{}", prefix, name),
+            };
+            entries.push(Entry {
+                path,
+                data: bytes[data_start..data_end].to_vec(),
+            });
+        }
+
+        offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    }
+
+    Ok(entries)
+}
+
+/// Whether a header can hold the path
+pub fn fits(path: &str) -> bool {
+    split_path(path).is_some()
+}
+
+/// The path as the header's prefix and name fields
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    match path.len() {
+        0..=100 => Some(("", path)),
+        _ => match path.rsplit_once('/') {
+            Some((prefix, name)) if prefix.len() <= 155 && name.len() <= 100 => {
+                Some((prefix, name))
+            }
+            _ => None,
+        },
+    }
+}
+
+fn header(path: &str, size: u64, mtime: u64) -> Result<[u8; BLOCK_SIZE], io::Error> {
+    let (prefix, name) = match split_path(path) {
+        Some((prefix, name)) => (prefix, name),
+        None => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Path is too long for a tar archive: \"{}\"", path);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut header[100..108], 0o644); // Mode
+    put_octal(&mut header[108..116], 0); // Owner
+    put_octal(&mut header[116..124], 0); // Group
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], mtime);
+    header[156] = b'0'; // Regular file
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is six octal digits, a NUL and a space
+    let checksum = checksum(&header);
+    header[148..154].copy_from_slice(format!("{:06o}", checksum).as_bytes());
+    header[154] = 0;
+    header[155] = b' ';
+    Ok(header)
+}
+
+/// The sum of the header's bytes, counting the checksum field as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| match index {
+            148..=155 => b' ' as u64,
+            _ => byte as u64,
+        })
+        .sum()
+}
+
+/// Zero padded octal, NUL terminated
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64, io::Error> {
+    let digits = text_field(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    match digits.is_empty() {
+        true => Ok(0),
+        false => u64::from_str_radix(digits, 8)
+            .map_err(|_| invalid_archive("a header holds a malformed number")),
+    }
+}
+
+fn text_field(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn invalid_archive(reason: &str) -> io::Error {
+    let e_kind = io::ErrorKind::InvalidData;
+    let e = format!("Not a valid tar archive: {}", reason);
+    io::Error::new(e_kind, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_long_paths_over_the_prefix() {
+        let long_path = format!("{}/{}", "p".repeat(64), "f".repeat(100));
+        assert!(fits(&long_path));
+        assert!(!fits(&format!("{}/{}", "p".repeat(64), "f".repeat(101))));
+        assert!(!fits(&"f".repeat(101)));
+
+        let file_path = std::env::temp_dir().join(format!("dustdb-tar-{}.tar", std::process::id()));
+        let entries = vec![
+            Entry {
+                path: "manifest.json".to_owned(),
+                data: b"{}".to_vec(),
+            },
+            Entry {
+                path: long_path.clone(),
+                data: vec![b'x'; 600],
+            },
+        ];
+        write(&file_path, &entries, 0).unwrap();
+        let read_entries = read(&file_path).unwrap();
+        fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(read_entries.len(), 2);
+        assert_eq!(read_entries[1].path, long_path);
+        assert_eq!(read_entries[1].data, vec![b'x'; 600]);
+    }
+}
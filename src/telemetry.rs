@@ -0,0 +1,63 @@
+/// Tracing.
+///
+/// Connections, requests and their stages are instrumented with `tracing`
+/// spans:
+///
+/// connection  one client connection
+/// request     one command, with its request ID
+/// parse       splitting and parsing the command
+/// <storage>   find, create, scan, ... (tagged with the pile)
+/// serialize   encoding payloads for the response
+///
+/// Built with the `otlp` feature and started with `DUST_OTLP_ENDPOINT` set
+/// (e.g. http://localhost:4317), the spans are exported over OTLP/gRPC to
+/// Jaeger, Tempo or any other collector. Otherwise nothing listens to them and
+/// they cost next to nothing.
+use std::error::Error;
+
+#[cfg(feature = "otlp")]
+pub fn init() -> Result<(), Box<dyn Error>> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let endpoint = match std::env::var("DUST_OTLP_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => return Ok(()),
+    };
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(&endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", "dustdb")]))
+        .build();
+    let tracer = provider.tracer("dustdb");
+    opentelemetry::global::set_tracer_provider(provider);
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+
+    crate::logging::diagnostic(
+        crate::logging::Level::Info,
+        &format!("dustdb exporting traces to: {}", endpoint),
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init() -> Result<(), Box<dyn Error>> {
+    if std::env::var("DUST_OTLP_ENDPOINT").is_ok() {
+        return Err(
+            "DUST_OTLP_ENDPOINT is set, but dustdb was built without the otlp feature".into(),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+/// Recycle bin for deleted documents.
+///
+/// A document deleted by a client (DELETE or DELETEWHERE, and whatever they
+/// cascade to) in a pile without soft delete is kept in the pile's hidden
+/// `.trash` directory for `DUST_TRASH_RETENTION_DAYS` (default 7, 0 turns the
+/// recycle bin off), and `UNDELETE <pile> <uuid>` brings it back until then.
+/// The janitor purges it afterwards (see janitor.rs).
+///
+/// Documents removed by the server itself (expiry, capped piles, purged
+/// tombstones) don't go through the recycle bin, nor do WAL replays. Deleting
+/// a document again replaces its earlier copy in the trash.
+use crate::pile::{pile_path, PileMeta};
+use crate::{env_or, write_new_document, DocumentId};
+use dustcfg::get_env_var;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const TRASH_DIR_NAME: &str = ".trash";
+
+fn retention() -> Option<Duration> {
+    match env_or("DUST_TRASH_RETENTION_DAYS", 7u64) {
+        0 => None,
+        days => Some(Duration::from_secs(days.saturating_mul(86_400))),
+    }
+}
+
+fn trash_file_path(pile_name: &str, uuid: &str) -> Result<PathBuf, io::Error> {
+    Ok(Path::new(&pile_path(pile_name)?)
+        .join(TRASH_DIR_NAME)
+        .join(format!("{}.{}", uuid, get_env_var("DUST_DATA_FMT"))))
+}
+
+/// Keeps a copy of the document before it's deleted. The copy's modification
+/// time records when it was deleted, which is what the purge goes by.
+pub fn keep(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    if retention().is_none() {
+        return Ok(());
+    }
+
+    let file_path = Path::new(&pile_path(pile_name)?).join(format!(
+        "{}.{}",
+        uuid,
+        get_env_var("DUST_DATA_FMT")
+    ));
+    let trash_path = trash_file_path(pile_name, uuid)?;
+    if let Some(trash_dir) = trash_path.parent() {
+        fs::create_dir_all(trash_dir)?;
+    }
+    match fs::remove_file(&trash_path) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    // A hard link costs nothing, the delete then only unlinks the original
+    if fs::hard_link(&file_path, &trash_path).is_err() {
+        fs::copy(&file_path, &trash_path)?;
+    }
+    fs::File::options()
+        .write(true)
+        .open(&trash_path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Example:
+/// in: UNDELETE users cd8abd45-ad36-4cf6-a520-c1c5d0671d96
+/// out:
+///
+/// Writes the document back under its ID, through the pile's rules like a
+/// new one, and takes it out of the trash. Fails if the ID was taken again
+/// since.
+pub fn undelete(pile_name: &str, uuid: &str) -> Result<(), io::Error> {
+    let trash_path = trash_file_path(pile_name, uuid)?;
+    let data = match fs::read_to_string(&trash_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = format!("Could not find document in the trash: \"{}\"", uuid);
+            return Err(io::Error::new(e_kind, e));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    write_new_document(&pile_meta, pile_name, DocumentId::New(uuid), &data)?;
+
+    match fs::remove_file(&trash_path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the pile's trashed documents past the retention window (or all of
+/// them once the recycle bin is off), returning how many were removed
+pub fn purge(pile_name: &str) -> Result<usize, io::Error> {
+    let trash_dir = Path::new(&pile_path(pile_name)?).join(TRASH_DIR_NAME);
+    let entries = match fs::read_dir(&trash_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let retention = retention().unwrap_or_default();
+    let mut purged = 0;
+    let mut kept = 0;
+    for entry in entries {
+        let entry = entry?;
+        let deleted_at = entry.metadata()?.modified()?;
+        if deleted_at
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed >= retention)
+        {
+            match fs::remove_file(entry.path()) {
+                Ok(_) => purged += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        } else {
+            kept += 1;
+        }
+    }
+
+    // An empty trash would keep an emptied pile from being cleaned up
+    if kept == 0 {
+        match fs::remove_dir(&trash_dir) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            // A document trashed in the meantime
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(purged)
+}
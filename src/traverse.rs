@@ -0,0 +1,111 @@
+/// Graph traversal over reference fields.
+///
+/// Starting from one document, TRAVERSE follows a field holding a UUID (or an
+/// array of UUIDs) breadth first, hop after hop, and returns every document
+/// it reaches. If the pile declares a REFERENCE on the field, the UUIDs are
+/// looked up in the referenced pile, otherwise in the same pile; that way a
+/// traversal can cross from pile to pile.
+use crate::payload::Encoding;
+use crate::pile::{pile_path, PileMeta};
+use crate::{document_file_path, document_with_id, env_or, is_valid_document_id};
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+
+/// The pile the UUIDs in `field_name` of `pile_name`'s documents point into
+fn target_pile(pile_name: &str, field_name: &str) -> Result<String, io::Error> {
+    let target_pile = PileMeta::load(pile_name)?
+        .references()
+        .into_iter()
+        .find(|reference| reference.field == field_name)
+        .map(|reference| reference.pile);
+
+    Ok(target_pile.unwrap_or_else(|| pile_name.to_owned()))
+}
+
+/// Every pile a traversal from `pile_name` can reach within `depth` hops
+pub fn reachable_piles(
+    pile_name: &str,
+    field_name: &str,
+    depth: usize,
+) -> Result<Vec<String>, io::Error> {
+    let mut pile_names = vec![pile_name.to_owned()];
+    for _ in 0..depth {
+        let next_pile = target_pile(pile_names.last().unwrap(), field_name)?;
+        if pile_names.contains(&next_pile) {
+            break;
+        }
+        pile_names.push(next_pile);
+    }
+
+    Ok(pile_names)
+}
+
+/// Example:
+/// in: TRAVERSE employees cd8abd45-ad36-4cf6-a520-c1c5d0671d96 manager_id DEPTH 2
+/// out: 7ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC07ABC0
+///
+/// The decoded output is JSONL in breadth first order, starting with the
+/// document itself. Each document carries its `_id`, the `_pile` it lives in
+/// and the `_depth` (number of hops) it was reached at. Documents are
+/// visited once, so cycles are harmless, and UUIDs that don't resolve are
+/// skipped. A traversal stops with an error once it would return more than
+/// `DUST_MAX_TRAVERSE_DOCUMENTS` (default 10000) documents.
+#[tracing::instrument(skip_all, fields(pile = pile_name))]
+pub fn traverse(
+    pile_name: &str,
+    uuid: &str,
+    field_name: &str,
+    depth: usize,
+    encoding: Encoding,
+) -> Result<String, io::Error> {
+    let max_documents = env_or("DUST_MAX_TRAVERSE_DOCUMENTS", 10_000);
+    let mut visited: HashSet<(String, String)> = HashSet::new();
+    let mut queue = VecDeque::from([(pile_name.to_owned(), uuid.to_owned(), 0)]);
+    let mut documents = Vec::new();
+
+    while let Some((pile_name, uuid, document_depth)) = queue.pop_front() {
+        if !visited.insert((pile_name.clone(), uuid.clone())) || !is_valid_document_id(&uuid) {
+            continue;
+        }
+
+        let file_path = document_file_path(&pile_path(&pile_name)?, &uuid);
+        if !Path::new(&file_path).is_file() {
+            continue;
+        }
+        let mut document = match document_with_id(Path::new(&file_path))? {
+            Some(document) => document,
+            None => continue,
+        };
+
+        if document_depth < depth {
+            let next_pile = target_pile(&pile_name, field_name)?;
+            let next_uuids: Vec<&str> = match document.get(field_name) {
+                Some(Value::String(next_uuid)) => vec![next_uuid],
+                Some(Value::Array(next_uuids)) => {
+                    next_uuids.iter().filter_map(Value::as_str).collect()
+                }
+                _ => Vec::new(),
+            };
+
+            for next_uuid in next_uuids {
+                queue.push_back((next_pile.clone(), next_uuid.to_owned(), document_depth + 1));
+            }
+        }
+
+        if documents.len() == max_documents {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Traversal reaches more than {} documents", max_documents);
+            return Err(io::Error::new(e_kind, e));
+        }
+
+        if let Some(json_object) = document.as_object_mut() {
+            json_object.insert("_pile".to_owned(), Value::from(pile_name));
+            json_object.insert("_depth".to_owned(), Value::from(document_depth));
+        }
+        documents.push(document.to_string());
+    }
+
+    Ok(encoding.encode_jsonl(&documents.join("\n")))
+}
@@ -0,0 +1,159 @@
+/// Write hooks declared in pile metadata.
+///
+/// After a write to a pile has been committed, each of the pile's triggers
+/// for that kind of write runs one of these actions:
+///
+/// COPY <pile>   write a copy of the new document (with `_source_pile` and
+///               `_source_id` added) into another pile
+/// AUDIT <pile>  append an audit record of the write to another pile
+///
+/// Trigger failures never undo the write that fired them; they are reported
+/// on stdout like other background errors.
+use crate::logging::{self, Level};
+use crate::{store_document, timestamp_now, DocumentId};
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::io;
+
+/// Triggers writing to piles with triggers of their own can chain; this caps
+/// how deep a chain (or an accidental cycle) may go
+const MAX_TRIGGER_DEPTH: u8 = 8;
+
+thread_local! {
+    static TRIGGER_DEPTH: Cell<u8> = const { Cell::new(0) };
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TriggerEvent {
+    Create,
+    Delete,
+}
+
+impl TriggerEvent {
+    pub fn parse(input: &str) -> Option<TriggerEvent> {
+        match input {
+            "CREATE" => Some(TriggerEvent::Create),
+            "DELETE" => Some(TriggerEvent::Delete),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            TriggerEvent::Create => "CREATE",
+            TriggerEvent::Delete => "DELETE",
+        }
+    }
+}
+
+pub enum TriggerAction {
+    Copy { target_pile: String },
+    Audit { target_pile: String },
+}
+
+pub struct Trigger {
+    pub event: TriggerEvent,
+    pub action: TriggerAction,
+}
+
+impl Trigger {
+    pub fn to_json(&self) -> Value {
+        match self.action {
+            TriggerAction::Copy { ref target_pile } => {
+                json!({ "on": self.event.as_str(), "action": "COPY", "target": target_pile })
+            }
+            TriggerAction::Audit { ref target_pile } => {
+                json!({ "on": self.event.as_str(), "action": "AUDIT", "target": target_pile })
+            }
+        }
+    }
+
+    pub fn from_json(trigger: &Value) -> Option<Trigger> {
+        let event = TriggerEvent::parse(trigger.get("on")?.as_str()?)?;
+        let target_pile = trigger
+            .get("target")
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+
+        let action = match (trigger.get("action")?.as_str()?, target_pile) {
+            ("COPY", Some(target_pile)) => TriggerAction::Copy { target_pile },
+            ("AUDIT", Some(target_pile)) => TriggerAction::Audit { target_pile },
+            _ => return None,
+        };
+
+        Some(Trigger { event, action })
+    }
+}
+
+/// Runs every trigger of `pile_name` declared for `event`
+pub fn run(
+    triggers: &[Trigger],
+    event: TriggerEvent,
+    pile_name: &str,
+    uuid: &str,
+    document: Option<&Value>,
+) {
+    let depth = TRIGGER_DEPTH.with(Cell::get);
+    if depth >= MAX_TRIGGER_DEPTH {
+        logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Error running triggers of pile \"{}\": trigger chain deeper than {}",
+                pile_name, MAX_TRIGGER_DEPTH
+            ),
+        );
+        return;
+    }
+
+    TRIGGER_DEPTH.with(|d| d.set(depth + 1));
+    for trigger in triggers.iter().filter(|trigger| trigger.event == event) {
+        if let Err(e) = run_action(&trigger.action, event, pile_name, uuid, document) {
+            logging::diagnostic(
+                Level::Error,
+                &format!("Error running trigger of pile \"{}\": {:?}", pile_name, e),
+            );
+        }
+    }
+    TRIGGER_DEPTH.with(|d| d.set(depth));
+}
+
+fn run_action(
+    action: &TriggerAction,
+    event: TriggerEvent,
+    pile_name: &str,
+    uuid: &str,
+    document: Option<&Value>,
+) -> Result<(), io::Error> {
+    match action {
+        TriggerAction::Copy { target_pile } => {
+            let mut copy = match document {
+                Some(document) => document.clone(),
+                None => return Ok(()),
+            };
+
+            if let Some(copy_object) = copy.as_object_mut() {
+                copy_object.insert("_source_pile".to_owned(), json!(pile_name));
+                copy_object.insert("_source_id".to_owned(), json!(uuid));
+            }
+
+            store_document(target_pile, DocumentId::Generated, &copy.to_string())?;
+            Ok(())
+        }
+        TriggerAction::Audit { target_pile } => {
+            let audit_record = json!({
+                "op": event.as_str(),
+                "pile": pile_name,
+                "uuid": uuid,
+                "at": timestamp_now(),
+                "document": document,
+            });
+
+            store_document(
+                target_pile,
+                DocumentId::Generated,
+                &audit_record.to_string(),
+            )?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,246 @@
+/// Background expiry of documents.
+///
+/// A document expires once the time in its `_expires_at` field (RFC 3339) has
+/// passed, or `_ttl_seconds` after it was created (its `_created_at` field if
+/// present, the file's modification time otherwise). Expired documents are
+/// deleted by a worker that sweeps every pile on a fixed interval.
+///
+/// `EXPIRE` and `PERSIST` attach expiry to an existing document or take it
+/// away, by setting or removing those fields.
+///
+/// The same sweep purges tombstones of soft deleted documents once they are
+/// older than their pile's `purge_after_days`.
+use crate::cache::read_document;
+use crate::logging::{self, Level};
+use crate::pile::{self, document_paths, pile_names, tombstone_paths, PileMeta};
+use crate::{delete_document, env_or, modify_document};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde_json::{from_str, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAX_TTL_SECONDS: i64 = i64::MAX / 1_000;
+
+/// Starts the sweeper on the tokio runtime. The interval is read from
+/// `DUST_TTL_SWEEP_INTERVAL_SECS` (default 60 seconds, 0 disables the worker).
+pub fn spawn_worker() {
+    let interval_secs: u64 = env_or("DUST_TTL_SWEEP_INTERVAL_SECS", 60);
+    if interval_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+
+            // The sweep is plain blocking filesystem work, keep it off the
+            // threads that drive client connections
+            match tokio::task::spawn_blocking(sweep).await {
+                Ok(Ok(0)) => (),
+                Ok(Ok(expired)) => {
+                    logging::diagnostic(Level::Info, &format!("Expired {} document(s)", expired))
+                }
+                Ok(Err(e)) => logging::diagnostic(
+                    Level::Error,
+                    &format!("Error sweeping expired documents: {:?}", e),
+                ),
+                Err(e) => logging::diagnostic(
+                    Level::Error,
+                    &format!("Error joining expiry sweep: {:?}", e),
+                ),
+            }
+        }
+    });
+}
+
+/// Example:
+/// in: EXPIRE sessions cd8abd45-ad36-4cf6-a520-c1c5d0671d96 3600
+/// out: 2024-05-01T13:00:00.000Z
+///
+/// Sets the document's `_expires_at` to the given number of seconds from now
+/// (replacing any `_ttl_seconds`) and returns it. The next sweep after that
+/// deletes the document.
+pub fn expire(pile_name: &str, uuid: &str, seconds: u64) -> Result<String, io::Error> {
+    let seconds = seconds.min(MAX_TTL_SECONDS as u64) as i64;
+    let expires_at = match Utc::now().checked_add_signed(Duration::seconds(seconds)) {
+        Some(expires_at) => expires_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        None => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Expiry is too far in the future: {} seconds", seconds);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let pile_meta = PileMeta::load(pile_name)?;
+    modify_document(
+        &pile_meta,
+        pile_name,
+        uuid,
+        |json_content| match json_content.as_object_mut() {
+            Some(json_object) => {
+                json_object.remove("_ttl_seconds");
+                json_object.insert("_expires_at".to_owned(), Value::String(expires_at.clone()));
+                Ok(())
+            }
+            None => {
+                let e_kind = io::ErrorKind::InvalidData;
+                let e = format!("Document \"{}\" is not a JSON object", uuid);
+                Err(io::Error::new(e_kind, e))
+            }
+        },
+    )?;
+
+    Ok(expires_at)
+}
+
+/// Example:
+/// in: PERSIST sessions cd8abd45-ad36-4cf6-a520-c1c5d0671d96
+/// out: 1
+///
+/// Removes the document's `_expires_at` and `_ttl_seconds`, so it's kept
+/// until deleted. Returns 1 if it had an expiry, 0 if it didn't.
+pub fn persist(pile_name: &str, uuid: &str) -> Result<usize, io::Error> {
+    let pile_meta = PileMeta::load(pile_name)?;
+    let persisted = modify_document(&pile_meta, pile_name, uuid, |json_content| {
+        if let Some(json_object) = json_content.as_object_mut() {
+            json_object.remove("_expires_at");
+            json_object.remove("_ttl_seconds");
+        }
+        Ok(())
+    })?;
+
+    Ok(persisted.is_some() as usize)
+}
+
+/// Deletes every expired document in every pile, returning how many were
+/// removed
+pub fn sweep() -> Result<usize, io::Error> {
+    let now = Utc::now();
+    let mut expired = 0;
+
+    for pile_name in pile_names()? {
+        // Nothing is ever removed from an append-only pile, expired or not
+        let pile_meta = PileMeta::load(&pile_name)?;
+        if pile_meta.append_only() {
+            continue;
+        }
+
+        if let Some(Some(purge_after_days)) = pile_meta.soft_delete() {
+            expired += purge_tombstones(&pile_name, purge_after_days)?;
+        }
+
+        for file_path in document_paths(&pile_name)? {
+            // Documents that vanish or don't parse mid-sweep are skipped
+            let document = match read_document(&file_path) {
+                Ok(document) => document,
+                Err(_) => continue,
+            };
+
+            if expires_at(&document.json, &file_path).is_some_and(|expires_at| expires_at <= now) {
+                if let Some(uuid) = file_path.file_stem().and_then(|stem| stem.to_str()) {
+                    expired += delete_if_expired(&pile_name, uuid, &file_path, now)? as usize;
+                }
+            }
+        }
+    }
+
+    Ok(expired)
+}
+
+fn purge_tombstones(pile_name: &str, purge_after_days: u64) -> Result<usize, io::Error> {
+    let retention = std::time::Duration::from_secs(purge_after_days.saturating_mul(86_400));
+    let mut purged = 0;
+
+    for tombstone_path in tombstone_paths(pile_name)? {
+        let deleted_at = match fs::metadata(&tombstone_path).and_then(|meta| meta.modified()) {
+            Ok(deleted_at) => deleted_at,
+            Err(_) => continue,
+        };
+
+        if deleted_at
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed >= retention)
+        {
+            if let Some(uuid) = tombstone_path.file_stem().and_then(|stem| stem.to_str()) {
+                purged += purge_if_old(pile_name, uuid, &tombstone_path, retention)? as usize;
+            }
+        }
+    }
+
+    Ok(purged)
+}
+
+/// The sweep reads documents without the pile's lock, so the expiry is
+/// checked again under it: an UPDATE or EXPIRE may have pushed it back since
+fn delete_if_expired(
+    pile_name: &str,
+    uuid: &str,
+    file_path: &Path,
+    now: DateTime<Utc>,
+) -> Result<bool, io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let json_content: Value = match fs::read_to_string(file_path) {
+        Ok(file_content) => match from_str(&file_content) {
+            Ok(json_content) => json_content,
+            Err(_) => return Ok(false),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if expires_at(&json_content, file_path).is_none_or(|expires_at| expires_at > now) {
+        return Ok(false);
+    }
+
+    delete_document(pile_name, uuid)?;
+    Ok(true)
+}
+
+/// Like `delete_if_expired`, against a RESTORE since the tombstone was found
+fn purge_if_old(
+    pile_name: &str,
+    uuid: &str,
+    tombstone_path: &Path,
+    retention: std::time::Duration,
+) -> Result<bool, io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let deleted_at = match fs::metadata(tombstone_path).and_then(|meta| meta.modified()) {
+        Ok(deleted_at) => deleted_at,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !deleted_at
+        .elapsed()
+        .is_ok_and(|elapsed| elapsed >= retention)
+    {
+        return Ok(false);
+    }
+
+    delete_document(pile_name, uuid)?;
+    Ok(true)
+}
+
+fn expires_at(json_content: &Value, file_path: &Path) -> Option<DateTime<Utc>> {
+    if let Some(expires_at) = json_content.get("_expires_at").and_then(Value::as_str) {
+        return DateTime::parse_from_rfc3339(expires_at)
+            .ok()
+            .map(|expires_at| expires_at.with_timezone(&Utc));
+    }
+
+    let ttl_seconds = json_content.get("_ttl_seconds").and_then(Value::as_i64)?;
+    let created_at = match json_content.get("_created_at").and_then(Value::as_str) {
+        Some(created_at) => DateTime::parse_from_rfc3339(created_at)
+            .ok()?
+            .with_timezone(&Utc),
+        None => DateTime::<Utc>::from(fs::metadata(file_path).ok()?.modified().ok()?),
+    };
+
+    // Clamped so absurd TTLs can't overflow the duration
+    let ttl_seconds = ttl_seconds.clamp(-MAX_TTL_SECONDS, MAX_TTL_SECONDS);
+    created_at.checked_add_signed(Duration::seconds(ttl_seconds))
+}
@@ -0,0 +1,320 @@
+/// Users and their per-pile rights.
+///
+/// Users live in the `.users` system pile, one document per user holding an
+/// Argon2 hash of their password and the rights granted to them:
+///
+/// {"password_hash":"$argon2id$...","grants":{"users":"WRITE","*":"ADMIN"}}
+///
+/// A grant on `*` applies to every pile, and an `ADMIN` grant on `*` is needed
+/// for server wide commands such as managing users. As long as no user exists
+/// the server stays open, and the first user created is made an admin of `*`.
+/// Once users exist, requests authenticate by prefixing the command with
+/// `AUTH <user> <password>`.
+use crate::pile::pile_path;
+use crate::OPTION_PREFIXES;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde_json::{from_str, json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const USERS_PILE: &str = ".users";
+pub const ALL_PILES: &str = "*";
+
+/// Serializes read-modify-write cycles on user documents
+static USERS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Rights a user can hold on a pile, where each right implies the ones before
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Right {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Right {
+    pub fn parse(input: &str) -> Option<Right> {
+        match input {
+            "READ" => Some(Right::Read),
+            "WRITE" => Some(Right::Write),
+            "ADMIN" => Some(Right::Admin),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Right::Read => "READ",
+            Right::Write => "WRITE",
+            Right::Admin => "ADMIN",
+        }
+    }
+}
+
+pub struct User {
+    pub name: String,
+    password_hash: String,
+    grants: HashMap<String, Right>,
+}
+
+impl User {
+    /// Whether the user holds `right` on `pile_name`, directly or through `*`
+    pub fn has_right(&self, pile_name: &str, right: Right) -> bool {
+        [pile_name, ALL_PILES]
+            .iter()
+            .filter_map(|pile_name| self.grants.get(*pile_name))
+            .any(|granted| *granted >= right)
+    }
+
+    fn to_json(&self) -> Value {
+        let grants: Map<String, Value> = self
+            .grants
+            .iter()
+            .map(|(pile_name, right)| (pile_name.clone(), Value::from(right.as_str())))
+            .collect();
+
+        json!({
+            "password_hash": self.password_hash,
+            "grants": grants,
+        })
+    }
+
+    fn from_json(name: &str, json_content: &Value) -> Option<User> {
+        let grants = json_content
+            .get("grants")
+            .and_then(Value::as_object)
+            .map(|grants| {
+                grants
+                    .iter()
+                    .filter_map(|(pile_name, right)| {
+                        Some((pile_name.clone(), Right::parse(right.as_str()?)?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(User {
+            name: name.to_owned(),
+            password_hash: json_content.get("password_hash")?.as_str()?.to_owned(),
+            grants,
+        })
+    }
+}
+
+/// Example:
+/// in: CREATE USER matthew hunter2
+/// out:
+pub fn create(name: &str, password: &str) -> Result<(), io::Error> {
+    let _guard = USERS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    if user_file_path(name)?.exists() {
+        let e_kind = io::ErrorKind::AlreadyExists;
+        let e = format!("User \"{}\" already exists", name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(password_hash) => password_hash.to_string(),
+        Err(e) => return Err(io::Error::other(e.to_string())),
+    };
+
+    // Whoever sets up the first user becomes the admin of the whole server
+    let mut grants = HashMap::new();
+    if !any_exist()? {
+        grants.insert(ALL_PILES.to_owned(), Right::Admin);
+    }
+
+    save(&User {
+        name: name.to_owned(),
+        password_hash,
+        grants,
+    })
+}
+
+/// Example:
+/// in: GRANT matthew users WRITE
+/// out:
+pub fn grant(name: &str, pile_name: &str, right: Right) -> Result<(), io::Error> {
+    let _guard = USERS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut user = load(name)?;
+    user.grants.insert(pile_name.to_owned(), right);
+    save(&user)
+}
+
+/// Example:
+/// in: REVOKE matthew users
+/// out:
+pub fn revoke(name: &str, pile_name: &str) -> Result<(), io::Error> {
+    let _guard = USERS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut user = load(name)?;
+    user.grants.remove(pile_name);
+    save(&user)
+}
+
+/// Verifies a user's password, failing with the same error whether the user
+/// doesn't exist or the password is wrong
+pub fn authenticate(name: &str, password: &str) -> Result<User, io::Error> {
+    let denied = || io::Error::new(io::ErrorKind::PermissionDenied, "Invalid credentials");
+
+    let user = match is_valid_user_name(name) {
+        true => load(name).map_err(|_| denied())?,
+        false => return Err(denied()),
+    };
+
+    let verified = PasswordHash::new(&user.password_hash).is_ok_and(|password_hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok()
+    });
+
+    match verified {
+        true => Ok(user),
+        false => Err(denied()),
+    }
+}
+
+/// Whether any user exists, i.e. whether requests must authenticate
+pub fn any_exist() -> Result<bool, io::Error> {
+    match fs::read_dir(pile_path(USERS_PILE)?) {
+        Ok(entries) => Ok(entries
+            .filter_map(|entry| entry.ok())
+            .any(|entry| !entry.file_name().to_string_lossy().starts_with('.'))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Masks passwords (and webhook secrets) in a request line before it is
+/// written to the logs. The line may not parse, so the `AUTH` and option
+/// prefixes in front of the command are stepped over however they are
+/// ordered.
+pub fn redact(line: &str) -> String {
+    let mut redacted = String::new();
+    let mut rest = line;
+    loop {
+        let parts: Vec<&str> = rest.splitn(4, ' ').collect();
+        match parts.as_slice() {
+            ["AUTH", name, _, command] => {
+                redacted.push_str(&format!("AUTH {} *** ", name));
+                rest = command;
+            }
+            ["AUTH", name, ..] => return format!("{}AUTH {} ***", redacted, name),
+            [option, value, ..] if OPTION_PREFIXES.contains(option) && parts.len() > 2 => {
+                redacted.push_str(&format!("{} {} ", option, value));
+                rest = &rest[option.len() + value.len() + 2..];
+            }
+            ["CREATE", "USER", name, _] => return format!("{}CREATE USER {} ***", redacted, name),
+            ["WEBHOOK", ..] => {
+                let mut words: Vec<&str> = rest.split(' ').collect();
+                for index in 1..words.len() {
+                    if words[index - 1] == "SECRET" {
+                        words[index] = "***";
+                    }
+                }
+                return format!("{}{}", redacted, words.join(" "));
+            }
+            _ => return format!("{}{}", redacted, rest),
+        }
+    }
+}
+
+fn load(name: &str) -> Result<User, io::Error> {
+    let file_content = match fs::read_to_string(user_file_path(name)?) {
+        Ok(file_content) => file_content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = format!("User \"{}\" does not exist", name);
+            return Err(io::Error::new(e_kind, e));
+        }
+        Err(e) => return Err(e),
+    };
+
+    match User::from_json(name, &from_str(&file_content)?) {
+        Some(user) => Ok(user),
+        None => {
+            let e_kind = io::ErrorKind::InvalidData;
+            let e = format!("User \"{}\" is invalid", name);
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+fn save(user: &User) -> Result<(), io::Error> {
+    let users_path = pile_path(USERS_PILE)?;
+    fs::create_dir_all(&users_path)?;
+
+    let file_path = user_file_path(&user.name)?;
+    let tmp_path = Path::new(&users_path).join(format!(".{}.tmp", user.name));
+    fs::write(&tmp_path, user.to_json().to_string())?;
+    fs::rename(tmp_path, file_path)
+}
+
+/// Every user file is reached through here, so a name that could step
+/// outside `.users` (e.g. `GRANT ../x ...`) is refused before it touches disk.
+fn user_file_path(name: &str) -> Result<PathBuf, io::Error> {
+    if !is_valid_user_name(name) {
+        let e_kind = io::ErrorKind::InvalidInput;
+        let e = format!("Invalid user name: \"{}\"", name);
+        return Err(io::Error::new(e_kind, e));
+    }
+
+    Ok(Path::new(&pile_path(USERS_PILE)?).join(format!("{}.json", name)))
+}
+
+fn is_valid_user_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_passwords_and_secrets() {
+        let cases = [
+            (
+                "AUTH app hunter2 FIND users age 18",
+                "AUTH app *** FIND users age 18",
+            ),
+            (
+                "REQUEST r1 AUTH app hunter2 READ users x",
+                "REQUEST r1 AUTH app *** READ users x",
+            ),
+            (
+                "FORMAT JSON CREATE USER app hunter2",
+                "FORMAT JSON CREATE USER app ***",
+            ),
+            (
+                "AUTH app hunter2 WEBHOOK users http://hooks.local EVENTS CREATE SECRET s3cr3t",
+                "AUTH app *** WEBHOOK users http://hooks.local EVENTS CREATE SECRET ***",
+            ),
+            (
+                "WEBHOOK users http://hooks.local SECRET s3cr3t",
+                "WEBHOOK users http://hooks.local SECRET ***",
+            ),
+            ("READ users x", "READ users x"),
+        ];
+        for (line, redacted) in cases {
+            assert_eq!(redact(line), redacted);
+        }
+    }
+
+    #[test]
+    fn refuses_names_outside_users() {
+        for name in ["../x", "a/b", "..", ""] {
+            let e = user_file_path(name).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            let e = load(name).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
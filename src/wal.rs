@@ -0,0 +1,291 @@
+/// Write-ahead log (WAL) for DustDB.
+///
+/// Every mutating operation is appended to the WAL as a single JSON line
+/// *before* it is applied to the piles on disk. Replaying the log in order
+/// onto a base snapshot of the storage root brings it back to any point in
+/// time covered by the log.
+///
+/// Appends are synced to disk before they're acknowledged, unless the
+/// write's concern says otherwise (see write_concern.rs).
+use crate::write_concern;
+use chrono::{DateTime, Utc};
+use dustcfg::get_env_var;
+use serde_json::{from_str, json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// Serializes appends from concurrently running client tasks
+static WAL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where each appended entry that isn't applied to the piles yet starts
+static IN_FLIGHT: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// Operations that are recorded in the WAL
+pub enum WalOp {
+    Create {
+        pile: String,
+        uuid: String,
+        data: String,
+    },
+    Delete {
+        pile: String,
+        uuid: String,
+    },
+    Tombstone {
+        pile: String,
+        uuid: String,
+    },
+    Restore {
+        pile: String,
+        uuid: String,
+    },
+}
+
+pub struct WalEntry {
+    pub timestamp: DateTime<Utc>,
+    pub op: WalOp,
+}
+
+impl WalEntry {
+    pub fn new(op: WalOp) -> WalEntry {
+        WalEntry {
+            timestamp: Utc::now(),
+            op,
+        }
+    }
+
+    fn serialize(&self) -> String {
+        match self.op {
+            WalOp::Create {
+                ref pile,
+                ref uuid,
+                ref data,
+            } => json!({
+                "timestamp": self.timestamp.to_rfc3339(),
+                "op": "CREATE",
+                "pile": pile,
+                "uuid": uuid,
+                "data": data,
+            })
+            .to_string(),
+            WalOp::Delete { ref pile, ref uuid } => json!({
+                "timestamp": self.timestamp.to_rfc3339(),
+                "op": "DELETE",
+                "pile": pile,
+                "uuid": uuid,
+            })
+            .to_string(),
+            WalOp::Tombstone { ref pile, ref uuid } => json!({
+                "timestamp": self.timestamp.to_rfc3339(),
+                "op": "TOMBSTONE",
+                "pile": pile,
+                "uuid": uuid,
+            })
+            .to_string(),
+            WalOp::Restore { ref pile, ref uuid } => json!({
+                "timestamp": self.timestamp.to_rfc3339(),
+                "op": "RESTORE",
+                "pile": pile,
+                "uuid": uuid,
+            })
+            .to_string(),
+        }
+    }
+
+    fn parse(line: &str) -> Result<WalEntry, io::Error> {
+        let json_content: Value = from_str(line)?;
+
+        let get_str = |key: &str| -> Result<String, io::Error> {
+            match json_content.get(key).and_then(Value::as_str) {
+                Some(value) => Ok(value.to_owned()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("WAL entry is missing field \"{}\"", key),
+                )),
+            }
+        };
+
+        let timestamp = match DateTime::parse_from_rfc3339(&get_str("timestamp")?) {
+            Ok(timestamp) => timestamp.with_timezone(&Utc),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        let op = match get_str("op")?.as_str() {
+            "CREATE" => WalOp::Create {
+                pile: get_str("pile")?,
+                uuid: get_str("uuid")?,
+                data: get_str("data")?,
+            },
+            "DELETE" => WalOp::Delete {
+                pile: get_str("pile")?,
+                uuid: get_str("uuid")?,
+            },
+            "TOMBSTONE" => WalOp::Tombstone {
+                pile: get_str("pile")?,
+                uuid: get_str("uuid")?,
+            },
+            "RESTORE" => WalOp::Restore {
+                pile: get_str("pile")?,
+                uuid: get_str("uuid")?,
+            },
+            op => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown WAL operation: {}", op),
+                ))
+            }
+        };
+
+        Ok(WalEntry { timestamp, op })
+    }
+}
+
+/// An appended entry that is still being applied to the piles. Until it is
+/// dropped, a backup replays the WAL from no later than where the entry
+/// starts (see `replay_offset`).
+#[must_use = "the entry only counts as in flight until this is dropped"]
+pub struct Applying {
+    offset: u64,
+}
+
+impl Drop for Applying {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = in_flight.iter().position(|offset| *offset == self.offset) {
+            in_flight.swap_remove(index);
+        }
+    }
+}
+
+/// Appends the entry to the end of the WAL, creating the log if needed. Hold
+/// on to the returned `Applying` until the entry is applied.
+pub fn append(entry: &WalEntry) -> Result<Applying, io::Error> {
+    append_all(std::slice::from_ref(entry))
+}
+
+/// Appends several entries with a single sync, e.g. for a bulk write
+pub fn append_all(entries: &[WalEntry]) -> Result<Applying, io::Error> {
+    let _guard = WAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut wal_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_env_var("DUST_WAL_PATH"))?;
+
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&entry.serialize());
+        lines.push('\n');
+    }
+
+    let offset = wal_file.metadata()?.len();
+    IN_FLIGHT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(offset);
+    let applying = Applying { offset };
+
+    wal_file.write_all(lines.as_bytes())?;
+    write_concern::sync_wal(&wal_file)?;
+    Ok(applying)
+}
+
+/// Returns the offset of the end of the log
+pub fn end_offset() -> Result<u64, io::Error> {
+    let _guard = WAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    wal_len()
+}
+
+/// Returns the offset a copy of the piles taken from now on is complete up
+/// to: the start of the oldest entry still being applied, or else the end of
+/// the log. Replaying the WAL from there onto the copy loses no write.
+pub fn replay_offset() -> Result<u64, io::Error> {
+    let _guard = WAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let in_flight = IN_FLIGHT.lock().unwrap_or_else(|e| e.into_inner());
+
+    match in_flight.iter().min() {
+        Some(offset) => Ok(*offset),
+        None => wal_len(),
+    }
+}
+
+fn wal_len() -> Result<u64, io::Error> {
+    match fs::metadata(get_env_var("DUST_WAL_PATH")) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads every entry in the WAL up to and including `until`, in log order
+pub fn read_until(until: &DateTime<Utc>) -> Result<Vec<WalEntry>, io::Error> {
+    let wal_content = match fs::read_to_string(get_env_var("DUST_WAL_PATH")) {
+        Ok(wal_content) => wal_content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    parse_entries(&wal_content, Some(until))
+}
+
+/// Cuts off an entry left half written at the end of the log (e.g. by a
+/// crash mid-append), returning how many bytes were dropped. The next append
+/// would otherwise be glued onto the torn line.
+pub fn truncate_torn_tail() -> Result<u64, io::Error> {
+    let _guard = WAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let wal_path = get_env_var("DUST_WAL_PATH");
+    let wal_bytes = match fs::read(&wal_path) {
+        Ok(wal_bytes) => wal_bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    // Every complete entry ends with a newline
+    let valid_len = match wal_bytes.iter().rposition(|byte| *byte == b'\n') {
+        Some(newline) => newline + 1,
+        None => 0,
+    };
+    if valid_len == wal_bytes.len() {
+        return Ok(0);
+    }
+
+    let wal_file = OpenOptions::new().write(true).open(&wal_path)?;
+    wal_file.set_len(valid_len as u64)?;
+    wal_file.sync_data()?;
+    Ok((wal_bytes.len() - valid_len) as u64)
+}
+
+/// Returns the raw WAL bytes appended since `offset`, together with the offset
+/// of the end of the log. Holding the lock guarantees the chunk ends on an
+/// entry boundary.
+pub fn read_since(offset: u64) -> Result<(Vec<u8>, u64), io::Error> {
+    let _guard = WAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let wal_bytes = match fs::read(get_env_var("DUST_WAL_PATH")) {
+        Ok(wal_bytes) => wal_bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let start = (offset as usize).min(wal_bytes.len());
+    Ok((wal_bytes[start..].to_vec(), wal_bytes.len() as u64))
+}
+
+/// Parses a chunk of WAL content (e.g. an archived segment), stopping at the
+/// first entry newer than `until` if one is given
+pub fn parse_entries(
+    wal_content: &str,
+    until: Option<&DateTime<Utc>>,
+) -> Result<Vec<WalEntry>, io::Error> {
+    let mut entries = Vec::new();
+    for line in wal_content.lines().filter(|line| !line.trim().is_empty()) {
+        let entry = WalEntry::parse(line)?;
+        if until.is_some_and(|until| entry.timestamp > *until) {
+            break;
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,459 @@
+/// Outbound webhooks on committed writes.
+///
+/// `WEBHOOK <pile> <url> [EVENTS <events>] [SECRET <secret>]` has the server
+/// POST every committed write to the pile to the URL (a comma separated list
+/// of CREATE, UPDATE and DELETE narrows it down, all three by default), and
+/// `WEBHOOK <pile> REMOVE <url>` stops it. Updates include every change made
+/// to a stored document (UPDATE, PATCH, INCR, migrations, ...), deletes the
+/// ones made by clients, as for triggers (see triggers.rs). The body is the
+/// event as JSON:
+///
+/// {"event":"UPDATE","pile":"users","id":"cd8abd45-...","document":{..},"timestamp":"..."}
+///
+/// with headers naming the event (`X-Dust-Event`), the delivery
+/// (`X-Dust-Delivery`, the same on every attempt) and, when the webhook has a
+/// secret, signing the body (`X-Dust-Signature: sha256=<hex HMAC-SHA256 of the
+/// body keyed by the secret>`). Any 2xx response counts as delivered.
+///
+/// Each URL has a background thread of its own, delivering to it one event
+/// at a time in the order the writes were committed, so a slow or dead
+/// receiver only holds up itself; deliveries never hold up or undo the
+/// write. A failed delivery is retried `DUST_WEBHOOK_MAX_ATTEMPTS` (default
+/// 5) times in all, waiting twice as long after each attempt (from one
+/// second, at most a minute), each attempt timing out after
+/// `DUST_WEBHOOK_TIMEOUT_SECS` (default 10). A delivery that still fails is
+/// appended to the dead letter log, `DUST_WEBHOOK_DEAD_LETTER_PATH` (default
+/// `webhooks.dead.jsonl`), one JSON line per event, and so is every event
+/// past the `DUST_WEBHOOK_QUEUE_SIZE` (default 1000) waiting for a URL.
+/// Pending deliveries are lost on shutdown. Only plain `http://` URLs are
+/// supported; put a TLS terminating proxy in between to reach an `https://`
+/// receiver.
+use crate::logging::{self, Level};
+use crate::pile::{self, PileMeta};
+use crate::{env_or, timestamp_now};
+use dustcfg::generate_v4_uuid;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A URL's delivery thread stops after this long without an event, and is
+/// started again by the next one
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    Create,
+    Update,
+    Delete,
+}
+
+impl WebhookEvent {
+    pub fn parse(input: &str) -> Option<WebhookEvent> {
+        match input {
+            "CREATE" => Some(WebhookEvent::Create),
+            "UPDATE" => Some(WebhookEvent::Update),
+            "DELETE" => Some(WebhookEvent::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Create => "CREATE",
+            WebhookEvent::Update => "UPDATE",
+            WebhookEvent::Delete => "DELETE",
+        }
+    }
+}
+
+pub struct Webhook {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn from_json(json_content: &Value) -> Option<Webhook> {
+        Some(Webhook {
+            url: json_content.get("url")?.as_str()?.to_owned(),
+            events: json_content
+                .get("events")?
+                .as_array()?
+                .iter()
+                .filter_map(|event| WebhookEvent::parse(event.as_str()?))
+                .collect(),
+            secret: json_content
+                .get("secret")
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "url": self.url,
+            "events": self.events.iter().map(WebhookEvent::as_str).collect::<Vec<&str>>(),
+            "secret": self.secret,
+        })
+    }
+}
+
+/// A URL split into what the request needs
+struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<HttpUrl, io::Error> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Webhook URL must start with http://: \"{}\"", url);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()),
+        _ => (authority, Some(80)),
+    };
+    match port {
+        Some(port) if !host.is_empty() => Ok(HttpUrl {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        }),
+        _ => {
+            let e_kind = io::ErrorKind::InvalidInput;
+            let e = format!("Invalid webhook URL: \"{}\"", url);
+            Err(io::Error::new(e_kind, e))
+        }
+    }
+}
+
+/// Example:
+/// in: WEBHOOK orders http://billing.internal:8080/hooks/orders EVENTS CREATE,UPDATE SECRET s3cr3t
+/// out:
+///
+/// Adding a webhook for a URL the pile already has replaces it
+pub fn add(pile_name: &str, webhook: Webhook) -> Result<(), io::Error> {
+    parse_url(&webhook.url)?;
+
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut webhooks = pile_meta.webhooks();
+    webhooks.retain(|existing| existing.url != webhook.url);
+    webhooks.push(webhook);
+
+    pile_meta.set(
+        "webhooks",
+        Value::Array(webhooks.iter().map(Webhook::to_json).collect()),
+    );
+    pile_meta.save()
+}
+
+/// Example:
+/// in: WEBHOOK orders REMOVE http://billing.internal:8080/hooks/orders
+/// out:
+pub fn remove(pile_name: &str, url: &str) -> Result<(), io::Error> {
+    let pile_lock = pile::lock(pile_name);
+    let _guard = pile_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut pile_meta = PileMeta::load(pile_name)?;
+    let mut webhooks = pile_meta.webhooks();
+    if !webhooks.iter().any(|webhook| webhook.url == url) {
+        let e_kind = io::ErrorKind::NotFound;
+        let e = format!("Pile \"{}\" has no webhook for \"{}\"", pile_name, url);
+        return Err(io::Error::new(e_kind, e));
+    }
+    webhooks.retain(|webhook| webhook.url != url);
+
+    pile_meta.set(
+        "webhooks",
+        Value::Array(webhooks.iter().map(Webhook::to_json).collect()),
+    );
+    pile_meta.save()
+}
+
+struct Delivery {
+    id: String,
+    url: String,
+    secret: Option<String>,
+    event: WebhookEvent,
+    body: String,
+}
+
+/// The queue of each URL's delivery thread. Deliveries are only queued with
+/// the lock held, which lets an idle thread remove its queue safely.
+fn queues() -> &'static Mutex<HashMap<String, mpsc::SyncSender<Delivery>>> {
+    static QUEUES: OnceLock<Mutex<HashMap<String, mpsc::SyncSender<Delivery>>>> = OnceLock::new();
+    QUEUES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn queue_size() -> usize {
+    env_or("DUST_WEBHOOK_QUEUE_SIZE", 1000_usize).max(1)
+}
+
+/// Queues the delivery for its URL's thread, starting one if needed. Gives
+/// the delivery back if the queue is full.
+fn enqueue(
+    queues: &mut HashMap<String, mpsc::SyncSender<Delivery>>,
+    delivery: Delivery,
+) -> Result<(), Delivery> {
+    let delivery = match queues.get(&delivery.url) {
+        Some(queue) => match queue.try_send(delivery) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::TrySendError::Full(delivery)) => return Err(delivery),
+            // The thread is gone, e.g. it panicked
+            Err(mpsc::TrySendError::Disconnected(delivery)) => delivery,
+        },
+        None => delivery,
+    };
+
+    let (queue, receiver) = mpsc::sync_channel(queue_size());
+    let url = delivery.url.clone();
+    // Can't fail, the queue is new and empty
+    let _ = queue.try_send(delivery);
+    queues.insert(url.clone(), queue);
+    thread::spawn(move || deliver_all(&url, receiver));
+    Ok(())
+}
+
+/// Delivers the URL's events as they're queued, until it's been idle for
+/// `WORKER_IDLE_TIMEOUT`
+fn deliver_all(url: &str, receiver: mpsc::Receiver<Delivery>) {
+    loop {
+        match receiver.recv_timeout(WORKER_IDLE_TIMEOUT) {
+            Ok(delivery) => deliver(&delivery),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let mut queues = queues().lock().unwrap_or_else(|e| e.into_inner());
+                // Nothing can be queued while the lock is held
+                match receiver.try_recv() {
+                    Ok(delivery) => {
+                        drop(queues);
+                        deliver(&delivery);
+                    }
+                    Err(_) => {
+                        queues.remove(url);
+                        return;
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Queues the write's event for every webhook of the pile that wants it.
+/// `document` is the document as stored, or as it was before a delete.
+pub fn notify(
+    pile_meta: &PileMeta,
+    event: WebhookEvent,
+    pile_name: &str,
+    uuid: &str,
+    document: Option<&Value>,
+) {
+    let webhooks = pile_meta.webhooks();
+    if !webhooks
+        .iter()
+        .any(|webhook| webhook.events.contains(&event))
+    {
+        return;
+    }
+
+    let body = json!({
+        "event": event.as_str(),
+        "pile": pile_name,
+        "id": uuid,
+        "document": document,
+        "timestamp": timestamp_now(),
+    })
+    .to_string();
+
+    let mut overflowed = Vec::new();
+    let mut queues = queues().lock().unwrap_or_else(|e| e.into_inner());
+    for webhook in webhooks {
+        if webhook.events.contains(&event) {
+            let delivery = Delivery {
+                id: generate_v4_uuid(),
+                url: webhook.url,
+                secret: webhook.secret,
+                event,
+                body: body.clone(),
+            };
+            if let Err(delivery) = enqueue(&mut queues, delivery) {
+                overflowed.push(delivery);
+            }
+        }
+    }
+    drop(queues);
+
+    for delivery in overflowed {
+        let e_kind = io::ErrorKind::WouldBlock;
+        let e = format!("Over {} deliveries are waiting for the URL", queue_size());
+        dead_letter(&delivery, 0, &io::Error::new(e_kind, e));
+    }
+}
+
+fn deliver(delivery: &Delivery) {
+    let max_attempts: u32 = env_or("DUST_WEBHOOK_MAX_ATTEMPTS", 5u32).max(1);
+    let mut retry_delay = Duration::from_secs(1);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let e = match post(delivery) {
+            Ok(_) => return,
+            Err(e) => e,
+        };
+
+        if attempts >= max_attempts {
+            logging::diagnostic(
+                Level::Error,
+                &format!(
+                    "Giving up on webhook delivery {} to {} after {} attempt(s): {}",
+                    delivery.id, delivery.url, attempts, e
+                ),
+            );
+            dead_letter(delivery, attempts, &e);
+            return;
+        }
+
+        thread::sleep(retry_delay);
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+fn post(delivery: &Delivery) -> Result<(), io::Error> {
+    let url = parse_url(&delivery.url)?;
+    let timeout = Duration::from_secs(env_or("DUST_WEBHOOK_TIMEOUT_SECS", 10u64).max(1));
+
+    let addr = match (url.host.trim_matches(['[', ']']), url.port)
+        .to_socket_addrs()?
+        .next()
+    {
+        Some(addr) => addr,
+        None => {
+            let e_kind = io::ErrorKind::NotFound;
+            let e = format!("Could not resolve webhook host: \"{}\"", url.host);
+            return Err(io::Error::new(e_kind, e));
+        }
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nX-Dust-Event: {}\r\nX-Dust-Delivery: {}\r\n",
+        url.path,
+        url.host,
+        url.port,
+        delivery.body.len(),
+        delivery.event.as_str(),
+        delivery.id
+    );
+    if let Some(ref secret) = delivery.secret {
+        let signature = sign(secret, &delivery.body)?;
+        request.push_str(&format!("X-Dust-Signature: sha256={}\r\n", signature));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    request.push_str(&delivery.body);
+    stream.write_all(request.as_bytes())?;
+
+    // Only the status line matters: "HTTP/1.1 204 No Content"
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 512];
+    while !response.contains(&b'\n') {
+        match stream.read(&mut buffer)? {
+            0 => break,
+            read => response.extend_from_slice(&buffer[..read]),
+        }
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') && status.len() == 3 => Ok(()),
+        _ => {
+            let e = format!("Webhook receiver answered \"{}\"", status_line);
+            Err(io::Error::other(e))
+        }
+    }
+}
+
+fn dead_letter(delivery: &Delivery, attempts: u32, e: &io::Error) {
+    let dead_letter_path = env_or(
+        "DUST_WEBHOOK_DEAD_LETTER_PATH",
+        "webhooks.dead.jsonl".to_owned(),
+    );
+    let line = json!({
+        "failed_at": timestamp_now(),
+        "delivery": delivery.id,
+        "url": delivery.url,
+        "attempts": attempts,
+        "error": e.to_string(),
+        "event": serde_json::from_str::<Value>(&delivery.body).unwrap_or(Value::Null),
+    });
+
+    let appended = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&dead_letter_path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = appended {
+        logging::diagnostic(
+            Level::Error,
+            &format!(
+                "Error writing webhook dead letter log {}: {:?}",
+                dead_letter_path, e
+            ),
+        );
+    }
+}
+
+/// The hex HMAC-SHA256 of the body, keyed by the secret
+fn sign(secret: &str, body: &str) -> Result<String, io::Error> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    mac.update(body.as_bytes());
+    Ok(mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4231, test cases 1 and 2
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign(&"\u{0b}".repeat(20), "Hi There").unwrap(),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+/// Write concerns.
+///
+/// How durable a write is by the time it's acknowledged, picked per command
+/// with the `WRITECONCERN <level>` option (see `CommandOptions`), or for the
+/// whole server with `DUST_WRITE_CONCERN`:
+///
+/// BUFFERED  the WAL append is left for the OS to flush, so an acknowledged
+///           write can be lost if the machine (not just the server) crashes
+/// WRITTEN   the WAL append is synced to disk before the write is applied,
+///           so it survives any crash through WAL replay (the default)
+/// FSYNC     the written documents and their piles' directories are synced
+///           too, so they're durable on their own, without a replay
+///
+/// The level applies to every write the command makes, including those of
+/// triggers it fires. Background writes (e.g. expiry) use the server's level.
+use crate::env_or;
+use std::cell::Cell;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WriteConcern {
+    Buffered,
+    Written,
+    Fsync,
+}
+
+impl std::str::FromStr for WriteConcern {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<WriteConcern, String> {
+        match input {
+            "BUFFERED" => Ok(WriteConcern::Buffered),
+            "WRITTEN" => Ok(WriteConcern::Written),
+            "FSYNC" => Ok(WriteConcern::Fsync),
+            _ => Err(format!("Unknown write concern: {}", input)),
+        }
+    }
+}
+
+impl WriteConcern {
+    pub fn server_default() -> WriteConcern {
+        env_or("DUST_WRITE_CONCERN", WriteConcern::Written)
+    }
+}
+
+thread_local! {
+    static WRITE_CONCERN: Cell<Option<WriteConcern>> = const { Cell::new(None) };
+}
+
+/// Applies the level to the writes made on this thread until the returned
+/// guard is dropped
+pub fn scoped(write_concern: WriteConcern) -> ScopedWriteConcern {
+    ScopedWriteConcern {
+        previous: WRITE_CONCERN.with(|current| current.replace(Some(write_concern))),
+    }
+}
+
+pub struct ScopedWriteConcern {
+    previous: Option<WriteConcern>,
+}
+
+impl Drop for ScopedWriteConcern {
+    fn drop(&mut self) {
+        WRITE_CONCERN.with(|current| current.set(self.previous));
+    }
+}
+
+fn current() -> WriteConcern {
+    WRITE_CONCERN
+        .with(Cell::get)
+        .unwrap_or_else(WriteConcern::server_default)
+}
+
+/// Syncs an append to the WAL, unless the write is only to be buffered
+pub fn sync_wal(wal_file: &File) -> Result<(), io::Error> {
+    match current() {
+        WriteConcern::Buffered => Ok(()),
+        WriteConcern::Written | WriteConcern::Fsync => wal_file.sync_data(),
+    }
+}
+
+/// Syncs a document's file (if it still exists) and its pile's directory,
+/// which records the file being added or removed, when the write asks for it
+pub fn sync_document(file_path: &Path) -> Result<(), io::Error> {
+    if current() != WriteConcern::Fsync {
+        return Ok(());
+    }
+
+    match File::open(file_path) {
+        Ok(file) => file.sync_all()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    match file_path.parent() {
+        Some(dir_path) if dir_path.is_dir() => File::open(dir_path)?.sync_all(),
+        _ => Ok(()),
+    }
+}